@@ -10,6 +10,7 @@ use std::net;
 use types::*;
 use utils::{assume_str, replace_default};
 use version_vector::*;
+use vnode::is_reserved_key;
 
 #[derive(Debug)]
 pub enum CommandError {
@@ -31,6 +32,7 @@ pub enum CommandError {
     MultiplePartitions,
     MultipleKeyMutations,
     Unavailable,
+    ValueTooLarge,
 }
 
 impl Into<RespValue> for CommandError {
@@ -53,6 +55,22 @@ fn parse_int<T: ::std::str::FromStr + Default>(
     }
 }
 
+fn parse_consistency(
+    try: bool,
+    args: &[&Bytes],
+    i: usize,
+    default: ConsistencyLevel,
+) -> Result<ConsistencyLevel, CommandError> {
+    if try {
+        args[i]
+            .as_ref()
+            .try_into()
+            .map_err(|_| CommandError::InvalidConsistencyValue)
+    } else {
+        Ok(default)
+    }
+}
+
 fn check_arg_count(count: usize, min: usize, max: usize) -> Result<(), CommandError> {
     if count < min || count > max {
         Err(CommandError::InvalidArgCount)
@@ -69,6 +87,15 @@ fn check_key_len(key_len: usize) -> Result<(), CommandError> {
     }
 }
 
+fn check_key(key: &[u8]) -> Result<(), CommandError> {
+    check_key_len(key.len())?;
+    if is_reserved_key(key) {
+        Err(CommandError::InvalidKey)
+    } else {
+        Ok(())
+    }
+}
+
 fn check_value_len(value_len: usize) -> Result<(), CommandError> {
     if value_len > config::MAX_VALUE_LEN {
         Err(CommandError::InvalidKey)
@@ -116,6 +143,7 @@ impl Database {
                 b"CSET" | b"cset" => self.cmd_cset(context, args),
                 b"INCRBY" | b"incrby" => self.cmd_incrby(context, args),
                 b"SET" | b"set" => self.cmd_set(context, args, false),
+                b"SETNX" | b"setnx" => self.cmd_setnx(context, args),
                 b"HSET" | b"hset" => self.cmd_hset(context, args),
                 b"HDEL" | b"hdel" => self.cmd_hdel(context, args),
                 b"SADD" | b"sadd" => self.cmd_sadd(context, args),
@@ -141,10 +169,12 @@ impl Database {
                 b"GET" | b"get" => self.cmd_get(context, args),
                 b"MGET" | b"mget" => self.cmd_mget(context, args),
                 b"SET" | b"set" => self.cmd_set(context, args, false),
+                b"SETNX" | b"setnx" => self.cmd_setnx(context, args),
                 b"CGET" | b"cget" => self.cmd_cget(context, args),
                 b"CSET" | b"cset" => self.cmd_cset(context, args),
                 b"INCRBY" | b"incrby" => self.cmd_incrby(context, args),
                 b"HGETALL" | b"hgetall" => self.cmd_hgetall(context, args),
+                b"HGET" | b"hget" => self.cmd_hget(context, args),
                 b"HSET" | b"hset" => self.cmd_hset(context, args),
                 b"HDEL" | b"hdel" => self.cmd_hdel(context, args),
                 b"SMEMBERS" | b"smembers" => self.cmd_smembers(context, args),
@@ -183,20 +213,32 @@ impl Database {
         }
     }
 
-    fn parse_consistency(
+    fn parse_read_consistency(
         &self,
         try: bool,
         args: &[&Bytes],
         i: usize,
     ) -> Result<ConsistencyLevel, CommandError> {
-        Ok(if try {
-            args[i]
-                .as_ref()
-                .try_into()
-                .map_err(|_| CommandError::InvalidConsistencyValue)?
-        } else {
-            self.config.consistency_read
-        })
+        let default = self
+            .cluster_config()
+            .1
+            .consistency_read
+            .unwrap_or(self.config.consistency_read);
+        parse_consistency(try, args, i, default)
+    }
+
+    fn parse_write_consistency(
+        &self,
+        try: bool,
+        args: &[&Bytes],
+        i: usize,
+    ) -> Result<ConsistencyLevel, CommandError> {
+        let default = self
+            .cluster_config()
+            .1
+            .consistency_write
+            .unwrap_or(self.config.consistency_write);
+        parse_consistency(try, args, i, default)
     }
 
     fn cmd_multi(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
@@ -211,7 +253,7 @@ impl Database {
             return Err(CommandError::InvalidExec);
         }
         check_arg_count(args.len(), 0, 1)?;
-        let consistency = self.parse_consistency(args.len() > 0, args, 0)?;
+        let consistency = self.parse_write_consistency(args.len() > 0, args, 0)?;
         assert!(!context.is_exec_cmd);
         context.is_exec_cmd = true;
         let mut cmds = replace_default(&mut context.commands);
@@ -230,20 +272,35 @@ impl Database {
     fn cmd_hgetall(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_GET.mark(1);
         check_arg_count(args.len(), 1, 2)?;
-        check_key_len(args[0].len())?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        check_key(args[0])?;
+        let consistency = self.parse_read_consistency(args.len() > 1, args, 1)?;
         self.get(context, args[0], consistency, Box::new(cubes::render_map))
     }
 
+    fn cmd_hget(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_GET.mark(1);
+        check_arg_count(args.len(), 2, 3)?;
+        check_key(args[0])?;
+        check_key_len(args[1].len())?;
+        let consistency = self.parse_read_consistency(args.len() > 2, args, 2)?;
+        self.get_field(
+            context,
+            args[0],
+            args[1],
+            consistency,
+            Box::new(cubes::render_map_field),
+        )
+    }
+
     fn cmd_hset(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_SET.mark(1);
         check_arg_count(args.len(), 3, 4)?;
-        check_key_len(args[0].len())?;
+        check_key(args[0])?;
         check_key_len(args[1].len())?;
         check_value_len(args[2].len())?;
         let hash_key = args[1].clone();
         let hash_value = args[2].clone();
-        let consistency = self.parse_consistency(args.len() > 3, args, 3)?;
+        let consistency = self.parse_write_consistency(args.len() > 3, args, 3)?;
         self.set(
             context,
             args[0],
@@ -261,10 +318,10 @@ impl Database {
     fn cmd_hdel(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_DEL.mark(1);
         check_arg_count(args.len(), 2, 3)?;
-        check_key_len(args[0].len())?;
+        check_key(args[0])?;
         check_key_len(args[1].len())?;
         let hash_key = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -282,18 +339,18 @@ impl Database {
     fn cmd_smembers(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_GET.mark(1);
         check_arg_count(args.len(), 1, 2)?;
-        check_key_len(args[0].len())?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        check_key(args[0])?;
+        let consistency = self.parse_read_consistency(args.len() > 1, args, 1)?;
         self.get(context, args[0], consistency, Box::new(cubes::render_set))
     }
 
     fn cmd_sadd(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_SET.mark(1);
         check_arg_count(args.len(), 2, 3)?;
-        check_key_len(args[0].len())?;
+        check_key(args[0])?;
         check_value_len(args[1].len())?;
         let set_value = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -311,10 +368,10 @@ impl Database {
     fn cmd_srem(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_DEL.mark(1);
         check_arg_count(args.len(), 2, 3)?;
-        check_key_len(args[0].len())?;
+        check_key(args[0])?;
         check_value_len(args[1].len())?;
         let set_value = args[1].clone();
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -332,8 +389,8 @@ impl Database {
     fn cmd_get(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_GET.mark(1);
         check_arg_count(args.len(), 1, 2)?;
-        check_key_len(args[0].len())?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        check_key(args[0])?;
+        let consistency = self.parse_read_consistency(args.len() > 1, args, 1)?;
         self.get(context, args[0], consistency, Box::new(cubes::render_value))
     }
 
@@ -347,9 +404,9 @@ impl Database {
         }
         let keys = &args[1..1 + key_count];
         let consistency =
-            self.parse_consistency(args.len() > 1 + key_count, args, 1 + key_count)?;
+            self.parse_read_consistency(args.len() > 1 + key_count, args, 1 + key_count)?;
         for key in keys {
-            check_key_len(key.len())?;
+            check_key(key)?;
         }
         self.mget(context, keys, consistency, Box::new(cubes::render_value))
     }
@@ -362,11 +419,11 @@ impl Database {
     ) -> Result<(), CommandError> {
         metrics::REQUEST_SET.mark(1);
         check_arg_count(args.len(), 2, 4)?;
-        check_key_len(args[0].len())?;
+        check_key(args[0])?;
         check_value_len(args[1].len())?;
         let value = args[1].clone();
         let vv = self.parse_vv(args.len() > 2, args, 2)?;
-        let consistency = self.parse_consistency(args.len() > 3, args, 3)?;
+        let consistency = self.parse_write_consistency(args.len() > 3, args, 3)?;
         self.set(
             context,
             args[0],
@@ -390,12 +447,35 @@ impl Database {
         )
     }
 
+    // Atomic as the coordinator vnode executes the mutator while locked,
+    // but setnx racing through different coordinators may all succeed.
+    fn cmd_setnx(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
+        metrics::REQUEST_SET.mark(1);
+        check_arg_count(args.len(), 2, 3)?;
+        check_key(args[0])?;
+        check_value_len(args[1].len())?;
+        let value = args[1].clone();
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
+        self.set(
+            context,
+            args[0],
+            Box::new(move |i, v, c: Cube| {
+                let mut cube_value = c.into_value().ok_or(CommandError::TypeError)?;
+                let result = cube_value.set_if_absent(i, v, value) as i64;
+                Ok((Cube::Value(cube_value), Some(RespValue::Int(result))))
+            }),
+            consistency,
+            false,
+            None,
+        )
+    }
+
     fn cmd_del(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_DEL.mark(1);
         check_arg_count(args.len(), 1, 3)?;
-        check_key_len(args[0].len())?;
+        check_key(args[0])?;
         let vv = self.parse_vv(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -412,9 +492,9 @@ impl Database {
     fn cmd_cset(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_SET.mark(1);
         check_arg_count(args.len(), 2, 3)?;
-        check_key_len(args[0].len())?;
+        check_key(args[0])?;
         let value: i64 = parse_int(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -433,8 +513,8 @@ impl Database {
     fn cmd_cget(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_GET.mark(1);
         check_arg_count(args.len(), 1, 2)?;
-        check_key_len(args[0].len())?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        check_key(args[0])?;
+        let consistency = self.parse_read_consistency(args.len() > 1, args, 1)?;
         self.get(
             context,
             args[0],
@@ -446,9 +526,9 @@ impl Database {
     fn cmd_incrby(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         metrics::REQUEST_SET.mark(1);
         check_arg_count(args.len(), 2, 3)?;
-        check_key_len(args[0].len())?;
+        check_key(args[0])?;
         let inc: i64 = parse_int(args.len() > 1, args, 1)?;
-        let consistency = self.parse_consistency(args.len() > 2, args, 2)?;
+        let consistency = self.parse_write_consistency(args.len() > 2, args, 2)?;
         self.set(
             context,
             args[0],
@@ -465,7 +545,8 @@ impl Database {
 
     fn cmd_type(&self, context: &mut Context, args: &[&Bytes]) -> Result<(), CommandError> {
         check_arg_count(args.len(), 1, 2)?;
-        let consistency = self.parse_consistency(args.len() > 1, args, 1)?;
+        check_key(args[0])?;
+        let consistency = self.parse_read_consistency(args.len() > 1, args, 1)?;
         self.get(context, args[0], consistency, Box::new(cubes::render_type))
     }
 
@@ -11,6 +11,8 @@ use log4rs;
 use num_cpus;
 use serde_yaml as yaml;
 
+use fabric::{FabricLimits, FabricRetryPolicy};
+use storage::StorageConfig;
 use types::ConsistencyLevel;
 use utils::GenericError;
 
@@ -39,16 +41,22 @@ pub struct Config {
     pub sync_timeout: u32,
     pub sync_msg_timeout: u32,
     pub sync_msg_inflight: u32,
+    pub sync_batch_size: u32,
     pub dht_sync_on_connect: bool,
     pub dht_sync_aae: bool,
     pub fabric_timeout: u32,
     pub request_timeout: u32,
+    pub idempotency_cache_size: u32,
+    pub idempotency_ttl: u32,
     pub client_connection_max: u32,
     pub value_version_max: u16,
     pub seed_nodes: Vec<SocketAddr>,
     // TODO: these should be in the cluster config instead
     pub consistency_read: ConsistencyLevel,
     pub consistency_write: ConsistencyLevel,
+    pub storage: StorageConfig,
+    pub fabric_limits: FabricLimits,
+    pub fabric_retry: FabricRetryPolicy,
 }
 
 impl Default for Config {
@@ -67,16 +75,22 @@ impl Default for Config {
             sync_timeout: 10_000,
             sync_msg_timeout: 1000,
             sync_msg_inflight: 10,
+            sync_batch_size: 1,
             sync_auto: true,
             dht_sync_on_connect: true,
             dht_sync_aae: true,
             fabric_timeout: 1000,
             request_timeout: 1000,
+            idempotency_cache_size: 10_000,
+            idempotency_ttl: 60_000,
             client_connection_max: 100,
             value_version_max: 100,
             seed_nodes: Vec::new(),
             consistency_read: ConsistencyLevel::One,
             consistency_write: ConsistencyLevel::One,
+            storage: Default::default(),
+            fabric_limits: Default::default(),
+            fabric_retry: Default::default(),
         }
     }
 }
@@ -184,8 +198,11 @@ pub fn read_config_file(path: &Path, config: &mut Config) {
     cfi!(yaml, config, sync_timeout, as_str, parse_duration);
     cfi!(yaml, config, sync_msg_timeout, as_str, parse_duration);
     cfi!(yaml, config, sync_msg_inflight, as_u64, try_into);
+    cfi!(yaml, config, sync_batch_size, as_u64, try_into);
     cfi!(yaml, config, fabric_timeout, as_str, parse_duration);
     cfi!(yaml, config, request_timeout, as_str, parse_duration);
+    cfi!(yaml, config, idempotency_cache_size, as_u64, try_into);
+    cfi!(yaml, config, idempotency_ttl, as_str, parse_duration);
     cfi!(yaml, config, client_connection_max, as_u64, try_into);
     cfi!(yaml, config, value_version_max, as_u64, try_into);
     cfi!(
@@ -216,6 +233,90 @@ pub fn read_config_file(path: &Path, config: &mut Config) {
             }).collect();
     }
 
+    if let Some(v) = yaml.get("log_memtable") {
+        config.storage.log_memtable = v
+            .as_str()
+            .expect("log_memtable is not a string")
+            .parse()
+            .expect("log_memtable can't be parsed");
+    }
+
+    if let Some(v) = yaml.get("inline_value_threshold") {
+        let threshold = parse_size(v.as_str().expect("inline_value_threshold is not a string"))
+            .expect("inline_value_threshold can't be parsed");
+        config.storage.inline_value_threshold = Some(threshold as usize);
+    }
+
+    if let Some(v) = yaml.get("max_total_wal_size") {
+        let size = parse_size(v.as_str().expect("max_total_wal_size is not a string"))
+            .expect("max_total_wal_size can't be parsed");
+        config.storage.max_total_wal_size = Some(size as u64);
+    }
+
+    if let Some(v) = yaml.get("recycle_log_file_num") {
+        config.storage.recycle_log_file_num =
+            Some(v.as_u64().expect("recycle_log_file_num is not a number"));
+    }
+
+    if let Some(v) = yaml.get("block_cache_shard_bits") {
+        config.storage.block_cache_shard_bits =
+            Some(v.as_u64().expect("block_cache_shard_bits is not a number") as u32);
+    }
+
+    if let Some(v) = yaml.get("log_column_families") {
+        config.storage.log_column_families =
+            v.as_u64().expect("log_column_families is not a number") as usize;
+    }
+
+    if let Some(v) = yaml.get("level0_file_num_compaction_trigger") {
+        config.storage.level0_file_num_compaction_trigger = Some(
+            v.as_u64()
+                .expect("level0_file_num_compaction_trigger is not a number") as u32,
+        );
+    }
+
+    if let Some(v) = yaml.get("level0_slowdown_writes_trigger") {
+        config.storage.level0_slowdown_writes_trigger =
+            Some(v.as_u64().expect("level0_slowdown_writes_trigger is not a number") as u32);
+    }
+
+    if let Some(v) = yaml.get("level0_stop_writes_trigger") {
+        config.storage.level0_stop_writes_trigger =
+            Some(v.as_u64().expect("level0_stop_writes_trigger is not a number") as u32);
+    }
+
+    if let Some(v) = yaml.get("cube_history") {
+        config.storage.cube_history =
+            Some(v.as_u64().expect("cube_history is not a number") as usize);
+    }
+
+    if let Some(v) = yaml.get("value_checksums") {
+        config.storage.value_checksums = v.as_bool().expect("value_checksums is not a bool");
+    }
+
+    if let Some(v) = yaml.get("max_value_bytes") {
+        let limit = parse_size(v.as_str().expect("max_value_bytes is not a string"))
+            .expect("max_value_bytes can't be parsed");
+        config.fabric_limits.max_value_bytes = limit as usize;
+    }
+
+    if let Some(v) = yaml.get("retry_max_attempts") {
+        config.fabric_retry.max_attempts =
+            v.as_u64().expect("retry_max_attempts is not a number") as u32;
+    }
+
+    if let Some(v) = yaml.get("retry_base_delay") {
+        let delay = parse_duration(v.as_str().expect("retry_base_delay is not a string"))
+            .expect("retry_base_delay can't be parsed");
+        config.fabric_retry.base_delay_ms = delay as u32;
+    }
+
+    if let Some(v) = yaml.get("retry_jitter") {
+        let jitter = parse_duration(v.as_str().expect("retry_jitter is not a string"))
+            .expect("retry_jitter can't be parsed");
+        config.fabric_retry.max_jitter_ms = jitter as u32;
+    }
+
     if let Some(config_value) = yaml.get("logging") {
         setup_logging(config_value);
     }
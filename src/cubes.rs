@@ -4,23 +4,198 @@ use command::CommandError;
 use linear_map::{Entry as LMEntry, LinearMap};
 use resp::RespValue;
 use std::boxed::FnBox;
-use std::time;
+use std::collections::VecDeque;
+use std::{mem, time};
 use version_vector::*;
 
 pub type MutatorFn =
     Box<FnBox(Id, Version, Cube) -> Result<(Cube, Option<RespValue>), CommandError> + Send>;
 pub type ResponseFn = Box<FnMut(Cube) -> RespValue + Send>;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+// Nanoseconds since the epoch, for timestamps replicated to other nodes.
+// A clock set before the epoch counts as 0 instead of panicking.
+fn wall_clock_ns() -> u64 {
+    let now = time::UNIX_EPOCH.elapsed().unwrap_or_default();
+    now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Cube {
     // the order is used to merge different types in a deterministic way
     Counter(Counter),
     Value(Value),
     Map(Map),
     Set(Set),
+    Lww(Lww),
     Void(VersionVector),
 }
 
+/// Type of a Cube, without its data
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CubeVariant {
+    Counter,
+    Value,
+    Map,
+    Set,
+    Lww,
+    Void,
+}
+
+/// Summary of Cube::apply_patch
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MergeResult {
+    // concurrent versions left besides one (Value siblings), plus 1 if the
+    // types differed and one side was discarded
+    pub conflicts: usize,
+    pub winner: CubeVariant,
+}
+
+/// User visible data of a Cube, without any causality information.
+/// Collections are sorted so equal payloads compare equal regardless of how
+/// they were built.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CubePayload {
+    Counter(i64),
+    // concurrent values (siblings), None for tombstones
+    Value(Vec<Option<Bytes>>),
+    Map(Vec<(Bytes, Bytes)>),
+    Set(Vec<Bytes>),
+    Lww(Bytes),
+    Void,
+}
+
+/// Kind of a mutation recorded in a Cube history
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CubeOp {
+    // the cube has this type after the write
+    Write(CubeVariant),
+    // the write left the cube without values
+    Delete,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CubeHistoryEntry {
+    pub timestamp_ns: u64,
+    pub node: Id,
+    pub op: CubeOp,
+}
+
+/// Last local mutations of a Cube, oldest first, see StorageConfig::cube_history
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CubeHistory {
+    max_len: u32,
+    entries: VecDeque<CubeHistoryEntry>,
+}
+
+impl CubeHistory {
+    pub fn entries(&self) -> &VecDeque<CubeHistoryEntry> {
+        &self.entries
+    }
+
+    fn push(&mut self, entry: CubeHistoryEntry, max_len: usize) {
+        self.max_len = max_len as u32;
+        self.entries.push_back(entry);
+        while self.entries.len() > max_len {
+            self.entries.pop_front();
+        }
+    }
+
+    // union of both, ordered by (timestamp_ns, node), keeping the last entries
+    fn merge(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        let (a, b) = match (a, b) {
+            (Some(a), Some(b)) => (a, b),
+            (a, b) => return a.or(b),
+        };
+        let max_len = a.max_len.max(b.max_len);
+        let mut entries: Vec<_> = a.entries.into_iter().chain(b.entries).collect();
+        entries.sort_by_key(|e| (e.timestamp_ns, e.node));
+        entries.dedup();
+        let skip = entries.len().saturating_sub(max_len as usize);
+        Some(CubeHistory {
+            max_len: max_len,
+            entries: entries.into_iter().skip(skip).collect(),
+        })
+    }
+}
+
+/// First byte of stored Cubes, the version of their encoding (see Cube::to_storage).
+/// Unversioned values start with their variant index instead, which is always lower.
+const CUBE_ENCODING_VERSION: u8 = 0x81;
+
+// Cubes as stored before the encoding was versioned,
+// without Lww registers nor histories
+mod legacy {
+    use super::MapValue;
+    use bytes::Bytes;
+    use linear_map::LinearMap;
+    use version_vector::*;
+
+    #[derive(Deserialize)]
+    pub enum Cube {
+        Counter(Counter),
+        Value(Value),
+        Map(Map),
+        Set(Set),
+        Void(VersionVector),
+    }
+
+    #[derive(Deserialize)]
+    pub struct Counter {
+        values: LinearMap<Id, (Version, i64)>,
+        vv: VersionVector,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Value {
+        values: DotMap<Option<Bytes>>,
+        vv: VersionVector,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Set {
+        values: CausalMap<Bytes, DotSet>,
+        dots: VersionVector,
+        vv: VersionVector,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Map {
+        values: CausalMap<Bytes, MapValue>,
+        dots: VersionVector,
+        vv: VersionVector,
+    }
+
+    impl From<Cube> for super::Cube {
+        fn from(cube: Cube) -> Self {
+            match cube {
+                Cube::Counter(a) => super::Cube::Counter(super::Counter {
+                    values: a.values,
+                    vv: a.vv,
+                    history: None,
+                }),
+                Cube::Value(a) => super::Cube::Value(super::Value {
+                    values: a.values,
+                    vv: a.vv,
+                    history: None,
+                }),
+                Cube::Map(a) => super::Cube::Map(super::Map {
+                    values: a.values,
+                    dots: a.dots,
+                    vv: a.vv,
+                    history: None,
+                }),
+                Cube::Set(a) => super::Cube::Set(super::Set {
+                    values: a.values,
+                    dots: a.dots,
+                    vv: a.vv,
+                    history: None,
+                }),
+                Cube::Void(vv) => super::Cube::Void(vv),
+            }
+        }
+    }
+}
+
 macro_rules! impl_into{
     ($s:ident, $v:ident) => {
         pub fn $s(self) -> Option<$v>{
@@ -47,14 +222,78 @@ impl Cube {
             Value(ref a) => a.values.is_empty() && a.vv.contained(bvv),
             Map(ref a) => a.values.is_empty() && a.vv.contained(bvv),
             Set(ref a) => a.values.is_empty() && a.vv.contained(bvv),
+            Lww(ref a) => a.value.is_empty() && a.vv.contained(bvv),
             Void(_) => unreachable!(),
         }
     }
 
+    /// Serializes the Cube for storage, prefixed by the encoding version
+    pub fn to_storage(&self) -> Vec<u8> {
+        let mut bytes = vec![CUBE_ENCODING_VERSION];
+        bincode::serialize_into(&mut bytes, self).expect("Can't serialize Cube");
+        bytes
+    }
+
+    /// Deserializes a stored Cube, versioned or not
+    pub fn from_storage(bytes: &[u8]) -> bincode::Result<Cube> {
+        match bytes.split_first() {
+            Some((&CUBE_ENCODING_VERSION, rest)) => bincode::deserialize(rest),
+            _ => bincode::deserialize::<legacy::Cube>(bytes).map(Into::into),
+        }
+    }
+
     impl_into!(into_value, Value);
     impl_into!(into_counter, Counter);
     impl_into!(into_map, Map);
     impl_into!(into_set, Set);
+    impl_into!(into_lww, Lww);
+
+    pub fn variant(&self) -> CubeVariant {
+        match *self {
+            Cube::Counter(_) => CubeVariant::Counter,
+            Cube::Value(_) => CubeVariant::Value,
+            Cube::Map(_) => CubeVariant::Map,
+            Cube::Set(_) => CubeVariant::Set,
+            Cube::Lww(_) => CubeVariant::Lww,
+            Cube::Void(_) => CubeVariant::Void,
+        }
+    }
+
+    /// Merges `patch` (e.g. received from another node) into self.
+    /// Patches already dominated by self leave it unchanged.
+    pub fn apply_patch(&mut self, patch: &Cube) -> MergeResult {
+        let (a, b) = (self.variant(), patch.variant());
+        let type_conflict = a != b && a != CubeVariant::Void && b != CubeVariant::Void;
+        let merged = mem::replace(self, Cube::default()).merge(patch.clone());
+        let siblings = match merged {
+            Cube::Value(ref v) => v.len().saturating_sub(1),
+            _ => 0,
+        };
+        *self = merged;
+        MergeResult {
+            conflicts: siblings + type_conflict as usize,
+            winner: self.variant(),
+        }
+    }
+
+    pub fn new_lww(value: Bytes, timestamp_ns: u64, node: Id) -> Cube {
+        Cube::Lww(self::Lww {
+            value,
+            timestamp_ns,
+            node,
+            vv: Default::default(),
+            content_type: None,
+            history: None,
+        })
+    }
+
+    /// Maps keep only `field` (see Map::project), other types are returned as is
+    pub fn project_field(self, field: &[u8]) -> Cube {
+        match self {
+            Cube::Map(m) => Cube::Map(m.project(field)),
+            cube => cube,
+        }
+    }
 
     // minimum set of dots required to assemble this cube
     // see comment at the bottom
@@ -65,10 +304,109 @@ impl Cube {
             Value(ref a) => a.values.iter().for_each(|(&(i, v), _)| cb(i, v)),
             Map(ref a) => a.dots.iter().for_each(|(i, v)| cb(i, v)),
             Set(ref a) => a.dots.iter().for_each(|(i, v)| cb(i, v)),
+            Lww(ref a) => a.vv.iter().for_each(|(i, v)| cb(i, v)),
             Void(_) => unreachable!(),
         }
     }
 
+    fn vv(&self) -> &VersionVector {
+        use self::Cube::*;
+        match *self {
+            Counter(ref a) => &a.vv,
+            Value(ref a) => &a.vv,
+            Map(ref a) => &a.vv,
+            Set(ref a) => &a.vv,
+            Lww(ref a) => &a.vv,
+            Void(ref vv) => vv,
+        }
+    }
+
+    /// Causal version embedded in the cube, as seen by readers
+    pub fn causal_version(&self) -> BitmappedVersionVector {
+        let mut bvv = BitmappedVersionVector::new();
+        for (id, version) in self.vv().iter() {
+            bvv.add_all(id, version);
+        }
+        bvv
+    }
+
+    /// Copy of the user visible data, for comparisons that don't care about causality
+    pub fn strip_version(&self) -> CubePayload {
+        use self::Cube::*;
+        match *self {
+            Counter(ref a) => CubePayload::Counter(a.get()),
+            Value(ref a) => {
+                let mut values: Vec<_> = a.values.values().cloned().collect();
+                values.sort();
+                CubePayload::Value(values)
+            }
+            Map(ref a) => {
+                let mut values: Vec<_> = a
+                    .values
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.value.clone()))
+                    .collect();
+                values.sort();
+                CubePayload::Map(values)
+            }
+            Set(ref a) => {
+                let mut values: Vec<_> = a.values.keys().cloned().collect();
+                values.sort();
+                CubePayload::Set(values)
+            }
+            Lww(ref a) => CubePayload::Lww(a.value.clone()),
+            Void(_) => CubePayload::Void,
+        }
+    }
+
+    /// Cheap estimate of the in memory size, not exact.
+    pub fn size_bytes(&self) -> usize {
+        use self::Cube::*;
+        fn vv_size(vv: &VersionVector) -> usize {
+            vv.len() * mem::size_of::<(Id, Version)>()
+        }
+        fn dots_size(dots: &DotSet) -> usize {
+            dots.len() * mem::size_of::<(Id, Version)>()
+        }
+        let inner = match *self {
+            Counter(ref a) => {
+                a.values.len() * mem::size_of::<(Id, (Version, i64))>() + vv_size(&a.vv)
+            }
+            Value(ref a) => {
+                a.values
+                    .values()
+                    .map(|v| {
+                        mem::size_of::<((Id, Version), Option<Bytes>)>()
+                            + v.as_ref().map_or(0, |v| v.len())
+                    }).sum::<usize>() + vv_size(&a.vv)
+            }
+            Map(ref a) => {
+                a.values
+                    .iter()
+                    .map(|(k, v)| {
+                        mem::size_of::<(Bytes, MapValue)>()
+                            + k.len()
+                            + v.value.len()
+                            + dots_size(&v.dots)
+                    }).sum::<usize>() + vv_size(&a.dots) + vv_size(&a.vv)
+            }
+            Set(ref a) => {
+                a.values
+                    .iter()
+                    .map(|(k, v)| mem::size_of::<(Bytes, DotSet)>() + k.len() + dots_size(v))
+                    .sum::<usize>() + vv_size(&a.dots) + vv_size(&a.vv)
+            }
+            Lww(ref a) => {
+                a.value.len() + a.content_type.as_ref().map_or(0, |t| t.len()) + vv_size(&a.vv)
+            }
+            Void(ref vv) => vv_size(vv),
+        };
+        let history = self.history().map_or(0, |h| {
+            h.entries.len() * mem::size_of::<CubeHistoryEntry>()
+        });
+        mem::size_of::<Self>() + inner + history
+    }
+
     pub fn new(bvv: &BitmappedVersionVector) -> Cube {
         let mut vv = VersionVector::new();
         for (&n, bv) in bvv.iter() {
@@ -84,23 +422,99 @@ impl Cube {
             Value(ref mut a) => a.set(id, version, None, vv),
             Map(ref mut a) => a.clear(id, version),
             Set(ref mut a) => a.clear(id, version),
+            Lww(ref mut a) => a.clear(id, version),
             Void(_) => return false,
         }
         true
     }
 
-    pub fn merge(self, other: Self) -> Self {
+    /// Mutations recorded by record_history, None if disabled or for Void cubes
+    pub fn history(&self) -> Option<&CubeHistory> {
+        use self::Cube::*;
+        match *self {
+            Counter(ref a) => a.history.as_ref(),
+            Value(ref a) => a.history.as_ref(),
+            Map(ref a) => a.history.as_ref(),
+            Set(ref a) => a.history.as_ref(),
+            Lww(ref a) => a.history.as_ref(),
+            Void(_) => None,
+        }
+    }
+
+    fn history_mut(&mut self) -> Option<&mut Option<CubeHistory>> {
+        use self::Cube::*;
+        match *self {
+            Counter(ref mut a) => Some(&mut a.history),
+            Value(ref mut a) => Some(&mut a.history),
+            Map(ref mut a) => Some(&mut a.history),
+            Set(ref mut a) => Some(&mut a.history),
+            Lww(ref mut a) => Some(&mut a.history),
+            Void(_) => None,
+        }
+    }
+
+    /// Appends a mutation by `node` (now) to the history, keeping the last `max_len`.
+    /// Void cubes have nowhere to keep it.
+    pub fn record_history(&mut self, node: Id, max_len: usize) {
+        let op = if self.is_empty() {
+            CubeOp::Delete
+        } else {
+            CubeOp::Write(self.variant())
+        };
+        let entry = CubeHistoryEntry {
+            timestamp_ns: wall_clock_ns(),
+            node: node,
+            op: op,
+        };
+        if let Some(history) = self.history_mut() {
+            history
+                .get_or_insert_with(|| CubeHistory {
+                    max_len: max_len as u32,
+                    entries: VecDeque::new(),
+                }).push(entry, max_len);
+        }
+    }
+
+    // no user visible values, ie. after a delete
+    fn is_empty(&self) -> bool {
+        use self::Cube::*;
+        match *self {
+            Counter(ref a) => a.values.is_empty(),
+            Value(ref a) => a.values.values().all(|v| v.is_none()),
+            Map(ref a) => a.values.is_empty(),
+            Set(ref a) => a.values.is_empty(),
+            Lww(ref a) => a.value.is_empty(),
+            Void(_) => true,
+        }
+    }
+
+    /// Merges the data and the histories of both cubes
+    pub fn merge(mut self, mut other: Self) -> Self {
+        let history = CubeHistory::merge(
+            self.history_mut().and_then(|h| h.take()),
+            other.history_mut().and_then(|h| h.take()),
+        );
+        let mut merged = self.merge_data(other);
+        if let Some(h) = merged.history_mut() {
+            *h = history;
+        }
+        merged
+    }
+
+    fn merge_data(self, other: Self) -> Self {
         use self::Cube::*;
         match (self, other) {
             (Counter(a), Counter(b)) => Counter(a.merge(b)),
             (Value(a), Value(b)) => Value(a.merge(b)),
             (Map(a), Map(b)) => Map(a.merge(b)),
             (Set(a), Set(b)) => Set(a.merge(b)),
+            (Lww(a), Lww(b)) => Lww(a.merge(b)),
             (Void(vv), a) | (a, Void(vv)) => match a {
                 Counter(a) => Counter(a.merge(self::Counter::with(vv))),
                 Value(a) => Value(a.merge(self::Value::with(vv))),
                 Map(a) => Map(a.merge(self::Map::with(vv))),
                 Set(a) => Set(a.merge(self::Set::with(vv))),
+                Lww(a) => Lww(a.merge(self::Lww::with(vv))),
                 Void(mut o_vv) => {
                     o_vv.merge(&vv);
                     Void(o_vv)
@@ -114,6 +528,7 @@ impl Cube {
                     (Value(a), _) | (_, Value(a)) => Value(a),
                     (Map(a), _) | (_, Map(a)) => Map(a),
                     (Set(a), _) | (_, Set(a)) => Set(a),
+                    (Lww(a), _) | (_, Lww(a)) => Lww(a),
                     (Void(_), _) | (_, Void(_)) => unreachable!(),
                 }
             }
@@ -122,10 +537,11 @@ impl Cube {
 }
 
 // RWCounter
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Counter {
     values: LinearMap<Id, (Version, i64)>,
     vv: VersionVector,
+    history: Option<CubeHistory>,
 }
 
 impl Counter {
@@ -133,6 +549,7 @@ impl Counter {
         Counter {
             values: Default::default(),
             vv,
+            history: None,
         }
     }
 
@@ -169,10 +586,11 @@ impl Counter {
 }
 
 // MultiRegister
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Value {
     values: DotMap<Option<Bytes>>,
     vv: VersionVector,
+    history: Option<CubeHistory>,
 }
 
 impl Value {
@@ -180,6 +598,7 @@ impl Value {
         Value {
             values: Default::default(),
             vv,
+            history: None,
         }
     }
 
@@ -193,6 +612,27 @@ impl Value {
         self.vv.add(node, version);
     }
 
+    /// Sets `value` superseding every value seen so far, `None` deletes.
+    pub fn overwrite(&mut self, node: Id, version: Version, value: Option<Bytes>) {
+        let vv = self.vv.clone();
+        self.set(node, version, value, &vv);
+    }
+
+    /// Concurrent values that aren't tombstones
+    pub fn live_values(&self) -> impl Iterator<Item = &Bytes> {
+        self.values.values().filter_map(|v| v.as_ref())
+    }
+
+    /// Sets `value` only if there's no live value, tombstones count as absent.
+    pub fn set_if_absent(&mut self, node: Id, version: Version, value: Bytes) -> bool {
+        if self.values.values().any(|v| v.is_some()) {
+            return false;
+        }
+        let vv = self.vv.clone();
+        self.set(node, version, Some(value), &vv);
+        true
+    }
+
     fn merge(mut self, mut other: Self) -> Self {
         self.values.merge(&mut other.values, &self.vv, &other.vv);
         self.vv.merge(&other.vv);
@@ -200,13 +640,93 @@ impl Value {
     }
 }
 
+// Last writer wins register
+// highest (timestamp_ns, node) wins, no causality between writes is considered
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lww {
+    value: Bytes,
+    timestamp_ns: u64,
+    node: Id,
+    vv: VersionVector,
+    // format of value (e.g. json, msgpack), follows the winning write
+    content_type: Option<Bytes>,
+    history: Option<CubeHistory>,
+}
+
+impl Lww {
+    fn with(vv: VersionVector) -> Self {
+        Lww {
+            value: Default::default(),
+            timestamp_ns: 0,
+            node: 0,
+            vv,
+            content_type: None,
+            history: None,
+        }
+    }
+
+    pub fn value(&self) -> &Bytes {
+        &self.value
+    }
+
+    pub fn timestamp_ns(&self) -> u64 {
+        self.timestamp_ns
+    }
+
+    pub fn node(&self) -> Id {
+        self.node
+    }
+
+    pub fn content_type(&self) -> Option<&Bytes> {
+        self.content_type.as_ref()
+    }
+
+    pub fn set(&mut self, node: Id, version: Version, value: Bytes, timestamp_ns: u64) {
+        self.set_with_content_type(node, version, value, None, timestamp_ns)
+    }
+
+    pub fn set_with_content_type(
+        &mut self,
+        node: Id,
+        version: Version,
+        value: Bytes,
+        content_type: Option<Bytes>,
+        timestamp_ns: u64,
+    ) {
+        if (timestamp_ns, node) >= (self.timestamp_ns, self.node) {
+            self.value = value;
+            self.content_type = content_type;
+            self.timestamp_ns = timestamp_ns;
+            self.node = node;
+        }
+        self.vv.add(node, version);
+    }
+
+    // a delete is a write of an empty value timestamped now
+    pub fn clear(&mut self, node: Id, version: Version) {
+        self.set(node, version, Bytes::new(), wall_clock_ns());
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        self.vv.merge(&other.vv);
+        if (other.timestamp_ns, other.node) > (self.timestamp_ns, self.node) {
+            self.value = other.value;
+            self.content_type = other.content_type;
+            self.timestamp_ns = other.timestamp_ns;
+            self.node = other.node;
+        }
+        self
+    }
+}
+
 /// Actor Observed removal
 /// Add wins on conflict
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Set {
     values: CausalMap<Bytes, DotSet>,
     dots: VersionVector,
     vv: VersionVector,
+    history: Option<CubeHistory>,
 }
 
 impl Set {
@@ -215,6 +735,7 @@ impl Set {
             values: Default::default(),
             dots: Default::default(),
             vv,
+            history: None,
         }
     }
 
@@ -251,11 +772,12 @@ impl Set {
 
 // Actor Observed removal
 // LWW on value conflict (max as tiebreaker)
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Map {
     values: CausalMap<Bytes, MapValue>,
     dots: VersionVector,
     vv: VersionVector,
+    history: Option<CubeHistory>,
 }
 
 impl Map {
@@ -264,6 +786,7 @@ impl Map {
             values: Default::default(),
             dots: Default::default(),
             vv,
+            history: None,
         }
     }
 
@@ -277,6 +800,34 @@ impl Map {
         result
     }
 
+    /// Current value of a single field
+    pub fn get(&self, key: &[u8]) -> Option<&Bytes> {
+        self.values.get(key).map(|v| &v.value)
+    }
+
+    /// Copy with only `key` (if present) but the causal context of the whole map,
+    /// projections of the same field from different replicas merge correctly.
+    pub fn project(&self, key: &[u8]) -> Self {
+        let mut values = CausalMap::new();
+        if let Some(value) = self.values.get(key) {
+            values.insert(Bytes::from(key), value.clone());
+        }
+        Map {
+            values: values,
+            dots: self.dots.clone(),
+            vv: self.vv.clone(),
+            history: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
     pub fn remove(&mut self, node: Id, version: Version, key: &[u8]) -> bool {
         let result = self.values.remove(key).is_some();
         self.vv.add(node, version);
@@ -298,7 +849,7 @@ impl Map {
     }
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 struct MapValue {
     dots: DotSet,
     value: Bytes,
@@ -369,6 +920,7 @@ pub fn render_type(cube: Cube) -> RespValue {
         Value(_) => "string",
         Map(_) => "hash",
         Set(_) => "set",
+        Lww(_) => "string",
         Void(_) => "none",
     };
     RespValue::Data(ty.into())
@@ -389,6 +941,22 @@ pub fn render_map(cube: Cube) -> RespValue {
     }
 }
 
+/// Renders a single map field like a value: [field value?, serialized vv]
+pub fn render_map_field(cube: Cube) -> RespValue {
+    let (value, vv) = match cube {
+        Cube::Map(m) => {
+            let value = m.values.into_iter().next().map(|(_, v)| v.value);
+            (value, m.vv)
+        }
+        Cube::Void(vv) => (None, vv),
+        _ => return CommandError::TypeError.into(),
+    };
+    let serialized_vv = bincode::serialize(&vv).unwrap();
+    let mut values: Vec<_> = value.into_iter().map(RespValue::Data).collect();
+    values.push(RespValue::Data(serialized_vv.into()));
+    RespValue::Array(values)
+}
+
 pub fn render_set(cube: Cube) -> RespValue {
     match cube {
         Cube::Set(s) => {
@@ -480,3 +1048,214 @@ What if n3 asks to sync dots n1-101 n1-102 ... with n2?
 Same problem and fix as the above.
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_concurrent_fields() {
+        let mut a = Map::with(Default::default());
+        let mut b = Map::with(Default::default());
+        assert!(a.insert(1, 1, "name".into(), "alice".into()));
+        assert!(b.insert(2, 1, "email".into(), "alice@example.com".into()));
+        let merged = Cube::Map(a.clone()).merge(Cube::Map(b.clone()));
+        let merged = merged.into_map().unwrap();
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged.get(b"name"), Some(&Bytes::from("alice")));
+        assert_eq!(merged.get(b"email"), Some(&Bytes::from("alice@example.com")));
+
+        // concurrent sets to the same field converge to a single value
+        a.insert(1, 2, "status".into(), "online".into());
+        b.insert(2, 2, "status".into(), "away".into());
+        let ab = a.clone().merge(b.clone());
+        let ba = b.merge(a);
+        assert_eq!(ab.len(), 3);
+        assert!(ab.get(b"status").is_some());
+        assert_eq!(ab.get(b"status"), ba.get(b"status"));
+    }
+
+    #[test]
+    fn apply_patch() {
+        let mut a = Value::with(Default::default());
+        a.set(1, 1, Some("a".into()), &VersionVector::new());
+        let mut b = Value::with(Default::default());
+        b.set(2, 1, Some("b".into()), &VersionVector::new());
+
+        // concurrent writes
+        let mut cube = Cube::Value(a.clone());
+        let result = cube.apply_patch(&Cube::Value(b));
+        assert_eq!(
+            result,
+            MergeResult {
+                conflicts: 1,
+                winner: CubeVariant::Value,
+            }
+        );
+        assert_eq!(cube.into_value().unwrap().len(), 2);
+
+        // a newer write from the same causal history dominates the patch
+        let mut newer = a.clone();
+        let vv = newer.vv.clone();
+        newer.set(1, 2, Some("c".into()), &vv);
+        let mut cube = Cube::Value(newer.clone());
+        let result = cube.apply_patch(&Cube::Value(a));
+        assert_eq!(result.conflicts, 0);
+        assert_eq!(cube, Cube::Value(newer));
+
+        // different types
+        let mut cube = Cube::new_lww("x".into(), 1, 1);
+        let result = cube.apply_patch(&Cube::Value(Value::with(Default::default())));
+        assert_eq!(result.conflicts, 1);
+    }
+
+    #[test]
+    fn lww_merge() {
+        let a = Cube::new_lww("a".into(), 2, 1);
+        let b = Cube::new_lww("b".into(), 1, 2);
+        for merged in vec![a.clone().merge(b.clone()), b.clone().merge(a.clone())] {
+            let merged = merged.into_lww().unwrap();
+            assert_eq!(merged.value(), &Bytes::from("a"));
+            assert_eq!(merged.timestamp_ns(), 2);
+        }
+
+        // same timestamp, the highest node wins
+        let c = Cube::new_lww("c".into(), 2, 3);
+        for merged in vec![a.clone().merge(c.clone()), c.clone().merge(a.clone())] {
+            let merged = merged.into_lww().unwrap();
+            assert_eq!(merged.value(), &Bytes::from("c"));
+            assert_eq!(merged.node(), 3);
+        }
+    }
+
+    #[test]
+    fn lww_content_type_merge() {
+        let mut a = Lww::with(Default::default());
+        a.set_with_content_type(1, 1, "{}".into(), Some("json".into()), 2);
+        let mut b = Lww::with(Default::default());
+        b.set_with_content_type(2, 1, "raw".into(), None, 1);
+        for merged in vec![a.clone().merge(b.clone()), b.clone().merge(a.clone())] {
+            assert_eq!(merged.value(), &Bytes::from("{}"));
+            assert_eq!(merged.content_type(), Some(&Bytes::from("json")));
+        }
+
+        // an untagged write that wins clears the tag
+        b.set(2, 2, "raw".into(), 3);
+        for merged in vec![a.clone().merge(b.clone()), b.clone().merge(a.clone())] {
+            assert_eq!(merged.value(), &Bytes::from("raw"));
+            assert_eq!(merged.content_type(), None);
+        }
+    }
+
+    #[test]
+    fn lww_content_type_serde() {
+        let mut lww = Lww::with(Default::default());
+        let value = Bytes::from_static(b"\x81\xa1a");
+        lww.set_with_content_type(1, 1, value, Some("msgpack".into()), 1);
+        let cube = Cube::Lww(lww);
+        assert_eq!(Cube::from_storage(&cube.to_storage()).unwrap(), cube);
+
+        // untagged values store None, a missing tag is an error
+        let cube = Cube::new_lww("a".into(), 1, 1);
+        let stored = cube.to_storage();
+        let deserialized = Cube::from_storage(&stored).unwrap();
+        assert_eq!(deserialized.into_lww().unwrap().content_type(), None);
+        assert!(Cube::from_storage(&stored[..stored.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn history_bounded() {
+        let mut cube = Cube::Counter(Counter::with(Default::default()));
+        for version in 1..11 {
+            if let Cube::Counter(ref mut c) = cube {
+                c.inc(1, version, 1);
+            }
+            cube.record_history(1, 5);
+        }
+        let entries = cube.history().unwrap().entries();
+        assert_eq!(entries.len(), 5);
+        assert!(entries.iter().all(|e| e.op == CubeOp::Write(CubeVariant::Counter)));
+        assert!(entries.iter().zip(entries.iter().skip(1)).all(|(a, b)| {
+            a.timestamp_ns <= b.timestamp_ns
+        }));
+
+        // survives a storage roundtrip and legacy values have none
+        let stored = cube.to_storage();
+        assert_eq!(Cube::from_storage(&stored).unwrap(), cube);
+        let legacy = Cube::Counter(Counter::with(Default::default()));
+        let serialized = bincode::serialize(&legacy).unwrap();
+        let serialized = &serialized[..serialized.len() - 1];
+        assert_eq!(Cube::from_storage(serialized).unwrap().history(), None);
+        // a truncated value is an error, not a legacy one
+        assert!(Cube::from_storage(&stored[..stored.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn legacy_storage_encoding() {
+        // unversioned values predate histories
+        let mut set = Set::with(Default::default());
+        set.insert(1, 1, "a".into());
+        let cube = Cube::Set(set);
+        let serialized = bincode::serialize(&cube).unwrap();
+        assert_eq!(serialized.last(), Some(&0));
+        let legacy = &serialized[..serialized.len() - 1];
+        assert_eq!(Cube::from_storage(legacy).unwrap(), cube);
+
+        // and Lww, which shifted the Void variant index
+        let mut vv = VersionVector::new();
+        vv.add(1, 1);
+        let mut serialized = bincode::serialize(&Cube::Void(vv.clone())).unwrap();
+        assert_eq!(serialized[0], 5);
+        serialized[0] = 4;
+        assert_eq!(Cube::from_storage(&serialized).unwrap(), Cube::Void(vv));
+    }
+
+    #[test]
+    fn history_merge() {
+        let mut a = Cube::Value(Value::with(Default::default()));
+        let mut b = Cube::Value(Value::with(Default::default()));
+        for i in 0..5 {
+            a.record_history(1, 4);
+            b.record_history(2, 4);
+            if i == 0 {
+                // already known entries aren't duplicated
+                a = a.merge(b.clone());
+            }
+        }
+        assert!(a.history().unwrap().entries().iter().all(|e| e.op == CubeOp::Delete));
+        let ab = a.clone().merge(b.clone());
+        let ba = b.merge(a);
+        let entries = ab.history().unwrap().entries();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(ab.history(), ba.history());
+        assert!(entries.iter().zip(entries.iter().skip(1)).all(|(a, b)| {
+            (a.timestamp_ns, a.node) < (b.timestamp_ns, b.node)
+        }));
+    }
+
+    #[test]
+    fn strip_version() {
+        // same data written by different nodes/versions
+        let mut a = Value::with(Default::default());
+        a.set(1, 1, Some("a".into()), &VersionVector::new());
+        let mut b = Value::with(Default::default());
+        b.set(2, 5, Some("a".into()), &VersionVector::new());
+        let (a, b) = (Cube::Value(a), Cube::Value(b));
+        assert_ne!(a, b);
+        assert_eq!(a.strip_version(), b.strip_version());
+        assert_eq!(a.strip_version(), CubePayload::Value(vec![Some("a".into())]));
+
+        let mut a = Set::with(Default::default());
+        a.insert(1, 1, "x".into());
+        a.insert(1, 2, "y".into());
+        let mut b = Set::with(Default::default());
+        b.insert(2, 1, "y".into());
+        b.insert(3, 1, "x".into());
+        assert_eq!(Cube::Set(a).strip_version(), Cube::Set(b).strip_version());
+
+        let mut c = Cube::default().into_counter().unwrap();
+        c.inc(1, 1, 2);
+        assert_ne!(Cube::Counter(c).strip_version(), CubePayload::Counter(1));
+        assert_eq!(Cube::default().strip_version(), CubePayload::Void);
+    }
+}
@@ -1,19 +1,24 @@
+use bincode;
 use bytes::Bytes;
 use command::CommandError;
 use config::Config;
 use cubes::*;
 use dht::{RingDescription, DHT};
 use fabric::*;
+use inflightmap::InFlightMap;
 use metrics::{self, Gauge};
 use rand::{thread_rng, Rng};
 use resp::RespValue;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::{net, time};
-use storage::{Storage, StorageManager};
+use storage::{Storage, StorageIterator, StorageManager, VNodeExport};
 pub use types::*;
 use utils::LoggerExt;
-use utils::{assume_str, is_dir_empty_or_absent, join_u64, replace_default, split_u64};
-use version_vector::Version;
+use utils::{
+    assume_str, is_dir_empty_or_absent, join_u64, replace_default, split_u64, GenericError,
+    IdHashMap, IdHasherBuilder,
+};
+use version_vector::{Version, VersionVector};
 use vnode::*;
 use vnode_sync::SyncDirection;
 use workers::*;
@@ -21,8 +26,21 @@ use workers::*;
 // require sync as it can be called from any worker thread
 pub type DatabaseResponseFn = Box<Fn(Context) + Send + Sync>;
 
+/// (vnode, old status, new status)
+pub type VNodeStatusEvent = (VNodeNo, VNodeStatus, VNodeStatus);
+
+/// (vnode, new stable frontier)
+pub type StableFrontierEvent = (VNodeNo, VersionVector);
+
+/// (entries, next cursor) of a range read, see Database::get_range
+pub type RangePage = (Vec<(Bytes, Cube)>, Option<Bytes>);
+
+type WaiterMap<T> =
+    InFlightMap<Cookie, mpsc::Sender<Result<T, FabricError>>, time::Instant, IdHasherBuilder>;
+
 pub enum WorkerMsg {
-    Fabric(NodeId, FabricMsg),
+    // with the time it arrived, request deadlines count from it
+    Fabric(NodeId, FabricMsg, time::Instant),
     Command(Context),
     Tick(time::Instant),
     DHTFabric(NodeId, FabricMsg),
@@ -47,6 +65,8 @@ impl ExitMsg for WorkerMsg {
 struct Stats {
     incomming_syncs: u16,
     outgoing_syncs: u16,
+    // entries reported by successfully finished incoming syncs
+    synced_entries: u64,
 }
 
 pub struct ContextRead {
@@ -134,10 +154,110 @@ pub struct Database {
     pub config: Config,
     stats: Mutex<Stats>,
     vnodes: RwLock<Vec<Mutex<VNode>>>,
+    // vnodes this node is the source of in a rebalance plan
+    exports: Mutex<IdHashMap<VNodeNo, PendingExport>>,
+    status_watchers: Mutex<Vec<mpsc::Sender<VNodeStatusEvent>>>,
+    frontier_watchers: Mutex<Vec<mpsc::Sender<StableFrontierEvent>>>,
+    // pending remote lock requests issued by this node, expire after request_timeout
+    lock_waiters: Mutex<WaiterMap<LockToken>>,
+    // pending remote range reads issued by this node
+    range_waiters: Mutex<IdHashMap<Cookie, mpsc::Sender<Result<RangePage, FabricError>>>>,
+    // pending remote appends issued by this node, expire after request_timeout
+    append_waiters: Mutex<WaiterMap<u64>>,
+    config_sync: Mutex<ConfigSyncState>,
     workers: Mutex<WorkerManager<WorkerMsg>>,
 }
 
+// cluster config distributed with ConfigSync
+struct ConfigSyncState {
+    epoch: u64,
+    config: ClusterConfig,
+    // highest epoch acked by each node
+    acks: IdHashMap<NodeId, u64>,
+    // false until a quorum of nodes acks the current epoch
+    committed: bool,
+    // requests received while the current epoch isn't committed
+    buffered: Vec<(time::Instant, NodeId, FabricMsg)>,
+}
+
+// entries per MsgRemoteSet sent by exports
+const EXPORT_BATCH_SIZE: usize = 100;
+
+// Export of a vnode snapshot, streamed to the destination with remote sets,
+// one batch at a time. Dropping it releases the snapshot.
+struct PendingExport {
+    epoch: u64,
+    destination: NodeId,
+    export: VNodeExport,
+    iterator: StorageIterator,
+    // batch waiting for an ack, resent after request_timeout
+    inflight: Option<(Cookie, Vec<(Bytes, Cube, bool)>)>,
+    count: u64,
+    last_send: time::Instant,
+    last_recv: time::Instant,
+}
+
+impl PendingExport {
+    fn new(epoch: u64, destination: NodeId, export: VNodeExport) -> Self {
+        let now = time::Instant::now();
+        PendingExport {
+            epoch: epoch,
+            destination: destination,
+            iterator: export.iterator(),
+            export: export,
+            inflight: None,
+            count: 0,
+            last_send: now,
+            last_recv: now,
+        }
+    }
+
+    // sends the next batch, returns false once everything was sent and acked
+    fn send_next(&mut self, db: &Database) -> Result<bool, GenericError> {
+        if self.inflight.is_none() {
+            let mut writes = Vec::with_capacity(EXPORT_BATCH_SIZE);
+            for (k, v) in self.iterator.iter().take(EXPORT_BATCH_SIZE) {
+                writes.push((Bytes::from(k), Cube::from_storage(v)?, false));
+            }
+            self.iterator.status()?;
+            if writes.is_empty() {
+                return Ok(false);
+            }
+            let cookie = Cookie::new(thread_rng().gen(), thread_rng().gen());
+            self.inflight = Some((cookie, writes));
+        }
+        let (cookie, ref writes) = *self.inflight.as_ref().unwrap();
+        self.last_send = time::Instant::now();
+        let _ = db.fabric.send_msg(
+            self.destination,
+            &MsgRemoteSet {
+                cookie: cookie,
+                vnode: self.export.vnode(),
+                writes: writes.clone(),
+                reply: true,
+                request_id: None,
+                idempotency_key: None,
+                expected_version: None,
+                trace_id: None,
+                deadline: None,
+            },
+        );
+        Ok(true)
+    }
+}
+
 macro_rules! fabric_send_error {
+    ($db:expr, $to:expr, $msg:expr, MsgSyncFin, $err:expr) => {
+        $db.fabric.send_msg(
+            $to,
+            &MsgSyncFin {
+                vnode: $msg.vnode,
+                cookie: $msg.cookie,
+                result: Err($err),
+                entries_synced: 0,
+            },
+        )
+    };
     ($db:expr, $to:expr, $msg:expr, $emsg:ident, $err:expr) => {
         $db.fabric.send_msg(
             $to,
@@ -177,8 +297,8 @@ impl Database {
             panic!("Can't init cluster when data directory isn't clean");
         }
 
-        let storage_manager =
-            StorageManager::new(&config.data_dir).expect("Failed to create storage manager");
+        let storage_manager = StorageManager::with_config(&config.data_dir, &config.storage)
+            .unwrap_or_else(|e| panic!("Failed to create storage manager: {}", e));
         let meta_storage = storage_manager
             .open(u16::max_value())
             .expect("Can't open storage");
@@ -194,6 +314,9 @@ impl Database {
         let meta_ring = meta_storage
             .get_vec(b"ring")
             .expect("Can't read previous ring from storage");
+        let meta_cluster_config = meta_storage
+            .get_vec(b"cluster_config")
+            .expect("Can't read cluster config from storage");
 
         let (old_node, node) = if let Some(s_node) = meta_node {
             let prev_node: NodeId = String::from_utf8(s_node).unwrap().parse().unwrap();
@@ -263,6 +386,10 @@ impl Database {
             .expect("Can't save ring");
         meta_storage.sync().expect("Can't sync storage");
 
+        let (config_epoch, cluster_config) = meta_cluster_config
+            .map(|b| bincode::deserialize(&b).expect("Can't deserialize cluster config"))
+            .unwrap_or_default();
+
         let workers = WorkerManager::new(node.to_string(), config.worker_count as _);
 
         let db = Arc::new(Database {
@@ -272,6 +399,19 @@ impl Database {
             meta_storage: meta_storage,
             response_fn: response_fn,
             vnodes: Default::default(),
+            exports: Default::default(),
+            status_watchers: Default::default(),
+            frontier_watchers: Default::default(),
+            lock_waiters: Mutex::new(InFlightMap::new()),
+            range_waiters: Default::default(),
+            append_waiters: Mutex::new(InFlightMap::new()),
+            config_sync: Mutex::new(ConfigSyncState {
+                epoch: config_epoch,
+                config: cluster_config,
+                acks: Default::default(),
+                committed: true,
+                buffered: Vec::new(),
+            }),
             workers: workers.into(),
             config: config.clone(),
             stats: Default::default(),
@@ -282,10 +422,12 @@ impl Database {
             Box::new(move |wm| {
                 if let Some(db) = cdb.upgrade() {
                     match wm {
-                        WorkerMsg::Fabric(from, m) => db.handler_fabric_msg(from, m),
+                        WorkerMsg::Fabric(from, m, arrived) => {
+                            db.handler_fabric_msg(from, m, arrived)
+                        }
                         WorkerMsg::Command(context) => db.handler_cmd(context),
                         WorkerMsg::Tick(time) => db.handler_tick(time),
-                        WorkerMsg::DHTFabric(from, m) => db.dht.handler_fabric_msg(from, m),
+                        WorkerMsg::DHTFabric(from, m) => db.handler_dht_fabric_msg(from, m),
                         WorkerMsg::DHTChange => db.handler_dht_change(),
                         WorkerMsg::Exit => (),
                     }
@@ -321,7 +463,7 @@ impl Database {
         for &msg_type in &[FabricMsgType::Crud, FabricMsgType::Synch] {
             let sender = db.sender();
             let callback = move |f, m| {
-                sender.send(WorkerMsg::Fabric(f, m));
+                sender.send(WorkerMsg::Fabric(f, m, time::Instant::now()));
             };
             db.fabric.register_msg_handler(msg_type, Box::new(callback));
         }
@@ -348,6 +490,153 @@ impl Database {
         db
     }
 
+    /// Subscribes to vnode status changes.
+    /// The current status of every vnode is delivered first, with old == new.
+    pub fn vnode_status_watch(&self) -> mpsc::Receiver<VNodeStatusEvent> {
+        let (tx, rx) = mpsc::channel();
+        let vnodes = self.vnodes.read().unwrap();
+        // hold all vnodes so no transition happens between the snapshot and subscribing
+        let locked: Vec<_> = vnodes.iter().map(|vn| vn.lock().unwrap()).collect();
+        for (i, vn) in locked.iter().enumerate() {
+            let _ = tx.send((i as VNodeNo, vn.status(), vn.status()));
+        }
+        self.status_watchers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn notify_vnode_status(&self, vnode: VNodeNo, old: VNodeStatus, new: VNodeStatus) {
+        self.status_watchers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send((vnode, old, new)).is_ok());
+    }
+
+    /// Writes coordinated by this node in `vnode` that were acked by all the replicas.
+    /// A down replica holds it back, see `stable_frontier_blockers`,
+    /// and so do writes at consistency One as the replicas don't ack them.
+    pub fn stable_frontier(&self, vnode: VNodeNo) -> VersionVector {
+        vnode!(self, vnode, |vn| vn.stable_frontier())
+    }
+
+    /// Replicas holding the stable frontier of `vnode` back, with their number of unacked writes
+    pub fn stable_frontier_blockers(&self, vnode: VNodeNo) -> Vec<(NodeId, usize)> {
+        vnode!(self, vnode, |vn| vn.stable_frontier_blockers())
+    }
+
+    /// Estimated lag of the other replicas of `vnode` compared to this node
+    pub fn replication_lag(&self, vnode: VNodeNo) -> LagInfo {
+        vnode!(self, vnode, |vn| vn.replication_lag(self))
+    }
+
+    /// The cluster config in use and its epoch
+    pub fn cluster_config(&self) -> (u64, ClusterConfig) {
+        let state = self.config_sync.lock().unwrap();
+        (state.epoch, state.config.clone())
+    }
+
+    /// Distributes `config` to the cluster members under a new epoch, returns the epoch.
+    /// Incoming requests are buffered until a quorum of nodes acks it.
+    pub fn propose_cluster_config(&self, config: ClusterConfig) -> u64 {
+        let epoch = {
+            let mut state = self.config_sync.lock().unwrap();
+            let epoch = state.epoch + 1;
+            self.apply_cluster_config(&mut state, epoch, config.clone());
+            epoch
+        };
+        let node = self.dht.node();
+        let msg = MsgConfigSync {
+            epoch: epoch,
+            config: config,
+        };
+        for &member in self.dht.members().keys() {
+            if member != node {
+                let _ = self.fabric.send_msg(member, &msg);
+            }
+        }
+        self.record_config_ack(node, epoch);
+        epoch
+    }
+
+    fn apply_cluster_config(
+        &self,
+        state: &mut ConfigSyncState,
+        epoch: u64,
+        config: ClusterConfig,
+    ) {
+        info!("Applying cluster config {:?} (epoch {})", config, epoch);
+        self.meta_storage
+            .set(b"cluster_config", &bincode::serialize(&(epoch, &config)).unwrap())
+            .log_error("Can't save cluster config");
+        self.meta_storage.sync().log_error("Can't sync cluster config");
+        state.epoch = epoch;
+        state.config = config;
+        state.committed = false;
+    }
+
+    fn record_config_ack(&self, node: NodeId, epoch: u64) {
+        let buffered = {
+            let mut state = self.config_sync.lock().unwrap();
+            {
+                let acked = state.acks.entry(node).or_insert(0);
+                *acked = ::std::cmp::max(*acked, epoch);
+            }
+            if state.committed {
+                return;
+            }
+            let members = self.dht.members();
+            let acks = members
+                .keys()
+                .filter(|n| state.acks.get(n).map_or(false, |&e| e >= state.epoch))
+                .count();
+            if acks < members.len() / 2 + 1 {
+                return;
+            }
+            info!("Cluster config epoch {} acked by {} nodes", state.epoch, acks);
+            state.committed = true;
+            replace_default(&mut state.buffered)
+        };
+        for (arrived, from, msg) in buffered {
+            self.process_fabric_msg(from, msg, arrived);
+        }
+    }
+
+    fn handler_config_sync(&self, from: NodeId, msg: MsgConfigSync) {
+        {
+            let mut state = self.config_sync.lock().unwrap();
+            if msg.epoch <= state.epoch {
+                debug!("Ignoring cluster config epoch {} from {}", msg.epoch, from);
+                return;
+            }
+            self.apply_cluster_config(&mut state, msg.epoch, msg.config);
+        }
+        let node = self.dht.node();
+        let ack = MsgConfigSyncAck {
+            epoch: msg.epoch,
+            node: node,
+        };
+        for &member in self.dht.members().keys() {
+            if member != node {
+                let _ = self.fabric.send_msg(member, &ack);
+            }
+        }
+        self.record_config_ack(from, msg.epoch);
+        self.record_config_ack(node, msg.epoch);
+    }
+
+    /// Subscribes to advances of the stable frontier of all vnodes
+    pub fn stable_frontier_watch(&self) -> mpsc::Receiver<StableFrontierEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.frontier_watchers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn notify_stable_frontier(&self, vnode: VNodeNo, frontier: VersionVector) {
+        self.frontier_watchers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send((vnode, frontier.clone())).is_ok());
+    }
+
     pub fn save(&self, shutdown: bool) {
         for vn in self.vnodes.read().unwrap().iter() {
             vn.lock().unwrap().save(self, shutdown);
@@ -390,6 +679,7 @@ impl Database {
 
     fn handler_tick(&self, time: time::Instant) {
         self.dht.handler_tick(time);
+        metrics::STORAGE_LOAD_FACTOR.set((self.meta_storage.load_factor() * 100.0) as _);
 
         let mut incomming_syncs = 0usize;
         let vnodes = self.vnodes.read().unwrap();
@@ -412,21 +702,288 @@ impl Database {
                 }
             }
         }
+        drop(vnodes);
+        self.export_tick(time);
+        expire_waiters(&self.lock_waiters, time);
+        expire_waiters(&self.append_waiters, time);
+
+        // don't hold requests forever if the config epoch can't reach a quorum
+        let expired: Vec<_> = {
+            let mut state = self.config_sync.lock().unwrap();
+            let timeout = time::Duration::from_millis(self.config.request_timeout as _);
+            let (expired, pending): (Vec<_>, Vec<_>) = replace_default(&mut state.buffered)
+                .into_iter()
+                .partition(|&(t, _, _)| t + timeout <= time);
+            state.buffered = pending;
+            expired
+        };
+        if !expired.is_empty() {
+            warn!("Processing {} requests buffered for too long", expired.len());
+        }
+        for (arrived, from, msg) in expired {
+            self.process_fabric_msg(from, msg, arrived);
+        }
+    }
+
+    fn handler_dht_fabric_msg(&self, from: NodeId, msg: FabricMsg) {
+        match msg {
+            FabricMsg::DHTRebalance(m) => self.handler_dht_rebalance(from, m),
+            FabricMsg::DHTRebalanceAck(m) => {
+                for (vnode, result) in m.results {
+                    if let Err(e) = result {
+                        warn!(
+                            "Node {} can't export vnode {} (epoch {}): {:?}",
+                            from, vnode, m.epoch, e
+                        );
+                    }
+                }
+            }
+            FabricMsg::ConfigSync(m) => self.handler_config_sync(from, m),
+            FabricMsg::ConfigSyncAck(m) => self.record_config_ack(m.node, m.epoch),
+            msg => self.dht.handler_fabric_msg(from, msg),
+        }
+    }
+
+    fn handler_dht_rebalance(&self, from: NodeId, msg: MsgDHTRebalance) {
+        let node = self.dht.node();
+        let members = self.dht.members();
+        let results: Vec<_> = msg
+            .plan
+            .into_iter()
+            .filter(|m| m.source == node)
+            .map(|m| (m.vnode, self.start_export(msg.epoch, &m, &members)))
+            .collect();
+        if results.is_empty() || from == node {
+            return;
+        }
+        let _ = self.fabric.send_msg(
+            from,
+            &MsgDHTRebalanceAck {
+                epoch: msg.epoch,
+                results: results,
+            },
+        );
+    }
+
+    fn start_export(
+        &self,
+        epoch: u64,
+        migration: &VNodeMigration,
+        members: &IdHashMap<NodeId, net::SocketAddr>,
+    ) -> Result<(), FabricError> {
+        let vnode = migration.vnode;
+        if !members.contains_key(&migration.destination) {
+            return Err(FabricError::NoRoute);
+        }
+        match self.vnodes.read().unwrap().get(vnode as usize) {
+            Some(vn) if vn.lock().unwrap().status() == VNodeStatus::Ready => (),
+            _ => return Err(FabricError::BadVNodeStatus),
+        }
+        let mut exports = self.exports.lock().unwrap();
+        if let Some(pending) = exports.get(&vnode) {
+            // already exporting for the same or a newer plan
+            if pending.epoch >= epoch {
+                return Ok(());
+            }
+        }
+        let export = self
+            .storage_manager
+            .export_vnode(vnode)
+            .map_err(|_| FabricError::StorageError)?;
+        info!(
+            "Exporting vnode {} to {} (epoch {})",
+            vnode, migration.destination, epoch
+        );
+        let mut pending = PendingExport::new(epoch, migration.destination, export);
+        match pending.send_next(self) {
+            Ok(true) => {
+                exports.insert(vnode, pending);
+            }
+            Ok(false) => info!("Exported vnode {} (empty)", vnode),
+            Err(e) => {
+                warn!("Can't export vnode {}: {}", vnode, e);
+                return Err(FabricError::StorageError);
+            }
+        }
+        Ok(())
+    }
+
+    // Acks of the remote sets sent by exports, returns false for other acks
+    fn handler_export_ack(&self, from: NodeId, msg: &MsgRemoteSetAck) -> bool {
+        let mut exports = self.exports.lock().unwrap();
+        let done = {
+            let pending = match exports.get_mut(&msg.vnode) {
+                Some(pending) => pending,
+                None => return false,
+            };
+            if pending.destination != from
+                || pending.inflight.as_ref().map(|i| i.0) != Some(msg.cookie)
+            {
+                return false;
+            }
+            pending.last_recv = time::Instant::now();
+            if let Err(ref e) = msg.result {
+                // resent by export_tick
+                debug!("Export of vnode {} failed in {}: {:?}", msg.vnode, from, e);
+                return true;
+            }
+            pending.count += pending.inflight.take().unwrap().1.len() as u64;
+            match pending.send_next(self) {
+                Ok(more) => !more,
+                Err(e) => {
+                    warn!("Can't export vnode {}: {}", msg.vnode, e);
+                    true
+                }
+            }
+        };
+        if done {
+            let pending = exports.remove(&msg.vnode).unwrap();
+            info!(
+                "Export of vnode {} to {} ended after {} entries",
+                msg.vnode, from, pending.count
+            );
+        }
+        true
+    }
+
+    // resends unacked export batches and gives up on the ones without progress
+    fn export_tick(&self, time: time::Instant) {
+        let resend = time::Duration::from_millis(self.config.request_timeout as _);
+        let timeout = time::Duration::from_millis(self.config.sync_timeout as _);
+        let mut exports = self.exports.lock().unwrap();
+        exports.retain(|&vnode, pending| {
+            if time.duration_since(pending.last_recv) > timeout {
+                warn!("Export of vnode {} to {} timed out", vnode, pending.destination);
+                return false;
+            }
+            if time.duration_since(pending.last_send) > resend {
+                if let Err(e) = pending.send_next(self) {
+                    warn!("Can't export vnode {}: {}", vnode, e);
+                    return false;
+                }
+            }
+            true
+        });
+    }
+
+    // Requests for vnodes this node doesn't own (per the live ring, so pending
+    // and retiring count) are rejected with NoRoute before reaching the vnode.
+    fn reject_unowned(&self, from: NodeId, msg: &FabricMsg) -> bool {
+        let vnode = match *msg {
+            FabricMsg::RemoteGet(ref m) => m.vnode,
+            FabricMsg::RemoteGetField(ref m) => m.vnode,
+            FabricMsg::RemoteSet(ref m) => m.vnode,
+            _ => return false,
+        };
+        if self.dht.is_vnode_owner(vnode, self.dht.node()) {
+            return false;
+        }
+        debug!("Rejecting request from {} for unowned vnode {}", from, vnode);
+        let _ = match *msg {
+            FabricMsg::RemoteGet(ref m) => self.fabric.send_msg(
+                from,
+                &MsgRemoteGetAck {
+                    vnode: vnode,
+                    cookie: m.cookie,
+                    result: Err(FabricError::NoRoute),
+                    trace_id: m.trace_id,
+                    version: None,
+                },
+            ),
+            FabricMsg::RemoteGetField(ref m) => self.fabric.send_msg(
+                from,
+                &MsgRemoteGetAck {
+                    vnode: vnode,
+                    cookie: m.cookie,
+                    result: Err(FabricError::NoRoute),
+                    trace_id: None,
+                    version: None,
+                },
+            ),
+            FabricMsg::RemoteSet(ref m) => self.fabric.send_msg(
+                from,
+                &MsgRemoteSetAck {
+                    vnode: vnode,
+                    cookie: m.cookie,
+                    result: Err(FabricError::NoRoute),
+                    trace_id: m.trace_id,
+                },
+            ),
+            _ => unreachable!(),
+        };
+        true
+    }
+
+    fn handler_fabric_msg(&self, from: NodeId, msg: FabricMsg, arrived: time::Instant) {
+        let is_request = match msg {
+            FabricMsg::RemoteGet(..)
+            | FabricMsg::RemoteGetField(..)
+            | FabricMsg::RemoteSet(..)
+            | FabricMsg::RemoteCompare(..)
+            | FabricMsg::RemoteLock(..)
+            | FabricMsg::RemoteGetRange(..)
+            | FabricMsg::RemoteAtomicAppend(..) => true,
+            _ => false,
+        };
+        if is_request {
+            let mut state = self.config_sync.lock().unwrap();
+            if !state.committed {
+                debug!("Buffering request from {} until config epoch {}", from, state.epoch);
+                state.buffered.push((arrived, from, msg));
+                return;
+            }
+        }
+        self.process_fabric_msg(from, msg, arrived);
     }
 
-    fn handler_fabric_msg(&self, from: NodeId, msg: FabricMsg) {
+    fn process_fabric_msg(&self, from: NodeId, msg: FabricMsg, arrived: time::Instant) {
+        if self.reject_unowned(from, &msg) {
+            return;
+        }
         match msg {
             FabricMsg::RemoteGet(m) => {
-                vnode!(self, m.vnode, |vn| vn.handler_get_remote(self, from, m));
+                vnode!(self, m.vnode, |vn| vn.handler_get_remote(self, from, m, arrived));
             }
             FabricMsg::RemoteGetAck(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_get_remote_ack(self, from, m));
             }
+            FabricMsg::RemoteGetField(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_get_field_remote(self, from, m, arrived));
+            }
             FabricMsg::RemoteSet(m) => {
-                vnode!(self, m.vnode, |vn| vn.handler_set_remote(self, from, m));
+                vnode!(self, m.vnode, |vn| vn.handler_set_remote(self, from, m, arrived));
             }
             FabricMsg::RemoteSetAck(m) => {
-                vnode!(self, m.vnode, |vn| vn.handler_set_remote_ack(self, from, m));
+                if !self.handler_export_ack(from, &m) {
+                    vnode!(self, m.vnode, |vn| vn.handler_set_remote_ack(self, from, m));
+                }
+            }
+            FabricMsg::RemoteCompare(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_compare_remote(self, from, m));
+            }
+            FabricMsg::RemoteCompareAck(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_compare_remote_ack(self, from, m));
+            }
+            FabricMsg::RemoteLock(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_lock_remote(self, from, m));
+            }
+            FabricMsg::RemoteLockAck(m) => {
+                self.handler_lock_remote_ack(from, m);
+            }
+            FabricMsg::RemoteUnlock(m) => {
+                vnode!(self, m.token.vnode, |vn| vn.handler_unlock_remote(self, from, m));
+            }
+            FabricMsg::RemoteGetRange(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_get_range_remote(self, from, m));
+            }
+            FabricMsg::RemoteGetRangeAck(m) => {
+                self.handler_get_range_remote_ack(from, m);
+            }
+            FabricMsg::RemoteAtomicAppend(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_atomic_append_remote(self, from, m));
+            }
+            FabricMsg::RemoteAtomicAppendAck(m) => {
+                self.handler_atomic_append_remote_ack(from, m);
             }
             FabricMsg::SyncStart(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_start(self, from, m));
@@ -434,16 +991,213 @@ impl Database {
             FabricMsg::SyncSend(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_send(self, from, m));
             }
+            FabricMsg::SyncSendBatch(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_sync_send_batch(self, from, m));
+            }
             FabricMsg::SyncAck(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_ack(self, from, m));
             }
             FabricMsg::SyncFin(m) => {
                 vnode!(self, m.vnode, |vn| vn.handler_sync_fin(self, from, m));
             }
+            FabricMsg::SyncCheckpoint(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_sync_checkpoint(self, from, m));
+            }
+            FabricMsg::SyncStats(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_sync_stats(self, from, m));
+            }
+            FabricMsg::SyncStatsAck(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_sync_stats_ack(self, from, m));
+            }
+            FabricMsg::SyncRollback(m) => {
+                vnode!(self, m.vnode, |vn| vn.handler_sync_rollback(self, from, m));
+            }
             msg => unreachable!("Can't handle {:?}", msg),
         }
     }
 
+    fn handler_lock_remote_ack(&self, from: NodeId, msg: MsgRemoteLockAck) {
+        match self.lock_waiters.lock().unwrap().remove(&msg.cookie) {
+            Some(waiter) => {
+                let _ = waiter.send(msg.result);
+            }
+            None => debug!("Lock ack {:?} from {} has no waiter", msg.cookie, from),
+        }
+    }
+
+    fn request_timeout(&self) -> time::Duration {
+        time::Duration::from_millis(self.config.request_timeout as _)
+    }
+
+    // locks (and appends) for a vnode are handled by a single node so they're serialized
+    fn lock_primary(&self, vnode: VNodeNo) -> Option<NodeId> {
+        self.dht.nodes_for_vnode(vnode, false, false).into_iter().min()
+    }
+
+    /// Acquires `key` for `ttl_ms`, unless it's held by someone else (`FabricError::LockHeld`).
+    /// The result is sent to the returned receiver, callers should use a timeout
+    /// as the request may be lost if the primary goes away.
+    pub fn lock(&self, key: &[u8], ttl_ms: u32) -> mpsc::Receiver<Result<LockToken, FabricError>> {
+        let (tx, rx) = mpsc::channel();
+        let vnode = self.dht.key_vnode(key);
+        let node = self.dht.node();
+        match self.lock_primary(vnode) {
+            None => {
+                let _ = tx.send(Err(FabricError::NoRoute));
+            }
+            Some(primary) if primary == node => {
+                let result = vnode!(self, vnode, |vn| vn.acquire_lock(self, key, ttl_ms, node));
+                let _ = tx.send(result);
+            }
+            Some(primary) => {
+                let cookie = Cookie::new(thread_rng().gen(), thread_rng().gen());
+                let expire = time::Instant::now() + self.request_timeout();
+                self.lock_waiters.lock().unwrap().insert(cookie, tx, expire);
+                let msg = MsgRemoteLock {
+                    vnode: vnode,
+                    cookie: cookie,
+                    lock_key: Bytes::from(key),
+                    ttl_ms: ttl_ms,
+                    owner: node,
+                };
+                if let Err(e) = self.fabric.send_msg(primary, &msg) {
+                    if let Some(waiter) = self.lock_waiters.lock().unwrap().remove(&cookie) {
+                        let _ = waiter.send(Err(e));
+                    }
+                }
+            }
+        }
+        rx
+    }
+
+    /// Releases a lock before its ttl, tokens of expired or overwritten locks are ignored
+    pub fn unlock(&self, token: LockToken) -> Result<(), FabricError> {
+        let primary = self.lock_primary(token.vnode).ok_or(FabricError::NoRoute)?;
+        if primary == self.dht.node() {
+            vnode!(self, token.vnode, |vn| vn.release_lock(self, &token));
+            Ok(())
+        } else {
+            self.fabric.send_msg(primary, &MsgRemoteUnlock { token: token })
+        }
+    }
+
+    fn handler_atomic_append_remote_ack(&self, from: NodeId, msg: MsgRemoteAtomicAppendAck) {
+        match self.append_waiters.lock().unwrap().remove(&msg.cookie) {
+            Some(waiter) => {
+                let _ = waiter.send(msg.result);
+            }
+            None => debug!("Append ack {:?} from {} has no waiter", msg.cookie, from),
+        }
+    }
+
+    /// Appends `value` to the log `prefix` of `vnode`, the result is the sequence number
+    /// assigned to it, distinct from all other appends to the same log.
+    /// Like `lock` the result is sent to the returned receiver and callers should use a timeout.
+    pub fn atomic_append(
+        &self,
+        vnode: VNodeNo,
+        prefix: u64,
+        value: &[u8],
+    ) -> mpsc::Receiver<Result<u64, FabricError>> {
+        let (tx, rx) = mpsc::channel();
+        let node = self.dht.node();
+        match self.lock_primary(vnode) {
+            None => {
+                let _ = tx.send(Err(FabricError::NoRoute));
+            }
+            Some(primary) if primary == node => {
+                let result = vnode!(self, vnode, |vn| vn.atomic_append(self, prefix, value));
+                let _ = tx.send(result);
+            }
+            Some(primary) => {
+                let cookie = Cookie::new(thread_rng().gen(), thread_rng().gen());
+                let expire = time::Instant::now() + self.request_timeout();
+                self.append_waiters.lock().unwrap().insert(cookie, tx, expire);
+                let msg = MsgRemoteAtomicAppend {
+                    vnode: vnode,
+                    cookie: cookie,
+                    prefix: prefix,
+                    value: Bytes::from(value),
+                };
+                if let Err(e) = self.fabric.send_msg(primary, &msg) {
+                    if let Some(waiter) = self.append_waiters.lock().unwrap().remove(&cookie) {
+                        let _ = waiter.send(Err(e));
+                    }
+                }
+            }
+        }
+        rx
+    }
+
+    /// Reads up to `limit` values appended to the log `prefix` of `vnode`, starting at
+    /// sequence number `from_seq`. Served by the local replica as appends are replicated
+    /// to all replicas of `vnode`, but the latest ones may only be visible in the primary.
+    pub fn read_appended(
+        &self,
+        vnode: VNodeNo,
+        prefix: u64,
+        from_seq: u64,
+        limit: u32,
+    ) -> Result<Vec<(u64, Bytes)>, FabricError> {
+        if !self.dht.nodes_for_vnode(vnode, false, false).contains(&self.dht.node()) {
+            return Err(FabricError::NoRoute);
+        }
+        vnode!(self, vnode, |vn| vn.read_appended(prefix, from_seq, limit))
+    }
+
+    fn handler_get_range_remote_ack(&self, from: NodeId, msg: MsgRemoteGetRangeAck) {
+        match self.range_waiters.lock().unwrap().remove(&msg.cookie) {
+            Some(waiter) => {
+                let next_cursor = msg.next_cursor;
+                let _ = waiter.send(msg.result.map(|entries| (entries, next_cursor)));
+            }
+            None => debug!("Range ack {:?} from {} has no waiter", msg.cookie, from),
+        }
+    }
+
+    /// Reads a page of up to `limit` keys of `vnode` in [start, end) (an empty end
+    /// is unbounded), resuming after `cursor`. Keys are hashed to vnodes, so a full
+    /// range read goes over every vnode passing the returned cursor until it's None.
+    /// Served locally if possible, otherwise by a replica. Like `lock` the result is
+    /// sent to the returned receiver and callers should use a timeout.
+    pub fn get_range(
+        &self,
+        vnode: VNodeNo,
+        start: &[u8],
+        end: &[u8],
+        limit: u32,
+        cursor: Option<Bytes>,
+    ) -> mpsc::Receiver<Result<RangePage, FabricError>> {
+        let (tx, rx) = mpsc::channel();
+        let node = self.dht.node();
+        let nodes = self.dht.nodes_for_vnode(vnode, false, false);
+        if nodes.contains(&node) {
+            let result = vnode!(self, vnode, |vn| {
+                vn.get_range(start, end, limit, cursor.as_ref().map(|c| &c[..]))
+            });
+            let _ = tx.send(result);
+        } else if let Some(&replica) = thread_rng().choose(&nodes) {
+            let cookie = Cookie::new(thread_rng().gen(), thread_rng().gen());
+            self.range_waiters.lock().unwrap().insert(cookie, tx);
+            let msg = MsgRemoteGetRange {
+                vnode: vnode,
+                cookie: cookie,
+                start: Bytes::from(start),
+                end: Bytes::from(end),
+                limit: limit,
+                cursor: cursor,
+            };
+            if let Err(e) = self.fabric.send_msg(replica, &msg) {
+                if let Some(waiter) = self.range_waiters.lock().unwrap().remove(&cookie) {
+                    let _ = waiter.send(Err(e));
+                }
+            }
+        } else {
+            let _ = tx.send(Err(FabricError::NoRoute));
+        }
+        rx
+    }
+
     fn syncs_inflight(&self) -> usize {
         self.vnodes
             .read()
@@ -455,6 +1209,23 @@ impl Database {
             }).sum()
     }
 
+    /// Asks the senders of all incoming syncs/bootstraps for their progress
+    pub fn request_sync_stats(&self) {
+        for vn in self.vnodes.read().unwrap().iter() {
+            vn.lock().unwrap().request_sync_stats(self);
+        }
+    }
+
+    /// Last progress reported by the senders of the incoming syncs/bootstraps
+    pub fn sync_peer_stats(&self) -> Vec<MsgSyncStatsAck> {
+        self.vnodes
+            .read()
+            .unwrap()
+            .iter()
+            .flat_map(|vn| vn.lock().unwrap().sync_peer_stats())
+            .collect()
+    }
+
     #[cfg(test)]
     fn _start_sync(&self, vnode: VNodeNo) -> bool {
         let vnodes = self.vnodes.read().unwrap();
@@ -496,6 +1267,15 @@ impl Database {
         }
     }
 
+    pub fn signal_sync_fin(&self, entries_synced: u64) {
+        self.stats.lock().unwrap().synced_entries += entries_synced;
+    }
+
+    /// Total entries received by successfully finished incoming syncs/bootstraps
+    pub fn synced_entries(&self) -> u64 {
+        self.stats.lock().unwrap().synced_entries
+    }
+
     // CLIENT CRUD
     pub fn set_flush(
         &self,
@@ -575,6 +1355,26 @@ impl Database {
         ))
     }
 
+    pub fn get_field(
+        &self,
+        context: &mut Context,
+        key: &Bytes,
+        field: &Bytes,
+        consistency: ConsistencyLevel,
+        response_fn: ResponseFn,
+    ) -> Result<(), CommandError> {
+        debug_assert!(!context.is_multi_cmd && !context.is_exec_cmd);
+        let vnode = self.dht.key_vnode(key);
+        vnode!(self, vnode, |vn| vn.do_get_field(
+            self,
+            context,
+            key,
+            field,
+            consistency,
+            response_fn
+        ))
+    }
+
     pub fn mget(
         &self,
         context: &mut Context,
@@ -606,6 +1406,15 @@ impl Database {
     }
 }
 
+// fails the waiters whose response didn't arrive in time
+fn expire_waiters<T>(waiters: &Mutex<WaiterMap<T>>, now: time::Instant) {
+    let mut waiters = waiters.lock().unwrap();
+    while let Some((cookie, waiter)) = waiters.pop_expired(now) {
+        debug!("Request {:?} expired without response", cookie);
+        let _ = waiter.send(Err(FabricError::Expired));
+    }
+}
+
 impl Drop for Database {
     fn drop(&mut self) {
         debug!("Droping database");
@@ -622,10 +1431,11 @@ mod tests {
     use env_logger;
     use resp::RespValue;
     use std::collections::HashMap;
-    use std::sync::{Arc, Mutex};
-    use std::{fs, net, ops};
+    use std::sync::{Arc, Barrier, Mutex};
+    use std::{fs, net, ops, thread};
     use utils::sleep_ms;
-    use version_vector::VersionVector;
+    use version_vector::{BitmappedVersionVector, VersionVector};
+    use vnode_sync::{save_sync_checkpoint, stage_sync_writes};
 
     #[allow(non_upper_case_globals)]
     const One: &[u8] = b"One";
@@ -652,6 +1462,8 @@ mod tests {
                 sync_incomming_max: 100,
                 sync_outgoing_max: 100,
                 sync_auto: false,
+                // retries are only sent on ticks, leave room for a few
+                request_timeout: 5_000,
                 cmd_init: if create {
                     Some(config::InitCommand {
                         replication_factor: 3,
@@ -707,6 +1519,40 @@ mod tests {
             sleep_ms(200);
         }
 
+        // a fabric node without a database connected to `peers`,
+        // the `msg_type` messages it receives are sent to the returned channel
+        fn bare_fabric_node(
+            node: NodeId,
+            fabric_addr: &str,
+            msg_type: FabricMsgType,
+            peers: &[&TestDatabase],
+        ) -> (Fabric, mpsc::Receiver<FabricMsg>) {
+            let config = config::Config {
+                fabric_addr: fabric_addr.parse().unwrap(),
+                ..Default::default()
+            };
+            let fabric = Fabric::new(node, &config).unwrap();
+            let (tx, rx) = mpsc::channel();
+            let tx = Mutex::new(tx);
+            fabric.register_msg_handler(
+                msg_type,
+                Box::new(move |_, msg| {
+                    let _ = tx.lock().unwrap().send(msg);
+                }),
+            );
+            for peer in peers {
+                fabric.register_node(peer.dht.node(), peer.fabric.addr());
+                peer.fabric.register_node(node, config.fabric_addr);
+            }
+            // until every peer can reply
+            for peer in peers {
+                while !peer.fabric.connections().contains(&node) {
+                    sleep_ms(10);
+                }
+            }
+            (fabric, rx)
+        }
+
         fn response_resp(&self, token: Token) -> RespValue {
             (0..1000)
                 .filter_map(|_| {
@@ -874,14 +1720,553 @@ mod tests {
         }
     }
 
-    const TEST_JOIN_SIZE: u64 = 100;
+    #[test]
+    fn test_remote_set_dedup() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+
+        let remote_set = |version, value: &'static str| {
+            let mut register = Cube::default().into_value().unwrap();
+            register.set(42, version, Some(Bytes::from(value)), &VersionVector::new());
+            FabricMsg::RemoteSet(MsgRemoteSet {
+                vnode: db.dht.key_vnode(b"test"),
+                cookie: Default::default(),
+                writes: vec![(Bytes::from("test"), Cube::Value(register), false)],
+                reply: true,
+                request_id: Some(1),
+                idempotency_key: None,
+                expected_version: None,
+                trace_id: None,
+                deadline: None,
+            })
+        };
+        db.handler_fabric_msg(42, remote_set(1, "value1"), time::Instant::now());
+        // a retry is dropped even if the writes differ
+        db.handler_fabric_msg(42, remote_set(2, "value2"), time::Instant::now());
+
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+    }
 
     #[test]
-    fn test_bootstrap() {
+    fn test_remote_set_idempotency_key() {
         let _ = fs::remove_dir_all("t/");
         let _ = env_logger::try_init();
-        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
-        for i in 0..TEST_JOIN_SIZE {
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+
+        let remote_set = |node, value: &'static str, idempotency_key| {
+            let mut register = Cube::default().into_value().unwrap();
+            register.set(node, 1, Some(Bytes::from(value)), &VersionVector::new());
+            FabricMsg::RemoteSet(MsgRemoteSet {
+                vnode: db.dht.key_vnode(b"test"),
+                cookie: Default::default(),
+                writes: vec![(Bytes::from("test"), Cube::Value(register), false)],
+                reply: true,
+                request_id: None,
+                idempotency_key: Some(idempotency_key),
+                expected_version: None,
+                trace_id: None,
+                deadline: None,
+            })
+        };
+        db.handler_fabric_msg(42, remote_set(42, "value1", [1; 16]), time::Instant::now());
+        // the same key is applied once, even if retried through another node
+        db.handler_fabric_msg(43, remote_set(43, "value2", [1; 16]), time::Instant::now());
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0, [b"value1"]);
+
+        // concurrent write with another key, becomes a sibling
+        db.handler_fabric_msg(43, remote_set(43, "value2", [2; 16]), time::Instant::now());
+        db.do_cmd(1, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(1).0.len(), 2);
+    }
+
+    #[test]
+    fn test_remote_set_expected_version() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+
+        // a bare fabric node to talk to the database
+        let (client, rx) =
+            TestDatabase::bare_fabric_node(42, "127.0.0.1:9042", FabricMsgType::Crud, &[&db]);
+        let recv = || rx.recv_timeout(time::Duration::from_secs(1)).unwrap();
+
+        let vnode = db.dht.key_vnode(b"test");
+        client
+            .send_msg(
+                db.dht.node(),
+                &MsgRemoteGet {
+                    vnode: vnode,
+                    cookie: Default::default(),
+                    keys: vec![Bytes::from("test")],
+                    trace_id: None,
+                    deadline: None,
+                },
+            ).unwrap();
+        let version = match recv() {
+            FabricMsg::RemoteGetAck(m) => {
+                assert!(m.result.is_ok());
+                m.version.unwrap()
+            }
+            msg => panic!("unexpected {:?}", msg),
+        };
+
+        let remote_set = |version: u64, value: &'static str, expected: &BitmappedVersionVector| {
+            // overwrite everything covered by the read version
+            let mut register = Cube::new(expected).into_value().unwrap();
+            register.set(42, version, Some(Bytes::from(value)), &VersionVector::new());
+            MsgRemoteSet {
+                vnode: vnode,
+                cookie: Default::default(),
+                writes: vec![(Bytes::from("test"), Cube::Value(register), false)],
+                reply: true,
+                request_id: None,
+                idempotency_key: None,
+                expected_version: Some(expected.clone()),
+                trace_id: None,
+                deadline: None,
+            }
+        };
+        client
+            .send_msg(db.dht.node(), &remote_set(1, "value2", &version))
+            .unwrap();
+        match recv() {
+            FabricMsg::RemoteSetAck(m) => assert!(m.result.is_ok()),
+            msg => panic!("unexpected {:?}", msg),
+        }
+        // the version read earlier is now stale
+        client
+            .send_msg(db.dht.node(), &remote_set(2, "value3", &version))
+            .unwrap();
+        match recv() {
+            FabricMsg::RemoteSetAck(m) => assert_eq!(m.result, Err(FabricError::VersionMismatch)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        db.do_cmd(2, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(2).0, [b"value2"]);
+    }
+
+    #[test]
+    fn test_remote_expired_deadline() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"SET", b"test", b"value1", b"", One]);
+        assert_eq!(db.response_resp(1), RespValue::Status("OK".into()));
+
+        let (client, rx) =
+            TestDatabase::bare_fabric_node(42, "127.0.0.1:9042", FabricMsgType::Crud, &[&db]);
+        let recv = || rx.recv_timeout(time::Duration::from_secs(1)).unwrap();
+
+        let vnode = db.dht.key_vnode(b"test");
+        let remote_get = |deadline| MsgRemoteGet {
+            vnode: vnode,
+            cookie: Default::default(),
+            keys: vec![Bytes::from("test")],
+            trace_id: None,
+            deadline: deadline,
+        };
+        client.send_msg(db.dht.node(), &remote_get(Some(0))).unwrap();
+        match recv() {
+            FabricMsg::RemoteGetAck(m) => assert_eq!(m.result, Err(FabricError::Expired)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+        client.send_msg(db.dht.node(), &remote_get(Some(1000))).unwrap();
+        match recv() {
+            FabricMsg::RemoteGetAck(m) => assert!(m.result.is_ok()),
+            msg => panic!("unexpected {:?}", msg),
+        }
+        // the deadline counts from the arrival, not from when the work starts
+        let arrived = time::Instant::now() - time::Duration::from_millis(100);
+        db.handler_fabric_msg(42, remote_get(Some(50)).into(), arrived);
+        match recv() {
+            FabricMsg::RemoteGetAck(m) => assert_eq!(m.result, Err(FabricError::Expired)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        let mut register = Cube::default().into_value().unwrap();
+        register.set(42, 1, Some(Bytes::from("value2")), &VersionVector::new());
+        client
+            .send_msg(
+                db.dht.node(),
+                &MsgRemoteSet {
+                    vnode: vnode,
+                    cookie: Default::default(),
+                    writes: vec![(Bytes::from("test"), Cube::Value(register), false)],
+                    reply: true,
+                    request_id: None,
+                    idempotency_key: None,
+                    expected_version: None,
+                    trace_id: None,
+                    deadline: Some(0),
+                },
+            ).unwrap();
+        match recv() {
+            FabricMsg::RemoteSetAck(m) => assert_eq!(m.result, Err(FabricError::Expired)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        // the expired write was never applied
+        db.do_cmd(2, &[b"GET", b"test", One]);
+        assert_eq!(db.response_values(2).0, [b"value1"]);
+    }
+
+    #[test]
+    fn test_remote_unowned_vnode() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        // joined but not rebalanced, owns no vnodes
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+
+        let peers = [&db1, &db2];
+        let (client, rx) =
+            TestDatabase::bare_fabric_node(42, "127.0.0.1:9042", FabricMsgType::Crud, &peers);
+        let recv = || rx.recv_timeout(time::Duration::from_secs(1)).unwrap();
+
+        let remote_get = |vnode| MsgRemoteGet {
+            vnode: vnode,
+            cookie: Default::default(),
+            keys: vec![Bytes::from("test")],
+            trace_id: None,
+            deadline: None,
+        };
+        let vnode = db1.dht.key_vnode(b"test");
+        client.send_msg(db2.dht.node(), &remote_get(vnode)).unwrap();
+        // the absent vnode itself would have answered BadVNodeStatus
+        match recv() {
+            FabricMsg::RemoteGetAck(m) => assert_eq!(m.result, Err(FabricError::NoRoute)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        // unknown vnode, indexing the vnodes would panic
+        client.send_msg(db1.dht.node(), &remote_get(PARTITIONS as VNodeNo)).unwrap();
+        match recv() {
+            FabricMsg::RemoteGetAck(m) => assert_eq!(m.result, Err(FabricError::NoRoute)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+        client.send_msg(db1.dht.node(), &remote_get(vnode)).unwrap();
+        match recv() {
+            FabricMsg::RemoteGetAck(m) => assert!(m.result.is_ok()),
+            msg => panic!("unexpected {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_dht_rebalance() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // data only node 1 has
+        let (node1, node2) = (db1.dht.node(), db2.dht.node());
+        let storage = db1.storage_manager.open(0).unwrap();
+        let value = Cube::new_lww("value".into(), 1, node1).to_storage();
+        for i in 0..1000 {
+            storage.set(format!("key{}", i).as_bytes(), &value).unwrap();
+        }
+
+        let migration = |vnode, source, destination| VNodeMigration {
+            vnode: vnode,
+            source: source,
+            destination: destination,
+        };
+        db1.handler_dht_fabric_msg(
+            node1,
+            MsgDHTRebalance {
+                epoch: 1,
+                plan: vec![
+                    migration(0, node1, node2),
+                    // not the source
+                    migration(1, node2, node1),
+                    // unknown vnode
+                    migration(PARTITIONS as VNodeNo, node1, node2),
+                    // unknown destination
+                    migration(2, node1, node2 + 1),
+                ],
+            }.into(),
+        );
+        {
+            let exports = db1.exports.lock().unwrap();
+            assert_eq!(exports.len(), 1);
+            let pending = &exports[&0];
+            assert_eq!(pending.epoch, 1);
+            assert_eq!(pending.destination, node2);
+            assert_eq!(pending.export.vnode(), 0);
+        }
+
+        // streamed in batches, the export (and its snapshot) is gone once acked
+        for _ in 0..200 {
+            if db1.exports.lock().unwrap().is_empty() {
+                break;
+            }
+            sleep_ms(10);
+        }
+        assert!(db1.exports.lock().unwrap().is_empty());
+        let storage = db2.storage_manager.open(0).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{}", i);
+            assert!(storage.get_vec(key.as_bytes()).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_vnode_status_watch() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let watch = db2.vnode_status_watch();
+        for i in 0..PARTITIONS as VNodeNo {
+            let event = watch.try_recv().unwrap();
+            assert_eq!(event, (i, VNodeStatus::Absent, VNodeStatus::Absent));
+        }
+        assert!(watch.try_recv().is_err());
+
+        db2.dht.rebalance().unwrap();
+        let event = watch.recv_timeout(time::Duration::from_secs(5)).unwrap();
+        assert_eq!((event.1, event.2), (VNodeStatus::Absent, VNodeStatus::Bootstrap));
+
+        db1.wait_syncs();
+        db2.wait_syncs();
+        let events: Vec<_> = watch.try_iter().collect();
+        assert!(events.contains(&(event.0, VNodeStatus::Bootstrap, VNodeStatus::Ready)));
+    }
+
+    #[test]
+    fn test_remote_get_not_ready() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", One]);
+        db.response_values(1);
+
+        let vnode = db.dht.key_vnode(b"test");
+        let vnodes = db.vnodes.read().unwrap();
+        let mut vn = vnodes[vnode as usize].lock().unwrap();
+        let keys = [Bytes::from("test")];
+        assert_eq!(vn.get_remote(&keys).unwrap().len(), 1);
+        // zombies still answer
+        vn._set_status(&db, VNodeStatus::Zombie);
+        assert_eq!(vn.get_remote(&keys).unwrap().len(), 1);
+
+        let mut other = vnodes[(vnode as usize + 1) % PARTITIONS].lock().unwrap();
+        other._set_status(&db, VNodeStatus::Bootstrap);
+        assert_eq!(other.get_remote(&keys).err(), Some(FabricError::NotReady));
+        other._set_status(&db, VNodeStatus::Absent);
+        assert_eq!(other.get_remote(&keys).err(), Some(FabricError::BadVNodeStatus));
+    }
+
+    #[test]
+    fn test_remote_set_rate_limited() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let vnode = db1.dht.key_vnode(b"test");
+        db2.vnodes.read().unwrap()[vnode as usize]
+            .lock()
+            .unwrap()
+            ._force_write_stalls(1);
+        let start = time::Instant::now();
+        db1.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", All]);
+        db1.response_values(1);
+        // db2 only acks the write after the retry
+        assert!(start.elapsed() >= time::Duration::from_millis(WRITE_STALL_RETRY_MS as _));
+
+        db2.do_cmd(2, &[b"GET", b"test", One]);
+        assert_eq!(db2.response_values(2).0, [b"value1"]);
+    }
+
+    #[test]
+    fn test_remote_set_retry_backoff() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let vnode = db1.dht.key_vnode(b"test");
+        db2.vnodes.read().unwrap()[vnode as usize]
+            .lock()
+            .unwrap()
+            ._force_not_ready(2);
+        db1.do_cmd(1, &[b"GETSET", b"test", b"value1", b"", All]);
+        db1.response_values(1);
+
+        // there's no other replica to re-route to, the third attempt to db2 wins
+        let vnodes = db1.vnodes.read().unwrap();
+        let delays = vnodes[vnode as usize].lock().unwrap()._retry_delays().to_vec();
+        assert_eq!(delays.len(), 2);
+        assert!(delays[0] < delays[1]);
+        db2.do_cmd(2, &[b"GET", b"test", One]);
+        assert_eq!(db2.response_values(2).0, [b"value1"]);
+    }
+
+    #[test]
+    fn test_remote_set_retry_reroute() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db1.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        db3.wait_syncs();
+
+        // db2 never recovers, whether the retry is re-routed to db3 (which was sent
+        // the write already) or db2 exhausts the attempts the write can't succeed
+        let vnode = db1.dht.key_vnode(b"test");
+        db2.vnodes.read().unwrap()[vnode as usize]
+            .lock()
+            .unwrap()
+            ._force_not_ready(10);
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        assert_eq!(db1.response_resp(1), RespValue::Error("Unavailable".into()));
+
+        db1.do_cmd(2, &[b"SET", b"test", b"value2", b"", Quorum]);
+        assert_eq!(db1.response_resp(2), RespValue::Status("OK".into()));
+        db3.do_cmd(3, &[b"GET", b"test", One]);
+        assert_eq!(db3.response_values(3).0, [b"value2"]);
+    }
+
+    #[test]
+    fn test_stable_frontier() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        let vnode = db1.dht.key_vnode(b"test");
+        db1.do_cmd(1, &[b"SET", b"test", b"value1", b"", All]);
+        db1.response_resp(1);
+        let frontier = db1.stable_frontier(vnode);
+        assert_eq!(frontier.len(), 1);
+        assert!(db1.stable_frontier_blockers(vnode).is_empty());
+
+        let watch = db1.stable_frontier_watch();
+        // db2 only acks the write after the rate limit retry
+        db2.vnodes.read().unwrap()[vnode as usize]
+            .lock()
+            .unwrap()
+            ._force_write_stalls(1);
+        db1.do_cmd(2, &[b"SET", b"test", b"value2", b"", All]);
+        sleep_ms(20);
+        assert_eq!(db1.stable_frontier(vnode), frontier);
+        assert_eq!(db1.stable_frontier_blockers(vnode), vec![(db2.dht.node(), 1)]);
+        assert!(watch.try_recv().is_err());
+
+        db1.response_resp(2);
+        let advanced = db1.stable_frontier(vnode);
+        assert!(advanced.strict_descends(&frontier));
+        assert!(db1.stable_frontier_blockers(vnode).is_empty());
+        assert_eq!(watch.try_recv().unwrap(), (vnode, advanced));
+
+        // writes at One aren't acked
+        db1.do_cmd(3, &[b"SET", b"test", b"value3", b"", One]);
+        db1.response_resp(3);
+        sleep_ms(20);
+        assert_eq!(db1.stable_frontier(vnode), advanced);
+        assert_eq!(db1.stable_frontier_blockers(vnode), vec![(db2.dht.node(), 1)]);
+    }
+
+    #[test]
+    fn test_hget_field() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db1.do_cmd(1, &[b"HSET", b"map", b"a", b"1", All]);
+        db1.response_resp(1);
+        db1.do_cmd(2, &[b"HSET", b"map", b"b", b"2", All]);
+        db1.response_resp(2);
+        db1.do_cmd(3, &[b"HSET", b"map", b"c", b"3", All]);
+        db1.response_resp(3);
+
+        db2.do_cmd(4, &[b"HGET", b"map", b"b", All]);
+        let (values, vv) = db2.response_values(4);
+        assert_eq!(values, [b"2"]);
+        assert_eq!(vv.len(), 1);
+
+        // missing fields still carry the clock of the whole map
+        db2.do_cmd(5, &[b"HGET", b"map", b"d", All]);
+        let (values, missing_vv) = db2.response_values(5);
+        assert!(values.is_empty());
+        assert_eq!(missing_vv, vv);
+    }
+
+    #[test]
+    fn test_setnx_race() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        db.do_cmd(1, &[b"SET", b"lock", b"stale", b"", One]);
+        db.response_resp(1);
+        db.do_cmd(1, &[b"GET", b"lock", One]);
+        let (_, vv) = db.response_values(1);
+        // a tombstone counts as absent
+        db.do_cmd(1, &[b"DEL", b"lock", &encode_vv(&vv), One]);
+        assert_eq!(db.response_resp(1), RespValue::Int(1));
+
+        let threads: Vec<_> = (10..12)
+            .map(|token| {
+                let db = db.db.clone();
+                thread::spawn(move || {
+                    let owner = token.to_string();
+                    let mut context = Context::new(token);
+                    context.commands.push(RespValue::Array(vec![
+                        RespValue::Data("SETNX".into()),
+                        RespValue::Data("lock".into()),
+                        RespValue::Data(owner.into()),
+                        RespValue::Data(One.into()),
+                    ]));
+                    db.handler_cmd(context);
+                })
+            }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let results: Vec<_> = (10..12).map(|token| db.response_resp(token)).collect();
+        let winner = match (&results[0], &results[1]) {
+            (&RespValue::Int(1), &RespValue::Int(0)) => 10,
+            (&RespValue::Int(0), &RespValue::Int(1)) => 11,
+            _ => panic!("expected exactly one setnx to succeed {:?}", results),
+        };
+        db.do_cmd(1, &[b"GET", b"lock", One]);
+        assert_eq!(db.response_values(1).0, [winner.to_string().as_bytes()]);
+    }
+
+    const TEST_JOIN_SIZE: u64 = 100;
+
+    #[test]
+    fn test_bootstrap() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..TEST_JOIN_SIZE {
             db1.do_cmd(
                 i,
                 &[
@@ -942,6 +2327,641 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sync_resume() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // node 2 is down while node 1 takes writes
+        db2.save(true);
+        drop(db2);
+        for i in 0..1000 {
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    b"",
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+        // as if node 2 crashed halfway through the previous syncs,
+        // having staged and checkpointed half the keys of each vnode
+        let node1 = db1.dht.node();
+        for vn in 0..PARTITIONS as VNodeNo {
+            let entries: Vec<(Bytes, Cube)> = db1
+                .storage_manager
+                .open(vn)
+                .unwrap()
+                .iterator()
+                .iter()
+                .map(|(k, v)| (Bytes::from(k), Cube::from_storage(v).unwrap()))
+                .collect();
+            let staged = entries.len() / 2;
+            if staged == 0 {
+                continue;
+            }
+            let mut checkpoint = SyncCheckpoint::default();
+            checkpoint.last_acked_seq = staged as u64 - 1;
+            for &(_, ref cube) in &entries[..staged] {
+                cube.for_each_dot(|n, v| {
+                    checkpoint.clocks.add(n, v);
+                });
+            }
+            stage_sync_writes(&db2, vn, node1, entries.into_iter().take(staged)).unwrap();
+            save_sync_checkpoint(&db2, vn, node1, &checkpoint);
+        }
+        // writes in between attempts change what the resumed syncs send
+        for i in 900..1100 {
+            let value = format!("{}b", i);
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    value.as_bytes(),
+                    b"",
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+        db2.force_syncs();
+
+        for vn in 0..PARTITIONS as VNodeNo {
+            let entries = |db: &TestDatabase| -> Vec<(Vec<u8>, CubePayload)> {
+                db.storage_manager
+                    .open(vn)
+                    .unwrap()
+                    .iterator()
+                    .iter()
+                    .map(|(k, v)| {
+                        let cube = Cube::from_storage(v).unwrap();
+                        (k.to_vec(), cube.strip_version())
+                    }).collect()
+            };
+            assert_eq!(entries(&db2), entries(&db1));
+        }
+    }
+
+    #[test]
+    fn test_sync_rollback() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // a bare fabric node takes over node 1 to act as the sync sender
+        let node1 = db1.dht.node();
+        drop(db1);
+        let (client, rx) =
+            TestDatabase::bare_fabric_node(node1, "127.0.0.1:9000", FabricMsgType::Synch, &[&db2]);
+
+        while !db2._start_sync(0) {
+            sleep_ms(1);
+        }
+        sleep_ms(100);
+        let cookie = rx
+            .try_iter()
+            .filter_map(|msg| match msg {
+                FabricMsg::SyncStart(ref m) if m.vnode == 0 => Some(m.cookie),
+                _ => None,
+            }).last()
+            .unwrap();
+
+        for i in 0..100u64 {
+            let mut register = Cube::default().into_value().unwrap();
+            register.set(node1, i + 1, Some(Bytes::from(i.to_string())), &VersionVector::new());
+            client
+                .send_msg(
+                    db2.dht.node(),
+                    &MsgSyncSend {
+                        vnode: 0,
+                        cookie: cookie,
+                        seq: i,
+                        key: Bytes::from(i.to_string()),
+                        value: Cube::Value(register),
+                    },
+                ).unwrap();
+        }
+        let mut acks = 0;
+        while acks < 100 {
+            match rx.recv_timeout(time::Duration::from_secs(1)).unwrap() {
+                FabricMsg::SyncAck(ref m) if m.cookie == cookie => acks += 1,
+                _ => (),
+            }
+        }
+        // small syncs are staged in memory only
+        let staged = |db: &TestDatabase| {
+            db.meta_storage
+                .iterator()
+                .iter()
+                .any(|(k, _)| k.starts_with(b"0_sync_staged_"))
+        };
+        assert!(!staged(&db2));
+        client
+            .send_msg(
+                db2.dht.node(),
+                &MsgSyncRollback {
+                    vnode: 0,
+                    cookie: cookie,
+                },
+            ).unwrap();
+        sleep_ms(100);
+
+        let storage = db2.storage_manager.open(0).unwrap();
+        assert_eq!(storage.iterator().iter().count(), 0);
+        // nothing staged is left behind either
+        assert!(!staged(&db2));
+    }
+
+    #[test]
+    fn test_sync_staging_spill() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // a bare fabric node takes over node 1 to act as the sync sender
+        let node1 = db1.dht.node();
+        drop(db1);
+        let (client, rx) =
+            TestDatabase::bare_fabric_node(node1, "127.0.0.1:9000", FabricMsgType::Synch, &[&db2]);
+
+        while !db2._start_sync(0) {
+            sleep_ms(1);
+        }
+        sleep_ms(100);
+        let cookie = rx
+            .try_iter()
+            .filter_map(|msg| match msg {
+                FabricMsg::SyncStart(ref m) if m.vnode == 0 => Some(m.cookie),
+                _ => None,
+            }).last()
+            .unwrap();
+
+        // more than fits in memory, without any checkpoint
+        let big_value = Bytes::from(vec![0u8; 1024 * 1024]);
+        for i in 0..20u64 {
+            let mut register = Cube::default().into_value().unwrap();
+            register.set(node1, i + 1, Some(big_value.clone()), &VersionVector::new());
+            client
+                .send_msg(
+                    db2.dht.node(),
+                    &MsgSyncSend {
+                        vnode: 0,
+                        cookie: cookie,
+                        seq: i,
+                        key: Bytes::from(i.to_string()),
+                        value: Cube::Value(register),
+                    },
+                ).unwrap();
+        }
+        let mut acks = 0;
+        while acks < 20 {
+            match rx.recv_timeout(time::Duration::from_secs(1)).unwrap() {
+                FabricMsg::SyncAck(ref m) if m.cookie == cookie => acks += 1,
+                _ => (),
+            }
+        }
+        // staged writes are persisted as they arrive
+        let staged = |db: &TestDatabase| {
+            db.meta_storage
+                .iterator()
+                .iter()
+                .filter(|&(k, _)| k.starts_with(b"0_sync_staged_"))
+                .count()
+        };
+        assert!(staged(&db2) > 0);
+        client
+            .send_msg(
+                db2.dht.node(),
+                &MsgSyncRollback {
+                    vnode: 0,
+                    cookie: cookie,
+                },
+            ).unwrap();
+        sleep_ms(100);
+
+        let storage = db2.storage_manager.open(0).unwrap();
+        assert_eq!(storage.iterator().iter().count(), 0);
+        assert_eq!(staged(&db2), 0);
+    }
+
+    #[test]
+    fn test_sync_send_batch() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        // a bare fabric node takes over node 1 to act as the sync sender
+        let node1 = db1.dht.node();
+        drop(db1);
+        let (client, rx) =
+            TestDatabase::bare_fabric_node(node1, "127.0.0.1:9000", FabricMsgType::Synch, &[&db2]);
+
+        while !db2._start_sync(0) {
+            sleep_ms(1);
+        }
+        sleep_ms(100);
+        let cookie = rx
+            .try_iter()
+            .filter_map(|msg| match msg {
+                FabricMsg::SyncStart(ref m) if m.vnode == 0 => Some(m.cookie),
+                _ => None,
+            }).last()
+            .unwrap();
+
+        for batch in 0..10u64 {
+            let entries = (batch * 10..batch * 10 + 10)
+                .map(|i| {
+                    let mut register = Cube::default().into_value().unwrap();
+                    register.set(
+                        node1,
+                        i + 1,
+                        Some(Bytes::from(i.to_string())),
+                        &VersionVector::new(),
+                    );
+                    (i, Bytes::from(i.to_string()), Cube::Value(register))
+                }).collect();
+            client
+                .send_msg(
+                    db2.dht.node(),
+                    &MsgSyncSendBatch {
+                        vnode: 0,
+                        cookie: cookie,
+                        entries: entries,
+                    },
+                ).unwrap();
+        }
+        // a single ack per batch, with its last seq
+        let mut acks = Vec::new();
+        while acks.len() < 10 {
+            match rx.recv_timeout(time::Duration::from_secs(1)).unwrap() {
+                FabricMsg::SyncAck(ref m) if m.cookie == cookie => acks.push(m.seq),
+                _ => (),
+            }
+        }
+        acks.sort();
+        assert_eq!(acks, (0..10).map(|b| b * 10 + 9).collect::<Vec<_>>());
+
+        let mut clocks = BitmappedVersionVector::new();
+        clocks.add_all(node1, 100);
+        client
+            .send_msg(
+                db2.dht.node(),
+                &MsgSyncFin {
+                    vnode: 0,
+                    cookie: cookie,
+                    result: Ok(clocks),
+                    entries_synced: 100,
+                },
+            ).unwrap();
+        sleep_ms(100);
+
+        let storage = db2.storage_manager.open(0).unwrap();
+        let mut keys: Vec<u64> = storage
+            .iterator()
+            .iter()
+            .map(|(k, _)| assume_str(k).parse().unwrap())
+            .collect();
+        keys.sort();
+        assert_eq!(keys, (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_sync_entries_synced() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let mut db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+
+        db2.save(true);
+        drop(db2);
+        for i in 0..500 {
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    b"",
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+        assert_eq!(db2.synced_entries(), 0);
+        db2.force_syncs();
+        assert_eq!(db2.synced_entries(), 500);
+    }
+
+    #[test]
+    fn test_get_range() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db", true);
+        // hash tags put all keys in the same vnode
+        let keys: Vec<_> = (0..1000).map(|i| format!("{{users}}:{:04}", i)).collect();
+        for (i, key) in keys.iter().enumerate() {
+            db.do_cmd(i as Token, &[b"SET", key.as_bytes(), b"v", b"", One]);
+        }
+        for i in 0..keys.len() {
+            assert_eq!(db.response_resp(i as Token), RespValue::Status("OK".into()));
+        }
+        let vnode = db.dht.key_vnode(keys[0].as_bytes());
+
+        let get_page = |start: &[u8], end: &[u8], cursor| {
+            db.get_range(vnode, start, end, 100, cursor)
+                .recv_timeout(time::Duration::from_secs(1))
+                .unwrap()
+                .unwrap()
+        };
+        let mut scanned = Vec::new();
+        let mut pages = 0;
+        let mut cursor = None;
+        loop {
+            let (entries, next_cursor) = get_page(b"", b"", cursor);
+            assert!(entries.len() <= 100);
+            pages += 1;
+            scanned.extend(entries.into_iter().map(|(k, _)| k));
+            if next_cursor.is_none() {
+                break;
+            }
+            assert_eq!(next_cursor.as_ref(), scanned.last());
+            cursor = next_cursor;
+        }
+        assert_eq!(pages, 10);
+        let expected: Vec<_> = keys.iter().map(|k| Bytes::from(k.as_bytes())).collect();
+        assert_eq!(scanned, expected);
+
+        // bounded range, the end is exclusive
+        let (entries, next_cursor) = get_page(b"{users}:0100", b"{users}:0150", None);
+        assert_eq!(entries.len(), 50);
+        assert_eq!(&entries[0].0[..], b"{users}:0100");
+        assert_eq!(next_cursor, None);
+
+        // other vnodes are empty
+        let other = (vnode + 1) % PARTITIONS as VNodeNo;
+        let page = db.get_range(other, b"", b"", 100, None).recv().unwrap();
+        assert_eq!(page.unwrap().0.len(), 0);
+    }
+
+    #[test]
+    fn test_remote_lock() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+
+        let barrier = Arc::new(Barrier::new(2));
+        let lockers: Vec<_> = vec![db1.db.clone(), db2.db.clone()]
+            .into_iter()
+            .map(|db| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    db.lock(b"lock", 60_000)
+                        .recv_timeout(time::Duration::from_secs(1))
+                        .unwrap()
+                })
+            }).collect();
+        let results: Vec<_> = lockers.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results.contains(&Err(FabricError::LockHeld)));
+
+        let (winner, loser) = if results[0].is_ok() {
+            (&db1, &db2)
+        } else {
+            (&db2, &db1)
+        };
+        let token = results.into_iter().find(|r| r.is_ok()).unwrap().unwrap();
+        assert_eq!(token.owner, winner.dht.node());
+        // locks are replicated as vnode data, so a new primary finds them
+        let storage = db1.storage_manager.open(token.vnode).unwrap();
+        let replica = db2.storage_manager.open(token.vnode).unwrap();
+        let lock_key = b"\x00sucredb_lock\x00lock";
+        assert!(storage.get_vec(lock_key).unwrap().is_some());
+        assert!((0..1000).any(|_| {
+            sleep_ms(1);
+            replica.get_vec(lock_key).unwrap().is_some()
+        }));
+        // but clients can't see nor overwrite it
+        winner.do_cmd(1, &[b"SET", lock_key, b"value", b"", One]);
+        assert_eq!(winner.response_resp(1), RespValue::Error("InvalidKey".into()));
+        let page = winner
+            .get_range(token.vnode, b"", b"", 100, None)
+            .recv_timeout(time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(page.unwrap().0.len(), 0);
+        // the unlock may be in flight
+        winner.unlock(token).unwrap();
+        let token = (0..1000)
+            .filter_map(|_| {
+                sleep_ms(1);
+                loser.lock(b"lock", 60_000).recv().unwrap().ok()
+            }).next()
+            .unwrap();
+        assert_eq!(token.owner, loser.dht.node());
+        assert_eq!(
+            winner.lock(b"lock", 60_000).recv().unwrap(),
+            Err(FabricError::LockHeld)
+        );
+
+        // expired locks can be taken
+        assert!(db2.lock(b"ttl", 50).recv().unwrap().is_ok());
+        sleep_ms(100);
+        assert!(db1.lock(b"ttl", 50).recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_atomic_append() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.wait_fabric();
+
+        // one of them appends locally and the other remotely
+        let barrier = Arc::new(Barrier::new(2));
+        let appenders: Vec<_> = vec![db1.db.clone(), db2.db.clone()]
+            .into_iter()
+            .map(|db| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    (0..50)
+                        .map(|i| {
+                            db.atomic_append(0, 7, i.to_string().as_bytes())
+                                .recv_timeout(time::Duration::from_secs(1))
+                                .unwrap()
+                                .unwrap()
+                        }).collect::<Vec<_>>()
+                })
+            }).collect();
+        let mut seqs: Vec<_> = appenders.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        seqs.sort();
+        assert_eq!(seqs, (1..101).collect::<Vec<_>>());
+
+        // other logs have their own sequence
+        assert_eq!(db1.atomic_append(0, 8, b"").recv().unwrap(), Ok(1));
+        assert_eq!(db2.atomic_append(0, 7, b"").recv().unwrap(), Ok(101));
+
+        // values are readable from any replica once replicated
+        for db in &[&db1, &db2] {
+            assert!((0..1000).any(|_| {
+                sleep_ms(1);
+                db.read_appended(0, 7, 1, 1000).unwrap().len() == 101
+            }));
+            // the last append of either appender
+            let values = db.read_appended(0, 7, 100, 10).unwrap();
+            assert_eq!(values, vec![(100, Bytes::from("49")), (101, Bytes::new())]);
+            assert_eq!(db.read_appended(0, 8, 1, 10).unwrap(), vec![(1, Bytes::new())]);
+        }
+        // the logs aren't visible to range reads
+        let page = db1.get_range(0, b"", b"", 100, None).recv().unwrap();
+        assert_eq!(page.unwrap().0.len(), 0);
+    }
+
+    #[test]
+    fn test_replication_lag() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let vnode = db1.dht.key_vnode(b"{lag}");
+        // no other replicas yet
+        assert_eq!(db1.replication_lag(vnode), LagInfo::default());
+
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        db1.wait_syncs();
+        db2.wait_syncs();
+        assert_eq!(db1.replication_lag(vnode), LagInfo::default());
+        assert_eq!(db2.replication_lag(vnode), LagInfo::default());
+
+        for i in 0..3 {
+            let key = format!("{{lag}}{}", i);
+            db1.do_cmd(i, &[b"SET", key.as_bytes(), b"v", b"", One]);
+            assert_eq!(db1.response_resp(i), RespValue::Status("OK".into()));
+        }
+        // replicated but not confirmed by a sync yet
+        let lag = db1.replication_lag(vnode);
+        assert_eq!(lag.missing_dots, 3);
+        assert_eq!(lag.oldest_unsynced.map(|(_, v)| v), Some(1));
+        assert!(lag.unknown_peers.is_empty());
+
+        while !db2._start_sync(vnode) {
+            sleep_ms(1);
+        }
+        db2.wait_syncs();
+        db1.wait_syncs();
+        assert_eq!(db1.replication_lag(vnode), LagInfo::default());
+        assert_eq!(db2.replication_lag(vnode), LagInfo::default());
+    }
+
+    #[test]
+    fn test_config_sync() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        let mut db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        db3.wait_fabric();
+        assert_eq!(db1.cluster_config(), (0, ClusterConfig::default()));
+
+        let config = ClusterConfig {
+            consistency_read: Some(ConsistencyLevel::Quorum),
+            consistency_write: None,
+        };
+        assert_eq!(db1.propose_cluster_config(config.clone()), 1);
+        for &db in &[&db1, &db2, &db3] {
+            for _ in 0..100 {
+                if db.cluster_config().0 == 1 {
+                    break;
+                }
+                sleep_ms(10);
+            }
+            assert_eq!(db.cluster_config(), (1, config.clone()));
+        }
+
+        // requests flow once the epoch is acked
+        db2.do_cmd(0, &[b"SET", b"key", b"value", b"", Quorum]);
+        assert_eq!(db2.response_resp(0), RespValue::Status("OK".into()));
+
+        db3.save(true);
+        drop(db3);
+        db3 = TestDatabase::new("127.0.0.1:9002".parse().unwrap(), "t/db3", false);
+        assert_eq!(db3.cluster_config(), (1, config));
+    }
+
+    #[test]
+    fn test_sync_stats() {
+        let _ = fs::remove_dir_all("t/");
+        let _ = env_logger::try_init();
+        let db1 = TestDatabase::new("127.0.0.1:9000".parse().unwrap(), "t/db1", true);
+        for i in 0..5_000u64 {
+            db1.do_cmd(
+                i,
+                &[
+                    b"GETSET",
+                    i.to_string().as_bytes(),
+                    i.to_string().as_bytes(),
+                    b"",
+                    One,
+                ],
+            );
+            db1.response_values(i);
+        }
+
+        let db2 = TestDatabase::new("127.0.0.1:9001".parse().unwrap(), "t/db2", false);
+        db2.dht.rebalance().unwrap();
+        // wait for the bootstraps to start
+        sleep_ms(200);
+        let mut seen = HashMap::new();
+        let mut updates = 0;
+        while db2.syncs_inflight() != 0 {
+            db2.request_sync_stats();
+            sleep_ms(1);
+            for stats in db2.sync_peer_stats() {
+                let prev = seen.insert(stats.cookie, stats.entries_sent).unwrap_or(0);
+                assert!(stats.entries_sent >= prev);
+                if stats.entries_sent > prev {
+                    updates += 1;
+                }
+                assert!(stats.entries_sent == 0 || stats.bytes_sent > 0);
+            }
+        }
+        assert!(updates > 0);
+    }
+
     #[test]
     fn test_bootstrap_2() {
         // similar to the previous, but values in n1 are rewritten + sibling
@@ -1160,6 +3180,23 @@ mod tests {
             assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
         }
 
+        // commands without a consistency level use the cluster config defaults
+        let config = ClusterConfig {
+            consistency_read: None,
+            consistency_write: Some(ConsistencyLevel::All),
+        };
+        assert_eq!(db1.propose_cluster_config(config.clone()), 1);
+        for _ in 0..100 {
+            if db1.cluster_config() == (1, config.clone()) {
+                break;
+            }
+            sleep_ms(10);
+        }
+        db1.do_cmd(0, &[b"GET", b"key"]);
+        assert_eq!(db1.response_values(0).0, [b"value"]);
+        db1.do_cmd(0, &[b"SET", b"other", b"", b""]);
+        assert_eq!(db1.response_resp(0), RespValue::Error("Unavailable".into()));
+
         drop(db2);
         for &cl in &[One] {
             db1.do_cmd(0, &[b"GET", b"key", cl]);
@@ -885,6 +885,17 @@ impl<T: Metadata> DHT<T> {
         result
     }
 
+    /// Whether node is listed in the vnode owners, with any status.
+    /// False for unknown vnodes.
+    pub fn is_vnode_owner(&self, vn_no: VNodeNo, node: NodeId) -> bool {
+        let inner = self.inner.read().unwrap();
+        inner
+            .ring
+            .vnodes
+            .get(vn_no as usize)
+            .map_or(false, |vn| vn.owners.contains_key(&node))
+    }
+
     // TODO: split into read_ and write_
     pub fn nodes_for_vnode(
         &self,
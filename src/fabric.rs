@@ -1,12 +1,14 @@
 use std::collections::hash_map::Entry as HMEntry;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{mpsc, Arc, RwLock};
-use std::time::Duration;
-use std::{io, thread};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::{cmp, io, mem, thread};
 
 use bincode;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{BufMut, Bytes, BytesMut};
 use linear_map::LinearMap;
 use rand::{thread_rng, Rng};
@@ -14,20 +16,63 @@ use rand::{thread_rng, Rng};
 use futures::future::Either;
 use futures::sync::mpsc as fmpsc;
 use futures::sync::oneshot as foneshot;
-use futures::{Future, Sink, Stream};
+use futures::{Async, Future, Poll, Sink, Stream};
 use tokio_codec as codec;
 use tokio_core as tokio;
 use tokio_io::{io as tokio_io, AsyncRead};
 
 use config::Config;
-use database::NodeId;
+use cubes::Cube;
+use database::{Cookie, NodeId, VNodeNo};
 pub use fabric_msg::*;
-use utils::{into_io_error, GenericError, IdHashMap};
+use inflightmap::InFlightMap;
+use utils::{GenericError, IdHashMap};
 
-// u32(le) payload len + bincode payload
-struct FramedBincodeCodec;
+/// Serialization format of the fabric message payloads.
+/// Both ends of a connection must use the same codec, compared by `id` during the handshake.
+pub trait FabricCodec: Send + Sync {
+    /// Unique tag of the format, exchanged in the handshake
+    fn id(&self) -> u8;
 
-impl codec::Decoder for FramedBincodeCodec {
+    fn encode(&self, item: &FabricMsgRef, dst: &mut BytesMut) -> Result<(), GenericError>;
+
+    fn decode(&self, payload: &[u8]) -> Result<FabricMsg, GenericError>;
+}
+
+/// The default codec, serde with bincode's default configuration
+pub struct BincodeCodec;
+
+impl FabricCodec for BincodeCodec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self, item: &FabricMsgRef, dst: &mut BytesMut) -> Result<(), GenericError> {
+        dst.reserve(bincode::serialized_size(item)? as usize);
+        bincode::serialize_into(&mut dst.writer(), item)?;
+        Ok(())
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<FabricMsg, GenericError> {
+        Ok(bincode::deserialize(payload)?)
+    }
+}
+
+// u32(le) payload len + u8 msg type + codec payload
+// the length includes the type byte
+struct FramedCodec(Arc<FabricCodec>);
+
+/// Reads the message type from the header of a serialized frame,
+/// without deserializing the payload.
+pub fn peek_type(frame: &[u8]) -> FabricMsgType {
+    if frame.len() > 4 {
+        FabricMsgType::from_u8(frame[4])
+    } else {
+        FabricMsgType::Unknown
+    }
+}
+
+impl codec::Decoder for FramedCodec {
     type Item = FabricMsg;
     type Error = io::Error;
 
@@ -35,10 +80,25 @@ impl codec::Decoder for FramedBincodeCodec {
         let (consumed, result) = {
             let mut bytes: &[u8] = &*src;
             if let Ok(msg_len) = bytes.read_u32::<LittleEndian>() {
-                if bytes.len() >= msg_len as usize {
-                    match bincode::deserialize_from(&mut bytes) {
+                if msg_len == 0 {
+                    (
+                        0,
+                        Err(io::Error::new(io::ErrorKind::InvalidData, "Empty fabric frame")),
+                    )
+                } else if msg_len > MAX_FRAME_LEN {
+                    // refused before buffering it
+                    (
+                        0,
+                        Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Fabric frame too large ({} bytes)", msg_len),
+                        )),
+                    )
+                } else if bytes.len() >= msg_len as usize {
+                    // skip the type tag, only used by peek_type
+                    match self.0.decode(&bytes[1..msg_len as usize]) {
                         Ok(v) => (4 + msg_len as usize, Ok(Some(v))),
-                        Err(e) => (0, Err(into_io_error(e))),
+                        Err(e) => (0, Err(io::Error::new(io::ErrorKind::InvalidData, e))),
                     }
                 } else {
                     (0, Ok(None))
@@ -52,17 +112,20 @@ impl codec::Decoder for FramedBincodeCodec {
     }
 }
 
-impl FramedBincodeCodec {
-    fn serialize(item: FabricMsgRef) -> Bytes {
-        let item_size = bincode::serialized_size(&item).unwrap();
-        let mut dst = BytesMut::with_capacity(item_size as usize + 4);
-        dst.put_u32_le(item_size as u32);
-        bincode::serialize_into(&mut (&mut dst).writer(), &item).unwrap();
+impl FramedCodec {
+    fn serialize(codec: &FabricCodec, item: FabricMsgRef) -> Bytes {
+        let mut dst = BytesMut::with_capacity(5);
+        // the length is patched once the payload is written
+        dst.put_u32_le(0);
+        dst.put_u8(item.get_type().to_u8());
+        codec.encode(&item, &mut dst).unwrap();
+        let msg_len = dst.len() as u32 - 4;
+        LittleEndian::write_u32(&mut dst[..4], msg_len);
         dst.into()
     }
 }
 
-impl codec::Encoder for FramedBincodeCodec {
+impl codec::Encoder for FramedCodec {
     type Item = Bytes;
     type Error = io::Error;
 
@@ -73,14 +136,210 @@ impl codec::Encoder for FramedBincodeCodec {
     }
 }
 
+// Max number of times a pending class can be passed over before it's served
+const SEND_QUEUE_MAX_SKIPS: usize = 16;
+
+// A serialized frame and the vnode it belongs to, if any
+type OutFrame = (Option<VNodeNo>, Bytes);
+
+/// Frames of a single class served round-robin across vnodes, so a vnode with
+/// lots of traffic (ie. a bulk sync) can't monopolize the connection.
+/// Only vnodes with queued frames take a turn.
+#[derive(Default)]
+struct FairQueue {
+    queues: HashMap<Option<VNodeNo>, VecDeque<Bytes>>,
+    // vnodes with queued frames, in serving order
+    ready: VecDeque<Option<VNodeNo>>,
+    len: usize,
+}
+
+impl FairQueue {
+    fn push(&mut self, vnode: Option<VNodeNo>, frame: Bytes) {
+        let queue = self.queues.entry(vnode).or_insert_with(Default::default);
+        if queue.is_empty() {
+            self.ready.push_back(vnode);
+        }
+        queue.push_back(frame);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Bytes> {
+        let vnode = self.ready.pop_front()?;
+        let (frame, idle) = {
+            let queue = self.queues.get_mut(&vnode).unwrap();
+            (queue.pop_front(), queue.is_empty())
+        };
+        if idle {
+            self.queues.remove(&vnode);
+        } else {
+            self.ready.push_back(vnode);
+        }
+        self.len -= 1;
+        frame
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// Send queue of serialized frames that drains Crud before DHT before Synch.
+/// A class that is passed over SEND_QUEUE_MAX_SKIPS times in a row gets served next,
+/// so control and sync traffic can't be starved by a stream of Crud messages.
+/// Within a class vnodes are served round-robin, see FairQueue.
+#[derive(Default)]
+struct SendQueue {
+    // Crud, DHT/Unknown, Synch, in priority order
+    queues: [FairQueue; 3],
+    skips: [usize; 3],
+}
+
+impl SendQueue {
+    fn class(frame: &[u8]) -> usize {
+        match peek_type(frame) {
+            FabricMsgType::Crud => 0,
+            FabricMsgType::DHT | FabricMsgType::Unknown => 1,
+            FabricMsgType::Synch => 2,
+        }
+    }
+
+    fn push(&mut self, vnode: Option<VNodeNo>, frame: Bytes) {
+        let class = Self::class(&frame);
+        self.queues[class].push(vnode, frame);
+    }
+
+    fn pop(&mut self) -> Option<Bytes> {
+        let starved = (0..self.queues.len()).find(|&i| self.skips[i] >= SEND_QUEUE_MAX_SKIPS);
+        let class =
+            starved.or_else(|| (0..self.queues.len()).find(|&i| !self.queues[i].is_empty()))?;
+        for i in 0..self.queues.len() {
+            if i == class || self.queues[i].is_empty() {
+                self.skips[i] = 0;
+            } else {
+                self.skips[i] += 1;
+            }
+        }
+        self.queues[class].pop()
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+}
+
+/// Wraps the connection channel, pulling everything available into a SendQueue
+/// so the socket is fed by priority instead of by arrival order.
+struct PrioritizedStream<S> {
+    inner: S,
+    inner_done: bool,
+    queue: SendQueue,
+}
+
+impl<S: Stream<Item = OutFrame>> PrioritizedStream<S> {
+    fn new(inner: S) -> Self {
+        PrioritizedStream {
+            inner: inner,
+            inner_done: false,
+            queue: Default::default(),
+        }
+    }
+}
+
+impl<S: Stream<Item = OutFrame>> Stream for PrioritizedStream<S> {
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, S::Error> {
+        while !self.inner_done {
+            match self.inner.poll()? {
+                Async::Ready(Some((vnode, frame))) => self.queue.push(vnode, frame),
+                Async::Ready(None) => self.inner_done = true,
+                Async::NotReady => break,
+            }
+        }
+        match self.queue.pop() {
+            Some(frame) => Ok(Async::Ready(Some(frame))),
+            None if self.inner_done => Ok(Async::Ready(None)),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Limits enforced on messages before they're sent over the fabric
+#[derive(Debug, Clone)]
+pub struct FabricLimits {
+    // based on Cube::size_bytes estimate
+    pub max_value_bytes: usize,
+}
+
+impl Default for FabricLimits {
+    fn default() -> Self {
+        FabricLimits {
+            max_value_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl FabricLimits {
+    pub fn check_value(&self, cube: &Cube) -> Result<(), FabricError> {
+        if cube.size_bytes() > self.max_value_bytes {
+            Err(FabricError::ValueTooLarge)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Backoff for requests that failed with a retryable `FabricError`
+#[derive(Debug, Clone)]
+pub struct FabricRetryPolicy {
+    // including the first attempt
+    pub max_attempts: u32,
+    // doubled on every retry
+    pub base_delay_ms: u32,
+    // a random delay up to this is added to each retry, should be below base_delay_ms
+    pub max_jitter_ms: u32,
+}
+
+impl Default for FabricRetryPolicy {
+    fn default() -> Self {
+        FabricRetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 50,
+            max_jitter_ms: 25,
+        }
+    }
+}
+
+impl FabricRetryPolicy {
+    /// Delay before sending `attempt` (the first retry is attempt 2),
+    /// None if the attempts are exhausted
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt < 2 || attempt > self.max_attempts {
+            return None;
+        }
+        let backoff = (self.base_delay_ms as u64) << (attempt - 2).min(16);
+        let jitter = thread_rng().gen_range(0, self.max_jitter_ms as u64 + 1);
+        Some(Duration::from_millis(backoff + jitter))
+    }
+}
+
 pub type FabricMsgFn = Box<Fn(NodeId, FabricMsg) + Sync + Send>;
 pub type FabricConFn = Box<Fn(NodeId) + Sync + Send>;
 
-type SenderChan = fmpsc::UnboundedSender<Bytes>;
+type SenderChan = fmpsc::UnboundedSender<OutFrame>;
 type InitType = io::Result<(Arc<SharedContext>, foneshot::Sender<()>)>;
 
 const FABRIC_KEEPALIVE_MS: u64 = 1000;
 const FABRIC_RECONNECT_INTERVAL_MS: u64 = 1000;
+// after an ack is sent, further acks to the same peer within this window are coalesced
+const FABRIC_ACK_COALESCE_MS: u64 = 1;
+/// Advertised in MsgPeerInfo, bumped on incompatible protocol changes
+pub const FABRIC_PROTOCOL_VERSION: u32 = 1;
 
 /// The messaging network that encompasses all nodes of the cluster
 /// using the fabric you can send messages (best-effort delivery)
@@ -92,10 +351,12 @@ const FABRIC_RECONNECT_INTERVAL_MS: u64 = 1000;
 /// the latency as much.
 pub struct Fabric {
     context: Arc<SharedContext>,
-    loop_thread: Option<(
-        foneshot::Sender<()>,
-        thread::JoinHandle<Result<(), GenericError>>,
-    )>,
+    loop_thread: Mutex<
+        Option<(
+            foneshot::Sender<()>,
+            thread::JoinHandle<Result<(), GenericError>>,
+        )>,
+    >,
 }
 
 struct ReaderContext {
@@ -109,6 +370,199 @@ struct WriterContext {
     connection_id: usize,
 }
 
+/// Buffers small acks per peer so the ones produced in quick succession
+/// can be sent in a single MsgAckBatch. An ack to an idle peer is sent right away
+/// and opens a coalescing window, so only acks under load are delayed.
+#[derive(Default)]
+struct AckCoalescer {
+    // peers with an open window and the acks buffered in it
+    pending: Mutex<IdHashMap<NodeId, Vec<FabricMsg>>>,
+}
+
+impl AckCoalescer {
+    /// Returns the ack back if there's no window open for the peer, in which case
+    /// the caller must send it and schedule a flush, which opens one.
+    fn push(&self, peer: NodeId, ack: FabricMsg) -> Option<FabricMsg> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.entry(peer) {
+            HMEntry::Occupied(mut o) => {
+                o.get_mut().push(ack);
+                None
+            }
+            HMEntry::Vacant(v) => {
+                v.insert(Vec::new());
+                Some(ack)
+            }
+        }
+    }
+
+    /// Takes the acks buffered for the peer, a lone ack is returned as is.
+    /// The window stays open if there were any, otherwise it's closed.
+    fn take(&self, peer: NodeId) -> Option<FabricMsg> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut acks = pending
+            .get_mut(&peer)
+            .map(|acks| mem::replace(acks, Vec::new()))
+            .unwrap_or_default();
+        if acks.is_empty() {
+            pending.remove(&peer);
+            return None;
+        }
+        if acks.len() == 1 {
+            acks.pop()
+        } else {
+            Some(MsgAckBatch { acks: acks }.into())
+        }
+    }
+}
+
+// Requests sent and still waiting for an ack, tracked for Fabric::drain.
+// Holds the error ack that completes each request if the drain times out.
+// Requests are forgotten after the request timeout (the sender gave up on them by then)
+// or once the connections to the peer are gone, as their acks won't arrive.
+struct Outstanding {
+    requests: Mutex<InFlightMap<(NodeId, Cookie), Vec<FabricMsg>, Instant>>,
+    // notified when requests becomes empty
+    empty: Condvar,
+    timeout: Duration,
+}
+
+impl Outstanding {
+    fn new(timeout: Duration) -> Self {
+        Outstanding {
+            requests: Mutex::new(InFlightMap::new()),
+            empty: Condvar::new(),
+            timeout: timeout,
+        }
+    }
+
+    // the ack completing `msg` with an error, None if msg isn't tracked
+    fn error_ack(msg: &FabricMsgRef) -> Option<(Cookie, FabricMsg)> {
+        let error_ack: FabricMsg = match *msg {
+            FabricMsgRef::RemoteGet(m) => MsgRemoteGetAck {
+                vnode: m.vnode,
+                cookie: m.cookie,
+                result: Err(FabricError::ShuttingDown),
+                trace_id: m.trace_id,
+                version: None,
+            }.into(),
+            FabricMsgRef::RemoteGetField(m) => MsgRemoteGetAck {
+                vnode: m.vnode,
+                cookie: m.cookie,
+                result: Err(FabricError::ShuttingDown),
+                trace_id: None,
+                version: None,
+            }.into(),
+            FabricMsgRef::RemoteSet(m) if m.reply => MsgRemoteSetAck {
+                vnode: m.vnode,
+                cookie: m.cookie,
+                result: Err(FabricError::ShuttingDown),
+                trace_id: m.trace_id,
+            }.into(),
+            FabricMsgRef::RemoteLock(m) => MsgRemoteLockAck {
+                cookie: m.cookie,
+                result: Err(FabricError::ShuttingDown),
+            }.into(),
+            FabricMsgRef::RemoteGetRange(m) => MsgRemoteGetRangeAck {
+                cookie: m.cookie,
+                result: Err(FabricError::ShuttingDown),
+                next_cursor: None,
+            }.into(),
+            FabricMsgRef::RemoteAtomicAppend(m) => MsgRemoteAtomicAppendAck {
+                cookie: m.cookie,
+                result: Err(FabricError::ShuttingDown),
+            }.into(),
+            // compare acks can't carry errors
+            _ => return None,
+        };
+        let cookie = match error_ack {
+            FabricMsg::RemoteGetAck(ref m) => m.cookie,
+            FabricMsg::RemoteSetAck(ref m) => m.cookie,
+            FabricMsg::RemoteLockAck(ref m) => m.cookie,
+            FabricMsg::RemoteGetRangeAck(ref m) => m.cookie,
+            FabricMsg::RemoteAtomicAppendAck(ref m) => m.cookie,
+            _ => unreachable!(),
+        };
+        Some((cookie, error_ack))
+    }
+
+    fn track(&self, node: NodeId, cookie: Cookie, error_ack: FabricMsg) {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        while let Some(((node, cookie), _)) = requests.pop_expired(now) {
+            debug!("Request {:?} to {} expired without an ack", cookie, node);
+        }
+        requests
+            .entry_with_timeout((node, cookie), now + self.timeout)
+            .or_insert_with(Vec::new)
+            .push(error_ack);
+    }
+
+    fn resolve(&self, node: NodeId, msg: &FabricMsg) {
+        match *msg {
+            FabricMsg::RemoteGetAck(ref m) => self.untrack(node, m.cookie),
+            FabricMsg::RemoteSetAck(ref m) => self.untrack(node, m.cookie),
+            FabricMsg::RemoteLockAck(ref m) => self.untrack(node, m.cookie),
+            FabricMsg::RemoteGetRangeAck(ref m) => self.untrack(node, m.cookie),
+            FabricMsg::RemoteAtomicAppendAck(ref m) => self.untrack(node, m.cookie),
+            _ => (),
+        }
+    }
+
+    fn untrack(&self, node: NodeId, cookie: Cookie) {
+        let mut requests = self.requests.lock().unwrap();
+        if let HMEntry::Occupied(mut o) = requests.entry((node, cookie)) {
+            o.get_mut().pop();
+            if o.get().is_empty() {
+                o.remove();
+            }
+        }
+        if requests.is_empty() {
+            self.empty.notify_all();
+        }
+    }
+
+    // forgets the requests to `node`, ie. after the connections to it are lost
+    fn untrack_node(&self, node: NodeId) {
+        let mut requests = self.requests.lock().unwrap();
+        let keys: Vec<_> = requests.keys().filter(|k| k.0 == node).cloned().collect();
+        if !keys.is_empty() {
+            debug!("Forgetting {} requests to {}", keys.len(), node);
+        }
+        for key in keys {
+            requests.remove(&key);
+        }
+        if requests.is_empty() {
+            self.empty.notify_all();
+        }
+    }
+
+    // waits up to `timeout` for all requests to be acked (or expire),
+    // then takes the remaining ones
+    fn wait_empty(&self, timeout: Duration) -> Vec<(NodeId, FabricMsg)> {
+        let deadline = Instant::now() + timeout;
+        let mut requests = self.requests.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            while requests.pop_expired(now).is_some() {}
+            if requests.is_empty() || now >= deadline {
+                break;
+            }
+            // wake up to drop the requests expiring in the meantime too
+            let wait = cmp::min(deadline - now, self.timeout);
+            requests = self.empty.wait_timeout(requests, wait).unwrap().0;
+        }
+        let keys: Vec<_> = requests.keys().cloned().collect();
+        let mut timed_out = Vec::new();
+        for key in keys {
+            let acks = requests.remove(&key).unwrap();
+            timed_out.extend(acks.into_iter().map(|ack| (key.0, ack)));
+        }
+        requests.clear();
+        timed_out
+    }
+}
+
 struct SharedContext {
     node: NodeId,
     addr: SocketAddr,
@@ -119,14 +573,114 @@ struct SharedContext {
     nodes_addr: RwLock<IdHashMap<NodeId, SocketAddr>>,
     connections: RwLock<IdHashMap<NodeId, Vec<(usize, SenderChan)>>>,
     connection_gen: AtomicUsize,
+    acks: AckCoalescer,
+    outstanding: Outstanding,
+    // new requests are refused while draining
+    draining: AtomicBool,
+    codec: Arc<FabricCodec>,
+    // advertised to peers on connection setup
+    capabilities: RwLock<Vec<String>>,
+    // capabilities supported by both ends, per connected peer
+    peer_capabilities: RwLock<IdHashMap<NodeId, Vec<String>>>,
 }
 
 impl SharedContext {
+    fn send_frame(
+        &self,
+        node: NodeId,
+        vnode: Option<VNodeNo>,
+        frame: Bytes,
+    ) -> Result<(), FabricError> {
+        let connections = self.connections.read().unwrap();
+        if let Some(o) = connections.get(&node) {
+            if let Some(&(connection_id, ref chan)) = thread_rng().choose::<(_, _)>(o) {
+                if let Err(_) = chan.unbounded_send((vnode, frame)) {
+                    warn!("Can't send to fabric {}-{} chan", node, connection_id,);
+                } else {
+                    return Ok(());
+                }
+            } else {
+                warn!("DROPING MSG - No channel available for {:?}", node);
+            }
+        } else {
+            warn!("DROPING MSG - No entry for node {:?}", node);
+        }
+
+        Err(FabricError::NoRoute)
+    }
+
+    fn send_ack(context: &Arc<Self>, node: NodeId, ack: FabricMsg) -> Result<(), FabricError> {
+        if !context.connections.read().unwrap().contains_key(&node) {
+            warn!("DROPING MSG - No entry for node {:?}", node);
+            return Err(FabricError::NoRoute);
+        }
+        if let Some(ack) = context.acks.push(node, ack) {
+            let result = context.send_ack_frame(node, &ack);
+            Self::schedule_ack_flush(context, node);
+            return result;
+        }
+        Ok(())
+    }
+
+    // flushes the acks buffered for the node at the end of the window,
+    // which is extended for as long as there are acks to flush
+    fn schedule_ack_flush(context: &Arc<Self>, node: NodeId) {
+        let context_cloned = context.clone();
+        context.loop_remote.spawn(move |h| {
+            tokio::reactor::Timeout::new(Duration::from_millis(FABRIC_ACK_COALESCE_MS), h)
+                .expect("Can't create ack timeout")
+                .then(move |_| {
+                    if let Some(msg) = context_cloned.acks.take(node) {
+                        let _ = context_cloned.send_ack_frame(node, &msg);
+                        Self::schedule_ack_flush(&context_cloned, node);
+                    }
+                    Ok(())
+                })
+        });
+    }
+
+    fn send_ack_frame(&self, node: NodeId, msg: &FabricMsg) -> Result<(), FabricError> {
+        let msg: FabricMsgRef = msg.into();
+        self.send_frame(node, msg.vnode(), FramedCodec::serialize(&*self.codec, msg))
+    }
+
+    fn peer_info(&self) -> MsgPeerInfo {
+        MsgPeerInfo {
+            node: self.node,
+            version: FABRIC_PROTOCOL_VERSION,
+            capabilities: self.capabilities.read().unwrap().clone(),
+        }
+    }
+
+    fn on_peer_info(&self, peer: NodeId, info: MsgPeerInfo) {
+        let accepted: Vec<_> = {
+            let capabilities = self.capabilities.read().unwrap();
+            info.capabilities
+                .into_iter()
+                .filter(|c| capabilities.contains(c))
+                .collect()
+        };
+        debug!(
+            "Node {} version {} accepted capabilities {:?}",
+            peer, info.version, accepted
+        );
+        self.peer_capabilities
+            .write()
+            .unwrap()
+            .insert(peer, accepted.clone());
+        let ack = MsgPeerInfoAck {
+            node: self.node,
+            accepted_capabilities: accepted,
+        };
+        let _ = self.send_frame(peer, None, FramedCodec::serialize(&*self.codec, (&ack).into()));
+    }
+
     fn register_node(&self, peer: NodeId, peer_addr: SocketAddr) -> Option<SocketAddr> {
         self.nodes_addr.write().unwrap().insert(peer, peer_addr)
     }
 
     fn remove_node(&self, peer: NodeId) -> Option<SocketAddr> {
+        self.peer_capabilities.write().unwrap().remove(&peer);
         self.nodes_addr.write().unwrap().remove(&peer)
     }
 
@@ -153,20 +707,29 @@ impl SharedContext {
 
     fn remove_connection(&self, peer: NodeId, connection_id: usize) {
         debug!("Remove_connection peer: {}, id: {:?}", peer, connection_id);
-        let mut locked = self.connections.write().unwrap();
-        if let HMEntry::Occupied(mut o) = locked.entry(peer) {
-            let p = o
-                .get()
-                .iter()
-                .position(|x| x.0 == connection_id)
-                .expect("connection_id not found");
-            o.get_mut().swap_remove(p);
-            // cleanup entry if empty
-            if o.get().is_empty() {
-                o.remove();
+        let is_last = {
+            let mut locked = self.connections.write().unwrap();
+            if let HMEntry::Occupied(mut o) = locked.entry(peer) {
+                let p = o
+                    .get()
+                    .iter()
+                    .position(|x| x.0 == connection_id)
+                    .expect("connection_id not found");
+                o.get_mut().swap_remove(p);
+                // cleanup entry if empty
+                if o.get().is_empty() {
+                    o.remove();
+                    true
+                } else {
+                    false
+                }
+            } else {
+                panic!("Peer not found in connections");
             }
-        } else {
-            panic!("Peer not found in connections");
+        };
+        // acks of requests sent through the lost connections won't arrive
+        if is_last {
+            self.outstanding.untrack_node(peer);
         }
     }
 }
@@ -180,6 +743,32 @@ impl ReaderContext {
     }
 
     fn dispatch(&self, msg: FabricMsg) {
+        let msg = match msg {
+            FabricMsg::AckBatch(batch) => {
+                for ack in batch.acks {
+                    self.dispatch(ack);
+                }
+                return;
+            }
+            FabricMsg::PeerInfo(info) => {
+                self.context.on_peer_info(self.peer, info);
+                return;
+            }
+            FabricMsg::PeerInfoAck(ack) => {
+                debug!(
+                    "Node {} acked capabilities {:?}",
+                    self.peer, ack.accepted_capabilities
+                );
+                self.context
+                    .peer_capabilities
+                    .write()
+                    .unwrap()
+                    .insert(self.peer, ack.accepted_capabilities);
+                return;
+            }
+            msg => msg,
+        };
+        self.context.outstanding.resolve(self.peer, &msg);
         let msg_type = msg.get_type();
         if let Some(handler) = self
             .context
@@ -293,14 +882,28 @@ impl Fabric {
         debug!("Stablished connection with {:?}", socket.peer_addr());
         let _ = socket.set_nodelay(true);
         let _ = socket.set_keepalive(Some(Duration::from_millis(FABRIC_KEEPALIVE_MS)));
-        let mut buffer = [0u8; 8];
+        // u64(le) node id + u8 codec id
+        let mut buffer = [0u8; 9];
         (&mut buffer[..])
             .write_u64::<LittleEndian>(context.node)
             .unwrap();
+        buffer[8] = context.codec.id();
         let fut = tokio_io::write_all(socket, buffer)
             .and_then(|(s, b)| tokio_io::read_exact(s, b))
             .and_then(move |(s, b)| {
                 let peer_id = (&b[..]).read_u64::<LittleEndian>().unwrap();
+                if b[8] != context.codec.id() {
+                    warn!(
+                        "Refusing connection to node {}, codec {} differs from {}",
+                        peer_id,
+                        b[8],
+                        context.codec.id()
+                    );
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Fabric codec mismatch",
+                    ));
+                }
                 debug!("Identified connection to node {}", peer_id);
                 Ok((s, peer_id, context))
             });
@@ -314,9 +917,12 @@ impl Fabric {
         context: Arc<SharedContext>,
     ) -> Box<Future<Item = (), Error = io::Error>> {
         let (socket_rx, socket_tx) = socket.split();
-        let socket_tx = codec::FramedWrite::new(socket_tx, FramedBincodeCodec);
-        let socket_rx = codec::FramedRead::new(socket_rx, FramedBincodeCodec);
+        let socket_tx = codec::FramedWrite::new(socket_tx, FramedCodec(context.codec.clone()));
+        let socket_rx = codec::FramedRead::new(socket_rx, FramedCodec(context.codec.clone()));
         let (chan_tx, chan_rx) = fmpsc::unbounded();
+        // goes out before anything else sent through the connection
+        let info = FramedCodec::serialize(&*context.codec, (&context.peer_info()).into());
+        let _ = chan_tx.unbounded_send((None, info));
 
         let ctx_rx = ReaderContext::new(context.clone(), peer);
         let fut_rx = socket_rx.for_each(move |msg| {
@@ -326,7 +932,9 @@ impl Fabric {
 
         let ctx_tx = WriterContext::new(context, peer, chan_tx);
         let fut_tx = socket_tx
-            .send_all(chan_rx.map_err(|_| io::Error::from(io::ErrorKind::Other)))
+            .send_all(PrioritizedStream::new(
+                chan_rx.map_err(|_| io::Error::from(io::ErrorKind::Other)),
+            ))
             .then(move |r| {
                 // hold onto ctx_tx until the stream is done
                 drop(ctx_tx);
@@ -339,6 +947,7 @@ impl Fabric {
     fn init(
         node: NodeId,
         config: Config,
+        codec: Arc<FabricCodec>,
         handle: tokio::reactor::Handle,
     ) -> Result<Arc<SharedContext>, GenericError> {
         let context = Arc::new(SharedContext {
@@ -350,6 +959,12 @@ impl Fabric {
             con_handlers: Default::default(),
             connections: Default::default(),
             connection_gen: Default::default(),
+            acks: Default::default(),
+            outstanding: Outstanding::new(Duration::from_millis(config.request_timeout as _)),
+            draining: Default::default(),
+            codec: codec,
+            capabilities: Default::default(),
+            peer_capabilities: Default::default(),
         });
 
         let listener = tokio::net::TcpListener::bind(&context.addr, &handle)?;
@@ -367,6 +982,16 @@ impl Fabric {
     }
 
     pub fn new(node: NodeId, config: &Config) -> Result<Self, GenericError> {
+        Self::with_codec(node, config, Arc::new(BincodeCodec))
+    }
+
+    /// Like `new` but using `codec` for the message payloads,
+    /// peers using a different codec are refused.
+    pub fn with_codec(
+        node: NodeId,
+        config: &Config,
+        codec: Arc<FabricCodec>,
+    ) -> Result<Self, GenericError> {
         let config = config.clone();
         let (init_tx, init_rx) = mpsc::channel();
         let thread = thread::Builder::new()
@@ -374,13 +999,15 @@ impl Fabric {
             .spawn(move || {
                 let mut core = tokio::reactor::Core::new().unwrap();
                 let (completer_tx, completer_rx) = foneshot::channel();
-                init_tx.send(Self::init(node, config, core.handle()).map(|c| (c, completer_tx)))?;
+                init_tx.send(
+                    Self::init(node, config, codec, core.handle()).map(|c| (c, completer_tx)),
+                )?;
                 core.run(completer_rx).map_err(From::from)
             }).unwrap();
         let (context, completer) = init_rx.recv()??;
         Ok(Fabric {
             context: context,
-            loop_thread: Some((completer, thread)),
+            loop_thread: Mutex::new(Some((completer, thread))),
         })
     }
 
@@ -392,6 +1019,23 @@ impl Fabric {
             .insert(msg_type as u8, handler);
     }
 
+    /// Sets the capabilities advertised to peers, connections established
+    /// before the call keep the previous ones.
+    pub fn set_capabilities(&self, capabilities: Vec<String>) {
+        *self.context.capabilities.write().unwrap() = capabilities;
+    }
+
+    /// Capabilities supported by both this node and `node`,
+    /// None until the peer info exchange of the connection completes.
+    pub fn peer_capabilities(&self, node: NodeId) -> Option<Vec<String>> {
+        self.context
+            .peer_capabilities
+            .read()
+            .unwrap()
+            .get(&node)
+            .cloned()
+    }
+
     pub fn register_con_handler(&self, handler: FabricConFn) {
         self.context.con_handlers.write().unwrap().push(handler);
     }
@@ -458,6 +1102,20 @@ impl Fabric {
         if node == self.context.node {
             panic!("Can't send message to self");
         }
+        let is_request = match msg {
+            FabricMsgRef::RemoteGet(..)
+            | FabricMsgRef::RemoteGetField(..)
+            | FabricMsgRef::RemoteSet(..)
+            | FabricMsgRef::RemoteCompare(..)
+            | FabricMsgRef::RemoteLock(..)
+            | FabricMsgRef::RemoteGetRange(..)
+            | FabricMsgRef::RemoteAtomicAppend(..)
+            | FabricMsgRef::SyncStart(..) => true,
+            _ => false,
+        };
+        if is_request && self.context.draining.load(Ordering::SeqCst) {
+            return Err(FabricError::ShuttingDown);
+        }
         if cfg!(test) {
             let droppable = match msg.get_type() {
                 FabricMsgType::Crud => false,
@@ -475,33 +1133,64 @@ impl Fabric {
             }
         }
 
-        let serialized_msg = FramedBincodeCodec::serialize(msg);
-        let connections = self.context.connections.read().unwrap();
-        if let Some(o) = connections.get(&node) {
-            if let Some(&(connection_id, ref chan)) = thread_rng().choose::<(_, _)>(o) {
-                if let Err(_) = chan.unbounded_send(serialized_msg) {
-                    warn!("Can't send to fabric {}-{} chan", node, connection_id,);
-                } else {
-                    return Ok(());
-                }
-            } else {
-                warn!("DROPING MSG - No channel available for {:?}", node);
-            }
-        } else {
-            warn!("DROPING MSG - No entry for node {:?}", node);
+        // small and frequent, worth coalescing
+        let ack: Option<FabricMsg> = match msg {
+            FabricMsgRef::SyncAck(m) => Some(m.clone().into()),
+            FabricMsgRef::RemoteSetAck(m) => Some(m.clone().into()),
+            _ => None,
+        };
+        if let Some(ack) = ack {
+            return SharedContext::send_ack(&self.context, node, ack);
         }
 
-        Err(FabricError::NoRoute)
+        // tracked before sending so the ack can't arrive first
+        let tracked = Outstanding::error_ack(&msg).map(|(cookie, ack)| {
+            self.context.outstanding.track(node, cookie, ack);
+            cookie
+        });
+        let result = self.context.send_frame(
+            node,
+            msg.vnode(),
+            FramedCodec::serialize(&*self.context.codec, msg),
+        );
+        if let (true, Some(cookie)) = (result.is_err(), tracked) {
+            self.context.outstanding.untrack(node, cookie);
+        }
+        result
+    }
+
+    /// Graceful shutdown: refuses new requests, waits up to `timeout` for the
+    /// outstanding ones to be acked (acks and other messages still flow) and closes
+    /// all connections. Requests still pending after the timeout are completed locally
+    /// with a ShuttingDown error ack, returns how many. Requests older than the
+    /// request timeout or to peers that disconnected aren't waited for.
+    pub fn drain(&self, timeout: Duration) -> usize {
+        info!("Draining fabric");
+        self.context.draining.store(true, Ordering::SeqCst);
+        let timed_out = self.context.outstanding.wait_empty(timeout);
+        for &(node, ref ack) in &timed_out {
+            debug!("Failing request to {} on drain: {:?}", node, ack);
+        }
+        let count = timed_out.len();
+        for (node, ack) in timed_out {
+            ReaderContext::new(self.context.clone(), node).dispatch(ack);
+        }
+        self.close();
+        count
+    }
+
+    fn close(&self) {
+        if let Some((c, t)) = self.loop_thread.lock().unwrap().take() {
+            let _ = c.send(());
+            let _ = t.join();
+        }
     }
 }
 
 impl Drop for Fabric {
     fn drop(&mut self) {
         warn!("droping fabric");
-        if let Some((c, t)) = self.loop_thread.take() {
-            let _ = c.send(());
-            let _ = t.join();
-        }
+        self.close();
     }
 }
 
@@ -513,6 +1202,700 @@ mod tests {
     use std::sync::{atomic, Arc};
     use std::thread;
     use std::time::Duration;
+    use types::{ClusterConfig, ConsistencyLevel, Cookie};
+    use version_vector::{BitmappedVersionVector, VersionVector};
+
+    fn check_round_trip(fabric_codec: Arc<FabricCodec>, msg: FabricMsg) {
+        let frame = FramedCodec::serialize(&*fabric_codec, (&msg).into());
+        assert_eq!(peek_type(&frame), msg.get_type());
+        let mut src = BytesMut::from(&frame[..]);
+        let decoded = codec::Decoder::decode(&mut FramedCodec(fabric_codec), &mut src)
+            .unwrap()
+            .unwrap();
+        assert!(src.is_empty());
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", msg));
+    }
+
+    fn check_peek_type(msg: FabricMsg) {
+        check_round_trip(Arc::new(BincodeCodec), msg);
+    }
+
+    // bincode with a version byte in front of every payload
+    struct VersionedCodec;
+
+    impl FabricCodec for VersionedCodec {
+        fn id(&self) -> u8 {
+            2
+        }
+
+        fn encode(&self, item: &FabricMsgRef, dst: &mut BytesMut) -> Result<(), GenericError> {
+            dst.reserve(1);
+            dst.put_u8(1);
+            BincodeCodec.encode(item, dst)
+        }
+
+        fn decode(&self, payload: &[u8]) -> Result<FabricMsg, GenericError> {
+            match payload.first() {
+                Some(&1) => BincodeCodec.decode(&payload[1..]),
+                _ => Err("Unknown payload version".into()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_len() {
+        let decode = |src: &mut BytesMut| {
+            codec::Decoder::decode(&mut FramedCodec(Arc::new(BincodeCodec)), src)
+        };
+        // oversized and empty frames are refused from the header alone
+        for &len in &[0, MAX_FRAME_LEN + 1, u32::max_value()] {
+            let mut src = BytesMut::new();
+            src.put_u32_le(len);
+            src.put_u8(FabricMsgType::Crud.to_u8());
+            assert_eq!(
+                decode(&mut src).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+        // frames up to the limit are buffered until complete
+        let mut src = BytesMut::new();
+        src.put_u32_le(MAX_FRAME_LEN);
+        src.put_u8(FabricMsgType::Crud.to_u8());
+        assert!(decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), 5);
+    }
+
+    #[test]
+    fn test_peer_info() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6489".parse().unwrap(),
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6490".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::new(2, &config2).unwrap();
+        fabric1.set_capabilities(vec!["compression/lz4".into(), "batched-gets".into()]);
+        fabric2.set_capabilities(vec!["batched-gets".into(), "other".into()]);
+        assert_eq!(fabric1.peer_capabilities(2), None);
+        fabric1.register_node(2, "127.0.0.1:6490".parse().unwrap());
+        thread::sleep(Duration::from_millis(50));
+        // both ends settle on the common subset
+        let accepted = Some(vec!["batched-gets".to_string()]);
+        assert_eq!(fabric1.peer_capabilities(2), accepted);
+        assert_eq!(fabric2.peer_capabilities(1), accepted);
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        let _ = env_logger::try_init();
+        check_round_trip(
+            Arc::new(VersionedCodec),
+            MsgRemoteSet {
+                vnode: 1,
+                cookie: Cookie::new(1, 2),
+                writes: vec![(Bytes::from("a"), Cube::default(), true)],
+                reply: true,
+                request_id: None,
+                idempotency_key: None,
+                expected_version: None,
+                trace_id: None,
+                deadline: None,
+            }.into(),
+        );
+
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6487".parse().unwrap(),
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6488".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::with_codec(2, &config2, Arc::new(VersionedCodec)).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6488".parse().unwrap());
+        fabric2.register_node(1, "127.0.0.1:6487".parse().unwrap());
+        thread::sleep(Duration::from_millis(10));
+        // peers with different codecs never talk
+        assert!(fabric1.connections().is_empty());
+        assert!(fabric2.connections().is_empty());
+    }
+
+    #[test]
+    fn test_peek_type() {
+        let cookie = Cookie::new(1, 2);
+        check_peek_type(
+            MsgRemoteGet {
+                vnode: 1,
+                cookie: cookie,
+                keys: vec![Bytes::from("a")],
+                trace_id: Some([1; 16]),
+                deadline: None,
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteGetAck {
+                vnode: 1,
+                cookie: cookie,
+                result: Err(FabricError::NoRoute),
+                trace_id: None,
+                version: None,
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteGetField {
+                vnode: 1,
+                cookie: cookie,
+                key: Bytes::from("a"),
+                field: Bytes::from("b"),
+                deadline: None,
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteSet {
+                vnode: 1,
+                cookie: cookie,
+                writes: vec![(Bytes::from("a"), Cube::default(), true)],
+                reply: true,
+                request_id: Some(3),
+                idempotency_key: None,
+                expected_version: None,
+                trace_id: None,
+                deadline: None,
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteSetAck {
+                vnode: 1,
+                cookie: cookie,
+                result: Ok(vec![None]),
+                trace_id: None,
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteCompare {
+                vnode: 1,
+                cookie: cookie,
+                key: Bytes::from("a"),
+                expected: Cube::default(),
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteCompareAck {
+                vnode: 1,
+                cookie: cookie,
+                matches: false,
+                actual: Some(Cube::default()),
+            }.into(),
+        );
+        let token = LockToken {
+            vnode: 1,
+            lock_key: Bytes::from("a"),
+            owner: 2,
+            id: 3,
+        };
+        check_peek_type(
+            MsgRemoteLock {
+                vnode: 1,
+                cookie: cookie,
+                lock_key: Bytes::from("a"),
+                ttl_ms: 1000,
+                owner: 2,
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteLockAck {
+                cookie: cookie,
+                result: Ok(token.clone()),
+            }.into(),
+        );
+        check_peek_type(MsgRemoteUnlock { token: token }.into());
+        check_peek_type(
+            MsgRemoteGetRange {
+                vnode: 1,
+                cookie: cookie,
+                start: Bytes::from("a"),
+                end: Bytes::new(),
+                limit: 10,
+                cursor: None,
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteGetRangeAck {
+                cookie: cookie,
+                result: Ok(vec![(Bytes::from("a"), Cube::default())]),
+                next_cursor: Some(Bytes::from("a")),
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteAtomicAppend {
+                vnode: 1,
+                cookie: cookie,
+                prefix: 2,
+                value: Bytes::from("a"),
+            }.into(),
+        );
+        check_peek_type(
+            MsgRemoteAtomicAppendAck {
+                cookie: cookie,
+                result: Ok(3),
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncStart {
+                vnode: 1,
+                cookie: cookie,
+                clocks_in_peer: BitmappedVersionVector::new(),
+                target: Some(2),
+                resume: Some(Default::default()),
+                batch_size: Some(10),
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncSend {
+                vnode: 1,
+                cookie: cookie,
+                seq: 1,
+                key: Bytes::from("a"),
+                value: Cube::default(),
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncSendBatch {
+                vnode: 1,
+                cookie: cookie,
+                entries: vec![(1, Bytes::from("a"), Cube::default())],
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncAck {
+                vnode: 1,
+                cookie: cookie,
+                seq: 1,
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncFin {
+                vnode: 1,
+                cookie: cookie,
+                result: Ok(BitmappedVersionVector::new()),
+                entries_synced: 1,
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncCheckpoint {
+                vnode: 1,
+                cookie: cookie,
+                checkpoint: SyncCheckpoint {
+                    last_acked_seq: 1,
+                    clocks: BitmappedVersionVector::new(),
+                    next_key: Some(Bytes::from("a")),
+                },
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncStats {
+                vnode: 1,
+                cookie: cookie,
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncRollback {
+                vnode: 1,
+                cookie: cookie,
+            }.into(),
+        );
+        check_peek_type(
+            MsgSyncStatsAck {
+                vnode: 1,
+                cookie: cookie,
+                entries_sent: 1,
+                bytes_sent: 1,
+                last_seq: 1,
+                elapsed_ms: 1,
+            }.into(),
+        );
+        check_peek_type(
+            MsgAckBatch {
+                acks: vec![
+                    MsgSyncAck {
+                        vnode: 1,
+                        cookie: cookie,
+                        seq: 1,
+                    }.into(),
+                ],
+            }.into(),
+        );
+        check_peek_type(FabricMsg::DHTAE(VersionVector::new()));
+        check_peek_type(FabricMsg::DHTSync(Bytes::from("dht")));
+        check_peek_type(
+            MsgDHTRebalance {
+                epoch: 1,
+                plan: vec![VNodeMigration {
+                    vnode: 1,
+                    source: 1,
+                    destination: 2,
+                }],
+            }.into(),
+        );
+        check_peek_type(
+            MsgDHTRebalanceAck {
+                epoch: 1,
+                results: vec![(1, Ok(())), (2, Err(FabricError::BadVNodeStatus))],
+            }.into(),
+        );
+        check_peek_type(
+            MsgConfigSync {
+                epoch: 1,
+                config: ClusterConfig {
+                    consistency_read: Some(ConsistencyLevel::Quorum),
+                    consistency_write: None,
+                },
+            }.into(),
+        );
+        check_peek_type(MsgConfigSyncAck { epoch: 1, node: 1 }.into());
+
+        assert_eq!(peek_type(&[]), FabricMsgType::Unknown);
+        assert_eq!(peek_type(&[1, 0, 0, 0]), FabricMsgType::Unknown);
+        assert_eq!(peek_type(&[1, 0, 0, 0, 255]), FabricMsgType::Unknown);
+    }
+
+    #[test]
+    fn test_send_queue() {
+        let frame = |msg: FabricMsg| FramedCodec::serialize(&BincodeCodec, (&msg).into());
+        let crud = || {
+            frame(
+                MsgRemoteGet {
+                    vnode: 0,
+                    cookie: Default::default(),
+                    keys: vec![],
+                    trace_id: None,
+                    deadline: None,
+                }.into(),
+            )
+        };
+        let synch = || {
+            frame(
+                MsgSyncAck {
+                    vnode: 0,
+                    cookie: Default::default(),
+                    seq: 0,
+                }.into(),
+            )
+        };
+        let dht = || frame(FabricMsg::DHTSync(Bytes::new()));
+
+        let mut queue = SendQueue::default();
+        for _ in 0..10 {
+            queue.push(Some(0), synch());
+        }
+        for _ in 0..10 {
+            queue.push(Some(0), crud());
+        }
+        queue.push(Some(0), dht());
+        assert_eq!(queue.len(), 21);
+        let types: Vec<_> = (0..21).map(|_| peek_type(&queue.pop().unwrap())).collect();
+        assert!(queue.pop().is_none());
+        assert!(types[..10].iter().all(|&t| t == FabricMsgType::Crud));
+        assert_eq!(types[10], FabricMsgType::DHT);
+        assert!(types[11..].iter().all(|&t| t == FabricMsgType::Synch));
+
+        // a steady stream of crud msgs doesn't starve the others
+        let mut queue = SendQueue::default();
+        queue.push(Some(0), dht());
+        queue.push(Some(0), synch());
+        let mut types = Vec::new();
+        for _ in 0..100 {
+            queue.push(Some(0), crud());
+            types.push(peek_type(&queue.pop().unwrap()));
+        }
+        let dht_pos = types.iter().position(|&t| t == FabricMsgType::DHT).unwrap();
+        let synch_pos = types.iter().position(|&t| t == FabricMsgType::Synch).unwrap();
+        assert_eq!(dht_pos, SEND_QUEUE_MAX_SKIPS);
+        assert!(synch_pos > dht_pos && synch_pos <= 2 * SEND_QUEUE_MAX_SKIPS + 1);
+        assert_eq!(types.iter().filter(|&&t| t == FabricMsgType::Crud).count(), 98);
+    }
+
+    #[test]
+    fn test_send_queue_fairness() {
+        let synch = |vnode| {
+            let msg: FabricMsg = MsgSyncAck {
+                vnode: vnode,
+                cookie: Default::default(),
+                seq: 0,
+            }.into();
+            FramedCodec::serialize(&BincodeCodec, (&msg).into())
+        };
+        let (bulk, other) = (synch(1), synch(2));
+
+        // vnode 1 is doing a bulk sync when vnode 2 starts sending
+        let mut queue = SendQueue::default();
+        for _ in 0..100 {
+            queue.push(Some(1), bulk.clone());
+        }
+        for _ in 0..5 {
+            queue.push(Some(2), other.clone());
+        }
+        let popped: Vec<_> = (0..10).map(|_| queue.pop().unwrap()).collect();
+        for pair in popped.chunks(2) {
+            assert_eq!(pair, &[bulk.clone(), other.clone()][..]);
+        }
+        // idle vnodes don't take a turn
+        assert_eq!(queue.queues[2].ready.len(), 1);
+        assert!(!queue.queues[2].queues.contains_key(&Some(2)));
+        assert!((0..95).all(|_| queue.pop().unwrap() == bulk));
+        assert!(queue.pop().is_none());
+        assert!(queue.queues[2].is_empty());
+
+        // a vnode that becomes active again is served right away
+        queue.push(Some(1), bulk.clone());
+        queue.push(Some(1), bulk.clone());
+        queue.push(Some(2), other.clone());
+        assert_eq!(queue.pop().unwrap(), bulk);
+        assert_eq!(queue.pop().unwrap(), other);
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_ack_coalescer() {
+        let ack = |seq| -> FabricMsg {
+            MsgSyncAck {
+                vnode: 0,
+                cookie: Default::default(),
+                seq: seq,
+            }.into()
+        };
+        let coalescer = AckCoalescer::default();
+        assert!(coalescer.take(1).is_none());
+
+        // the first ack goes out right away and opens the window
+        assert!(coalescer.push(1, ack(0)).is_some());
+        assert!(coalescer.push(1, ack(1)).is_none());
+        match coalescer.take(1) {
+            Some(FabricMsg::SyncAck(m)) => assert_eq!(m.seq, 1),
+            msg => panic!("unexpected {:?}", msg),
+        }
+
+        // the window stays open while there are acks
+        assert!(coalescer.push(1, ack(2)).is_none());
+        assert!(coalescer.push(2, ack(3)).is_some());
+        assert!(coalescer.push(1, ack(4)).is_none());
+        assert!(coalescer.push(1, ack(5)).is_none());
+        match coalescer.take(1) {
+            Some(FabricMsg::AckBatch(m)) => assert_eq!(m.acks.len(), 3),
+            msg => panic!("unexpected {:?}", msg),
+        }
+        // and closes once idle
+        assert!(coalescer.take(1).is_none());
+        assert!(coalescer.push(1, ack(6)).is_some());
+        assert!(coalescer.take(2).is_none());
+        assert!(coalescer.push(2, ack(7)).is_some());
+    }
+
+    #[test]
+    fn test_ack_batching() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6483".parse().unwrap(),
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6484".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::new(2, &config2).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6484".parse().unwrap());
+        fabric2.register_node(1, "127.0.0.1:6483".parse().unwrap());
+        thread::sleep(Duration::from_millis(10));
+
+        let seqs = Arc::new(Mutex::new(Vec::new()));
+        let seqs_ = seqs.clone();
+        fabric2.register_msg_handler(
+            FabricMsgType::Synch,
+            Box::new(move |_, msg| match msg {
+                FabricMsg::SyncAck(m) => seqs_.lock().unwrap().push(m.seq),
+                msg => panic!("unexpected {:?}", msg),
+            }),
+        );
+        let ack = |seq| MsgSyncAck {
+            vnode: 0,
+            cookie: Default::default(),
+            seq: seq,
+        };
+
+        // a lone ack isn't held back
+        fabric1.send_msg(2, &ack(0)).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(*seqs.lock().unwrap(), vec![0]);
+        assert!(fabric1.context.acks.pending.lock().unwrap().is_empty());
+
+        // a burst is sent as a batch and unpacked on the other side
+        for seq in 1..10 {
+            fabric1.send_msg(2, &ack(seq)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(10));
+        // there are 2 connections between the nodes, so order isn't guaranteed
+        let mut seqs = seqs.lock().unwrap().clone();
+        seqs.sort();
+        assert_eq!(seqs, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_drain() {
+        let _ = env_logger::try_init();
+        let config1 = Config {
+            fabric_addr: "127.0.0.1:6485".parse().unwrap(),
+            ..Default::default()
+        };
+        let config2 = Config {
+            fabric_addr: "127.0.0.1:6486".parse().unwrap(),
+            ..Default::default()
+        };
+        let fabric1 = Fabric::new(1, &config1).unwrap();
+        let fabric2 = Fabric::new(2, &config2).unwrap();
+        fabric1.register_node(2, "127.0.0.1:6486".parse().unwrap());
+        fabric2.register_node(1, "127.0.0.1:6485".parse().unwrap());
+        thread::sleep(Duration::from_millis(10));
+
+        let (req_tx, req_rx) = mpsc::channel();
+        let req_tx = Mutex::new(req_tx);
+        fabric2.register_msg_handler(
+            FabricMsgType::Crud,
+            Box::new(move |_, msg| {
+                let _ = req_tx.lock().unwrap().send(msg);
+            }),
+        );
+        let (ack_tx, ack_rx) = mpsc::channel();
+        let ack_tx = Mutex::new(ack_tx);
+        fabric1.register_msg_handler(
+            FabricMsgType::Crud,
+            Box::new(move |_, msg| {
+                let _ = ack_tx.lock().unwrap().send(msg);
+            }),
+        );
+
+        let set = |cookie| MsgRemoteSet {
+            vnode: 0,
+            cookie: cookie,
+            writes: vec![(Bytes::from("a"), Cube::default(), true)],
+            reply: true,
+            request_id: None,
+            idempotency_key: None,
+            expected_version: None,
+            trace_id: None,
+            deadline: None,
+        };
+        let (answered, pending) = (Cookie::new(1, 1), Cookie::new(2, 2));
+        fabric1.send_msg(2, &set(answered)).unwrap();
+        fabric1.send_msg(2, &set(pending)).unwrap();
+        for _ in 0..2 {
+            req_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        }
+        // only the first request is answered
+        fabric2
+            .send_msg(
+                1,
+                &MsgRemoteSetAck {
+                    vnode: 0,
+                    cookie: answered,
+                    result: Ok(vec![]),
+                    trace_id: None,
+                },
+            ).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(fabric1.drain(Duration::from_millis(200)), 1);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        let mut acks: Vec<_> = ack_rx
+            .try_iter()
+            .map(|msg| match msg {
+                FabricMsg::RemoteSetAck(m) => (m.cookie, m.result.is_ok()),
+                msg => panic!("unexpected {:?}", msg),
+            }).collect();
+        acks.sort_by_key(|&(c, _)| c == pending);
+        assert_eq!(acks, vec![(answered, true), (pending, false)]);
+        assert_eq!(
+            fabric1.send_msg(2, &set(Cookie::new(3, 3))),
+            Err(FabricError::ShuttingDown)
+        );
+    }
+
+    #[test]
+    fn test_outstanding() {
+        let error_ack = |cookie| -> FabricMsg {
+            MsgRemoteSetAck {
+                vnode: 0,
+                cookie: cookie,
+                result: Err(FabricError::ShuttingDown),
+                trace_id: None,
+            }.into()
+        };
+        let outstanding = Arc::new(Outstanding::new(Duration::from_millis(50)));
+        let (c1, c2) = (Cookie::new(1, 1), Cookie::new(2, 2));
+        outstanding.track(2, c1, error_ack(c1));
+        outstanding.track(3, c2, error_ack(c2));
+        // the connections to 3 were lost
+        outstanding.untrack_node(3);
+        let timed_out = outstanding.wait_empty(Duration::from_millis(0));
+        assert_eq!(timed_out.len(), 1);
+        assert_eq!(timed_out[0].0, 2);
+
+        // requests are forgotten after the timeout
+        outstanding.track(2, c1, error_ack(c1));
+        thread::sleep(Duration::from_millis(60));
+        assert!(outstanding.wait_empty(Duration::from_millis(0)).is_empty());
+
+        // waiters wake up as soon as the last request is acked
+        outstanding.track(2, c1, error_ack(c1));
+        let outstanding_cloned = outstanding.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            outstanding_cloned.untrack(2, c1);
+        });
+        let start = Instant::now();
+        assert!(outstanding.wait_empty(Duration::from_secs(10)).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut value = Cube::default().into_value().unwrap();
+        let bytes = Bytes::from(vec![0u8; 1024]);
+        value.set(1, 1, Some(bytes), &Default::default());
+        let cube = Cube::Value(value);
+        let size = cube.size_bytes();
+        assert!(size > 1024);
+        let limits = FabricLimits {
+            max_value_bytes: size,
+        };
+        assert_eq!(limits.check_value(&cube), Ok(()));
+        let limits = FabricLimits {
+            max_value_bytes: size - 1,
+        };
+        assert_eq!(limits.check_value(&cube), Err(FabricError::ValueTooLarge));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = FabricRetryPolicy {
+            max_attempts: 4,
+            base_delay_ms: 10,
+            max_jitter_ms: 5,
+        };
+        assert_eq!(policy.delay(1), None);
+        let delays: Vec<_> = (2..5).map(|a| policy.delay(a).unwrap()).collect();
+        for (delay, base) in delays.iter().zip(&[10, 20, 40]) {
+            assert!(*delay >= Duration::from_millis(*base));
+            assert!(*delay <= Duration::from_millis(*base + 5));
+        }
+        assert_eq!(policy.delay(5), None);
+    }
 
     #[test]
     fn test() {
@@ -547,6 +1930,7 @@ mod tests {
                         cookie: Default::default(),
                         vnode: Default::default(),
                         result: Ok(Vec::new()),
+                        trace_id: None,
                     },
                 ).unwrap();
         }
@@ -3,7 +3,10 @@ use cubes::Cube;
 use database::*;
 use version_vector::*;
 
-#[derive(Debug, Copy, Clone)]
+/// Max length (type byte + payload) of the fabric frames accepted from peers
+pub const MAX_FRAME_LEN: u32 = 128 * 1024 * 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum FabricMsgType {
     Crud,
     Synch,
@@ -11,7 +14,28 @@ pub enum FabricMsgType {
     Unknown,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+impl FabricMsgType {
+    /// Tag written in the frame header, see `fabric::peek_type`
+    pub fn to_u8(self) -> u8 {
+        match self {
+            FabricMsgType::Crud => 1,
+            FabricMsgType::Synch => 2,
+            FabricMsgType::DHT => 3,
+            FabricMsgType::Unknown => 0,
+        }
+    }
+
+    pub fn from_u8(tag: u8) -> Self {
+        match tag {
+            1 => FabricMsgType::Crud,
+            2 => FabricMsgType::Synch,
+            3 => FabricMsgType::DHT,
+            _ => FabricMsgType::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum FabricError {
     NoRoute,
     CookieNotFound,
@@ -19,20 +43,71 @@ pub enum FabricError {
     NotReady,
     SyncInterrupted,
     StorageError,
+    ValueTooLarge,
+    // the receiver is throttling writes, retry after the suggested delay
+    RateLimited { retry_after_ms: u32 },
+    // the fabric is draining/closed
+    ShuttingDown,
+    // the lock is owned by someone else and didn't expire yet
+    LockHeld,
+    // the stored causal version differs from the expected one
+    VersionMismatch,
+    // the request deadline passed before the work was done
+    Expired,
+}
+
+impl FabricError {
+    /// Transient errors, the request may succeed if sent again later
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            FabricError::NotReady | FabricError::SyncInterrupted => true,
+            _ => false,
+        }
+    }
+
+    /// Errors caused by the state of the replying node, other replicas may succeed
+    pub fn is_host_specific(&self) -> bool {
+        match *self {
+            FabricError::NotReady => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum FabricMsg {
     RemoteGet(MsgRemoteGet),
     RemoteGetAck(MsgRemoteGetAck),
+    RemoteGetField(MsgRemoteGetField),
     RemoteSet(MsgRemoteSet),
     RemoteSetAck(MsgRemoteSetAck),
+    RemoteCompare(MsgRemoteCompare),
+    RemoteCompareAck(MsgRemoteCompareAck),
+    RemoteLock(MsgRemoteLock),
+    RemoteLockAck(MsgRemoteLockAck),
+    RemoteUnlock(MsgRemoteUnlock),
+    RemoteGetRange(MsgRemoteGetRange),
+    RemoteGetRangeAck(MsgRemoteGetRangeAck),
+    RemoteAtomicAppend(MsgRemoteAtomicAppend),
+    RemoteAtomicAppendAck(MsgRemoteAtomicAppendAck),
     SyncStart(MsgSyncStart),
     SyncSend(MsgSyncSend),
     SyncAck(MsgSyncAck),
     SyncFin(MsgSyncFin),
+    SyncCheckpoint(MsgSyncCheckpoint),
+    SyncStats(MsgSyncStats),
+    SyncStatsAck(MsgSyncStatsAck),
+    SyncRollback(MsgSyncRollback),
+    SyncSendBatch(MsgSyncSendBatch),
+    AckBatch(MsgAckBatch),
+    PeerInfo(MsgPeerInfo),
+    PeerInfoAck(MsgPeerInfoAck),
     DHTAE(VersionVector),
     DHTSync(Bytes),
+    DHTRebalance(MsgDHTRebalance),
+    DHTRebalanceAck(MsgDHTRebalanceAck),
+    ConfigSync(MsgConfigSync),
+    ConfigSyncAck(MsgConfigSyncAck),
     Unknown,
 }
 
@@ -40,14 +115,36 @@ pub enum FabricMsg {
 pub enum FabricMsgRef<'a> {
     RemoteGet(&'a MsgRemoteGet),
     RemoteGetAck(&'a MsgRemoteGetAck),
+    RemoteGetField(&'a MsgRemoteGetField),
     RemoteSet(&'a MsgRemoteSet),
     RemoteSetAck(&'a MsgRemoteSetAck),
+    RemoteCompare(&'a MsgRemoteCompare),
+    RemoteCompareAck(&'a MsgRemoteCompareAck),
+    RemoteLock(&'a MsgRemoteLock),
+    RemoteLockAck(&'a MsgRemoteLockAck),
+    RemoteUnlock(&'a MsgRemoteUnlock),
+    RemoteGetRange(&'a MsgRemoteGetRange),
+    RemoteGetRangeAck(&'a MsgRemoteGetRangeAck),
+    RemoteAtomicAppend(&'a MsgRemoteAtomicAppend),
+    RemoteAtomicAppendAck(&'a MsgRemoteAtomicAppendAck),
     SyncStart(&'a MsgSyncStart),
     SyncSend(&'a MsgSyncSend),
     SyncAck(&'a MsgSyncAck),
     SyncFin(&'a MsgSyncFin),
+    SyncCheckpoint(&'a MsgSyncCheckpoint),
+    SyncStats(&'a MsgSyncStats),
+    SyncStatsAck(&'a MsgSyncStatsAck),
+    SyncRollback(&'a MsgSyncRollback),
+    SyncSendBatch(&'a MsgSyncSendBatch),
+    PeerInfo(&'a MsgPeerInfo),
+    PeerInfoAck(&'a MsgPeerInfoAck),
+    AckBatch(&'a MsgAckBatch),
     DHTAE(&'a VersionVector),
     DHTSync(&'a Bytes),
+    DHTRebalance(&'a MsgDHTRebalance),
+    DHTRebalanceAck(&'a MsgDHTRebalanceAck),
+    ConfigSync(&'a MsgConfigSync),
+    ConfigSyncAck(&'a MsgConfigSyncAck),
     Unknown,
 }
 
@@ -56,40 +153,158 @@ impl FabricMsg {
         match *self {
             FabricMsg::RemoteGet(..)
             | FabricMsg::RemoteGetAck(..)
+            | FabricMsg::RemoteGetField(..)
             | FabricMsg::RemoteSet(..)
-            | FabricMsg::RemoteSetAck(..) => FabricMsgType::Crud,
+            | FabricMsg::RemoteSetAck(..)
+            | FabricMsg::RemoteCompare(..)
+            | FabricMsg::RemoteCompareAck(..)
+            | FabricMsg::RemoteLock(..)
+            | FabricMsg::RemoteLockAck(..)
+            | FabricMsg::RemoteUnlock(..)
+            | FabricMsg::RemoteGetRange(..)
+            | FabricMsg::RemoteGetRangeAck(..)
+            | FabricMsg::RemoteAtomicAppend(..)
+            | FabricMsg::RemoteAtomicAppendAck(..)
+            | FabricMsg::AckBatch(..)
+            | FabricMsg::PeerInfo(..)
+            | FabricMsg::PeerInfoAck(..) => FabricMsgType::Crud,
             FabricMsg::SyncStart(..)
             | FabricMsg::SyncSend(..)
             | FabricMsg::SyncAck(..)
-            | FabricMsg::SyncFin(..) => FabricMsgType::Synch,
-            FabricMsg::DHTSync(..) | FabricMsg::DHTAE(..) => FabricMsgType::DHT,
+            | FabricMsg::SyncFin(..)
+            | FabricMsg::SyncCheckpoint(..)
+            | FabricMsg::SyncStats(..)
+            | FabricMsg::SyncStatsAck(..)
+            | FabricMsg::SyncRollback(..)
+            | FabricMsg::SyncSendBatch(..) => FabricMsgType::Synch,
+            FabricMsg::DHTSync(..)
+            | FabricMsg::DHTAE(..)
+            | FabricMsg::DHTRebalance(..)
+            | FabricMsg::DHTRebalanceAck(..)
+            | FabricMsg::ConfigSync(..)
+            | FabricMsg::ConfigSyncAck(..) => FabricMsgType::DHT,
             _ => unreachable!(),
         }
     }
 }
 
+impl FabricMsg {
+    /// Sets the trace id of the messages that carry one, others are returned as is
+    pub fn with_trace_id(mut self, id: TraceId) -> Self {
+        match self {
+            FabricMsg::RemoteGet(ref mut m) => m.trace_id = Some(id),
+            FabricMsg::RemoteGetAck(ref mut m) => m.trace_id = Some(id),
+            FabricMsg::RemoteSet(ref mut m) => m.trace_id = Some(id),
+            FabricMsg::RemoteSetAck(ref mut m) => m.trace_id = Some(id),
+            _ => (),
+        }
+        self
+    }
+
+    pub fn trace_id(&self) -> Option<TraceId> {
+        match *self {
+            FabricMsg::RemoteGet(ref m) => m.trace_id,
+            FabricMsg::RemoteGetAck(ref m) => m.trace_id,
+            FabricMsg::RemoteSet(ref m) => m.trace_id,
+            FabricMsg::RemoteSetAck(ref m) => m.trace_id,
+            _ => None,
+        }
+    }
+}
+
 impl<'a> FabricMsgRef<'a> {
     pub fn get_type(&self) -> FabricMsgType {
         match *self {
             FabricMsgRef::RemoteGet(..)
             | FabricMsgRef::RemoteGetAck(..)
+            | FabricMsgRef::RemoteGetField(..)
             | FabricMsgRef::RemoteSet(..)
-            | FabricMsgRef::RemoteSetAck(..) => FabricMsgType::Crud,
+            | FabricMsgRef::RemoteSetAck(..)
+            | FabricMsgRef::RemoteCompare(..)
+            | FabricMsgRef::RemoteCompareAck(..)
+            | FabricMsgRef::RemoteLock(..)
+            | FabricMsgRef::RemoteLockAck(..)
+            | FabricMsgRef::RemoteUnlock(..)
+            | FabricMsgRef::RemoteGetRange(..)
+            | FabricMsgRef::RemoteGetRangeAck(..)
+            | FabricMsgRef::RemoteAtomicAppend(..)
+            | FabricMsgRef::RemoteAtomicAppendAck(..)
+            | FabricMsgRef::AckBatch(..)
+            | FabricMsgRef::PeerInfo(..)
+            | FabricMsgRef::PeerInfoAck(..) => FabricMsgType::Crud,
             FabricMsgRef::SyncStart(..)
             | FabricMsgRef::SyncSend(..)
             | FabricMsgRef::SyncAck(..)
-            | FabricMsgRef::SyncFin(..) => FabricMsgType::Synch,
-            FabricMsgRef::DHTSync(..) | FabricMsgRef::DHTAE(..) => FabricMsgType::DHT,
+            | FabricMsgRef::SyncFin(..)
+            | FabricMsgRef::SyncCheckpoint(..)
+            | FabricMsgRef::SyncStats(..)
+            | FabricMsgRef::SyncStatsAck(..)
+            | FabricMsgRef::SyncRollback(..)
+            | FabricMsgRef::SyncSendBatch(..) => FabricMsgType::Synch,
+            FabricMsgRef::DHTSync(..)
+            | FabricMsgRef::DHTAE(..)
+            | FabricMsgRef::DHTRebalance(..)
+            | FabricMsgRef::DHTRebalanceAck(..)
+            | FabricMsgRef::ConfigSync(..)
+            | FabricMsgRef::ConfigSyncAck(..) => FabricMsgType::DHT,
             _ => unreachable!(),
         }
     }
+
+    /// The vnode the message refers to, None for cluster wide messages and batches
+    pub fn vnode(&self) -> Option<VNodeNo> {
+        match *self {
+            FabricMsgRef::RemoteGet(m) => Some(m.vnode),
+            FabricMsgRef::RemoteGetAck(m) => Some(m.vnode),
+            FabricMsgRef::RemoteGetField(m) => Some(m.vnode),
+            FabricMsgRef::RemoteSet(m) => Some(m.vnode),
+            FabricMsgRef::RemoteSetAck(m) => Some(m.vnode),
+            FabricMsgRef::RemoteCompare(m) => Some(m.vnode),
+            FabricMsgRef::RemoteCompareAck(m) => Some(m.vnode),
+            FabricMsgRef::RemoteLock(m) => Some(m.vnode),
+            FabricMsgRef::RemoteLockAck(m) => m.result.as_ref().ok().map(|t| t.vnode),
+            FabricMsgRef::RemoteUnlock(m) => Some(m.token.vnode),
+            FabricMsgRef::RemoteGetRange(m) => Some(m.vnode),
+            FabricMsgRef::RemoteAtomicAppend(m) => Some(m.vnode),
+            FabricMsgRef::SyncStart(m) => Some(m.vnode),
+            FabricMsgRef::SyncSend(m) => Some(m.vnode),
+            FabricMsgRef::SyncAck(m) => Some(m.vnode),
+            FabricMsgRef::SyncFin(m) => Some(m.vnode),
+            FabricMsgRef::SyncCheckpoint(m) => Some(m.vnode),
+            FabricMsgRef::SyncStats(m) => Some(m.vnode),
+            FabricMsgRef::SyncStatsAck(m) => Some(m.vnode),
+            FabricMsgRef::SyncRollback(m) => Some(m.vnode),
+            FabricMsgRef::SyncSendBatch(m) => Some(m.vnode),
+            FabricMsgRef::RemoteGetRangeAck(..)
+            | FabricMsgRef::RemoteAtomicAppendAck(..)
+            | FabricMsgRef::AckBatch(..)
+            | FabricMsgRef::PeerInfo(..)
+            | FabricMsgRef::PeerInfoAck(..)
+            | FabricMsgRef::DHTAE(..)
+            | FabricMsgRef::DHTSync(..)
+            | FabricMsgRef::DHTRebalance(..)
+            | FabricMsgRef::DHTRebalanceAck(..)
+            | FabricMsgRef::ConfigSync(..)
+            | FabricMsgRef::ConfigSyncAck(..)
+            | FabricMsgRef::Unknown => None,
+        }
+    }
 }
 
+// Distributed tracing id, propagated from requests to their acks
+pub type TraceId = [u8; 16];
+
+// Client chosen id of a write, see MsgRemoteSet::idempotency_key
+pub type IdempotencyKey = [u8; 16];
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MsgRemoteGet {
     pub vnode: VNodeNo,
     pub cookie: Cookie,
     pub keys: Vec<Bytes>,
+    pub trace_id: Option<TraceId>,
+    // millis left for the request when sent, relative so it's immune to clock differences
+    pub deadline: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -97,6 +312,21 @@ pub struct MsgRemoteGetAck {
     pub vnode: VNodeNo,
     pub cookie: Cookie,
     pub result: Result<Vec<Cube>, FabricError>,
+    pub trace_id: Option<TraceId>,
+    // merged causal version of the returned cubes, None on errors
+    pub version: Option<BitmappedVersionVector>,
+}
+
+// Reads a single field of a map, acked with a MsgRemoteGetAck holding
+// a map with just that field (or none) and the causal context of the whole map
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteGetField {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub key: Bytes,
+    pub field: Bytes,
+    // see MsgRemoteGet
+    pub deadline: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -105,13 +335,120 @@ pub struct MsgRemoteSet {
     pub cookie: Cookie,
     pub writes: Vec<(Bytes, Cube, bool)>,
     pub reply: bool,
+    // set by senders that may retry, duplicates (per sender) are dropped by the receiver
+    pub request_id: Option<u64>,
+    // like request_id but regardless of the sender, writes with a recently applied
+    // key aren't applied again and get the original ack
+    pub idempotency_key: Option<IdempotencyKey>,
+    // if set the writes are only applied if the stored causal version matches it
+    pub expected_version: Option<BitmappedVersionVector>,
+    pub trace_id: Option<TraceId>,
+    // see MsgRemoteGet
+    pub deadline: Option<u64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MsgRemoteSetAck {
     pub vnode: VNodeNo,
     pub cookie: Cookie,
     pub result: Result<Vec<Option<Cube>>, FabricError>,
+    pub trace_id: Option<TraceId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteCompare {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub key: Bytes,
+    pub expected: Cube,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteCompareAck {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub matches: bool,
+    // only sent if it doesn't match
+    pub actual: Option<Cube>,
+}
+
+impl MsgRemoteCompareAck {
+    pub fn new(vnode: VNodeNo, cookie: Cookie, expected: &Cube, actual: Cube) -> Self {
+        let matches = *expected == actual;
+        MsgRemoteCompareAck {
+            vnode: vnode,
+            cookie: cookie,
+            matches: matches,
+            actual: if matches { None } else { Some(actual) },
+        }
+    }
+}
+
+// Proof of ownership of a lock, required to release it early
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockToken {
+    pub vnode: VNodeNo,
+    pub lock_key: Bytes,
+    pub owner: NodeId,
+    pub id: u64,
+}
+
+// Acquires lock_key for ttl_ms, granted by the first owner of the vnode
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteLock {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub lock_key: Bytes,
+    pub ttl_ms: u32,
+    pub owner: NodeId,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteLockAck {
+    pub cookie: Cookie,
+    pub result: Result<LockToken, FabricError>,
+}
+
+// Releases the lock before its ttl, ignored if the token doesn't match
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteUnlock {
+    pub token: LockToken,
+}
+
+// Reads up to limit keys of the vnode in [start, end), an empty end means unbounded.
+// Pages after the first pass the previous next_cursor to resume after it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteGetRange {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub start: Bytes,
+    pub end: Bytes,
+    pub limit: u32,
+    pub cursor: Option<Bytes>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteGetRangeAck {
+    pub cookie: Cookie,
+    pub result: Result<Vec<(Bytes, Cube)>, FabricError>,
+    // last returned key if the range may have more entries, None once it's exhausted
+    pub next_cursor: Option<Bytes>,
+}
+
+// Appends value to the log `prefix` of the vnode, handled by the same node as locks.
+// Acked with the sequence number assigned to the value.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteAtomicAppend {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub prefix: u64,
+    pub value: Bytes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgRemoteAtomicAppendAck {
+    pub cookie: Cookie,
+    pub result: Result<u64, FabricError>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -120,6 +457,10 @@ pub struct MsgSyncStart {
     pub cookie: Cookie,
     pub clocks_in_peer: BitmappedVersionVector,
     pub target: Option<NodeId>,
+    // resume an interrupted sync/bootstrap from this checkpoint
+    pub resume: Option<SyncCheckpoint>,
+    // entries per MsgSyncSendBatch, None (or 1) for individual MsgSyncSend
+    pub batch_size: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -127,6 +468,8 @@ pub struct MsgSyncFin {
     pub vnode: VNodeNo,
     pub cookie: Cookie,
     pub result: Result<BitmappedVersionVector, FabricError>,
+    // count of entries dispatched by the sender, including resumed attempts (0 for errors)
+    pub entries_synced: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -138,26 +481,161 @@ pub struct MsgSyncSend {
     pub value: Cube,
 }
 
+// several MsgSyncSend in one message, with consecutive seqs.
+// Acked by a single MsgSyncAck with the highest seq applied, so an ack for
+// a seq before the last one means the entries after it failed to apply.
 #[derive(Debug, Serialize, Deserialize)]
+pub struct MsgSyncSendBatch {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub entries: Vec<(u64, Bytes, Cube)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MsgSyncAck {
     pub vnode: VNodeNo,
     pub cookie: Cookie,
     pub seq: u64,
 }
 
+/// Progress of a sync/bootstrap, persisted by the receiver to resume from it.
+/// New writes shift the positions of what the sender streams between attempts,
+/// so resuming is based on what was delivered instead.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncCheckpoint {
+    // all messages up to this seq were acked
+    pub last_acked_seq: u64,
+    // syncs: dots of the writes received, skipped by the resumed sync.
+    // bootstraps: clocks snapshot of the first attempt, also claimed by the resumed ones.
+    pub clocks: BitmappedVersionVector,
+    // bootstraps: keys are sent in order and all the ones before this were acked
+    pub next_key: Option<Bytes>,
+}
+
+// sent by the sender, receivers complete the checkpoint with what they received
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgSyncCheckpoint {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub checkpoint: SyncCheckpoint,
+}
+
+// progress query for a sync/bootstrap, answered by the sender
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgSyncStats {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MsgSyncStatsAck {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+    pub entries_sent: u64,
+    // approximate, based on Cube::size_bytes
+    pub bytes_sent: u64,
+    pub last_seq: u64,
+    pub elapsed_ms: u64,
+}
+
+// aborts a sync, the receiver discards the writes staged for it instead of applying them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgSyncRollback {
+    pub vnode: VNodeNo,
+    pub cookie: Cookie,
+}
+
+// acks coalesced by the sender, unpacked and dispatched individually by the receiver
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgAckBatch {
+    pub acks: Vec<FabricMsg>,
+}
+
+// first message of every connection, advertising what the sender supports
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MsgPeerInfo {
+    pub node: NodeId,
+    pub version: u32,
+    pub capabilities: Vec<String>,
+}
+
+// the capabilities of a MsgPeerInfo also supported by the receiver
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MsgPeerInfoAck {
+    pub node: NodeId,
+    pub accepted_capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VNodeMigration {
+    pub vnode: VNodeNo,
+    pub source: NodeId,
+    pub destination: NodeId,
+}
+
+// broadcasted after a topology change, sources start exporting their vnodes
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgDHTRebalance {
+    pub epoch: u64,
+    pub plan: Vec<VNodeMigration>,
+}
+
+// results for the migrations where the replying node is the source
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgDHTRebalanceAck {
+    pub epoch: u64,
+    pub results: Vec<(VNodeNo, Result<(), FabricError>)>,
+}
+
+// broadcasted when the cluster config changes, newer epochs replace older ones
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgConfigSync {
+    pub epoch: u64,
+    pub config: ClusterConfig,
+}
+
+// broadcasted by every node that applied the config of `epoch`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MsgConfigSyncAck {
+    pub epoch: u64,
+    pub node: NodeId,
+}
+
 impl<'a> Into<FabricMsgRef<'a>> for &'a FabricMsg {
     fn into(self) -> FabricMsgRef<'a> {
         match self {
             &FabricMsg::RemoteGet(ref a) => FabricMsgRef::RemoteGet(a),
             &FabricMsg::RemoteGetAck(ref a) => FabricMsgRef::RemoteGetAck(a),
+            &FabricMsg::RemoteGetField(ref a) => FabricMsgRef::RemoteGetField(a),
             &FabricMsg::RemoteSet(ref a) => FabricMsgRef::RemoteSet(a),
             &FabricMsg::RemoteSetAck(ref a) => FabricMsgRef::RemoteSetAck(a),
+            &FabricMsg::RemoteCompare(ref a) => FabricMsgRef::RemoteCompare(a),
+            &FabricMsg::RemoteCompareAck(ref a) => FabricMsgRef::RemoteCompareAck(a),
+            &FabricMsg::RemoteLock(ref a) => FabricMsgRef::RemoteLock(a),
+            &FabricMsg::RemoteLockAck(ref a) => FabricMsgRef::RemoteLockAck(a),
+            &FabricMsg::RemoteUnlock(ref a) => FabricMsgRef::RemoteUnlock(a),
+            &FabricMsg::RemoteGetRange(ref a) => FabricMsgRef::RemoteGetRange(a),
+            &FabricMsg::RemoteGetRangeAck(ref a) => FabricMsgRef::RemoteGetRangeAck(a),
+            &FabricMsg::RemoteAtomicAppend(ref a) => FabricMsgRef::RemoteAtomicAppend(a),
+            &FabricMsg::RemoteAtomicAppendAck(ref a) => FabricMsgRef::RemoteAtomicAppendAck(a),
             &FabricMsg::SyncStart(ref a) => FabricMsgRef::SyncStart(a),
             &FabricMsg::SyncSend(ref a) => FabricMsgRef::SyncSend(a),
             &FabricMsg::SyncAck(ref a) => FabricMsgRef::SyncAck(a),
             &FabricMsg::SyncFin(ref a) => FabricMsgRef::SyncFin(a),
+            &FabricMsg::SyncCheckpoint(ref a) => FabricMsgRef::SyncCheckpoint(a),
+            &FabricMsg::SyncStats(ref a) => FabricMsgRef::SyncStats(a),
+            &FabricMsg::SyncStatsAck(ref a) => FabricMsgRef::SyncStatsAck(a),
+            &FabricMsg::SyncRollback(ref a) => FabricMsgRef::SyncRollback(a),
+            &FabricMsg::SyncSendBatch(ref a) => FabricMsgRef::SyncSendBatch(a),
+            &FabricMsg::AckBatch(ref a) => FabricMsgRef::AckBatch(a),
+            &FabricMsg::PeerInfo(ref a) => FabricMsgRef::PeerInfo(a),
+            &FabricMsg::PeerInfoAck(ref a) => FabricMsgRef::PeerInfoAck(a),
             &FabricMsg::DHTSync(ref a) => FabricMsgRef::DHTSync(a),
             &FabricMsg::DHTAE(ref a) => FabricMsgRef::DHTAE(a),
+            &FabricMsg::DHTRebalance(ref a) => FabricMsgRef::DHTRebalance(a),
+            &FabricMsg::DHTRebalanceAck(ref a) => FabricMsgRef::DHTRebalanceAck(a),
+            &FabricMsg::ConfigSync(ref a) => FabricMsgRef::ConfigSync(a),
+            &FabricMsg::ConfigSyncAck(ref a) => FabricMsgRef::ConfigSyncAck(a),
             _ => unreachable!(),
         }
     }
@@ -180,9 +658,616 @@ macro_rules! impl_into {
 
 impl_into!(RemoteGet, MsgRemoteGet);
 impl_into!(RemoteGetAck, MsgRemoteGetAck);
+impl_into!(RemoteGetField, MsgRemoteGetField);
 impl_into!(RemoteSet, MsgRemoteSet);
 impl_into!(RemoteSetAck, MsgRemoteSetAck);
+impl_into!(RemoteCompare, MsgRemoteCompare);
+impl_into!(RemoteCompareAck, MsgRemoteCompareAck);
+impl_into!(RemoteLock, MsgRemoteLock);
+impl_into!(RemoteLockAck, MsgRemoteLockAck);
+impl_into!(RemoteUnlock, MsgRemoteUnlock);
+impl_into!(RemoteGetRange, MsgRemoteGetRange);
+impl_into!(RemoteGetRangeAck, MsgRemoteGetRangeAck);
+impl_into!(RemoteAtomicAppend, MsgRemoteAtomicAppend);
+impl_into!(RemoteAtomicAppendAck, MsgRemoteAtomicAppendAck);
 impl_into!(SyncAck, MsgSyncAck);
 impl_into!(SyncSend, MsgSyncSend);
 impl_into!(SyncFin, MsgSyncFin);
 impl_into!(SyncStart, MsgSyncStart);
+impl_into!(SyncCheckpoint, MsgSyncCheckpoint);
+impl_into!(SyncStats, MsgSyncStats);
+impl_into!(SyncStatsAck, MsgSyncStatsAck);
+impl_into!(SyncRollback, MsgSyncRollback);
+impl_into!(SyncSendBatch, MsgSyncSendBatch);
+impl_into!(AckBatch, MsgAckBatch);
+impl_into!(PeerInfo, MsgPeerInfo);
+impl_into!(PeerInfoAck, MsgPeerInfoAck);
+impl_into!(DHTRebalance, MsgDHTRebalance);
+impl_into!(DHTRebalanceAck, MsgDHTRebalanceAck);
+impl_into!(ConfigSync, MsgConfigSync);
+impl_into!(ConfigSyncAck, MsgConfigSyncAck);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode;
+    use proptest::prelude::*;
+
+    fn bytes() -> impl Strategy<Value = Bytes> {
+        prop::collection::vec(any::<u8>(), 0..32).prop_map(Bytes::from)
+    }
+
+    // favor boundary values
+    fn vnode() -> impl Strategy<Value = VNodeNo> {
+        prop_oneof![Just(0), Just(VNodeNo::max_value()), any::<VNodeNo>()]
+    }
+
+    fn seq() -> impl Strategy<Value = u64> {
+        prop_oneof![Just(0), Just(u64::max_value()), any::<u64>()]
+    }
+
+    fn cookie() -> impl Strategy<Value = Cookie> {
+        (any::<u64>(), any::<u64>()).prop_map(|(a, b)| Cookie::new(a, b))
+    }
+
+    fn trace_id() -> impl Strategy<Value = Option<TraceId>> {
+        prop::option::of(any::<TraceId>())
+    }
+
+    fn fabric_error() -> impl Strategy<Value = FabricError> {
+        prop_oneof![
+            Just(FabricError::NoRoute),
+            Just(FabricError::CookieNotFound),
+            Just(FabricError::BadVNodeStatus),
+            Just(FabricError::NotReady),
+            Just(FabricError::SyncInterrupted),
+            Just(FabricError::StorageError),
+            Just(FabricError::ValueTooLarge),
+            any::<u32>().prop_map(|retry_after_ms| FabricError::RateLimited { retry_after_ms }),
+            Just(FabricError::ShuttingDown),
+            Just(FabricError::LockHeld),
+            Just(FabricError::VersionMismatch),
+            Just(FabricError::Expired),
+        ]
+    }
+
+    fn vv() -> impl Strategy<Value = VersionVector> {
+        prop::collection::vec((any::<Id>(), seq()), 0..4).prop_map(|dots| {
+            let mut vv = VersionVector::new();
+            for (id, version) in dots {
+                vv.add(id, version);
+            }
+            vv
+        })
+    }
+
+    fn bvv() -> impl Strategy<Value = BitmappedVersionVector> {
+        // base is bounded so the bitmap doesn't overflow
+        prop::collection::vec((any::<Id>(), 0..1u64 << 62, any::<u32>()), 0..4).prop_map(|bvs| {
+            let mut bvv = BitmappedVersionVector::new();
+            for (id, base, bitmap) in bvs {
+                bvv.add_bv(id, &BitmappedVersion::new(base, bitmap));
+            }
+            bvv
+        })
+    }
+
+    fn cube() -> impl Strategy<Value = Cube> {
+        (vv(), any::<Id>(), seq(), prop::option::of(bytes()), 0..4u8).prop_map(
+            |(vv, node, version, value, kind)| {
+                let cube = Cube::Void(vv.clone());
+                match kind {
+                    0 => cube,
+                    1 => {
+                        let mut counter = cube.into_counter().unwrap();
+                        counter.inc(node, version, 1);
+                        Cube::Counter(counter)
+                    }
+                    2 => {
+                        let mut register = cube.into_lww().unwrap();
+                        register.set(node, version, value.unwrap_or_default(), version);
+                        Cube::Lww(register)
+                    }
+                    _ => {
+                        let mut register = cube.into_value().unwrap();
+                        register.set(node, version, value, &vv);
+                        Cube::Value(register)
+                    }
+                }
+            },
+        )
+    }
+
+    fn lock_token() -> impl Strategy<Value = LockToken> {
+        (vnode(), bytes(), any::<NodeId>(), seq()).prop_map(|(vnode, lock_key, owner, id)| {
+            LockToken {
+                vnode,
+                lock_key,
+                owner,
+                id,
+            }
+        })
+    }
+
+    fn result<T: ::std::fmt::Debug>(
+        ok: impl Strategy<Value = T>,
+    ) -> impl Strategy<Value = Result<T, FabricError>> {
+        prop_oneof![ok.prop_map(Ok), fabric_error().prop_map(Err)]
+    }
+
+    fn consistency() -> impl Strategy<Value = Option<ConsistencyLevel>> {
+        prop::option::of(prop_oneof![
+            Just(ConsistencyLevel::One),
+            Just(ConsistencyLevel::Quorum),
+            Just(ConsistencyLevel::All),
+        ])
+    }
+
+    fn crud_msg() -> impl Strategy<Value = FabricMsg> {
+        prop_oneof![
+            (
+                vnode(),
+                cookie(),
+                prop::collection::vec(bytes(), 0..4),
+                trace_id(),
+                prop::option::of(any::<u64>())
+            ).prop_map(|(vnode, cookie, keys, trace_id, deadline)| {
+                FabricMsg::RemoteGet(MsgRemoteGet {
+                    vnode,
+                    cookie,
+                    keys,
+                    trace_id,
+                    deadline,
+                })
+            }),
+            (
+                vnode(),
+                cookie(),
+                result(prop::collection::vec(cube(), 0..4)),
+                trace_id(),
+                prop::option::of(bvv())
+            ).prop_map(|(vnode, cookie, result, trace_id, version)| {
+                FabricMsg::RemoteGetAck(MsgRemoteGetAck {
+                    vnode,
+                    cookie,
+                    result,
+                    trace_id,
+                    version,
+                })
+            }),
+            (
+                vnode(),
+                cookie(),
+                bytes(),
+                bytes(),
+                prop::option::of(any::<u64>())
+            ).prop_map(|(vnode, cookie, key, field, deadline)| {
+                FabricMsg::RemoteGetField(MsgRemoteGetField {
+                    vnode,
+                    cookie,
+                    key,
+                    field,
+                    deadline,
+                })
+            }),
+            (
+                vnode(),
+                cookie(),
+                prop::collection::vec((bytes(), cube(), any::<bool>()), 0..4),
+                any::<bool>(),
+                prop::option::of(any::<u64>()),
+                prop::option::of(any::<IdempotencyKey>()),
+                prop::option::of(bvv()),
+                trace_id(),
+                prop::option::of(any::<u64>())
+            ).prop_map(
+                |(
+                    vnode,
+                    cookie,
+                    writes,
+                    reply,
+                    request_id,
+                    idempotency_key,
+                    expected_version,
+                    trace_id,
+                    deadline,
+                )| {
+                    FabricMsg::RemoteSet(MsgRemoteSet {
+                        vnode,
+                        cookie,
+                        writes,
+                        reply,
+                        request_id,
+                        idempotency_key,
+                        expected_version,
+                        trace_id,
+                        deadline,
+                    })
+                },
+            ),
+            (
+                vnode(),
+                cookie(),
+                result(prop::collection::vec(prop::option::of(cube()), 0..4)),
+                trace_id()
+            ).prop_map(|(vnode, cookie, result, trace_id)| {
+                FabricMsg::RemoteSetAck(MsgRemoteSetAck {
+                    vnode,
+                    cookie,
+                    result,
+                    trace_id,
+                })
+            }),
+            (vnode(), cookie(), bytes(), cube()).prop_map(|(vnode, cookie, key, expected)| {
+                FabricMsg::RemoteCompare(MsgRemoteCompare {
+                    vnode,
+                    cookie,
+                    key,
+                    expected,
+                })
+            }),
+            (vnode(), cookie(), cube(), cube()).prop_map(|(vnode, cookie, expected, actual)| {
+                FabricMsg::RemoteCompareAck(MsgRemoteCompareAck::new(
+                    vnode, cookie, &expected, actual,
+                ))
+            }),
+            (vnode(), cookie(), bytes(), any::<u32>(), any::<NodeId>()).prop_map(
+                |(vnode, cookie, lock_key, ttl_ms, owner)| {
+                    FabricMsg::RemoteLock(MsgRemoteLock {
+                        vnode,
+                        cookie,
+                        lock_key,
+                        ttl_ms,
+                        owner,
+                    })
+                }
+            ),
+            (cookie(), result(lock_token())).prop_map(|(cookie, result)| {
+                FabricMsg::RemoteLockAck(MsgRemoteLockAck { cookie, result })
+            }),
+            lock_token().prop_map(|token| FabricMsg::RemoteUnlock(MsgRemoteUnlock { token })),
+        ]
+    }
+
+    fn range_msg() -> impl Strategy<Value = FabricMsg> {
+        prop_oneof![
+            (
+                vnode(),
+                cookie(),
+                bytes(),
+                bytes(),
+                any::<u32>(),
+                prop::option::of(bytes())
+            ).prop_map(|(vnode, cookie, start, end, limit, cursor)| {
+                FabricMsg::RemoteGetRange(MsgRemoteGetRange {
+                    vnode,
+                    cookie,
+                    start,
+                    end,
+                    limit,
+                    cursor,
+                })
+            }),
+            (
+                cookie(),
+                result(prop::collection::vec((bytes(), cube()), 0..4)),
+                prop::option::of(bytes())
+            ).prop_map(|(cookie, result, next_cursor)| {
+                FabricMsg::RemoteGetRangeAck(MsgRemoteGetRangeAck {
+                    cookie,
+                    result,
+                    next_cursor,
+                })
+            }),
+        ]
+    }
+
+    fn checkpoint() -> impl Strategy<Value = SyncCheckpoint> {
+        (seq(), bvv(), prop::option::of(bytes())).prop_map(
+            |(last_acked_seq, clocks, next_key)| SyncCheckpoint {
+                last_acked_seq,
+                clocks,
+                next_key,
+            },
+        )
+    }
+
+    fn sync_msg() -> impl Strategy<Value = FabricMsg> {
+        prop_oneof![
+            (
+                vnode(),
+                cookie(),
+                bvv(),
+                prop::option::of(any::<NodeId>()),
+                prop::option::of(checkpoint()),
+                prop::option::of(any::<u32>())
+            ).prop_map(|(vnode, cookie, clocks_in_peer, target, resume, batch_size)| {
+                FabricMsg::SyncStart(MsgSyncStart {
+                    vnode,
+                    cookie,
+                    clocks_in_peer,
+                    target,
+                    resume,
+                    batch_size,
+                })
+            }),
+            (
+                vnode(),
+                cookie(),
+                prop::collection::vec((seq(), bytes(), cube()), 0..4)
+            ).prop_map(|(vnode, cookie, entries)| {
+                FabricMsg::SyncSendBatch(MsgSyncSendBatch {
+                    vnode,
+                    cookie,
+                    entries,
+                })
+            }),
+            (vnode(), cookie(), seq(), bytes(), cube()).prop_map(
+                |(vnode, cookie, seq, key, value)| FabricMsg::SyncSend(MsgSyncSend {
+                    vnode,
+                    cookie,
+                    seq,
+                    key,
+                    value,
+                })
+            ),
+            (vnode(), cookie(), seq()).prop_map(|(vnode, cookie, seq)| {
+                FabricMsg::SyncAck(MsgSyncAck { vnode, cookie, seq })
+            }),
+            (vnode(), cookie(), result(bvv()), seq()).prop_map(
+                |(vnode, cookie, result, entries_synced)| FabricMsg::SyncFin(MsgSyncFin {
+                    vnode,
+                    cookie,
+                    result,
+                    entries_synced,
+                })
+            ),
+            (vnode(), cookie(), checkpoint()).prop_map(|(vnode, cookie, checkpoint)| {
+                FabricMsg::SyncCheckpoint(MsgSyncCheckpoint {
+                    vnode,
+                    cookie,
+                    checkpoint,
+                })
+            }),
+            (vnode(), cookie())
+                .prop_map(|(vnode, cookie)| FabricMsg::SyncStats(MsgSyncStats { vnode, cookie })),
+            (vnode(), cookie()).prop_map(|(vnode, cookie)| {
+                FabricMsg::SyncRollback(MsgSyncRollback { vnode, cookie })
+            }),
+            (vnode(), cookie(), seq(), seq(), seq(), seq()).prop_map(
+                |(vnode, cookie, entries_sent, bytes_sent, last_seq, elapsed_ms)| {
+                    FabricMsg::SyncStatsAck(MsgSyncStatsAck {
+                        vnode,
+                        cookie,
+                        entries_sent,
+                        bytes_sent,
+                        last_seq,
+                        elapsed_ms,
+                    })
+                }
+            ),
+        ]
+    }
+
+    fn dht_msg() -> impl Strategy<Value = FabricMsg> {
+        prop_oneof![
+            vv().prop_map(FabricMsg::DHTAE),
+            bytes().prop_map(FabricMsg::DHTSync),
+            (
+                seq(),
+                prop::collection::vec((vnode(), any::<NodeId>(), any::<NodeId>()), 0..4)
+            ).prop_map(|(epoch, plan)| {
+                FabricMsg::DHTRebalance(MsgDHTRebalance {
+                    epoch,
+                    plan: plan
+                        .into_iter()
+                        .map(|(vnode, source, destination)| VNodeMigration {
+                            vnode,
+                            source,
+                            destination,
+                        }).collect(),
+                })
+            }),
+            (seq(), prop::collection::vec((vnode(), result(Just(()))), 0..4)).prop_map(
+                |(epoch, results)| FabricMsg::DHTRebalanceAck(MsgDHTRebalanceAck {
+                    epoch,
+                    results,
+                })
+            ),
+            (seq(), consistency(), consistency()).prop_map(
+                |(epoch, consistency_read, consistency_write)| {
+                    FabricMsg::ConfigSync(MsgConfigSync {
+                        epoch,
+                        config: ClusterConfig {
+                            consistency_read,
+                            consistency_write,
+                        },
+                    })
+                }
+            ),
+            (seq(), any::<NodeId>()).prop_map(|(epoch, node)| {
+                FabricMsg::ConfigSyncAck(MsgConfigSyncAck { epoch, node })
+            }),
+        ]
+    }
+
+    fn peer_info_msg() -> impl Strategy<Value = FabricMsg> {
+        let capabilities = || prop::collection::vec(".{0,16}", 0..4);
+        prop_oneof![
+            (any::<NodeId>(), any::<u32>(), capabilities()).prop_map(
+                |(node, version, capabilities)| FabricMsg::PeerInfo(MsgPeerInfo {
+                    node,
+                    version,
+                    capabilities,
+                })
+            ),
+            (any::<NodeId>(), capabilities()).prop_map(|(node, accepted_capabilities)| {
+                FabricMsg::PeerInfoAck(MsgPeerInfoAck {
+                    node,
+                    accepted_capabilities,
+                })
+            }),
+        ]
+    }
+
+    fn append_msg() -> impl Strategy<Value = FabricMsg> {
+        prop_oneof![
+            (vnode(), cookie(), seq(), bytes()).prop_map(|(vnode, cookie, prefix, value)| {
+                FabricMsg::RemoteAtomicAppend(MsgRemoteAtomicAppend {
+                    vnode,
+                    cookie,
+                    prefix,
+                    value,
+                })
+            }),
+            (cookie(), result(seq())).prop_map(|(cookie, result)| {
+                FabricMsg::RemoteAtomicAppendAck(MsgRemoteAtomicAppendAck { cookie, result })
+            }),
+        ]
+    }
+
+    fn fabric_msg() -> impl Strategy<Value = FabricMsg> {
+        prop_oneof![
+            crud_msg(),
+            range_msg(),
+            append_msg(),
+            sync_msg(),
+            prop::collection::vec(crud_msg(), 0..4)
+                .prop_map(|acks| FabricMsg::AckBatch(MsgAckBatch { acks })),
+            dht_msg(),
+            peer_info_msg(),
+        ]
+    }
+
+    fn check_roundtrip(msg: &FabricMsg) {
+        let serialized = bincode::serialize(msg).unwrap();
+        let deserialized: FabricMsg = bincode::deserialize(&serialized).unwrap();
+        // messages don't implement PartialEq, compare the encoding and debug repr instead
+        assert_eq!(bincode::serialize(&deserialized).unwrap(), serialized);
+        assert_eq!(format!("{:?}", deserialized), format!("{:?}", msg));
+        // the ref version is what's actually sent over the wire
+        let msg_ref: FabricMsgRef = msg.into();
+        assert_eq!(bincode::serialize(&msg_ref).unwrap(), serialized);
+    }
+
+    proptest! {
+        #[test]
+        fn fabric_msg_roundtrip(msg in fabric_msg()) {
+            check_roundtrip(&msg);
+        }
+    }
+
+    #[test]
+    fn fabric_msg_roundtrip_edges() {
+        check_roundtrip(&FabricMsg::DHTSync(Bytes::new()));
+        check_roundtrip(
+            &MsgSyncSend {
+                vnode: VNodeNo::max_value(),
+                cookie: Cookie::new(u64::max_value(), 0),
+                seq: u64::max_value(),
+                key: Bytes::new(),
+                value: Cube::Void(VersionVector::new()),
+            }.into(),
+        );
+        check_roundtrip(
+            &MsgRemoteGet {
+                vnode: 0,
+                cookie: Default::default(),
+                keys: vec![Bytes::new(), Bytes::new()],
+                trace_id: Some([0xFF; 16]),
+                deadline: None,
+            }.into(),
+        );
+        check_roundtrip(
+            &MsgRemoteGetField {
+                vnode: VNodeNo::max_value(),
+                cookie: Default::default(),
+                key: Bytes::from("map"),
+                field: Bytes::new(),
+                deadline: None,
+            }.into(),
+        );
+    }
+
+    #[test]
+    fn remote_compare_ack() {
+        let mut register = Cube::default().into_value().unwrap();
+        register.set(1, 1, Some(Bytes::from("a")), &VersionVector::new());
+        let cube = Cube::Value(register.clone());
+
+        let ack = MsgRemoteCompareAck::new(1, Default::default(), &cube, cube.clone());
+        assert!(ack.matches);
+        assert!(ack.actual.is_none());
+
+        register.set(2, 1, Some(Bytes::from("b")), &VersionVector::new());
+        let diverged = Cube::Value(register);
+        let ack = MsgRemoteCompareAck::new(1, Default::default(), &cube, diverged.clone());
+        assert!(!ack.matches);
+        assert_eq!(ack.actual, Some(diverged));
+        check_roundtrip(&ack.into());
+    }
+
+    #[test]
+    fn trace_id_roundtrip() {
+        let trace_id = [7; 16];
+        let get: FabricMsg = MsgRemoteGet {
+            vnode: 1,
+            cookie: Default::default(),
+            keys: vec![Bytes::from("a")],
+            trace_id: None,
+            deadline: None,
+        }.into();
+        let ack: FabricMsg = MsgRemoteSetAck {
+            vnode: 1,
+            cookie: Default::default(),
+            result: Ok(vec![None]),
+            trace_id: None,
+        }.into();
+        for msg in vec![get, ack] {
+            assert_eq!(msg.trace_id(), None);
+            let msg = msg.with_trace_id(trace_id);
+            check_roundtrip(&msg);
+            let serialized = bincode::serialize(&msg).unwrap();
+            let deserialized: FabricMsg = bincode::deserialize(&serialized).unwrap();
+            assert_eq!(deserialized.trace_id(), Some(trace_id));
+        }
+        let ack: FabricMsg = MsgRemoteGetAck {
+            vnode: 1,
+            cookie: Default::default(),
+            result: Ok(vec![]),
+            trace_id: Some(trace_id),
+            version: None,
+        }.into();
+        match bincode::deserialize(&bincode::serialize(&ack).unwrap()).unwrap() {
+            FabricMsg::RemoteGetAck(m) => assert_eq!(m.trace_id, Some(trace_id)),
+            msg => panic!("unexpected {:?}", msg),
+        }
+        // messages without a trace id are left as is
+        let msg = FabricMsg::DHTSync(Bytes::new()).with_trace_id(trace_id);
+        assert_eq!(msg.trace_id(), None);
+    }
+
+    #[test]
+    fn dht_rebalance_roundtrip() {
+        let msg: FabricMsg = MsgDHTRebalance {
+            epoch: 7,
+            plan: (0..100)
+                .map(|i| VNodeMigration {
+                    vnode: i,
+                    source: i as NodeId,
+                    destination: i as NodeId + 1,
+                }).collect(),
+        }.into();
+        check_roundtrip(&msg);
+        let serialized = bincode::serialize(&msg).unwrap();
+        match bincode::deserialize(&serialized).unwrap() {
+            FabricMsg::DHTRebalance(m) => {
+                assert_eq!(m.epoch, 7);
+                assert_eq!(m.plan.len(), 100);
+                assert_eq!(m.plan[99].vnode, 99);
+                assert_eq!(m.plan[99].destination, 100);
+            }
+            msg => panic!("unexpected {:?}", msg),
+        }
+    }
+}
@@ -40,6 +40,9 @@ extern crate tokio_io;
 
 #[cfg(test)]
 extern crate env_logger;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 
 #[macro_use]
 mod utils;
@@ -13,4 +13,6 @@ lazy_static! {
     pub static ref SYNC_RESEND: Arc<StdMeter> = { StdMeter::new() };
     pub static ref SYNC_OUTGOING: Arc<StdGauge> = { StdGauge::new() };
     pub static ref SYNC_INCOMING: Arc<StdGauge> = { StdGauge::new() };
+    // fullness of the storage write buffers in percent, see Storage::load_factor
+    pub static ref STORAGE_LOAD_FACTOR: Arc<StdGauge> = { StdGauge::new() };
 }
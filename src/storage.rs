@@ -1,9 +1,22 @@
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crc16;
+use futures::sync::mpsc as fmpsc;
+use futures::{Poll, Stream};
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
 use rocksdb::{self, Writable};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::io::Write;
 use std::path::Path;
-use std::sync::Arc;
-use std::{mem, str};
+use std::str::FromStr;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use std::{fmt, mem, ops, str, thread};
+use types::NodeId;
 use utils::*;
 
 struct U16BeSuffixTransform;
@@ -18,10 +31,187 @@ impl rocksdb::SliceTransform for U16BeSuffixTransform {
     }
 }
 
+// (vnode, node) prefix of log keys
+struct LogPrefixTransform;
+
+impl rocksdb::SliceTransform for LogPrefixTransform {
+    fn transform<'a>(&mut self, key: &'a [u8]) -> &'a [u8] {
+        &key[..2 + 8]
+    }
+
+    fn in_domain(&mut self, key: &[u8]) -> bool {
+        key.len() >= 2 + 8
+    }
+}
+
+/// Memtable representation used by the log column family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemtableKind {
+    SkipList,
+    // requires a prefix extractor, the (vnode, node) part of log keys is used
+    HashSkipList { bucket_count: usize },
+    Vector,
+}
+
+impl FromStr for MemtableKind {
+    type Err = GenericError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "skiplist" => Ok(MemtableKind::SkipList),
+            "hash_skiplist" => Ok(MemtableKind::HashSkipList {
+                bucket_count: 1_000_000,
+            }),
+            "vector" => Ok(MemtableKind::Vector),
+            _ => Err(format!("Unknown memtable kind `{}`", s).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    pub log_memtable: MemtableKind,
+    // Values smaller than this are kept inline in the sst blocks, values
+    // of this size or larger are stored separately in blob files.
+    // None keeps everything inline.
+    pub inline_value_threshold: Option<usize>,
+    // Enables Storage::begin_transaction
+    pub transactions: bool,
+    // Once the WALs grow past this the oldest memtables are flushed so their logs
+    // can be deleted, bounding disk usage and replay time after a crash.
+    // Small limits force frequent small flushes (more write amplification).
+    pub max_total_wal_size: Option<u64>,
+    // Number of WAL files kept for reuse instead of being deleted and recreated.
+    pub recycle_log_file_num: Option<u64>,
+    // Block caches are split in 2^bits shards, each with its own lock.
+    // Each column family already has a separate cache. None uses rocksdb's default.
+    pub block_cache_shard_bits: Option<u32>,
+    // Vnode logs are spread over this many column families (by vnode number), so busy
+    // vnodes don't share a compaction queue with all others. Can't change once created.
+    pub log_column_families: usize,
+    // Number of level 0 files of the data column family that triggers a compaction,
+    // slows down writes or stops them until compactions catch up.
+    // None uses rocksdb's default (4, 20 and 36).
+    pub level0_file_num_compaction_trigger: Option<u32>,
+    pub level0_slowdown_writes_trigger: Option<u32>,
+    pub level0_stop_writes_trigger: Option<u32>,
+    // Keep the last N local mutations of each key inside its value, for debugging.
+    // None disables it.
+    pub cube_history: Option<usize>,
+    // Store a checksum with each value written and verify it on reads. Can be changed
+    // at any time, values stored with and without checksums are both readable.
+    pub value_checksums: bool,
+}
+
+const DATA_BLOCK_CACHE_BYTES: usize = 128 * 1024 * 1024;
+const LOG_BLOCK_CACHE_BYTES: usize = 64 * 1024 * 1024;
+const WRITE_BUFFER_BYTES: u64 = 32 * 1024 * 1024;
+const MIN_BLOCK_CACHE_SHARD_BYTES: usize = 512 * 1024;
+const MAX_LOG_COLUMN_FAMILIES: usize = 64;
+// rocksdb defaults, used to validate partially set level 0 triggers
+const DEFAULT_LEVEL0_SLOWDOWN_WRITES_TRIGGER: u32 = 20;
+const DEFAULT_LEVEL0_STOP_WRITES_TRIGGER: u32 = 36;
+
+// the first one keeps the original name so existing dbs open unchanged
+fn log_cf_name(shard: usize) -> String {
+    if shard == 0 {
+        "log".into()
+    } else {
+        format!("log_{}", shard)
+    }
+}
+
+fn is_log_cf_name(name: &str) -> bool {
+    name == "log" || name.starts_with("log_") && name[4..].parse::<usize>().is_ok()
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            log_memtable: MemtableKind::SkipList,
+            inline_value_threshold: None,
+            transactions: false,
+            max_total_wal_size: None,
+            recycle_log_file_num: None,
+            block_cache_shard_bits: None,
+            log_column_families: 1,
+            level0_file_num_compaction_trigger: None,
+            level0_slowdown_writes_trigger: None,
+            level0_stop_writes_trigger: None,
+            cube_history: None,
+            value_checksums: false,
+        }
+    }
+}
+
+impl StorageConfig {
+    fn validate(&self) -> Result<(), GenericError> {
+        if self.inline_value_threshold == Some(0) {
+            return Err("inline_value_threshold must be positive".into());
+        }
+        if self.max_total_wal_size == Some(0) {
+            return Err("max_total_wal_size must be positive".into());
+        }
+        if self.log_column_families == 0 || self.log_column_families > MAX_LOG_COLUMN_FAMILIES {
+            return Err(format!(
+                "log_column_families must be between 1 and {}",
+                MAX_LOG_COLUMN_FAMILIES
+            ).into());
+        }
+        if let Some(bits) = self.block_cache_shard_bits {
+            // shards smaller than this waste memory as entries can't spread evenly
+            let smallest_cache = DATA_BLOCK_CACHE_BYTES.min(LOG_BLOCK_CACHE_BYTES);
+            if bits > 19 || smallest_cache >> bits < MIN_BLOCK_CACHE_SHARD_BYTES {
+                return Err(format!(
+                    "block_cache_shard_bits {} is too large for a {} bytes cache",
+                    bits, smallest_cache
+                ).into());
+            }
+        }
+        if self.cube_history == Some(0) {
+            return Err("cube_history must be positive".into());
+        }
+        if self.level0_file_num_compaction_trigger == Some(0) {
+            return Err("level0_file_num_compaction_trigger must be positive".into());
+        }
+        let slowdown = self
+            .level0_slowdown_writes_trigger
+            .unwrap_or(DEFAULT_LEVEL0_SLOWDOWN_WRITES_TRIGGER);
+        let stop = self
+            .level0_stop_writes_trigger
+            .unwrap_or(DEFAULT_LEVEL0_STOP_WRITES_TRIGGER);
+        if stop < slowdown {
+            return Err(format!(
+                "level0_stop_writes_trigger {} is lower than level0_slowdown_writes_trigger {}",
+                stop, slowdown
+            ).into());
+        }
+        match self.log_memtable {
+            MemtableKind::HashSkipList { bucket_count: 0 } => {
+                Err("log_memtable hash_skiplist requires a positive bucket_count".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 pub struct StorageManager {
     db: Arc<rocksdb::DB>,
+    // number of live iterators across all Storages of this manager
+    pending_iters: Arc<AtomicUsize>,
+    watchers: Arc<RwLock<Vec<PrefixWatch>>>,
+    // serializes read-modify-writes (atomic_increment, log_set_cas) of all Storages
+    rmw_lock: Arc<Mutex<()>>,
+    // only present if transactions are enabled
+    txns: Option<Arc<Mutex<TxnState>>>,
+    // vnode logs are sharded by num over these, see log_cf_name
+    log_cfs: usize,
+    cube_history: Option<usize>,
+    // see StorageConfig::value_checksums
+    checksums: bool,
 }
 
+// The empty key is a valid key, stored as just the vnode prefix. It's distinct from
+// every other key and sorts first, so vnode iterators (seeking to the prefix) return it first.
 #[inline]
 fn build_key<'a>(buffer: &'a mut [u8], num: u16, key: &[u8]) -> &'a [u8] {
     (&mut buffer[..2]).write_u16::<BigEndian>(num).unwrap();
@@ -29,8 +219,24 @@ fn build_key<'a>(buffer: &'a mut [u8], num: u16, key: &[u8]) -> &'a [u8] {
     &buffer[..2 + key.len()]
 }
 
+// Log keys are (prefix, version) optionally followed by a type byte.
+// Untyped keys (the original layout) sort right before the typed ones with the same
+// (prefix, version), so both can live in the same log and iterate in order.
+// Only iter_typed yields typed entries, as their (prefix, version) isn't unique.
+const LOG_KEY_MAX_LEN: usize = 2 + 8 + 8 + 1;
+
 #[inline]
 fn build_log_key<'a>(buffer: &'a mut [u8], num: u16, log_key: (u64, u64)) -> &'a [u8] {
+    build_log_key_typed(buffer, num, log_key, None)
+}
+
+#[inline]
+fn build_log_key_typed<'a>(
+    buffer: &'a mut [u8],
+    num: u16,
+    log_key: (u64, u64),
+    log_type: Option<u8>,
+) -> &'a [u8] {
     (&mut buffer[..2]).write_u16::<BigEndian>(num).unwrap();
     (&mut buffer[2..2 + 8])
         .write_u64::<BigEndian>(log_key.0)
@@ -38,7 +244,21 @@ fn build_log_key<'a>(buffer: &'a mut [u8], num: u16, log_key: (u64, u64)) -> &'a
     (&mut buffer[2 + 8..2 + 8 + 8])
         .write_u64::<BigEndian>(log_key.1)
         .unwrap();
-    &buffer[..2 + 8 + 8]
+    if let Some(log_type) = log_type {
+        buffer[2 + 8 + 8] = log_type;
+        &buffer[..2 + 8 + 8 + 1]
+    } else {
+        &buffer[..2 + 8 + 8]
+    }
+}
+
+// inverse of build_log_key_typed, `key` excludes the vnode prefix
+#[inline]
+fn parse_log_key(key: &[u8]) -> ((u64, u64), Option<u8>) {
+    debug_assert!(key.len() == 8 + 8 || key.len() == 8 + 8 + 1);
+    let first = (&key[..8]).read_u64::<BigEndian>().unwrap();
+    let second = (&key[8..8 + 8]).read_u64::<BigEndian>().unwrap();
+    ((first, second), key.get(8 + 8).cloned())
 }
 
 #[inline]
@@ -50,6 +270,261 @@ fn build_log_prefix<'a>(buffer: &'a mut [u8], num: u16, prefix: u64) -> &'a [u8]
     &buffer[..2 + 8]
 }
 
+const INDEX_ESCAPE: u8 = 0x00;
+const INDEX_ESCAPED_ZERO: u8 = 0xFF;
+const INDEX_TERMINATOR: u8 = 0x01;
+
+/// Builder for composite index keys `(field, ..., primary_key)`.
+///
+/// Fields are escaped (0x00 -> 0x00 0xFF) and terminated by 0x00 0x01 so
+/// the encoding is unambiguous and keys sort by field bytes first,
+/// then by primary key. Store them with `Storage::set` and scan with
+/// `Storage::iterator_prefix(&IndexKey::new().field(..).prefix())`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexKey(Vec<u8>);
+
+impl IndexKey {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn field(mut self, field: &[u8]) -> Self {
+        self.0.reserve(field.len() + 2);
+        for &b in field {
+            self.0.push(b);
+            if b == INDEX_ESCAPE {
+                self.0.push(INDEX_ESCAPED_ZERO);
+            }
+        }
+        self.0.push(INDEX_ESCAPE);
+        self.0.push(INDEX_TERMINATOR);
+        self
+    }
+
+    /// Encoded prefix matching every primary key with the fields so far
+    pub fn prefix(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// Full encoded key, the primary key is appended as is
+    pub fn primary_key(mut self, primary_key: &[u8]) -> Vec<u8> {
+        self.0.extend_from_slice(primary_key);
+        self.0
+    }
+
+    /// Splits an encoded key into its `num_fields` fields and the primary key
+    pub fn decode(key: &[u8], num_fields: usize) -> Result<(Vec<Vec<u8>>, &[u8]), GenericError> {
+        let mut fields = Vec::with_capacity(num_fields);
+        let mut pos = 0;
+        while fields.len() < num_fields {
+            let mut field = Vec::new();
+            loop {
+                match (key.get(pos), key.get(pos + 1)) {
+                    (Some(&INDEX_ESCAPE), Some(&INDEX_ESCAPED_ZERO)) => {
+                        field.push(INDEX_ESCAPE);
+                        pos += 2;
+                    }
+                    (Some(&INDEX_ESCAPE), Some(&INDEX_TERMINATOR)) => {
+                        pos += 2;
+                        break;
+                    }
+                    (Some(&INDEX_ESCAPE), _) | (None, _) => {
+                        return Err("Malformed index key".into())
+                    }
+                    (Some(&b), _) => {
+                        field.push(b);
+                        pos += 1;
+                    }
+                }
+            }
+            fields.push(field);
+        }
+        Ok((fields, &key[pos..]))
+    }
+}
+
+const LOG_METADATA_LEN: usize = 8 + 8 + 1;
+
+/// Fixed size header optionally prepended to log values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogMetadata {
+    pub source_node: NodeId,
+    pub timestamp_ns: u64,
+    pub flags: u8,
+}
+
+impl LogMetadata {
+    fn write(&self, buffer: &mut Vec<u8>) {
+        buffer.write_u64::<BigEndian>(self.source_node).unwrap();
+        buffer.write_u64::<BigEndian>(self.timestamp_ns).unwrap();
+        buffer.write_u8(self.flags).unwrap();
+    }
+
+    fn read(mut bytes: &[u8]) -> Result<(LogMetadata, &[u8]), GenericError> {
+        if bytes.len() < LOG_METADATA_LEN {
+            return Err("Log value too short for metadata".into());
+        }
+        let metadata = LogMetadata {
+            source_node: bytes.read_u64::<BigEndian>()?,
+            timestamp_ns: bytes.read_u64::<BigEndian>()?,
+            flags: bytes.read_u8()?,
+        };
+        Ok((metadata, bytes))
+    }
+}
+
+// Values written with value_checksums are prefixed with magic + version + crc16(be)
+// of the payload, other values are stored as is, so both can be in the same db.
+// They're told apart by the magic (0xFE never appears in utf-8, cubes and counters
+// don't start with it either), values that would start with it always get a header.
+const CHECKSUM_MAGIC: [u8; 2] = [0xFE, 0xC5];
+const CHECKSUM_VERSION: u8 = 1;
+const CHECKSUM_HEADER_LEN: usize = 2 + 1 + 2;
+
+fn value_checksum(value: &[u8]) -> u16 {
+    crc16::State::<crc16::XMODEM>::calculate(value)
+}
+
+fn encode_checksummed(value: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(CHECKSUM_HEADER_LEN + value.len());
+    buffer.extend_from_slice(&CHECKSUM_MAGIC);
+    buffer.push(CHECKSUM_VERSION);
+    buffer
+        .write_u16::<BigEndian>(value_checksum(value))
+        .unwrap();
+    buffer.extend_from_slice(value);
+    buffer
+}
+
+// the value to store, with a checksum header if enabled or if it'd look like one
+fn encode_value(checksums: bool, value: &[u8]) -> Cow<[u8]> {
+    if checksums || value.starts_with(&CHECKSUM_MAGIC) {
+        Cow::Owned(encode_checksummed(value))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+// the stored value without its checksum header, if it has one
+fn decode_value(value: &[u8]) -> Result<&[u8], StorageError> {
+    if !value.starts_with(&CHECKSUM_MAGIC) {
+        return Ok(value);
+    }
+    if value.len() < CHECKSUM_HEADER_LEN {
+        return Err(StorageError::Corruption(
+            "Value checksum header truncated".into(),
+        ));
+    }
+    if value[2] != CHECKSUM_VERSION {
+        return Err(StorageError::Corruption(format!(
+            "Unknown value checksum version {}",
+            value[2]
+        )));
+    }
+    let expected = (&value[3..5]).read_u16::<BigEndian>().unwrap();
+    let payload = &value[CHECKSUM_HEADER_LEN..];
+    if value_checksum(payload) != expected {
+        return Err(StorageError::Corruption("Value checksum mismatch".into()));
+    }
+    Ok(payload)
+}
+
+// Merge operator for Storage::atomic_increment.
+// Existing values and operands are i64(be), overflows wrap around.
+fn counter_merge(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut rocksdb::MergeOperands,
+) -> Vec<u8> {
+    encode_value(false, &counter_merge_value(key, existing, operands)).into_owned()
+}
+
+// counter_merge with value checksums
+fn checksummed_counter_merge(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut rocksdb::MergeOperands,
+) -> Vec<u8> {
+    encode_checksummed(&counter_merge_value(key, existing, operands))
+}
+
+fn counter_merge_value(
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut rocksdb::MergeOperands,
+) -> Vec<u8> {
+    let mut counter = existing
+        .map(|v| {
+            let counter = decode_value(v)
+                .map_err(GenericError::from)
+                .and_then(|mut v| Ok(v.read_i64::<BigEndian>()?));
+            match counter {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Invalid counter for key {:?}: {}", key, e);
+                    0
+                }
+            }
+        }).unwrap_or(0);
+    for mut operand in operands {
+        match operand.read_i64::<BigEndian>() {
+            Ok(delta) => counter = counter.wrapping_add(delta),
+            Err(e) => error!("Invalid counter delta for key {:?}: {}", key, e),
+        }
+    }
+    let mut buffer = Vec::with_capacity(8);
+    buffer.write_i64::<BigEndian>(counter).unwrap();
+    buffer
+}
+
+// keeps the first value ever written to the key, see Storage::log_set_cas
+fn first_write_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &mut rocksdb::MergeOperands,
+) -> Vec<u8> {
+    match existing {
+        Some(existing) => existing.to_vec(),
+        None => operands.next().map_or_else(Vec::new, |v| v.to_vec()),
+    }
+}
+
+/// Decides whether a (vnode, key, value) of the data column family survives compaction.
+/// Must be deterministic and side effect free, it runs in background threads
+/// and may see the same entry several times (or never).
+pub trait CompactionFilter: Send + Sync {
+    fn keep(&self, vnode: u16, key: &[u8], value: &[u8]) -> bool;
+}
+
+impl<F: Fn(u16, &[u8], &[u8]) -> bool + Send + Sync> CompactionFilter for F {
+    fn keep(&self, vnode: u16, key: &[u8], value: &[u8]) -> bool {
+        self(vnode, key, value)
+    }
+}
+
+struct UserCompactionFilter(Arc<CompactionFilter>);
+
+impl rocksdb::CompactionFilter for UserCompactionFilter {
+    fn filter(
+        &mut self,
+        _level: usize,
+        key: &[u8],
+        value: &[u8],
+        _new_value: &mut Vec<u8>,
+        _value_changed: &mut bool,
+    ) -> bool {
+        if key.len() < 2 {
+            return false;
+        }
+        let vnode = (&key[..2]).read_u16::<BigEndian>().unwrap();
+        // corrupted values are kept so they can still be reported
+        match decode_value(value) {
+            Ok(value) => !self.0.keep(vnode, &key[2..], value),
+            Err(_) => false,
+        }
+    }
+}
+
 // TODO: support TTL
 // TODO: specific comparator for log cf
 // TODO: merge operator could be a big win
@@ -58,6 +533,15 @@ pub struct Storage {
     cf: &'static rocksdb::CFHandle,
     log_cf: &'static rocksdb::CFHandle,
     num: u16,
+    checksum: bool,
+    pending_iters: Arc<AtomicUsize>,
+    // (key prefix, max bytes)
+    quotas: RwLock<Vec<(Vec<u8>, u64)>>,
+    // shared by all Storages of the manager
+    watchers: Arc<RwLock<Vec<PrefixWatch>>>,
+    rmw_lock: Arc<Mutex<()>>,
+    txns: Option<Arc<Mutex<TxnState>>>,
+    cube_history: Option<usize>,
 }
 
 unsafe impl Sync for Storage {}
@@ -66,455 +550,3352 @@ unsafe impl Send for Storage {}
 pub struct StorageBatch<'a> {
     storage: &'a Storage,
     wb: rocksdb::WriteBatch,
+    // (key, new size) of mutations, only tracked if there are quotas
+    quota_ops: Option<Vec<(Vec<u8>, Option<usize>)>>,
+    // only tracked if there are watchers for this storage
+    watch_ops: Option<Vec<WatchEvent>>,
 }
 
-pub struct SendableStorageBatch(rocksdb::WriteBatch);
-
-impl<'a> From<StorageBatch<'a>> for SendableStorageBatch {
-    fn from(sb: StorageBatch<'a>) -> Self {
-        SendableStorageBatch(sb.wb)
-    }
+/// A write observed by a PrefixWatcher, value is None for deletes
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchEvent {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
 }
 
-struct GenericIterator {
-    db: Arc<rocksdb::DB>,
-    iterator: rocksdb::rocksdb::DBIterator<Arc<rocksdb::DB>>,
-    first: bool,
+struct PrefixWatch {
+    num: u16,
+    prefix: Vec<u8>,
+    sender: fmpsc::UnboundedSender<WatchEvent>,
 }
 
-pub struct StorageIterator(GenericIterator);
-
-pub struct LogStorageIterator(GenericIterator);
-
-unsafe impl Send for GenericIterator {}
+/// Stream of the writes done through Storage/StorageBatch under a key prefix
+pub struct PrefixWatcher(fmpsc::UnboundedReceiver<WatchEvent>);
 
-impl StorageManager {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<StorageManager, GenericError> {
-        let mut opts = rocksdb::DBOptions::new();
-        opts.create_if_missing(true);
-        opts.set_max_background_jobs(4);
-        opts.enable_pipelined_write(true);
-        let mut def_cf_opts = rocksdb::ColumnFamilyOptions::new();
-        def_cf_opts
-            .set_prefix_extractor("U16BeSuffixTransform", Box::new(U16BeSuffixTransform))
-            .unwrap();
-        def_cf_opts.compression_per_level(&[
-            rocksdb::DBCompressionType::No,
-            rocksdb::DBCompressionType::No,
-            rocksdb::DBCompressionType::Lz4,
-            rocksdb::DBCompressionType::Lz4,
-            rocksdb::DBCompressionType::Lz4,
-            rocksdb::DBCompressionType::Lz4,
-            rocksdb::DBCompressionType::Lz4,
-        ]);
-        def_cf_opts.set_write_buffer_size(32 * 1024 * 1024);
-        def_cf_opts.set_max_bytes_for_level_base(4 * 32 * 1024 * 1024);
-        def_cf_opts.set_max_write_buffer_number(4);
+impl Stream for PrefixWatcher {
+    type Item = WatchEvent;
+    type Error = ();
 
-        let mut block_opts = rocksdb::BlockBasedOptions::new();
-        block_opts.set_bloom_filter(10, false);
-        block_opts.set_lru_cache(4 * 32 * 1024 * 1024, -1, 0, 0f64);
-        def_cf_opts.set_block_based_table_factory(&block_opts);
+    fn poll(&mut self) -> Poll<Option<WatchEvent>, ()> {
+        self.0.poll()
+    }
+}
 
-        let mut log_cf_opts = rocksdb::ColumnFamilyOptions::new();
-        log_cf_opts.compression(rocksdb::DBCompressionType::No);
-        let mut fifo_opts = rocksdb::FifoCompactionOptions::new();
-        fifo_opts.set_ttl(3600 * 72); // 72 hours
-        log_cf_opts.set_fifo_compaction_options(fifo_opts);
-        log_cf_opts.set_compaction_style(rocksdb::DBCompactionStyle::Fifo);
-        log_cf_opts.set_write_buffer_size(32 * 1024 * 1024);
-        log_cf_opts.set_max_write_buffer_number(4);
+/// Returned by batch_write if the batch would take a prefix over its quota
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub prefix: Vec<u8>,
+    pub max_bytes: u64,
+    pub estimated_bytes: u64,
+}
 
-        let mut block_opts = rocksdb::BlockBasedOptions::new();
-        block_opts.set_bloom_filter(10, false);
-        block_opts.set_lru_cache(2 * 32 * 1024 * 1024, -1, 0, 0f64);
-        log_cf_opts.set_block_based_table_factory(&block_opts);
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Quota exceeded for prefix {:?}: ~{} of {} bytes",
+            String::from_utf8_lossy(&self.prefix),
+            self.estimated_bytes,
+            self.max_bytes
+        )
+    }
+}
 
-        // TODO: Rocksdb is complicated, we might want to tune some more options
+impl Error for QuotaExceeded {
+    fn description(&self) -> &str {
+        "Quota exceeded"
+    }
+}
 
-        let db = rocksdb::DB::open_cf(
-            opts.clone(),
-            path.as_ref().to_str().unwrap(),
-            vec![
-                ("default", def_cf_opts.clone()),
-                ("log", log_cf_opts.clone()),
-            ],
-        ).or_else(|_| -> Result<_, String> {
-            let mut db = rocksdb::DB::open_cf(
-                opts,
-                path.as_ref().to_str().unwrap(),
-                vec![("default", def_cf_opts)],
-            )?;
+/// Rocksdb errors classified by their status message, so callers can tell
+/// transient failures (DatabaseLocked, Busy) from permanent ones.
+/// Each variant holds the original message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageError {
+    Io(String),
+    Corruption(String),
+    InvalidArgument(String),
+    // The LOCK of the db is held, by this or another process
+    DatabaseLocked(String),
+    // busy, timed out or try again
+    Busy(String),
+    Other(String),
+}
 
-            db.create_cf(("log", log_cf_opts))?;
-            Ok(db)
-        })?;
+impl StorageError {
+    /// Whether the same operation may succeed if retried later
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            StorageError::DatabaseLocked(_) | StorageError::Busy(_) => true,
+            _ => false,
+        }
+    }
 
-        Ok(StorageManager { db: Arc::new(db) })
+    /// Prefixes the message with `context`, keeping the classification
+    pub fn context(self, context: &str) -> Self {
+        match self {
+            StorageError::Io(m) => StorageError::Io(format!("{}: {}", context, m)),
+            StorageError::Corruption(m) => StorageError::Corruption(format!("{}: {}", context, m)),
+            StorageError::InvalidArgument(m) => {
+                StorageError::InvalidArgument(format!("{}: {}", context, m))
+            }
+            StorageError::DatabaseLocked(m) => {
+                StorageError::DatabaseLocked(format!("{}: {}", context, m))
+            }
+            StorageError::Busy(m) => StorageError::Busy(format!("{}: {}", context, m)),
+            StorageError::Other(m) => StorageError::Other(format!("{}: {}", context, m)),
+        }
     }
+}
 
-    pub fn open(&self, db_num: u16) -> Result<Storage, GenericError> {
-        Ok(Storage {
-            db: self.db.clone(),
-            cf: unsafe { mem::transmute(self.db.cf_handle("default").unwrap()) },
-            log_cf: unsafe { mem::transmute(self.db.cf_handle("log").unwrap()) },
-            num: db_num,
-        })
+impl From<String> for StorageError {
+    fn from(message: String) -> Self {
+        if is_lock_message(&message) {
+            StorageError::DatabaseLocked(message)
+        } else if message.starts_with("IO error") {
+            StorageError::Io(message)
+        } else if message.starts_with("Corruption") {
+            StorageError::Corruption(message)
+        } else if message.starts_with("Invalid argument") {
+            StorageError::InvalidArgument(message)
+        } else if message.starts_with("Resource busy")
+            || message.starts_with("Operation timed out")
+            || message.starts_with("Operation failed. Try again")
+        {
+            StorageError::Busy(message)
+        } else {
+            StorageError::Other(message)
+        }
     }
+}
 
-    pub fn batch_write(&self, batch: SendableStorageBatch) -> Result<(), GenericError> {
-        Ok(self.db.write(batch.0)?)
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StorageError::Io(ref m)
+            | StorageError::Corruption(ref m)
+            | StorageError::InvalidArgument(ref m)
+            | StorageError::DatabaseLocked(ref m)
+            | StorageError::Busy(ref m)
+            | StorageError::Other(ref m) => f.write_str(m),
+        }
     }
 }
 
-impl Drop for StorageManager {
-    fn drop(&mut self) {
-        let sc = Arc::strong_count(&self.db);
-        let wc = Arc::weak_count(&self.db);
-        assert_eq!(wc, 0);
-        assert_eq!(sc, 1);
+impl Error for StorageError {
+    fn description(&self) -> &str {
+        match *self {
+            StorageError::Io(_) => "Storage io error",
+            StorageError::Corruption(_) => "Storage corruption",
+            StorageError::InvalidArgument(_) => "Invalid storage argument",
+            StorageError::DatabaseLocked(_) => "Database locked",
+            StorageError::Busy(_) => "Storage busy",
+            StorageError::Other(_) => "Storage error",
+        }
     }
 }
 
-impl Storage {
-    pub fn iterator(&self) -> StorageIterator {
-        let mut key_prefix = [0u8; 2];
-        build_key(&mut key_prefix, self.num, b"");
-        let mut ro = rocksdb::ReadOptions::new();
-        ro.set_total_order_seek(false);
-        ro.set_prefix_same_as_start(true);
-        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.cf, ro);
-        iterator.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
-        StorageIterator(GenericIterator {
-            db: self.db.clone(),
-            iterator: iterator,
-            first: true,
-        })
+/// Value returned by Storage::pin_key, derefs to the value bytes.
+/// The binding doesn't expose rocksdb's PinnableSlice, so this holds the buffer
+/// returned by rocksdb itself (no extra copy like get_vec) which stays valid
+/// regardless of later writes to the key.
+pub struct PinnedValue {
+    value: rocksdb::DBVector,
+    // skips the checksum header, if any
+    offset: usize,
+}
+
+impl ops::Deref for PinnedValue {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.value[self.offset..]
     }
+}
 
-    pub fn log_iterator_all(&self) -> LogStorageIterator {
-        let mut key_prefix = [0u8; 2];
-        build_key(&mut key_prefix, self.num, b"");
-        let mut end_prefix = [0u8; 2];
-        build_key(&mut end_prefix, self.num + 1, b"");
-        let mut ro = rocksdb::ReadOptions::new();
-        ro.set_iterate_upper_bound(&end_prefix[..]);
-        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.log_cf, ro);
-        iterator.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
-        LogStorageIterator(GenericIterator {
-            db: self.db.clone(),
-            iterator: iterator,
-            first: true,
-        })
+// keys sampled per range by partition_keys, more gives more even ranges
+const PARTITION_SAMPLES_PER_RANGE: usize = 64;
+
+// Commit bookkeeping for optimistic transactions.
+// The binding doesn't expose rocksdb's TransactionDB, but the manager owns the db
+// so conflicts can be detected in process by comparing commit sequences.
+#[derive(Default)]
+struct TxnState {
+    // sequence of the last commit
+    seq: u64,
+    // number of open transactions
+    active: usize,
+    // (vnode, key) -> sequence of the last commit that wrote it
+    last_writes: HashMap<(u16, Vec<u8>), u64>,
+}
+
+/// Returned by StorageTransaction::commit if a key read for update or written by the
+/// transaction was written by another transaction after it began.
+/// The transaction should be retried from the start.
+#[derive(Debug)]
+pub struct TransactionConflict {
+    pub key: Vec<u8>,
+}
+
+impl fmt::Display for TransactionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Transaction conflict on key {:?}",
+            String::from_utf8_lossy(&self.key)
+        )
     }
+}
 
-    pub fn log_iterator(&self, prefix: u64, start: u64) -> LogStorageIterator {
-        let mut end_prefix = [0u8; 2 + 8];
-        build_log_prefix(&mut end_prefix, self.num, prefix + 1);
-        let mut start_key = [0u8; 2 + 8 + 8];
-        build_log_key(&mut start_key, self.num, (prefix, start));
-        let mut ro = rocksdb::ReadOptions::new();
-        ro.set_iterate_upper_bound(&end_prefix[..]);
-        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.log_cf, ro);
-        iterator.seek(rocksdb::SeekKey::Key(&start_key[..]));
-        LogStorageIterator(GenericIterator {
-            db: self.db.clone(),
-            iterator: iterator,
-            first: true,
-        })
+impl Error for TransactionConflict {
+    fn description(&self) -> &str {
+        "Transaction conflict"
     }
+}
 
-    pub fn get<R, F: FnOnce(&[u8]) -> R>(
-        &self,
-        key: &[u8],
-        callback: F,
-    ) -> Result<Option<R>, GenericError> {
-        let mut buffer = [0u8; 512];
-        let buffer = build_key(&mut buffer, self.num, key);
-        let r = self.db.get_cf(self.cf, buffer)?;
-        trace!(
-            "get {:?} ({:?} bytes)",
-            str::from_utf8(key),
-            r.as_ref().map(|x| x.len())
-        );
-        Ok(r.map(|r| callback(&*r)))
-    }
+/// Snapshot isolated read-modify-write over the keys of a vnode.
+/// Reads see the storage as of `begin_transaction` plus the transaction's own writes,
+/// writes are applied atomically on commit. Dropping it is the same as `rollback`.
+pub struct StorageTransaction<'a> {
+    storage: &'a Storage,
+    txns: Arc<Mutex<TxnState>>,
+    snapshot: StorageSnapshot,
+    start_seq: u64,
+    // keys read for update, checked for conflicts on commit
+    reads: HashSet<Vec<u8>>,
+    // pending writes, None for deletes
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
 
-    pub fn log_get<R, F: FnOnce(&[u8]) -> R>(
-        &self,
-        log_key: (u64, u64),
-        callback: F,
-    ) -> Result<Option<R>, GenericError> {
-        let mut buffer = [0u8; 2 + 8 + 8];
-        let buffer = build_log_key(&mut buffer, self.num, log_key);
-        let r = self.db.get_cf(self.log_cf, buffer)?;
-        trace!(
-            "log_get {:?} ({:?} bytes)",
-            log_key,
-            r.as_ref().map(|x| x.len())
-        );
-        Ok(r.map(|r| callback(&*r)))
-    }
+pub struct SendableStorageBatch(rocksdb::WriteBatch);
 
-    pub fn get_vec(&self, key: &[u8]) -> Result<Option<Vec<u8>>, GenericError> {
-        self.get(key, |v| v.to_owned())
+impl<'a> From<StorageBatch<'a>> for SendableStorageBatch {
+    fn from(sb: StorageBatch<'a>) -> Self {
+        SendableStorageBatch(sb.wb)
     }
+}
 
-    pub fn log_get_vec(&self, log_key: (u64, u64)) -> Result<Option<Vec<u8>>, GenericError> {
-        self.log_get(log_key, |v| v.to_owned())
-    }
+/// A single logical write submitted to a GroupCommitter
+pub enum GroupWrite {
+    Set(Vec<u8>, Vec<u8>),
+    Del(Vec<u8>),
+    LogSet((u64, u64), Vec<u8>),
+}
 
-    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<(), GenericError> {
-        let mut b = self.batch_new(0);
-        b.set(key, value);
-        self.batch_write(b)
-    }
+type GroupCommitMsg = (Vec<GroupWrite>, mpsc::Sender<Result<(), String>>);
 
-    pub fn del(&self, key: &[u8]) -> Result<(), GenericError> {
-        let mut b = self.batch_new(0);
-        b.del(key);
-        self.batch_write(b)
-    }
+/// Coalesces writes from multiple callers into a single synced WriteBatch per interval.
+/// Durability contract: `commit` only returns Ok after the group containing the
+/// writes was written and synced to disk, at the cost of up to `interval` latency.
+pub struct GroupCommitter {
+    sender: Option<mpsc::Sender<GroupCommitMsg>>,
+    thread: Option<thread::JoinHandle<()>>,
+    syncs: Arc<AtomicUsize>,
+}
 
-    pub fn batch_new(&self, reserve: usize) -> StorageBatch {
-        StorageBatch {
-            storage: self,
-            wb: rocksdb::WriteBatch::with_capacity(reserve),
+impl GroupCommitter {
+    fn new(storage: Storage, interval: Duration) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let syncs_cloned = syncs.clone();
+        let thread = thread::Builder::new()
+            .name(format!("GroupCommitter:{}", storage.num))
+            .spawn(move || Self::run(&storage, &receiver, interval, &syncs_cloned))
+            .unwrap();
+        GroupCommitter {
+            sender: Some(sender),
+            thread: Some(thread),
+            syncs: syncs,
         }
     }
 
-    pub fn batch_write(&self, batch: StorageBatch) -> Result<(), GenericError> {
-        Ok(self.db.write(batch.wb)?)
-    }
-
-    pub fn clear(&self) {
-        trace!("clear");
-        let mut from = [0u8; 2];
-        let mut to = [0u8; 2];
-        (&mut from[..]).write_u16::<BigEndian>(self.num).unwrap();
-        (&mut to[..]).write_u16::<BigEndian>(self.num + 1).unwrap();
+    fn run(
+        storage: &Storage,
+        receiver: &mpsc::Receiver<GroupCommitMsg>,
+        interval: Duration,
+        syncs: &AtomicUsize,
+    ) {
+        // the group starts with the first write, so a lone write waits at most interval
+        while let Ok(first) = receiver.recv() {
+            let deadline = Instant::now() + interval;
+            let mut group = vec![first];
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                match receiver.recv_timeout(deadline - now) {
+                    Ok(msg) => group.push(msg),
+                    Err(_) => break,
+                }
+            }
 
-        for &cf in &[self.cf, self.log_cf] {
-            self.db
-                .delete_files_in_range_cf(cf, &from[..], &to[..], false)
-                .unwrap();
-            let mut ro = rocksdb::ReadOptions::new();
-            ro.set_total_order_seek(false);
-            ro.set_prefix_same_as_start(true);
-            ro.set_iterate_upper_bound(&to[..]);
-            let mut iter = self.db.iter_cf_opt(cf, ro);
-            iter.seek(rocksdb::SeekKey::Key(&from[..]));
-            while iter.valid() {
-                self.db.delete_cf(cf, iter.key()).unwrap();
-                iter.next();
+            let mut batch = storage.batch_new(0);
+            for write in group.iter().flat_map(|&(ref writes, _)| writes) {
+                match *write {
+                    GroupWrite::Set(ref k, ref v) => batch.set(k, v),
+                    GroupWrite::Del(ref k) => batch.del(k),
+                    GroupWrite::LogSet(k, ref v) => batch.log_set(k, v),
+                }
+            }
+            let mut write_opts = rocksdb::WriteOptions::new();
+            write_opts.set_sync(true);
+            let StorageBatch { wb, watch_ops, .. } = batch;
+            let result = storage
+                .db
+                .write_opt(wb, &write_opts)
+                .map_err(|e| e.to_string());
+            if let (true, Some(events)) = (result.is_ok(), watch_ops) {
+                storage.notify_watchers(events);
+            }
+            syncs.fetch_add(1, Ordering::Relaxed);
+            trace!("group commit of {} writers: {:?}", group.len(), result);
+            for (_, waiter) in group {
+                let _ = waiter.send(result.clone());
             }
         }
     }
 
-    pub fn sync(&self) -> Result<(), GenericError> {
-        debug!("sync");
-        Ok(self.db.sync_wal()?)
+    /// Blocks until the writes are durable, see the struct docs
+    pub fn commit(&self, writes: Vec<GroupWrite>) -> Result<(), GenericError> {
+        let (sender, receiver) = mpsc::channel();
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send((writes, sender))
+            .map_err(|_| "Group committer is gone")?;
+        receiver
+            .recv()
+            .map_err(|_| "Group committer is gone")?
+            .map_err(Into::into)
+    }
+
+    /// Number of synced writes performed so far
+    pub fn syncs(&self) -> usize {
+        self.syncs.load(Ordering::Relaxed)
     }
 }
 
-impl<'a> StorageBatch<'a> {
-    pub fn is_empty(&self) -> bool {
-        self.wb.is_empty()
+impl Drop for GroupCommitter {
+    fn drop(&mut self) {
+        // disconnecting makes the thread flush the pending group and exit
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
     }
+}
 
-    pub fn set(&mut self, key: &[u8], value: &[u8]) {
-        trace!("set {:?} ({} bytes)", str::from_utf8(key), value.len());
-        let mut buffer = [0u8; 512];
-        let buffer = build_key(&mut buffer, self.storage.num, key);
-        self.wb.put_cf(self.storage.cf, buffer, value).unwrap();
+struct GenericIterator {
+    db: Arc<rocksdb::DB>,
+    iterator: rocksdb::rocksdb::DBIterator<Arc<rocksdb::DB>>,
+    first: bool,
+    // keeps the snapshot (if any) alive for as long as the iterator
+    snapshot: Option<StorageSnapshot>,
+    // must be the last field so it's dropped after the rocksdb iterator
+    _pending: PendingIterGuard,
+}
+
+/// A point in time view of the storage
+#[derive(Clone)]
+pub struct StorageSnapshot(Arc<rocksdb::Snapshot<Arc<rocksdb::DB>>>);
+
+unsafe impl Send for StorageSnapshot {}
+unsafe impl Sync for StorageSnapshot {}
+
+const COPY_BATCH_SIZE: u64 = 1_000;
+const MIGRATE_BATCH_SIZE: u64 = 1_000;
+
+/// Progress of a `Storage::copy_to_checkpoint`, pass the same value again to resume
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CopyCheckpoint {
+    // last data key written to the target
+    last_key: Option<Vec<u8>>,
+    // last log key written to the target
+    last_log_key: Option<(u64, u64)>,
+    data_done: bool,
+    log_done: bool,
+    copied: u64,
+}
+
+impl CopyCheckpoint {
+    pub fn new() -> Self {
+        Default::default()
     }
 
-    pub fn log_set(&mut self, key: (u64, u64), value: &[u8]) {
-        trace!("log_set {:?} ({} bytes)", key, value.len());
-        let mut buffer = [0u8; 2 + 8 + 8];
-        let buffer = build_log_key(&mut buffer, self.storage.num, key);
-        self.wb.put_cf(self.storage.log_cf, buffer, value).unwrap();
+    pub fn is_done(&self) -> bool {
+        self.data_done && self.log_done
     }
 
-    pub fn del(&mut self, key: &[u8]) {
-        trace!("del {:?}", str::from_utf8(key));
-        let mut buffer = [0u8; 512];
-        let buffer = build_key(&mut buffer, self.storage.num, key);
-        self.wb.delete_cf(self.storage.cf, buffer).unwrap()
+    /// Number of entries (data and log) copied so far
+    pub fn copied(&self) -> u64 {
+        self.copied
     }
 }
 
-impl GenericIterator {
-    pub fn iter<'a>(&'a mut self) -> GenericIteratorIter<'a> {
-        GenericIteratorIter { it: self }
-    }
+/// Rocksdb estimates to tell whether a manual compaction is worthwhile,
+/// see Storage::compaction_estimate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    // bytes rocksdb expects to rewrite to bring all levels under their targets
+    pub pending_compaction_bytes: u64,
+    // deletes (tombstones) in the active memtables
+    pub num_deletions: u64,
+    pub num_live_keys: u64,
 }
 
-pub struct GenericIteratorIter<'a> {
-    it: &'a mut GenericIterator,
+const CHECKPOINT_MANIFEST_FILE: &str = "SUCREDB_MANIFEST";
+
+/// Log high-water marks of a checkpoint, see StorageManager::checkpoint_with_manifest.
+/// A node restored from the checkpoint can resume replication from these positions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckpointManifest {
+    // (vnode, log prefix, max seq), in order
+    pub log_heads: Vec<(u16, u64, u64)>,
 }
 
-impl<'a> Iterator for GenericIteratorIter<'a> {
-    type Item = (u16, &'a [u8], &'a [u8]);
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.it.first {
-            self.it.first = false;
-        } else {
-            // this iterator isn't fused so we need to check for valid here too
-            if !self.it.iterator.valid() {
-                return None;
-            }
-            self.it.iterator.next();
+impl CheckpointManifest {
+    /// Reads the manifest of the checkpoint at `checkpoint_dir`
+    pub fn read<P: AsRef<Path>>(checkpoint_dir: P) -> Result<Self, GenericError> {
+        let bytes = fs::read(checkpoint_dir.as_ref().join(CHECKPOINT_MANIFEST_FILE))?;
+        let mut bytes = &bytes[..];
+        let len = bytes.read_u32::<BigEndian>()?;
+        let mut log_heads = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            log_heads.push((
+                bytes.read_u16::<BigEndian>()?,
+                bytes.read_u64::<BigEndian>()?,
+                bytes.read_u64::<BigEndian>()?,
+            ));
         }
-        if self.it.iterator.valid() {
-            unsafe {
-                let key = self.it.iterator.key();
-                let value = self.it.iterator.value();
-                // FIXME: bogus lifetime as slices are only valid until the next call to next()
-                Some((
-                    (&key[..2]).read_u16::<BigEndian>().unwrap(),
-                    mem::transmute(&key[2..]),
-                    mem::transmute(value),
-                ))
-            }
-        } else {
-            None
+        if !bytes.is_empty() {
+            return Err("Trailing bytes in checkpoint manifest".into());
         }
+        Ok(CheckpointManifest { log_heads })
     }
-}
 
-pub struct StorageIteratorIter<'a>(GenericIteratorIter<'a>);
+    fn write<P: AsRef<Path>>(&self, checkpoint_dir: P) -> Result<(), GenericError> {
+        let mut buffer = Vec::with_capacity(4 + self.log_heads.len() * (2 + 8 + 8));
+        buffer.write_u32::<BigEndian>(self.log_heads.len() as u32)?;
+        for &(vnode, prefix, seq) in &self.log_heads {
+            buffer.write_u16::<BigEndian>(vnode)?;
+            buffer.write_u64::<BigEndian>(prefix)?;
+            buffer.write_u64::<BigEndian>(seq)?;
+        }
+        // write + rename so a partial manifest is never observed
+        let path = checkpoint_dir.as_ref().join(CHECKPOINT_MANIFEST_FILE);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(&buffer)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
 
-impl StorageIterator {
-    pub fn iter<'a>(&'a mut self) -> StorageIteratorIter<'a> {
-        StorageIteratorIter(self.0.iter())
+    /// Max seq logged for `prefix` in `vnode`, if any
+    pub fn head(&self, vnode: u16, prefix: u64) -> Option<u64> {
+        self.log_heads
+            .binary_search_by(|&(v, p, _)| (v, p).cmp(&(vnode, prefix)))
+            .ok()
+            .map(|i| self.log_heads[i].2)
     }
 }
 
-impl<'a> Iterator for StorageIteratorIter<'a> {
-    type Item = (&'a [u8], &'a [u8]);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.next().map(|(_, k, v)| (k, v))
+/// Data of a vnode as of the time StorageManager::export_vnode was called
+pub struct VNodeExport {
+    storage: Storage,
+    snapshot: StorageSnapshot,
+}
+
+impl VNodeExport {
+    pub fn vnode(&self) -> u16 {
+        self.storage.num
+    }
+
+    pub fn iterator(&self) -> StorageIterator {
+        self.storage.iterator_at(&self.snapshot)
     }
 }
 
-pub struct LogStorageIteratorIter<'a>(GenericIteratorIter<'a>);
+struct PendingIterGuard(Arc<AtomicUsize>);
 
-impl<'a> Iterator for LogStorageIteratorIter<'a> {
-    type Item = ((u64, u64), &'a [u8]);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.0.next().map(|(_, key, value)| {
-            let first = (&key[..8]).read_u64::<BigEndian>().unwrap();
-            let second = (&key[8..8 + 8]).read_u64::<BigEndian>().unwrap();
-            ((first, second), value)
-        })
+impl PendingIterGuard {
+    fn new(pending_iters: &Arc<AtomicUsize>) -> Self {
+        pending_iters.fetch_add(1, Ordering::SeqCst);
+        PendingIterGuard(pending_iters.clone())
     }
 }
 
-impl LogStorageIterator {
-    pub fn iter<'a>(&'a mut self) -> LogStorageIteratorIter<'a> {
-        LogStorageIteratorIter(self.0.iter())
+impl Drop for PendingIterGuard {
+    fn drop(&mut self) {
+        let prev = self.0.fetch_sub(1, Ordering::SeqCst);
+        debug_assert!(prev > 0);
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs;
+pub struct StorageIterator {
+    it: GenericIterator,
+    // set if iteration stopped at a corrupted value
+    corruption: Option<StorageError>,
+}
 
-    #[test]
-    fn test_simple() {
-        let _ = fs::remove_dir_all("t/test_simple");
-        let sm = StorageManager::new("t/test_simple").unwrap();
-        let storage = sm.open(1).unwrap();
-        assert_eq!(storage.get_vec(b"sample").unwrap(), None);
-        storage.set(b"sample", b"sample_value").unwrap();
-        assert_eq!(
-            storage.get_vec(b"sample").unwrap().unwrap(),
-            b"sample_value"
-        );
-        storage.del(b"sample").unwrap();
-        assert_eq!(storage.get_vec(b"sample").unwrap(), None);
-    }
+pub struct LogStorageIterator(GenericIterator);
 
-    #[test]
-    fn test_simple_log() {
-        let _ = fs::remove_dir_all("t/test_simple_log");
-        let sm = StorageManager::new("t/test_simple_log").unwrap();
-        let storage = sm.open(1).unwrap();
-        assert_eq!(storage.get_vec(b"sample").unwrap(), None);
-        let mut b = storage.batch_new(0);
-        b.set(b"sample", b"sample_value");
-        b.log_set((1, 1), b"sample");
-        storage.batch_write(b).unwrap();
-        assert_eq!(
-            storage.get_vec(b"sample").unwrap().unwrap(),
-            b"sample_value"
-        );
-        assert_eq!(storage.log_get_vec((1, 1)).unwrap().unwrap(), b"sample");
+unsafe impl Send for GenericIterator {}
+
+fn is_lock_error(e: &Error) -> bool {
+    match e.downcast_ref::<StorageError>() {
+        Some(&StorageError::DatabaseLocked(_)) => true,
+        _ => is_lock_message(&e.to_string()),
     }
+}
 
-    #[test]
-    fn test_iter() {
-        let _ = fs::remove_dir_all("t/test_iter");
-        let sm = StorageManager::new("t/test_iter").unwrap();
-        for &i in &[0, 1, 2] {
-            let storage = sm.open(i).unwrap();
-            storage.set(b"1", i.to_string().as_bytes()).unwrap();
-            storage.set(b"2", i.to_string().as_bytes()).unwrap();
-            storage.set(b"3", i.to_string().as_bytes()).unwrap();
-        }
-        for &i in &[0, 1, 2] {
-            let storage = sm.open(i).unwrap();
-            let results: Vec<Vec<u8>> = storage.iterator().iter().map(|(_, v)| v.into()).collect();
-            assert_eq!(results, vec![i.to_string().as_bytes(); 3]);
+// rocksdb reports both in-process and os level locks as IO errors on the LOCK file
+fn is_lock_message(message: &str) -> bool {
+    message.starts_with("IO error") && message.contains("LOCK")
+}
+
+// rocksdb can't enumerate its properties, these are the integer ones it exposes per cf
+const CF_INT_PROPERTIES: &[&str] = &[
+    "rocksdb.num-immutable-mem-table",
+    "rocksdb.num-immutable-mem-table-flushed",
+    "rocksdb.mem-table-flush-pending",
+    "rocksdb.num-running-flushes",
+    "rocksdb.compaction-pending",
+    "rocksdb.num-running-compactions",
+    "rocksdb.background-errors",
+    "rocksdb.cur-size-active-mem-table",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.size-all-mem-tables",
+    "rocksdb.num-entries-active-mem-table",
+    "rocksdb.num-entries-imm-mem-tables",
+    "rocksdb.num-deletes-active-mem-table",
+    "rocksdb.num-deletes-imm-mem-tables",
+    "rocksdb.estimate-num-keys",
+    "rocksdb.estimate-table-readers-mem",
+    "rocksdb.is-file-deletions-enabled",
+    "rocksdb.num-snapshots",
+    "rocksdb.oldest-snapshot-time",
+    "rocksdb.num-live-versions",
+    "rocksdb.current-super-version-number",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.min-log-number-to-keep",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.live-sst-files-size",
+    "rocksdb.base-level",
+    "rocksdb.estimate-pending-compaction-bytes",
+    "rocksdb.actual-delayed-write-rate",
+    "rocksdb.is-write-stopped",
+    "rocksdb.block-cache-capacity",
+    "rocksdb.block-cache-usage",
+    "rocksdb.block-cache-pinned-usage",
+];
+
+// 1234567 -> "1,234,567"
+fn format_thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut result = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i != 0 && (digits.len() - i) % 3 == 0 {
+            result.push(',');
         }
+        result.push(c);
     }
+    result
+}
 
-    #[test]
-    fn test_iter_log() {
-        let _ = fs::remove_dir_all("t/test_iter_log");
-        let sm = StorageManager::new("t/test_iter_log").unwrap();
-        for &i in &[0u64, 1, 2] {
-            let storage = sm.open(i as u16).unwrap();
-            let mut b = storage.batch_new(0);
-            b.log_set((i, i + 0), (i + 0).to_string().as_bytes());
-            b.log_set((i, i + 1), (i + 1).to_string().as_bytes());
-            b.log_set((i, i + 2), (i + 2).to_string().as_bytes());
-            b.log_set((i + 1, i), b"");
-            storage.batch_write(b).unwrap();
-        }
-        for &i in &[0u64, 1, 2] {
-            let storage = sm.open(i as u16).unwrap();
-            let results: Vec<(_, Vec<u8>)> = storage
-                .log_iterator(i, i + 1)
-                .iter()
-                .map(|(k, v)| (k, v.into()))
-                .collect();
-            assert_eq!(
-                results,
-                vec![
-                    ((i, i + 1), (i + 1).to_string().into_bytes()),
-                    ((i, i + 2), (i + 2).to_string().into_bytes()),
-                ]
-            );
-            assert_eq!(storage.log_iterator(i, i).iter().count(), 3);
-            assert_eq!(storage.log_iterator_all().iter().count(), 4);
-        }
+impl StorageManager {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<StorageManager, GenericError> {
+        Self::with_config(path, &Default::default())
     }
 
-    #[test]
-    fn test_clear() {
-        let _ = fs::remove_dir_all("t/test_clear");
-        let sm = StorageManager::new("t/test_clear").unwrap();
-        for &i in &[0u64, 1, 2] {
-            let storage = sm.open(i as u16).unwrap();
-            let mut b = storage.batch_new(0);
-            b.set(i.to_string().as_bytes(), i.to_string().as_bytes());
-            b.log_set((i, i), i.to_string().as_bytes());
-            storage.batch_write(b).unwrap();
-        }
-        for &i in &[0u64, 1, 2] {
+    fn options(
+        config: &StorageConfig,
+    ) -> (
+        rocksdb::DBOptions,
+        rocksdb::ColumnFamilyOptions,
+        rocksdb::ColumnFamilyOptions,
+    ) {
+        let mut opts = rocksdb::DBOptions::new();
+        opts.create_if_missing(true);
+        opts.set_max_background_jobs(4);
+        opts.enable_pipelined_write(true);
+        if let Some(size) = config.max_total_wal_size {
+            opts.set_max_total_wal_size(size);
+        }
+        if let Some(num) = config.recycle_log_file_num {
+            opts.set_recycle_log_file_num(num);
+        }
+        let mut def_cf_opts = rocksdb::ColumnFamilyOptions::new();
+        def_cf_opts
+            .set_prefix_extractor("U16BeSuffixTransform", Box::new(U16BeSuffixTransform))
+            .unwrap();
+        if config.value_checksums {
+            def_cf_opts.add_merge_operator("counter_merge", checksummed_counter_merge);
+        } else {
+            def_cf_opts.add_merge_operator("counter_merge", counter_merge);
+        }
+        def_cf_opts.compression_per_level(&[
+            rocksdb::DBCompressionType::No,
+            rocksdb::DBCompressionType::No,
+            rocksdb::DBCompressionType::Lz4,
+            rocksdb::DBCompressionType::Lz4,
+            rocksdb::DBCompressionType::Lz4,
+            rocksdb::DBCompressionType::Lz4,
+            rocksdb::DBCompressionType::Lz4,
+        ]);
+        def_cf_opts.set_write_buffer_size(WRITE_BUFFER_BYTES);
+        def_cf_opts.set_max_bytes_for_level_base(4 * WRITE_BUFFER_BYTES);
+        def_cf_opts.set_max_write_buffer_number(4);
+        if let Some(trigger) = config.level0_file_num_compaction_trigger {
+            def_cf_opts.set_level_zero_file_num_compaction_trigger(trigger as i32);
+        }
+        if let Some(trigger) = config.level0_slowdown_writes_trigger {
+            def_cf_opts.set_level_zero_slowdown_writes_trigger(trigger as i32);
+        }
+        if let Some(trigger) = config.level0_stop_writes_trigger {
+            def_cf_opts.set_level_zero_stop_writes_trigger(trigger as i32);
+        }
+
+        let mut block_opts = rocksdb::BlockBasedOptions::new();
+        block_opts.set_bloom_filter(10, false);
+        let shard_bits = config.block_cache_shard_bits.map_or(-1, |b| b as i32);
+        block_opts.set_lru_cache(DATA_BLOCK_CACHE_BYTES, shard_bits, 0, 0f64);
+        def_cf_opts.set_block_based_table_factory(&block_opts);
+
+        if let Some(threshold) = config.inline_value_threshold {
+            // titan separates values >= min_blob_size from the lsm tree
+            let mut titan_opts = rocksdb::TitanDBOptions::new();
+            titan_opts.set_min_blob_size(threshold as u64);
+            opts.set_titandb_options(&titan_opts);
+            def_cf_opts.set_titandb_options(&titan_opts);
+        }
+
+        let mut log_cf_opts = rocksdb::ColumnFamilyOptions::new();
+        log_cf_opts.compression(rocksdb::DBCompressionType::No);
+        log_cf_opts.add_merge_operator("first_write_merge", first_write_merge);
+        let mut fifo_opts = rocksdb::FifoCompactionOptions::new();
+        fifo_opts.set_ttl(3600 * 72); // 72 hours
+        log_cf_opts.set_fifo_compaction_options(fifo_opts);
+        log_cf_opts.set_compaction_style(rocksdb::DBCompactionStyle::Fifo);
+        log_cf_opts.set_write_buffer_size(WRITE_BUFFER_BYTES);
+        log_cf_opts.set_max_write_buffer_number(4);
+        match config.log_memtable {
+            MemtableKind::SkipList => (),
+            MemtableKind::HashSkipList { bucket_count } => {
+                log_cf_opts
+                    .set_prefix_extractor("LogPrefixTransform", Box::new(LogPrefixTransform))
+                    .unwrap();
+                log_cf_opts.set_hash_skiplist_rep(bucket_count, 4, 4);
+            }
+            MemtableKind::Vector => {
+                log_cf_opts.set_vector_rep();
+            }
+        }
+        if config.log_memtable != MemtableKind::SkipList {
+            // only the skiplist memtable supports concurrent writes
+            opts.allow_concurrent_memtable_write(false);
+        }
+
+        let mut block_opts = rocksdb::BlockBasedOptions::new();
+        block_opts.set_bloom_filter(10, false);
+        block_opts.set_lru_cache(LOG_BLOCK_CACHE_BYTES, shard_bits, 0, 0f64);
+        log_cf_opts.set_block_based_table_factory(&block_opts);
+
+        // TODO: Rocksdb is complicated, we might want to tune some more options
+
+        (opts, def_cf_opts, log_cf_opts)
+    }
+
+    pub fn with_config<P: AsRef<Path>>(
+        path: P,
+        config: &StorageConfig,
+    ) -> Result<StorageManager, GenericError> {
+        Self::with_compaction_filter(path, config, None)
+    }
+
+    /// Like `new` but retries while the db lock file is held, ie. by a process
+    /// that crashed and is still being torn down. The delay doubles after each retry.
+    /// Returns the last error once `max_retries` are exhausted or if it's not a lock error.
+    pub fn open_with_retry<P: AsRef<Path>>(
+        path: P,
+        max_retries: u32,
+        retry_delay: Duration,
+    ) -> Result<StorageManager, GenericError> {
+        let mut delay = retry_delay;
+        let mut retries = 0;
+        loop {
+            match Self::new(path.as_ref()) {
+                Err(ref e) if retries < max_retries && is_lock_error(&**e) => {
+                    warn!(
+                        "Storage {:?} is locked, retrying in {:?}",
+                        path.as_ref(),
+                        delay
+                    );
+                    thread::sleep(delay);
+                    delay *= 2;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Like `with_config` but `filter` can drop data entries during compactions
+    pub fn with_compaction_filter<P: AsRef<Path>>(
+        path: P,
+        config: &StorageConfig,
+        filter: Option<Box<CompactionFilter>>,
+    ) -> Result<StorageManager, GenericError> {
+        config.validate()?;
+        let (opts, base_def_cf_opts, log_cf_opts) = Self::options(config);
+        let filter: Option<Arc<CompactionFilter>> = filter.map(Into::into);
+        // the filter is owned by the options it's set on, so set it on every copy
+        let def_cf_opts = || {
+            let mut def_cf_opts = base_def_cf_opts.clone();
+            if let Some(ref filter) = filter {
+                def_cf_opts
+                    .set_compaction_filter(
+                        "user_compaction_filter",
+                        false,
+                        Box::new(UserCompactionFilter(filter.clone())),
+                    ).unwrap();
+            }
+            def_cf_opts
+        };
+        // the vnode -> log cf mapping depends on it
+        let existing_log_cfs = Self::log_column_families(path.as_ref())?;
+        if existing_log_cfs != 0 && existing_log_cfs != config.log_column_families {
+            return Err(format!(
+                "Storage {:?} has {} log column families but {} are configured",
+                path.as_ref(),
+                existing_log_cfs,
+                config.log_column_families
+            ).into());
+        }
+        let log_cf_names: Vec<_> = (0..config.log_column_families).map(log_cf_name).collect();
+        // rocksdb refuses to open unless all column families are listed
+        let unexpected_cfs = Self::unexpected_column_families(path.as_ref())?;
+        let extra_cfs = || {
+            unexpected_cfs
+                .iter()
+                .map(|cf| (cf.as_str(), rocksdb::ColumnFamilyOptions::new()))
+        };
+        let db = rocksdb::DB::open_cf(
+            opts.clone(),
+            path.as_ref().to_str().unwrap(),
+            vec![("default", def_cf_opts())]
+                .into_iter()
+                .chain(
+                    log_cf_names
+                        .iter()
+                        .map(|name| (name.as_str(), log_cf_opts.clone())),
+                ).chain(extra_cfs())
+                .collect(),
+        ).or_else(|_| -> Result<_, StorageError> {
+            let mut db = rocksdb::DB::open_cf(
+                opts,
+                path.as_ref().to_str().unwrap(),
+                vec![("default", def_cf_opts())]
+                    .into_iter()
+                    .chain(extra_cfs())
+                    .collect(),
+            )?;
+
+            for name in &log_cf_names {
+                db.create_cf((name.as_str(), log_cf_opts.clone()))?;
+            }
+            Ok(db)
+        }).map_err(|e| e.context(&format!("Can't open storage with {:?}", config)))?;
+
+        Ok(StorageManager {
+            db: Arc::new(db),
+            pending_iters: Default::default(),
+            watchers: Default::default(),
+            rmw_lock: Default::default(),
+            txns: if config.transactions {
+                Some(Default::default())
+            } else {
+                None
+            },
+            log_cfs: config.log_column_families,
+            cube_history: config.cube_history,
+            checksums: config.value_checksums,
+        })
+    }
+
+    /// Names of the column families of the db in `path`
+    pub fn list_column_families<P: AsRef<Path>>(path: P) -> Result<Vec<String>, GenericError> {
+        Ok(rocksdb::DB::list_column_families(
+            &rocksdb::DBOptions::new(),
+            path.as_ref().to_str().unwrap(),
+        ).map_err(StorageError::from)?)
+    }
+
+    // number of log column families of the db in `path`, 0 if there's none (yet)
+    fn log_column_families(path: &Path) -> Result<usize, GenericError> {
+        if !path.join("CURRENT").exists() {
+            return Ok(0);
+        }
+        Ok(Self::list_column_families(path)?
+            .iter()
+            .filter(|cf| is_log_cf_name(cf))
+            .count())
+    }
+
+    // column families not created by this version, possibly from a newer one
+    fn unexpected_column_families(path: &Path) -> Result<Vec<String>, GenericError> {
+        if !path.join("CURRENT").exists() {
+            return Ok(Vec::new());
+        }
+        let unexpected: Vec<_> = Self::list_column_families(path)?
+            .into_iter()
+            .filter(|cf| cf != "default" && !is_log_cf_name(cf))
+            .collect();
+        for cf in &unexpected {
+            warn!("Unexpected column family `{}` in {:?}", cf, path);
+        }
+        Ok(unexpected)
+    }
+
+    /// Flushes the memtables of all column families and fsyncs the wal and the
+    /// db directory. Everything written before the call is on stable storage
+    /// once it returns.
+    pub fn barrier(&self) -> Result<(), GenericError> {
+        self.db.sync_wal().map_err(StorageError::from)?;
+        for cf in self.cf_names() {
+            self.db
+                .flush_cf(self.db.cf_handle(&cf).unwrap(), true)
+                .map_err(StorageError::from)?;
+        }
+        fs::File::open(self.db.path())?.sync_all()?;
+        Ok(())
+    }
+
+    /// Creates a rocksdb checkpoint (data and log) at `dest`, which must not exist,
+    /// plus a manifest with the max seq of every log prefix in it. The manifest is
+    /// computed from the checkpoint itself so both are consistent.
+    pub fn checkpoint_with_manifest<P: AsRef<Path>>(
+        &self,
+        dest: P,
+    ) -> Result<CheckpointManifest, GenericError> {
+        let dest = dest.as_ref();
+        rocksdb::Checkpointer::new(&self.db)
+            .and_then(|c| c.create_at(dest, 0))
+            .map_err(StorageError::from)?;
+        let manifest = CheckpointManifest {
+            log_heads: StorageManager::with_config(
+                dest,
+                &StorageConfig {
+                    log_column_families: self.log_cfs,
+                    value_checksums: self.checksums,
+                    ..Default::default()
+                },
+            )?.log_heads(),
+        };
+        manifest.write(dest)?;
+        Ok(manifest)
+    }
+
+    // (vnode, prefix, max seq) of every log prefix, visiting a couple entries per prefix
+    fn log_heads(&self) -> Vec<(u16, u64, u64)> {
+        let mut heads = Vec::new();
+        for shard in 0..self.log_cfs {
+            self.log_heads_cf(&log_cf_name(shard), &mut heads);
+        }
+        heads.sort();
+        heads
+    }
+
+    fn log_heads_cf(&self, cf_name: &str, heads: &mut Vec<(u16, u64, u64)>) {
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(true);
+        let cf = self.db.cf_handle(cf_name).unwrap();
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), cf, ro);
+        iterator.seek(rocksdb::SeekKey::Start);
+        while iterator.valid() {
+            let (num, prefix) = {
+                let key = iterator.key();
+                (
+                    (&key[..2]).read_u16::<BigEndian>().unwrap(),
+                    (&key[2..2 + 8]).read_u64::<BigEndian>().unwrap(),
+                )
+            };
+            // first key past this prefix, None if it's the last possible one
+            let next = if let Some(next_prefix) = prefix.checked_add(1) {
+                let mut buffer = vec![0u8; 2 + 8];
+                build_log_prefix(&mut buffer, num, next_prefix);
+                Some(buffer)
+            } else if let Some(next_num) = num.checked_add(1) {
+                let mut buffer = vec![0u8; 2];
+                build_key(&mut buffer, next_num, b"");
+                Some(buffer)
+            } else {
+                None
+            };
+            match next {
+                Some(ref next) => iterator.seek_for_prev(rocksdb::SeekKey::Key(next)),
+                None => iterator.seek(rocksdb::SeekKey::End),
+            };
+            let ((_, seq), _) = parse_log_key(&iterator.key()[2..]);
+            heads.push((num, prefix, seq));
+            match next {
+                Some(ref next) => iterator.seek(rocksdb::SeekKey::Key(next)),
+                None => break,
+            };
+        }
+    }
+
+    // default first, then the log ones in shard order
+    fn cf_names(&self) -> Vec<String> {
+        let mut names = vec!["default".to_string()];
+        names.extend((0..self.log_cfs).map(log_cf_name));
+        names
+    }
+
+    /// Name of the log column family holding the logs of vnode `num`
+    pub fn log_cf_name(&self, num: u16) -> String {
+        log_cf_name(num as usize % self.log_cfs)
+    }
+
+    /// Panics if any iterator created from this manager is still alive.
+    /// Uses a single counter so concurrent iterator creation/destruction
+    /// can't make it observe an inconsistent state.
+    pub fn check_pending_iters(&self) {
+        let pending = self.pending_iters.load(Ordering::SeqCst);
+        assert_eq!(pending, 0, "{} iterators still alive", pending);
+    }
+
+    /// Human readable dump of the rocksdb properties, one section per column family.
+    /// Properties not supported by the underlying rocksdb are omitted.
+    pub fn properties_summary(&self) -> String {
+        let mut summary = String::new();
+        for cf_name in self.cf_names() {
+            let cf = self.db.cf_handle(&cf_name).unwrap();
+            let values: Vec<_> = CF_INT_PROPERTIES
+                .iter()
+                .filter_map(|&name| {
+                    self.db
+                        .get_property_int_cf(cf, name)
+                        .map(|v| (name, format_thousands(v)))
+                }).collect();
+            let name_width = values.iter().map(|&(n, _)| n.len()).max().unwrap_or(0);
+            let value_width = values.iter().map(|&(_, ref v)| v.len()).max().unwrap_or(0);
+            summary.push_str(&format!("[{}]\n", cf_name));
+            for (name, value) in values {
+                summary.push_str(&format!(
+                    "{:<nw$}  {:>vw$}\n",
+                    name,
+                    value,
+                    nw = name_width,
+                    vw = value_width
+                ));
+            }
+            summary.push('\n');
+        }
+        summary
+    }
+
+    pub fn open(&self, db_num: u16) -> Result<Storage, GenericError> {
+        Ok(Storage {
+            db: self.db.clone(),
+            cf: unsafe { mem::transmute(self.db.cf_handle("default").unwrap()) },
+            log_cf: unsafe {
+                mem::transmute(
+                    self.db
+                        .cf_handle(&log_cf_name(db_num as usize % self.log_cfs))
+                        .unwrap(),
+                )
+            },
+            num: db_num,
+            checksum: self.checksums,
+            pending_iters: self.pending_iters.clone(),
+            quotas: Default::default(),
+            watchers: self.watchers.clone(),
+            rmw_lock: self.rmw_lock.clone(),
+            txns: self.txns.clone(),
+            cube_history: self.cube_history,
+        })
+    }
+
+    /// Numbers of the dbs (vnodes) holding any data, in order.
+    /// Seeks past each db instead of walking all of its keys.
+    pub fn list_dbnums(&self) -> Vec<u16> {
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(true);
+        let cf = self.db.cf_handle("default").unwrap();
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), cf, ro);
+        iterator.seek(rocksdb::SeekKey::Start);
+        let mut nums = Vec::new();
+        while iterator.valid() {
+            let num = (&iterator.key()[..2]).read_u16::<BigEndian>().unwrap();
+            nums.push(num);
+            if let Some(next_num) = num.checked_add(1) {
+                let mut buffer = [0u8; 2];
+                build_key(&mut buffer, next_num, b"");
+                iterator.seek(rocksdb::SeekKey::Key(&buffer[..]));
+            } else {
+                break;
+            }
+        }
+        nums
+    }
+
+    /// An iterator for each db with data (see list_dbnums), in (db, key) order.
+    /// Iterators are created lazily, each one with its own snapshot.
+    pub fn iter_all_vnodes<'a>(&'a self) -> impl Iterator<Item = (u16, StorageIterator)> + 'a {
+        self.list_dbnums().into_iter().map(move |num| {
+            let storage = self.open(num).expect("Can't open storage");
+            (num, storage.iterator())
+        })
+    }
+
+    pub fn batch_write(&self, batch: SendableStorageBatch) -> Result<(), GenericError> {
+        Ok(self.db.write(batch.0).map_err(StorageError::from)?)
+    }
+
+    /// Rewrites every value of every db with `f`, in batches of MIGRATE_BATCH_SIZE.
+    /// `f` gets the raw stored value and returns None to leave it as is.
+    /// Running it again after an interruption is fine as long as `f` is idempotent.
+    /// Watchers aren't notified. Returns the number of rewritten values.
+    pub fn migrate_values<F>(&self, f: F) -> Result<u64, GenericError>
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>>,
+    {
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(true);
+        let cf = self.db.cf_handle("default").unwrap();
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), cf, ro);
+        iterator.seek(rocksdb::SeekKey::Start);
+        let mut batch = rocksdb::WriteBatch::new();
+        let mut batch_len = 0;
+        let mut migrated = 0;
+        while iterator.valid() {
+            if let Some(value) = f(iterator.value()) {
+                batch
+                    .put_cf(cf, iterator.key(), &value)
+                    .map_err(StorageError::from)?;
+                batch_len += 1;
+                if batch_len == MIGRATE_BATCH_SIZE {
+                    self.db
+                        .write(mem::replace(&mut batch, rocksdb::WriteBatch::new()))
+                        .map_err(StorageError::from)?;
+                    migrated += batch_len;
+                    batch_len = 0;
+                }
+            }
+            iterator.next();
+        }
+        iterator.status().map_err(StorageError::from)?;
+        self.db.write(batch).map_err(StorageError::from)?;
+        Ok(migrated + batch_len)
+    }
+
+    /// Pins a point-in-time view of the vnode data so it can be moved to another node
+    pub fn export_vnode(&self, vnode: u16) -> Result<VNodeExport, GenericError> {
+        let storage = self.open(vnode)?;
+        let snapshot = storage.snapshot();
+        Ok(VNodeExport {
+            storage: storage,
+            snapshot: snapshot,
+        })
+    }
+}
+
+impl Drop for StorageManager {
+    fn drop(&mut self) {
+        self.check_pending_iters();
+        let sc = Arc::strong_count(&self.db);
+        let wc = Arc::weak_count(&self.db);
+        assert_eq!(wc, 0);
+        assert_eq!(sc, 1);
+    }
+}
+
+impl Storage {
+    /// Starts a GroupCommitter writing to this storage.
+    /// Prefix quotas aren't enforced for writes done through it.
+    pub fn group_committer(&self, interval: Duration) -> GroupCommitter {
+        let storage = Storage {
+            db: self.db.clone(),
+            cf: self.cf,
+            log_cf: self.log_cf,
+            num: self.num,
+            checksum: self.checksum,
+            pending_iters: self.pending_iters.clone(),
+            quotas: Default::default(),
+            watchers: self.watchers.clone(),
+            rmw_lock: self.rmw_lock.clone(),
+            txns: self.txns.clone(),
+            cube_history: self.cube_history,
+        };
+        GroupCommitter::new(storage, interval)
+    }
+
+    /// Max number of mutations recorded in the history of each value, see StorageConfig
+    pub fn cube_history(&self) -> Option<usize> {
+        self.cube_history
+    }
+
+    /// Iterates over a consistent point in time view of the storage,
+    /// the snapshot is released when the iterator is dropped.
+    pub fn iterator(&self) -> StorageIterator {
+        self.iterator_opt(Some(self.snapshot()), b"", None)
+    }
+
+    /// Whether rocksdb is currently stopping or delaying writes (ie. compaction debt)
+    pub fn is_write_stalled(&self) -> bool {
+        let property = |name| self.db.get_property_int_cf(self.cf, name).unwrap_or(0);
+        property("rocksdb.is-write-stopped") != 0
+            || property("rocksdb.actual-delayed-write-rate") != 0
+    }
+
+    /// How full the active write buffers are, from 0.0 (empty) to 1.0 (about to be
+    /// flushed), the fullest of data and log. Column families are shared by all
+    /// vnodes so this is for the whole db, not just this vnode.
+    pub fn load_factor(&self) -> f32 {
+        [self.cf, self.log_cf]
+            .iter()
+            .map(|&cf| {
+                let size = self
+                    .db
+                    .get_property_int_cf(cf, "rocksdb.cur-size-active-mem-table")
+                    .unwrap_or(0);
+                (size as f32 / WRITE_BUFFER_BYTES as f32).min(1.0)
+            }).fold(0.0, f32::max)
+    }
+
+    /// Estimates from rocksdb properties, data and log included. Column families are
+    /// shared by all vnodes so these are for the whole db, not just this vnode.
+    pub fn compaction_estimate(&self) -> CompactionEstimate {
+        let property = |name| {
+            [self.cf, self.log_cf]
+                .iter()
+                .map(|&cf| self.db.get_property_int_cf(cf, name).unwrap_or(0))
+                .sum()
+        };
+        CompactionEstimate {
+            pending_compaction_bytes: property("rocksdb.estimate-pending-compaction-bytes"),
+            num_deletions: property("rocksdb.num-deletes-active-mem-table"),
+            num_live_keys: property("rocksdb.estimate-num-keys"),
+        }
+    }
+
+    /// Uniform random sample of up to `n` keys (reservoir sampling),
+    /// the same seed gives the same sample for the same data.
+    pub fn sample_keys(&self, n: usize, seed: u64) -> Vec<Vec<u8>> {
+        let mut rng_seed = [0u8; 16];
+        (&mut rng_seed[..8]).write_u64::<BigEndian>(seed).unwrap();
+        // XorShiftRng can't be seeded with all zeros
+        (&mut rng_seed[8..])
+            .write_u64::<BigEndian>(!seed)
+            .unwrap();
+        let mut rng = XorShiftRng::from_seed(rng_seed);
+        let mut sample = Vec::with_capacity(n);
+        if n == 0 {
+            return sample;
+        }
+        let mut iterator = self.iterator();
+        for (i, (k, _)) in iterator.iter().enumerate() {
+            if i < n {
+                sample.push(k.to_vec());
+            } else {
+                let j = rng.gen_range(0, i + 1);
+                if j < n {
+                    sample[j] = k.to_vec();
+                }
+            }
+        }
+        sample
+    }
+
+    /// Splits the keys of the vnode in up to `n` contiguous [start, end) ranges of
+    /// roughly the same number of keys, to be read with `scan`. None means unbounded.
+    /// Boundaries come from a sample of the keys, so sizes are only approximate.
+    pub fn partition_keys(&self, n: usize) -> Vec<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+        let mut sample = self.sample_keys(n.saturating_mul(PARTITION_SAMPLES_PER_RANGE), 0);
+        sample.sort();
+        let mut boundaries: Vec<Vec<u8>> = (1..n)
+            .filter_map(|i| sample.get(i * sample.len() / n).cloned())
+            .collect();
+        boundaries.dedup();
+        let mut ranges = Vec::with_capacity(boundaries.len() + 1);
+        let mut start = None;
+        for boundary in boundaries {
+            ranges.push((start, Some(boundary.clone())));
+            start = Some(boundary);
+        }
+        ranges.push((start, None));
+        ranges
+    }
+
+    /// Iterates over the keys in [start, end), in key order. None means unbounded.
+    pub fn scan(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> StorageIterator {
+        let start = start.unwrap_or(b"");
+        let mut start_key = vec![0u8; 2 + start.len()];
+        build_key(&mut start_key, self.num, start);
+        let end_key = match end {
+            Some(end) => {
+                let mut end_key = vec![0u8; 2 + end.len()];
+                build_key(&mut end_key, self.num, end);
+                Some(end_key)
+            }
+            None => self.end_bound().map(|end| end.to_vec()),
+        };
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(true);
+        if let Some(ref end_key) = end_key {
+            ro.set_iterate_upper_bound(&end_key[..]);
+        }
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.cf, ro);
+        iterator.seek(rocksdb::SeekKey::Key(&start_key[..]));
+        StorageIterator {
+            it: GenericIterator {
+                db: self.db.clone(),
+                iterator: iterator,
+                first: true,
+                snapshot: None,
+                _pending: PendingIterGuard::new(&self.pending_iters),
+            },
+            corruption: None,
+        }
+    }
+
+    /// Copies all data and log entries into `target`, which may belong to
+    /// another StorageManager. Returns the number of entries copied.
+    pub fn copy_to(&self, target: &Storage) -> Result<u64, GenericError> {
+        let mut checkpoint = CopyCheckpoint::new();
+        self.copy_to_checkpoint(target, &mut checkpoint, &AtomicBool::new(false))?;
+        Ok(checkpoint.copied())
+    }
+
+    /// Resumable `copy_to`, returns early once `interrupt` is set, leaving
+    /// `checkpoint` at the last batch written. Each call copies from a fresh snapshot,
+    /// so writes racing with the copy must be caught up afterwards (ie. from the log).
+    pub fn copy_to_checkpoint(
+        &self,
+        target: &Storage,
+        checkpoint: &mut CopyCheckpoint,
+        interrupt: &AtomicBool,
+    ) -> Result<(), GenericError> {
+        let snapshot = self.snapshot();
+        if !checkpoint.data_done {
+            let mut iterator = {
+                let start = checkpoint.last_key.as_ref().map_or(&b""[..], |k| &k[..]);
+                self.iterator_opt(Some(snapshot.clone()), start, None)
+            };
+            let mut batch = target.batch_new(0);
+            let mut batch_len = 0;
+            let mut last_key = Vec::new();
+            for (k, v) in iterator.iter() {
+                // seek is inclusive
+                if checkpoint.last_key.as_ref().map_or(false, |l| k <= &l[..]) {
+                    continue;
+                }
+                batch.set(k, v);
+                batch_len += 1;
+                last_key.clear();
+                last_key.extend_from_slice(k);
+                if batch_len == COPY_BATCH_SIZE {
+                    target.batch_write(mem::replace(&mut batch, target.batch_new(0)))?;
+                    checkpoint.last_key = Some(last_key.clone());
+                    checkpoint.copied += batch_len;
+                    batch_len = 0;
+                    if interrupt.load(Ordering::Relaxed) {
+                        return iterator.status();
+                    }
+                }
+            }
+            iterator.status()?;
+            target.batch_write(batch)?;
+            if batch_len != 0 {
+                checkpoint.last_key = Some(last_key);
+                checkpoint.copied += batch_len;
+            }
+            checkpoint.data_done = true;
+        }
+        if !checkpoint.log_done {
+            let mut iterator =
+                self.log_iterator_opt(Some(snapshot), checkpoint.last_log_key.unwrap_or((0, 0)));
+            let mut batch = target.batch_new(0);
+            let mut batch_len = 0;
+            let mut last_log_key = None;
+            for (k, v) in iterator.iter() {
+                if checkpoint.last_log_key.map_or(false, |l| k <= l) {
+                    continue;
+                }
+                batch.log_set(k, v);
+                batch_len += 1;
+                last_log_key = Some(k);
+                if batch_len == COPY_BATCH_SIZE {
+                    target.batch_write(mem::replace(&mut batch, target.batch_new(0)))?;
+                    checkpoint.last_log_key = last_log_key;
+                    checkpoint.copied += batch_len;
+                    batch_len = 0;
+                    if interrupt.load(Ordering::Relaxed) {
+                        return iterator.status();
+                    }
+                }
+            }
+            iterator.status()?;
+            target.batch_write(batch)?;
+            if batch_len != 0 {
+                checkpoint.last_log_key = last_log_key;
+                checkpoint.copied += batch_len;
+            }
+            checkpoint.log_done = true;
+        }
+        Ok(())
+    }
+
+    /// Keys with different values in `self` and `other`, including
+    /// keys only present in one of them. Log entries aren't compared.
+    pub fn diff_with(&self, other: &Storage) -> Result<Vec<Vec<u8>>, GenericError> {
+        let mut diff = Vec::new();
+        let mut a_iterator = self.iterator();
+        let mut b_iterator = other.iterator();
+        {
+            let mut a_iter = a_iterator.iter();
+            let mut b_iter = b_iterator.iter();
+            let (mut a, mut b) = (a_iter.next(), b_iter.next());
+            loop {
+                match (a, b) {
+                    (None, None) => break,
+                    (Some((ak, av)), Some((bk, bv))) if ak == bk => {
+                        if av != bv {
+                            diff.push(ak.to_vec());
+                        }
+                        a = a_iter.next();
+                        b = b_iter.next();
+                    }
+                    (Some((ak, _)), Some((bk, _))) if ak < bk => {
+                        diff.push(ak.to_vec());
+                        a = a_iter.next();
+                    }
+                    (Some((ak, _)), None) => {
+                        diff.push(ak.to_vec());
+                        a = a_iter.next();
+                    }
+                    (_, Some((bk, _))) => {
+                        diff.push(bk.to_vec());
+                        b = b_iter.next();
+                    }
+                }
+            }
+        }
+        a_iterator.status()?;
+        b_iterator.status()?;
+        Ok(diff)
+    }
+
+    /// Iterator over a consistent point in time view of the storage,
+    /// writes done after this call aren't visible to the iterator.
+    /// The returned snapshot is also kept alive by the iterator itself.
+    pub fn iter_with_snapshot(&self) -> (StorageSnapshot, StorageIterator) {
+        let snapshot = self.snapshot();
+        let iterator = self.iterator_at(&snapshot);
+        (snapshot, iterator)
+    }
+
+    pub fn snapshot(&self) -> StorageSnapshot {
+        StorageSnapshot(Arc::new(rocksdb::Snapshot::new(self.db.clone())))
+    }
+
+    pub fn iterator_at(&self, snapshot: &StorageSnapshot) -> StorageIterator {
+        self.iterator_opt(Some(snapshot.clone()), b"", None)
+    }
+
+    /// Iterator reading ahead `bytes` from disk, for large sequential scans
+    pub fn iterator_with_readahead(&self, bytes: usize) -> StorageIterator {
+        self.iterator_opt(None, b"", Some(bytes))
+    }
+
+    fn iterator_opt(
+        &self,
+        snapshot: Option<StorageSnapshot>,
+        start: &[u8],
+        readahead: Option<usize>,
+    ) -> StorageIterator {
+        let mut key_prefix = vec![0u8; 2 + start.len()];
+        build_key(&mut key_prefix, self.num, start);
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(false);
+        ro.set_prefix_same_as_start(true);
+        if let Some(readahead) = readahead {
+            ro.set_readahead_size(readahead);
+        }
+        if let Some(ref snapshot) = snapshot {
+            unsafe {
+                ro.set_snapshot(snapshot.0.get_snapshot());
+            }
+        }
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.cf, ro);
+        iterator.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
+        StorageIterator {
+            it: GenericIterator {
+                db: self.db.clone(),
+                iterator: iterator,
+                first: true,
+                snapshot: snapshot,
+                _pending: PendingIterGuard::new(&self.pending_iters),
+            },
+            corruption: None,
+        }
+    }
+
+    /// Iterates over the keys >= `start`, in key order
+    pub fn iterator_from(&self, start: &[u8]) -> StorageIterator {
+        self.iterator_opt(None, start, None)
+    }
+
+    /// Iterates over the keys starting with `prefix`, in key order
+    pub fn iterator_prefix(&self, prefix: &[u8]) -> StorageIterator {
+        let mut start_key = vec![0u8; 2 + prefix.len()];
+        build_key(&mut start_key, self.num, prefix);
+        // smallest key greater than all keys starting with prefix
+        let mut end_key = start_key.clone();
+        while end_key.len() > 2 && end_key.last() == Some(&0xFF) {
+            end_key.pop();
+        }
+        let end_key = if end_key.len() > 2 {
+            *end_key.last_mut().unwrap() += 1;
+            Some(end_key)
+        } else {
+            self.end_bound().map(|end| end.to_vec())
+        };
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(true);
+        if let Some(ref end_key) = end_key {
+            ro.set_iterate_upper_bound(&end_key[..]);
+        }
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.cf, ro);
+        iterator.seek(rocksdb::SeekKey::Key(&start_key[..]));
+        StorageIterator {
+            it: GenericIterator {
+                db: self.db.clone(),
+                iterator: iterator,
+                first: true,
+                snapshot: None,
+                _pending: PendingIterGuard::new(&self.pending_iters),
+            },
+            corruption: None,
+        }
+    }
+
+    pub fn log_iterator_all(&self) -> LogStorageIterator {
+        self.log_iterator_opt(None, (0, 0))
+    }
+
+    fn log_iterator_opt(
+        &self,
+        snapshot: Option<StorageSnapshot>,
+        start: (u64, u64),
+    ) -> LogStorageIterator {
+        let mut key_prefix = [0u8; 2 + 8 + 8];
+        build_log_key(&mut key_prefix, self.num, start);
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(true);
+        if let Some(end_prefix) = self.end_bound() {
+            ro.set_iterate_upper_bound(&end_prefix[..]);
+        }
+        if let Some(ref snapshot) = snapshot {
+            unsafe {
+                ro.set_snapshot(snapshot.0.get_snapshot());
+            }
+        }
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.log_cf, ro);
+        iterator.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
+        LogStorageIterator(GenericIterator {
+            db: self.db.clone(),
+            iterator: iterator,
+            first: true,
+            snapshot: snapshot,
+            _pending: PendingIterGuard::new(&self.pending_iters),
+        })
+    }
+
+    /// Distinct log prefixes of this vnode, in order.
+    /// Seeks past each prefix instead of walking all of its entries.
+    pub fn iter_log_prefixes(&self) -> impl Iterator<Item = u64> {
+        let LogStorageIterator(it) = self.log_iterator_opt(None, (0, 0));
+        LogPrefixIter(it)
+    }
+
+    pub fn log_iterator(&self, prefix: u64, start: u64) -> LogStorageIterator {
+        let mut end_prefix = [0u8; 2 + 8];
+        build_log_prefix(&mut end_prefix, self.num, prefix + 1);
+        let mut start_key = [0u8; 2 + 8 + 8];
+        build_log_key(&mut start_key, self.num, (prefix, start));
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_iterate_upper_bound(&end_prefix[..]);
+        let mut iterator = rocksdb::DBIterator::new_cf(self.db.clone(), self.log_cf, ro);
+        iterator.seek(rocksdb::SeekKey::Key(&start_key[..]));
+        LogStorageIterator(GenericIterator {
+            db: self.db.clone(),
+            iterator: iterator,
+            first: true,
+            snapshot: None,
+            _pending: PendingIterGuard::new(&self.pending_iters),
+        })
+    }
+
+    pub fn get<R, F: FnOnce(&[u8]) -> R>(
+        &self,
+        key: &[u8],
+        callback: F,
+    ) -> Result<Option<R>, GenericError> {
+        let mut buffer = [0u8; 512];
+        let buffer = build_key(&mut buffer, self.num, key);
+        let r = self
+            .db
+            .get_cf(self.cf, buffer)
+            .map_err(StorageError::from)?;
+        trace!(
+            "get {:?} ({:?} bytes)",
+            str::from_utf8(key),
+            r.as_ref().map(|x| x.len())
+        );
+        if let Some(r) = r {
+            match decode_value(&*r) {
+                Ok(value) => Ok(Some(callback(value))),
+                Err(e) => {
+                    error!("Corrupted value for key {:?}: {}", str::from_utf8(key), e);
+                    Err(e.into())
+                }
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn log_get<R, F: FnOnce(&[u8]) -> R>(
+        &self,
+        log_key: (u64, u64),
+        callback: F,
+    ) -> Result<Option<R>, GenericError> {
+        let mut buffer = [0u8; 2 + 8 + 8];
+        let buffer = build_log_key(&mut buffer, self.num, log_key);
+        let r = self
+            .db
+            .get_cf(self.log_cf, buffer)
+            .map_err(StorageError::from)?;
+        trace!(
+            "log_get {:?} ({:?} bytes)",
+            log_key,
+            r.as_ref().map(|x| x.len())
+        );
+        Ok(r.map(|r| callback(&*r)))
+    }
+
+    /// Writes the log entry only if there's none for `log_key` yet, returns whether it did.
+    /// Calls of all Storages of the manager are serialized, so only one of the racing
+    /// callers gets true. The write is a merge keeping the first value, so plain log
+    /// writes racing with it aren't overwritten either.
+    pub fn log_set_cas(&self, log_key: (u64, u64), value: &[u8]) -> Result<bool, GenericError> {
+        let mut buffer = [0u8; 2 + 8 + 8];
+        let buffer = build_log_key(&mut buffer, self.num, log_key);
+        let _lock = self.rmw_lock.lock().unwrap();
+        if self
+            .db
+            .get_cf(self.log_cf, buffer)
+            .map_err(StorageError::from)?
+            .is_some()
+        {
+            trace!("log_set_cas {:?} already set", log_key);
+            return Ok(false);
+        }
+        self.db
+            .merge_cf(self.log_cf, buffer, value)
+            .map_err(StorageError::from)?;
+        let written = self
+            .db
+            .get_cf(self.log_cf, buffer)
+            .map_err(StorageError::from)?;
+        let success = written.map_or(false, |v| &*v == value);
+        trace!("log_set_cas {:?} ({} bytes) {}", log_key, value.len(), success);
+        Ok(success)
+    }
+
+    /// Like log_get but for entries written with log_set_with_metadata
+    pub fn log_get_with_metadata<R, F: FnOnce(LogMetadata, &[u8]) -> R>(
+        &self,
+        log_key: (u64, u64),
+        callback: F,
+    ) -> Result<Option<R>, GenericError> {
+        let mut buffer = [0u8; 2 + 8 + 8];
+        let buffer = build_log_key(&mut buffer, self.num, log_key);
+        let r = self
+            .db
+            .get_cf(self.log_cf, buffer)
+            .map_err(StorageError::from)?;
+        trace!(
+            "log_get_with_metadata {:?} ({:?} bytes)",
+            log_key,
+            r.as_ref().map(|x| x.len())
+        );
+        if let Some(r) = r {
+            let (metadata, value) = LogMetadata::read(&*r)?;
+            Ok(Some(callback(metadata, value)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn pin_key(&self, key: &[u8]) -> Result<Option<PinnedValue>, GenericError> {
+        let mut buffer = [0u8; 512];
+        let buffer = build_key(&mut buffer, self.num, key);
+        match self
+            .db
+            .get_cf(self.cf, buffer)
+            .map_err(StorageError::from)?
+        {
+            Some(value) => {
+                let offset = value.len() - decode_value(&*value)?.len();
+                Ok(Some(PinnedValue {
+                    value: value,
+                    offset: offset,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_vec(&self, key: &[u8]) -> Result<Option<Vec<u8>>, GenericError> {
+        self.get(key, |v| v.to_owned())
+    }
+
+    /// Length of the value without copying it, None if the key doesn't exist
+    pub fn value_len(&self, key: &[u8]) -> Result<Option<usize>, GenericError> {
+        self.get(key, |v| v.len())
+    }
+
+    pub fn log_get_vec(&self, log_key: (u64, u64)) -> Result<Option<Vec<u8>>, GenericError> {
+        self.log_get(log_key, |v| v.to_owned())
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<(), GenericError> {
+        let mut b = self.batch_new(0);
+        b.set(key, value);
+        self.batch_write(b)
+    }
+
+    pub fn del(&self, key: &[u8]) -> Result<(), GenericError> {
+        let mut b = self.batch_new(0);
+        b.del(key);
+        self.batch_write(b)
+    }
+
+    /// Adds `delta` to the i64(be) counter stored at `key` and returns the new value,
+    /// missing counters start at 0. Increments of all Storages of the manager are
+    /// serialized, so the returned value only includes increments done before this one.
+    /// Plain writes to the key aren't, and race with it.
+    pub fn atomic_increment(&self, key: &[u8], delta: i64) -> Result<i64, GenericError> {
+        let mut buffer = [0u8; 512];
+        let buffer = build_key(&mut buffer, self.num, key);
+        let mut operand = [0u8; 8];
+        (&mut operand[..]).write_i64::<BigEndian>(delta).unwrap();
+        let _lock = self.rmw_lock.lock().unwrap();
+        self.db
+            .merge_cf(self.cf, buffer, &operand)
+            .map_err(StorageError::from)?;
+        let value = self.get(key, |mut v| v.read_i64::<BigEndian>())?;
+        Ok(value.unwrap_or(Ok(0))?)
+    }
+
+    /// Starts an optimistic transaction, requires `StorageConfig::transactions`.
+    /// Only transactions are checked for conflicts, writes done outside of them aren't.
+    pub fn begin_transaction(&self) -> Result<StorageTransaction, GenericError> {
+        let txns = self
+            .txns
+            .clone()
+            .ok_or("Transactions aren't enabled for this storage")?;
+        let start_seq = {
+            let mut state = txns.lock().unwrap();
+            state.active += 1;
+            state.seq
+        };
+        Ok(StorageTransaction {
+            storage: self,
+            txns: txns,
+            snapshot: self.snapshot(),
+            start_seq: start_seq,
+            reads: Default::default(),
+            writes: Default::default(),
+        })
+    }
+
+    pub fn batch_new(&self, reserve: usize) -> StorageBatch {
+        let quota_ops = if self.quotas.read().unwrap().is_empty() {
+            None
+        } else {
+            Some(Vec::new())
+        };
+        let watch_ops = if self.watchers.read().unwrap().iter().any(|w| w.num == self.num) {
+            Some(Vec::new())
+        } else {
+            None
+        };
+        StorageBatch {
+            storage: self,
+            wb: rocksdb::WriteBatch::with_capacity(reserve),
+            quota_ops: quota_ops,
+            watch_ops: watch_ops,
+        }
+    }
+
+    pub fn batch_write(&self, batch: StorageBatch) -> Result<(), GenericError> {
+        if let Some(ref quota_ops) = batch.quota_ops {
+            self.check_quotas(quota_ops)?;
+        }
+        let StorageBatch { wb, watch_ops, .. } = batch;
+        self.db.write(wb).map_err(StorageError::from)?;
+        if let Some(events) = watch_ops {
+            self.notify_watchers(events);
+        }
+        Ok(())
+    }
+
+    /// Watches writes to keys starting with `prefix`.
+    /// Rocksdb event listeners only report flushes and compactions, so only the writes
+    /// done through Storage/StorageBatch of this vnode are observed, after they succeed.
+    pub fn watch_prefix(&self, prefix: &[u8]) -> PrefixWatcher {
+        let (sender, receiver) = fmpsc::unbounded();
+        self.watchers.write().unwrap().push(PrefixWatch {
+            num: self.num,
+            prefix: prefix.to_vec(),
+            sender: sender,
+        });
+        PrefixWatcher(receiver)
+    }
+
+    fn notify_watchers(&self, events: Vec<WatchEvent>) {
+        // watchers that were dropped are cleaned up here
+        self.watchers.write().unwrap().retain(|w| {
+            w.num != self.num || events
+                .iter()
+                .filter(|e| e.key.starts_with(&w.prefix))
+                .all(|e| w.sender.unbounded_send(e.clone()).is_ok())
+        });
+    }
+
+    /// Soft limit on the bytes (keys + values) stored under `prefix`.
+    /// Writes that would grow the prefix past `max_bytes` are rejected with
+    /// QuotaExceeded. Sizes are rocksdb estimates so the limit isn't exact.
+    pub fn set_prefix_quota(&self, prefix: &[u8], max_bytes: u64) {
+        let mut quotas = self.quotas.write().unwrap();
+        quotas.retain(|&(ref p, _)| p != prefix);
+        quotas.push((prefix.to_vec(), max_bytes));
+    }
+
+    fn prefix_size(&self, prefix: &[u8]) -> u64 {
+        let mut start = [0u8; 512];
+        let start = build_key(&mut start, self.num, prefix);
+        let mut end = start.to_vec();
+        // smallest key after all keys with prefix
+        while let Some(last) = end.pop() {
+            if last != 0xFF {
+                end.push(last + 1);
+                break;
+            }
+        }
+        self.approximate_size(self.cf, start, &end)
+    }
+
+    // sst + memtable estimate for the full keys in [start, end)
+    fn approximate_size(&self, cf: &rocksdb::CFHandle, start: &[u8], end: &[u8]) -> u64 {
+        let range = rocksdb::Range::new(start, end);
+        let sst_size = self.db.get_approximate_sizes_cf(cf, &[range])[0];
+        let (_, mem_size) = self.db.get_approximate_memtable_stats_cf(cf, &range);
+        sst_size + mem_size
+    }
+
+    /// Approximate bytes used by the keys in [start, end), an empty `end`
+    /// extends the range to the end of the vnode.
+    pub fn size_of_key_range(&self, start: &[u8], end: &[u8]) -> u64 {
+        let mut start_key = vec![0u8; 2 + start.len()];
+        build_key(&mut start_key, self.num, start);
+        let end_key = if end.is_empty() {
+            self.finite_end_bound(self.cf)
+        } else {
+            let mut end_key = vec![0u8; 2 + end.len()];
+            build_key(&mut end_key, self.num, end);
+            end_key
+        };
+        self.approximate_size(self.cf, &start_key, &end_key)
+    }
+
+    /// Approximate bytes used by the vnode, data and log included
+    pub fn size_bytes(&self) -> u64 {
+        let mut start = [0u8; 2];
+        build_key(&mut start, self.num, b"");
+        [self.cf, self.log_cf]
+            .iter()
+            .map(|&cf| self.approximate_size(cf, &start, &self.finite_end_bound(cf)))
+            .sum()
+    }
+
+    /// Approximate bytes on disk (sst files) used by the vnode, data and log included.
+    /// Unflushed writes aren't counted. Files spanning several vnodes are split by
+    /// rocksdb using the positions of the range in their index, so the result for
+    /// vnodes sharing files is only roughly proportional to their data.
+    pub fn disk_usage(&self) -> u64 {
+        let mut start = [0u8; 2];
+        build_key(&mut start, self.num, b"");
+        [self.cf, self.log_cf]
+            .iter()
+            .map(|&cf| {
+                let end = self.finite_end_bound(cf);
+                self.db
+                    .get_approximate_sizes_cf(cf, &[rocksdb::Range::new(&start, &end)])[0]
+            }).sum()
+    }
+
+    /// Compacts the data of the keys in [start, end), an empty `end`
+    /// extends the range to the end of the vnode.
+    pub fn compact_range(&self, start: &[u8], end: &[u8]) {
+        let mut start_key = vec![0u8; 2 + start.len()];
+        build_key(&mut start_key, self.num, start);
+        let end_key = if end.is_empty() {
+            self.end_bound().map(|end| end.to_vec())
+        } else {
+            let mut end_key = vec![0u8; 2 + end.len()];
+            build_key(&mut end_key, self.num, end);
+            Some(end_key)
+        };
+        self.db.compact_range_cf(
+            self.cf,
+            Some(&start_key[..]),
+            end_key.as_ref().map(|k| &k[..]),
+        );
+    }
+
+    fn check_quotas(&self, quota_ops: &[(Vec<u8>, Option<usize>)]) -> Result<(), GenericError> {
+        let quotas = self.quotas.read().unwrap();
+        for &(ref prefix, max_bytes) in quotas.iter() {
+            let mut delta = 0i64;
+            for &(ref key, new_size) in quota_ops
+                .iter()
+                .filter(|&&(ref k, _)| k.starts_with(prefix))
+            {
+                // overwritten and deleted values don't count anymore
+                if let Some(old_size) = self.get(key, |v| key.len() + v.len())? {
+                    delta -= old_size as i64;
+                }
+                delta += new_size.unwrap_or(0) as i64;
+            }
+            // writes that don't grow the prefix are always allowed
+            if delta <= 0 {
+                continue;
+            }
+            let estimated_bytes = self.prefix_size(prefix) + delta as u64;
+            if estimated_bytes > max_bytes {
+                return Err(Box::new(QuotaExceeded {
+                    prefix: prefix.clone(),
+                    max_bytes: max_bytes,
+                    estimated_bytes: estimated_bytes,
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    // Smallest key after all the keys of the vnode. There's none for the last vnode,
+    // its range is open ended like in `list_dbnums`.
+    fn end_bound(&self) -> Option<[u8; 2]> {
+        self.num.checked_add(1).map(|next_num| {
+            let mut end = [0u8; 2];
+            build_key(&mut end, next_num, b"");
+            end
+        })
+    }
+
+    // Like `end_bound` for the calls that need a finite range,
+    // the last vnode ends right after the last key of `cf`.
+    fn finite_end_bound(&self, cf: &rocksdb::CFHandle) -> Vec<u8> {
+        if let Some(end) = self.end_bound() {
+            return end.to_vec();
+        }
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(true);
+        let mut iter = self.db.iter_cf_opt(cf, ro);
+        iter.seek(rocksdb::SeekKey::End);
+        let mut end = if iter.valid() {
+            iter.key().to_vec()
+        } else {
+            vec![0xFF, 0xFF]
+        };
+        end.push(0);
+        end
+    }
+
+    fn clear_bounds(&self) -> ([u8; 2], Option<[u8; 2]>) {
+        let mut from = [0u8; 2];
+        (&mut from[..]).write_u16::<BigEndian>(self.num).unwrap();
+        (from, self.end_bound())
+    }
+
+    // log cf may have a prefix extractor incompatible with the vnode prefix
+    fn clear_iter(
+        &self,
+        cf: &rocksdb::CFHandle,
+        total_order: bool,
+        from: &[u8],
+        to: Option<&[u8]>,
+    ) -> rocksdb::DBIterator<&rocksdb::DB> {
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.set_total_order_seek(total_order);
+        ro.set_prefix_same_as_start(!total_order);
+        if let Some(to) = to {
+            ro.set_iterate_upper_bound(to);
+        }
+        let mut iter = self.db.iter_cf_opt(cf, ro);
+        iter.seek(rocksdb::SeekKey::Key(from));
+        iter
+    }
+
+    pub fn clear(&self) {
+        trace!("clear");
+        let (from, to) = self.clear_bounds();
+        for &(cf, total_order) in &[(self.cf, false), (self.log_cf, true)] {
+            self.db
+                .delete_files_in_range_cf(cf, &from[..], &self.finite_end_bound(cf), false)
+                .unwrap();
+            let mut iter = self.clear_iter(cf, total_order, &from[..], to.as_ref().map(|t| &t[..]));
+            while iter.valid() {
+                self.db.delete_cf(cf, iter.key()).unwrap();
+                iter.next();
+            }
+        }
+    }
+
+    /// Like `clear` but returns the number of keys (data and log) removed.
+    /// With `dry_run` nothing is deleted, the keys are only counted.
+    /// The counting iterators are raw and dropped before returning,
+    /// so they never show up in `check_pending_iters`.
+    pub fn clear_opt(&self, dry_run: bool) -> u64 {
+        trace!("clear dry_run:{}", dry_run);
+        let (from, to) = self.clear_bounds();
+        let mut count = 0;
+        for &(cf, total_order) in &[(self.cf, false), (self.log_cf, true)] {
+            let mut iter = self.clear_iter(cf, total_order, &from[..], to.as_ref().map(|t| &t[..]));
+            while iter.valid() {
+                count += 1;
+                iter.next();
+            }
+        }
+        if !dry_run {
+            self.clear();
+        }
+        count
+    }
+
+    pub fn sync(&self) -> Result<(), GenericError> {
+        debug!("sync");
+        Ok(self.db.sync_wal().map_err(StorageError::from)?)
+    }
+
+    /// Best-effort hint to bring the whole vnode dataset into the block cache.
+    /// Rocksdb can't pin or evict arbitrary ranges so this is just a full scan
+    /// with fill_cache, hot vnodes will stay in cache as long as LRU allows.
+    pub fn pin_prefix(&self) -> Result<(), GenericError> {
+        trace!("pin_prefix");
+        let mut key_prefix = [0u8; 2];
+        build_key(&mut key_prefix, self.num, b"");
+        let mut ro = rocksdb::ReadOptions::new();
+        ro.fill_cache(true);
+        ro.set_total_order_seek(false);
+        ro.set_prefix_same_as_start(true);
+        let mut iter = self.db.iter_cf_opt(self.cf, ro);
+        iter.seek(rocksdb::SeekKey::Key(&key_prefix[..]));
+        while iter.valid() {
+            iter.next();
+        }
+        Ok(iter.status().map_err(StorageError::from)?)
+    }
+}
+
+impl<'a> StorageBatch<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.wb.is_empty()
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) {
+        trace!("set {:?} ({} bytes)", str::from_utf8(key), value.len());
+        if let Some(ref mut quota_ops) = self.quota_ops {
+            quota_ops.push((key.to_vec(), Some(key.len() + value.len())));
+        }
+        if let Some(ref mut watch_ops) = self.watch_ops {
+            watch_ops.push(WatchEvent {
+                key: key.to_vec(),
+                value: Some(value.to_vec()),
+            });
+        }
+        let mut buffer = [0u8; 512];
+        let buffer = build_key(&mut buffer, self.storage.num, key);
+        let value = encode_value(self.storage.checksum, value);
+        self.wb.put_cf(self.storage.cf, buffer, &value).unwrap();
+    }
+
+    pub fn set_many<K, V, I>(&mut self, pairs: I)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        for (key, value) in pairs {
+            self.set(key.as_ref(), value.as_ref());
+        }
+    }
+
+    pub fn log_set(&mut self, key: (u64, u64), value: &[u8]) {
+        trace!("log_set {:?} ({} bytes)", key, value.len());
+        let mut buffer = [0u8; 2 + 8 + 8];
+        let buffer = build_log_key(&mut buffer, self.storage.num, key);
+        self.wb.put_cf(self.storage.log_cf, buffer, value).unwrap();
+    }
+
+    /// Like log_set but the key carries an extra type byte, see build_log_key_typed
+    pub fn log_set_typed(&mut self, key: (u64, u64), log_type: u8, value: &[u8]) {
+        trace!("log_set {:?} type {} ({} bytes)", key, log_type, value.len());
+        let mut buffer = [0u8; LOG_KEY_MAX_LEN];
+        let buffer = build_log_key_typed(&mut buffer, self.storage.num, key, Some(log_type));
+        self.wb.put_cf(self.storage.log_cf, buffer, value).unwrap();
+    }
+
+    /// Like calling log_set for each entry, reusing the key buffer
+    pub fn log_set_many<V, I>(&mut self, entries: I)
+    where
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = ((u64, u64), V)>,
+    {
+        let mut buffer = [0u8; 2 + 8 + 8];
+        for (key, value) in entries {
+            let value = value.as_ref();
+            trace!("log_set {:?} ({} bytes)", key, value.len());
+            let buffer = build_log_key(&mut buffer, self.storage.num, key);
+            self.wb.put_cf(self.storage.log_cf, buffer, value).unwrap();
+        }
+    }
+
+    pub fn log_set_with_metadata(&mut self, key: (u64, u64), value: &[u8], metadata: &LogMetadata) {
+        let mut buffer = Vec::with_capacity(LOG_METADATA_LEN + value.len());
+        metadata.write(&mut buffer);
+        buffer.extend_from_slice(value);
+        self.log_set(key, &buffer);
+    }
+
+    pub fn del(&mut self, key: &[u8]) {
+        trace!("del {:?}", str::from_utf8(key));
+        if let Some(ref mut quota_ops) = self.quota_ops {
+            quota_ops.push((key.to_vec(), None));
+        }
+        if let Some(ref mut watch_ops) = self.watch_ops {
+            watch_ops.push(WatchEvent {
+                key: key.to_vec(),
+                value: None,
+            });
+        }
+        let mut buffer = [0u8; 512];
+        let buffer = build_key(&mut buffer, self.storage.num, key);
+        self.wb.delete_cf(self.storage.cf, buffer).unwrap()
+    }
+}
+
+impl<'a> StorageTransaction<'a> {
+    /// Reads `key` and makes commit fail if another transaction writes it in the meantime
+    pub fn get_for_update(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, GenericError> {
+        self.reads.insert(key.to_vec());
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let mut buffer = [0u8; 512];
+        let buffer = build_key(&mut buffer, self.storage.num, key);
+        match (self.snapshot.0).get_cf(self.storage.cf, buffer)? {
+            Some(r) => Ok(Some(decode_value(&*r)?.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn del(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    /// Fails with TransactionConflict if the transaction must be retried
+    pub fn commit(self) -> Result<(), GenericError> {
+        let num = self.storage.num;
+        // held until the batch is written so commits are serialized
+        let mut state = self.txns.lock().unwrap();
+        for key in self.reads.iter().chain(self.writes.keys()) {
+            let last_write = state.last_writes.get(&(num, key.clone())).cloned();
+            if last_write.map_or(false, |seq| seq > self.start_seq) {
+                return Err(Box::new(TransactionConflict { key: key.clone() }));
+            }
+        }
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        let mut batch = self.storage.batch_new(self.writes.len());
+        for (key, value) in &self.writes {
+            match *value {
+                Some(ref value) => batch.set(key, value),
+                None => batch.del(key),
+            }
+        }
+        self.storage.batch_write(batch)?;
+        state.seq += 1;
+        let seq = state.seq;
+        for key in self.writes.keys() {
+            state.last_writes.insert((num, key.clone()), seq);
+        }
+        Ok(())
+    }
+
+    pub fn rollback(self) {}
+}
+
+impl<'a> Drop for StorageTransaction<'a> {
+    fn drop(&mut self) {
+        let mut state = self.txns.lock().unwrap();
+        state.active -= 1;
+        // no transaction can conflict with past commits anymore
+        if state.active == 0 {
+            state.last_writes.clear();
+        }
+    }
+}
+
+impl GenericIterator {
+    pub fn iter<'a>(&'a mut self) -> GenericIteratorIter<'a> {
+        GenericIteratorIter { it: self }
+    }
+
+    fn status(&self) -> Result<(), GenericError> {
+        Ok(self.iterator.status().map_err(StorageError::from)?)
+    }
+}
+
+pub struct GenericIteratorIter<'a> {
+    it: &'a mut GenericIterator,
+}
+
+impl<'a> Iterator for GenericIteratorIter<'a> {
+    type Item = (u16, &'a [u8], &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.it.first {
+            self.it.first = false;
+        } else {
+            // this iterator isn't fused so we need to check for valid here too
+            if !self.it.iterator.valid() {
+                return None;
+            }
+            self.it.iterator.next();
+        }
+        if self.it.iterator.valid() {
+            unsafe {
+                let key = self.it.iterator.key();
+                let value = self.it.iterator.value();
+                // FIXME: bogus lifetime as slices are only valid until the next call to next()
+                Some((
+                    (&key[..2]).read_u16::<BigEndian>().unwrap(),
+                    mem::transmute(&key[2..]),
+                    mem::transmute(value),
+                ))
+            }
+        } else {
+            None
+        }
+    }
+}
+
+pub struct StorageIteratorIter<'a> {
+    it: GenericIteratorIter<'a>,
+    corruption: &'a mut Option<StorageError>,
+}
+
+impl StorageIterator {
+    pub fn iter<'a>(&'a mut self) -> StorageIteratorIter<'a> {
+        StorageIteratorIter {
+            it: self.it.iter(),
+            corruption: &mut self.corruption,
+        }
+    }
+
+    /// Iteration stops on errors (including corrupted values) as if it was exhausted,
+    /// check this after draining the iterator to tell both apart.
+    pub fn status(&self) -> Result<(), GenericError> {
+        if let Some(ref e) = self.corruption {
+            return Err(e.clone().into());
+        }
+        self.it.status()
+    }
+}
+
+impl<'a> Iterator for StorageIteratorIter<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.corruption.is_some() {
+            return None;
+        }
+        let (_, k, v) = self.it.next()?;
+        match decode_value(v) {
+            Ok(v) => Some((k, v)),
+            Err(e) => {
+                error!("Corrupted value for key {:?}: {}", str::from_utf8(k), e);
+                *self.corruption = Some(e);
+                None
+            }
+        }
+    }
+}
+
+/// Iterators kept open on behalf of clients between requests (scan cursors).
+/// Cursors not advanced for `idle_timeout` are dropped by `reap`, otherwise a client
+/// that goes away leaks the iterator, pinning sst files and tripping check_pending_iters.
+pub struct CursorTable {
+    idle_timeout: Duration,
+    next_id: u64,
+    cursors: HashMap<u64, (StorageIterator, Instant)>,
+}
+
+impl CursorTable {
+    pub fn new(idle_timeout: Duration) -> Self {
+        CursorTable {
+            idle_timeout: idle_timeout,
+            next_id: 0,
+            cursors: Default::default(),
+        }
+    }
+
+    pub fn open(&mut self, iterator: StorageIterator) -> u64 {
+        self.next_id += 1;
+        self.cursors.insert(self.next_id, (iterator, Instant::now()));
+        self.next_id
+    }
+
+    /// Next entry of the cursor, restarting its idle timer.
+    /// Fails if the cursor was closed or reaped.
+    pub fn next(&mut self, id: u64) -> Result<Option<(Vec<u8>, Vec<u8>)>, GenericError> {
+        let &mut (ref mut iterator, ref mut last_used) = self
+            .cursors
+            .get_mut(&id)
+            .ok_or("Unknown cursor, it may have been idle for too long")?;
+        *last_used = Instant::now();
+        let next = iterator.iter().next().map(|(k, v)| (k.to_vec(), v.to_vec()));
+        if next.is_none() {
+            iterator.status()?;
+        }
+        Ok(next)
+    }
+
+    pub fn close(&mut self, id: u64) -> bool {
+        self.cursors.remove(&id).is_some()
+    }
+
+    /// Drops the cursors idle for longer than the timeout, returns how many.
+    pub fn reap(&mut self) -> usize {
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout;
+        let before = self.cursors.len();
+        self.cursors
+            .retain(|_, &mut (_, last_used)| now.duration_since(last_used) < idle_timeout);
+        let reaped = before - self.cursors.len();
+        if reaped != 0 {
+            debug!("Reaped {} idle cursors", reaped);
+        }
+        reaped
+    }
+
+    pub fn len(&self) -> usize {
+        self.cursors.len()
+    }
+}
+
+pub struct LogStorageIteratorIter<'a>(GenericIteratorIter<'a>);
+
+impl<'a> Iterator for LogStorageIteratorIter<'a> {
+    type Item = ((u64, u64), &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((_, key, value)) = self.0.next() {
+            if let (log_key, None) = parse_log_key(key) {
+                return Some((log_key, value));
+            }
+        }
+        None
+    }
+}
+
+pub struct TypedLogStorageIteratorIter<'a>(GenericIteratorIter<'a>);
+
+impl<'a> Iterator for TypedLogStorageIteratorIter<'a> {
+    type Item = ((u64, u64), Option<u8>, &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, key, value)| {
+            let (log_key, log_type) = parse_log_key(key);
+            (log_key, log_type, value)
+        })
+    }
+}
+
+struct LogPrefixIter(GenericIterator);
+
+impl Iterator for LogPrefixIter {
+    type Item = u64;
+    fn next(&mut self) -> Option<Self::Item> {
+        let it = &mut self.0;
+        if !it.iterator.valid() {
+            return None;
+        }
+        let (num, prefix) = {
+            let key = it.iterator.key();
+            (
+                (&key[..2]).read_u16::<BigEndian>().unwrap(),
+                (&key[2..2 + 8]).read_u64::<BigEndian>().unwrap(),
+            )
+        };
+        if let Some(next_prefix) = prefix.checked_add(1) {
+            let mut buffer = [0u8; 2 + 8];
+            build_log_prefix(&mut buffer, num, next_prefix);
+            it.iterator.seek(rocksdb::SeekKey::Key(&buffer[..]));
+        } else {
+            // u64::MAX is the last possible prefix, seek past the end of the vnode
+            it.iterator.seek(rocksdb::SeekKey::End);
+            it.iterator.next();
+        }
+        Some(prefix)
+    }
+}
+
+impl LogStorageIterator {
+    /// Iterates over the untyped entries
+    pub fn iter<'a>(&'a mut self) -> LogStorageIteratorIter<'a> {
+        LogStorageIteratorIter(self.0.iter())
+    }
+
+    /// Like iter but also yields typed entries, with their type byte
+    pub fn iter_typed<'a>(&'a mut self) -> TypedLogStorageIteratorIter<'a> {
+        TypedLogStorageIteratorIter(self.0.iter())
+    }
+
+    /// See `StorageIterator::status`
+    pub fn status(&self) -> Result<(), GenericError> {
+        self.0.status()
+    }
+
+    /// Skips entries which sequence doesn't match `predicate`,
+    /// only the key is inspected for skipped entries.
+    pub fn filter<F: Fn(u64) -> bool + Send + 'static>(self, predicate: F) -> FilteredLogIterator {
+        FilteredLogIterator {
+            it: self,
+            predicate: Box::new(predicate),
+        }
+    }
+
+    /// Yields every `stride`-th entry (the 1st, the `stride + 1`th, ...),
+    /// only the key is inspected for skipped entries.
+    pub fn with_stride(self, stride: u64) -> StridedLogIterator {
+        assert!(stride > 0, "stride must be positive");
+        StridedLogIterator {
+            it: self,
+            stride: stride,
+        }
+    }
+}
+
+pub struct StridedLogIterator {
+    it: LogStorageIterator,
+    stride: u64,
+}
+
+impl StridedLogIterator {
+    /// See `StorageIterator::status`
+    pub fn status(&self) -> Result<(), GenericError> {
+        self.it.status()
+    }
+}
+
+impl Iterator for StridedLogIterator {
+    type Item = ((u64, u64), Vec<u8>);
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut iter = self.it.iter();
+        let next = iter.next().map(|(log_key, value)| (log_key, value.to_vec()));
+        if next.is_some() {
+            // position the iterator at the last skipped entry
+            for _ in 1..self.stride {
+                if iter.next().is_none() {
+                    break;
+                }
+            }
+        }
+        next
+    }
+}
+
+pub struct FilteredLogIterator {
+    it: LogStorageIterator,
+    predicate: Box<Fn(u64) -> bool + Send>,
+}
+
+pub struct FilteredLogIteratorIter<'a> {
+    it: GenericIteratorIter<'a>,
+    predicate: &'a (Fn(u64) -> bool + Send),
+}
+
+impl FilteredLogIterator {
+    pub fn iter<'a>(&'a mut self) -> FilteredLogIteratorIter<'a> {
+        FilteredLogIteratorIter {
+            it: self.it.0.iter(),
+            predicate: &*self.predicate,
+        }
+    }
+
+    /// See `StorageIterator::status`
+    pub fn status(&self) -> Result<(), GenericError> {
+        self.it.status()
+    }
+}
+
+impl<'a> Iterator for FilteredLogIteratorIter<'a> {
+    type Item = ((u64, u64), &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((_, key, value)) = self.it.next() {
+            match parse_log_key(key) {
+                (log_key, None) if (self.predicate)(log_key.1) => return Some((log_key, value)),
+                _ => (),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn test_simple() {
+        let _ = fs::remove_dir_all("t/test_simple");
+        let sm = StorageManager::new("t/test_simple").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert_eq!(storage.get_vec(b"sample").unwrap(), None);
+        storage.set(b"sample", b"sample_value").unwrap();
+        assert_eq!(
+            storage.get_vec(b"sample").unwrap().unwrap(),
+            b"sample_value"
+        );
+        storage.del(b"sample").unwrap();
+        assert_eq!(storage.get_vec(b"sample").unwrap(), None);
+    }
+
+    #[test]
+    fn test_checksum() {
+        let _ = fs::remove_dir_all("t/test_checksum");
+        let config = StorageConfig {
+            value_checksums: true,
+            ..Default::default()
+        };
+        let sm = StorageManager::with_config("t/test_checksum", &config).unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"a", b"a_value").unwrap();
+        storage.set(b"b", b"b_value").unwrap();
+        storage.set(b"c", b"c_value").unwrap();
+        assert_eq!(storage.get_vec(b"b").unwrap().unwrap(), b"b_value");
+        assert_eq!(storage.iterator().iter().count(), 3);
+        storage.atomic_increment(b"counter", 2).unwrap();
+        assert_eq!(storage.atomic_increment(b"counter", 3).unwrap(), 5);
+
+        {
+            let raw_put = |key: &[u8], value: &[u8]| {
+                let mut buffer = [0u8; 512];
+                let buffer = build_key(&mut buffer, 1, key);
+                sm.db.put_cf(storage.cf, buffer, value).unwrap();
+            };
+            // flip a bit in the payload
+            let mut corrupted = encode_checksummed(b"b_value");
+            *corrupted.last_mut().unwrap() ^= 1;
+            raw_put(b"b", &corrupted);
+            let err = storage.get_vec(b"b").unwrap_err();
+            match err.downcast_ref::<StorageError>() {
+                Some(&StorageError::Corruption(_)) => (),
+                e => panic!("{:?}", e),
+            }
+            // iteration stops at the corrupted value instead of skipping it
+            let mut iterator = storage.iterator();
+            let keys: Vec<_> = iterator.iter().map(|(k, _)| k.to_vec()).collect();
+            assert_eq!(keys, vec![b"a".to_vec()]);
+            assert!(iterator.status().is_err());
+            // values without a header, e.g. written before enabling checksums, are fine
+            raw_put(b"b", b"b_value");
+            assert_eq!(storage.get_vec(b"b").unwrap().unwrap(), b"b_value");
+            assert_eq!(storage.iterator().iter().count(), 4);
+        }
+        drop(storage);
+        drop(sm);
+
+        // checksums can be disabled later, both kinds of values stay readable
+        let sm = StorageManager::new("t/test_checksum").unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"d", b"d_value").unwrap();
+        assert_eq!(storage.get_vec(b"a").unwrap().unwrap(), b"a_value");
+        assert_eq!(storage.get_vec(b"d").unwrap().unwrap(), b"d_value");
+        assert_eq!(storage.atomic_increment(b"counter", 1).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_no_checksum() {
+        let _ = fs::remove_dir_all("t/test_no_checksum");
+        let sm = StorageManager::new("t/test_no_checksum").unwrap();
+        let storage = sm.open(1).unwrap();
+        // values that happen to look like a checksum header are returned as is
+        let value = encode_checksummed(b"sample_value");
+        storage.set(b"sample", &value).unwrap();
+        storage.set(b"magic", &CHECKSUM_MAGIC).unwrap();
+        storage.set(b"other", &[CHECKSUM_MAGIC[0]]).unwrap();
+        assert_eq!(storage.get_vec(b"sample").unwrap().unwrap(), value);
+        assert_eq!(storage.get_vec(b"magic").unwrap().unwrap(), CHECKSUM_MAGIC);
+        assert_eq!(
+            storage.get_vec(b"other").unwrap().unwrap(),
+            [CHECKSUM_MAGIC[0]]
+        );
+        assert_eq!(storage.iterator().iter().count(), 3);
+        drop(storage);
+        drop(sm);
+
+        // checksums can be enabled later
+        let config = StorageConfig {
+            value_checksums: true,
+            ..Default::default()
+        };
+        let sm = StorageManager::with_config("t/test_no_checksum", &config).unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"checksummed", b"value").unwrap();
+        assert_eq!(storage.get_vec(b"sample").unwrap().unwrap(), value);
+        assert_eq!(storage.get_vec(b"checksummed").unwrap().unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_prefix_quota() {
+        let _ = fs::remove_dir_all("t/test_prefix_quota");
+        let sm = StorageManager::new("t/test_prefix_quota").unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set_prefix_quota(b"t1:", 1024);
+        storage.set(b"t1:a", &[0u8; 500]).unwrap();
+        let err = storage.set(b"t1:b", &[0u8; 2000]).unwrap_err();
+        assert!(err.downcast_ref::<QuotaExceeded>().is_some());
+        assert!(storage.get_vec(b"t1:b").unwrap().is_none());
+
+        // fits only because the delete in the same batch frees space
+        let mut b = storage.batch_new(0);
+        b.set(b"t1:b", &[0u8; 800]);
+        assert!(storage.batch_write(b).is_err());
+        let mut b = storage.batch_new(0);
+        b.del(b"t1:a");
+        b.set(b"t1:b", &[0u8; 800]);
+        storage.batch_write(b).unwrap();
+        assert!(storage.get_vec(b"t1:a").unwrap().is_none());
+        assert_eq!(storage.get_vec(b"t1:b").unwrap().unwrap().len(), 800);
+
+        // other prefixes aren't affected
+        storage.set(b"t2:a", &[0u8; 2000]).unwrap();
+    }
+
+    #[test]
+    fn test_simple_log() {
+        let _ = fs::remove_dir_all("t/test_simple_log");
+        let sm = StorageManager::new("t/test_simple_log").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert_eq!(storage.get_vec(b"sample").unwrap(), None);
+        let mut b = storage.batch_new(0);
+        b.set(b"sample", b"sample_value");
+        b.log_set((1, 1), b"sample");
+        storage.batch_write(b).unwrap();
+        assert_eq!(
+            storage.get_vec(b"sample").unwrap().unwrap(),
+            b"sample_value"
+        );
+        assert_eq!(storage.log_get_vec((1, 1)).unwrap().unwrap(), b"sample");
+    }
+
+    #[test]
+    fn test_log_metadata() {
+        let _ = fs::remove_dir_all("t/test_log_metadata");
+        let sm = StorageManager::new("t/test_log_metadata").unwrap();
+        let storage = sm.open(1).unwrap();
+        let metadata = LogMetadata {
+            source_node: 123,
+            timestamp_ns: u64::max_value(),
+            flags: 0b101,
+        };
+        let mut b = storage.batch_new(0);
+        b.log_set_with_metadata((1, 1), b"sample", &metadata);
+        b.log_set_with_metadata((1, 2), b"", &metadata);
+        b.log_set((1, 3), b"");
+        storage.batch_write(b).unwrap();
+        assert_eq!(
+            storage
+                .log_get_with_metadata((1, 1), |m, v| (m, v.to_owned()))
+                .unwrap()
+                .unwrap(),
+            (metadata, b"sample".to_vec())
+        );
+        assert_eq!(
+            storage
+                .log_get_with_metadata((1, 2), |m, v| (m, v.to_owned()))
+                .unwrap()
+                .unwrap(),
+            (metadata, vec![])
+        );
+        assert!(storage.log_get_with_metadata((1, 3), |_, _| ()).is_err());
+        assert_eq!(
+            storage.log_get_with_metadata((1, 4), |_, _| ()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_barrier() {
+        let _ = fs::remove_dir_all("t/test_barrier");
+        {
+            let sm = StorageManager::new("t/test_barrier").unwrap();
+            let storage = sm.open(1).unwrap();
+            let mut b = storage.batch_new(0);
+            b.set(b"1", b"1");
+            b.log_set((1, 1), b"1");
+            storage.batch_write(b).unwrap();
+            sm.barrier().unwrap();
+        }
+        let sm = StorageManager::new("t/test_barrier").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert_eq!(storage.get_vec(b"1").unwrap().unwrap(), b"1");
+        assert_eq!(storage.log_get_vec((1, 1)).unwrap().unwrap(), b"1");
+    }
+
+    #[test]
+    fn test_log_column_families() {
+        let _ = fs::remove_dir_all("t/test_log_column_families");
+        let config = StorageConfig {
+            log_column_families: 2,
+            ..Default::default()
+        };
+        {
+            let sm = StorageManager::with_config("t/test_log_column_families", &config).unwrap();
+            assert_eq!(sm.log_cf_name(1), "log_1");
+            assert_eq!(sm.log_cf_name(2), "log");
+            for &num in &[1u16, 2] {
+                let storage = sm.open(num).unwrap();
+                let mut b = storage.batch_new(0);
+                b.log_set((num as u64, 1), &[num as u8]);
+                storage.batch_write(b).unwrap();
+            }
+            for &(num, cf_name, other) in &[(1u16, "log_1", "log"), (2, "log", "log_1")] {
+                let mut key = [0u8; 2 + 8 + 8];
+                build_log_key(&mut key, num, (num as u64, 1));
+                let cf = |name| sm.db.cf_handle(name).unwrap();
+                assert_eq!(&*sm.db.get_cf(cf(cf_name), &key).unwrap().unwrap(), &[num as u8]);
+                assert!(sm.db.get_cf(cf(other), &key).unwrap().is_none());
+                let storage = sm.open(num).unwrap();
+                assert_eq!(
+                    storage
+                        .log_iterator(num as u64, 0)
+                        .iter()
+                        .map(|(k, v)| (k, v.to_vec()))
+                        .collect::<Vec<_>>(),
+                    vec![((num as u64, 1), vec![num as u8])]
+                );
+            }
+        }
+        assert_eq!(
+            StorageManager::list_column_families("t/test_log_column_families").unwrap(),
+            vec!["default", "log", "log_1"]
+        );
+        // the vnode -> cf mapping would change
+        assert!(StorageManager::new("t/test_log_column_families").is_err());
+        let sm = StorageManager::with_config("t/test_log_column_families", &config).unwrap();
+        assert_eq!(sm.open(1).unwrap().log_get_vec((1, 1)).unwrap().unwrap(), [1]);
+    }
+
+    #[test]
+    fn test_list_column_families() {
+        let _ = fs::remove_dir_all("t/test_list_column_families");
+        StorageManager::new("t/test_list_column_families").unwrap();
+        assert_eq!(
+            StorageManager::list_column_families("t/test_list_column_families").unwrap(),
+            vec!["default", "log"]
+        );
+        assert!(
+            StorageManager::unexpected_column_families("t/test_list_column_families".as_ref())
+                .unwrap()
+                .is_empty()
+        );
+        {
+            let (opts, def_cf_opts, log_cf_opts) = StorageManager::options(&Default::default());
+            let mut db = rocksdb::DB::open_cf(
+                opts,
+                "t/test_list_column_families",
+                vec![("default", def_cf_opts), ("log", log_cf_opts)],
+            ).unwrap();
+            db.create_cf(("extra", rocksdb::ColumnFamilyOptions::new()))
+                .unwrap();
+        }
+        assert_eq!(
+            StorageManager::unexpected_column_families("t/test_list_column_families".as_ref())
+                .unwrap(),
+            vec!["extra"]
+        );
+        // still opens, with a warning
+        let sm = StorageManager::new("t/test_list_column_families").unwrap();
+        sm.open(1).unwrap().set(b"1", b"1").unwrap();
+    }
+
+    #[test]
+    fn test_log_memtable() {
+        for (i, &kind) in [
+            MemtableKind::HashSkipList { bucket_count: 1024 },
+            MemtableKind::Vector,
+        ].iter()
+            .enumerate()
+        {
+            let path = format!("t/test_log_memtable_{}", i);
+            let _ = fs::remove_dir_all(&path);
+            let config = StorageConfig {
+                log_memtable: kind,
+                ..Default::default()
+            };
+            let sm = StorageManager::with_config(&path, &config).unwrap();
+            let storage = sm.open(1).unwrap();
+            let mut b = storage.batch_new(0);
+            b.log_set((1, 1), b"1");
+            b.log_set((1, 2), b"2");
+            b.log_set((2, 1), b"3");
+            storage.batch_write(b).unwrap();
+            assert_eq!(storage.log_get_vec((1, 2)).unwrap().unwrap(), b"2");
+            assert_eq!(storage.log_iterator(1, 1).iter().count(), 2);
+            assert_eq!(storage.log_iterator_all().iter().count(), 3);
+            storage.clear();
+            assert_eq!(storage.log_iterator_all().iter().count(), 0);
+        }
+
+        let config = StorageConfig {
+            log_memtable: MemtableKind::HashSkipList { bucket_count: 0 },
+            ..Default::default()
+        };
+        assert!(StorageManager::with_config("t/test_log_memtable_invalid", &config).is_err());
+    }
+
+    #[test]
+    fn test_inline_value_threshold() {
+        let sizes = [0, 1, 63, 64, 65, 4096];
+        for (i, &threshold) in [None, Some(1), Some(64), Some(1024)].iter().enumerate() {
+            let path = format!("t/test_inline_value_threshold_{}", i);
+            let _ = fs::remove_dir_all(&path);
+            let config = StorageConfig {
+                inline_value_threshold: threshold,
+                ..Default::default()
+            };
+            let sm = StorageManager::with_config(&path, &config).unwrap();
+            let storage = sm.open(1).unwrap();
+            for &size in &sizes {
+                storage
+                    .set(size.to_string().as_bytes(), &vec![size as u8; size])
+                    .unwrap();
+            }
+            for &size in &sizes {
+                assert_eq!(
+                    storage.get_vec(size.to_string().as_bytes()).unwrap().unwrap(),
+                    vec![size as u8; size]
+                );
+            }
+            assert_eq!(storage.iterator().iter().count(), sizes.len());
+        }
+
+        let config = StorageConfig {
+            inline_value_threshold: Some(0),
+            ..Default::default()
+        };
+        assert!(
+            StorageManager::with_config("t/test_inline_value_threshold_invalid", &config).is_err()
+        );
+    }
+
+    #[test]
+    fn test_iter() {
+        let _ = fs::remove_dir_all("t/test_iter");
+        let sm = StorageManager::new("t/test_iter").unwrap();
+        for &i in &[0, 1, 2] {
+            let storage = sm.open(i).unwrap();
+            storage.set(b"1", i.to_string().as_bytes()).unwrap();
+            storage.set(b"2", i.to_string().as_bytes()).unwrap();
+            storage.set(b"3", i.to_string().as_bytes()).unwrap();
+        }
+        for &i in &[0, 1, 2] {
+            let storage = sm.open(i).unwrap();
+            let results: Vec<Vec<u8>> = storage.iterator().iter().map(|(_, v)| v.into()).collect();
+            assert_eq!(results, vec![i.to_string().as_bytes(); 3]);
+        }
+    }
+
+    #[test]
+    fn test_iter_with_snapshot() {
+        let _ = fs::remove_dir_all("t/test_iter_with_snapshot");
+        let sm = StorageManager::new("t/test_iter_with_snapshot").unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"1", b"1").unwrap();
+        storage.set(b"3", b"3").unwrap();
+        {
+            let (snapshot, mut iterator) = storage.iter_with_snapshot();
+            let mut iter = iterator.iter();
+            assert_eq!(iter.next().unwrap().0, b"1");
+            storage.set(b"2", b"2").unwrap();
+            storage.set(b"4", b"4").unwrap();
+            storage.del(b"3").unwrap();
+            drop(snapshot);
+            let rest: Vec<Vec<u8>> = iter.map(|(k, _)| k.into()).collect();
+            assert_eq!(rest, vec![b"3".to_vec()]);
+        }
+        assert_eq!(storage.iterator().iter().count(), 3);
+    }
+
+    #[test]
+    fn test_iterator_isolation() {
+        let _ = fs::remove_dir_all("t/test_iterator_isolation");
+        let sm = StorageManager::new("t/test_iterator_isolation").unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"1", b"1").unwrap();
+        storage.set(b"3", b"3").unwrap();
+        {
+            let mut iterator = storage.iterator();
+            let mut iter = iterator.iter();
+            assert_eq!(iter.next().unwrap().0, b"1");
+            storage.set(b"2", b"2").unwrap();
+            storage.set(b"3", b"changed").unwrap();
+            let rest: Vec<_> = iter.map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+            assert_eq!(rest, vec![(b"3".to_vec(), b"3".to_vec())]);
+        }
+        assert_eq!(storage.iterator().iter().count(), 3);
+    }
+
+    #[test]
+    fn test_checkpoint_with_manifest() {
+        let _ = fs::remove_dir_all("t/test_checkpoint_with_manifest");
+        let sm = StorageManager::new("t/test_checkpoint_with_manifest/db").unwrap();
+        let (s1, s2) = (sm.open(1).unwrap(), sm.open(2).unwrap());
+        s1.set(b"a", b"a").unwrap();
+        let mut b = s1.batch_new(0);
+        for seq in 1..11 {
+            b.log_set((7, seq), b"");
+        }
+        b.log_set((8, 3), b"");
+        b.log_set_typed((8, 5), 1, b"");
+        s1.batch_write(b).unwrap();
+        let mut b = s2.batch_new(0);
+        b.log_set((7, 42), b"");
+        b.log_set((u64::max_value(), 1), b"");
+        s2.batch_write(b).unwrap();
+
+        let dest = "t/test_checkpoint_with_manifest/backup";
+        let manifest = sm.checkpoint_with_manifest(dest).unwrap();
+        // written after the checkpoint, not part of it
+        let mut b = s1.batch_new(0);
+        b.log_set((7, 11), b"");
+        s1.batch_write(b).unwrap();
+
+        let expected = vec![
+            (1, 7, 10),
+            (1, 8, 5),
+            (2, 7, 42),
+            (2, u64::max_value(), 1),
+        ];
+        assert_eq!(manifest.log_heads, expected);
+        let read = CheckpointManifest::read(dest).unwrap();
+        assert_eq!(read, manifest);
+        assert_eq!(read.head(1, 7), Some(10));
+        assert_eq!(read.head(1, 9), None);
+
+        // the checkpoint has the data too
+        let restored = StorageManager::new(dest).unwrap();
+        assert_eq!(restored.open(1).unwrap().get_vec(b"a").unwrap(), Some(b"a".to_vec()));
+        assert_eq!(restored.open(1).unwrap().log_iterator(7, 0).iter().count(), 10);
+    }
+
+    #[test]
+    fn test_iter_all_vnodes() {
+        let _ = fs::remove_dir_all("t/test_iter_all_vnodes");
+        let sm = StorageManager::new("t/test_iter_all_vnodes").unwrap();
+        for &num in &[2u16, 0, 1] {
+            let storage = sm.open(num).unwrap();
+            for key in &[b"b", b"a", b"c"] {
+                storage.set(*key, &[num as u8]).unwrap();
+            }
+        }
+        // log entries aren't part of the scan
+        let mut b = sm.open(5).unwrap().batch_new(0);
+        b.log_set((1, 1), b"1");
+        sm.open(5).unwrap().batch_write(b).unwrap();
+        assert_eq!(sm.list_dbnums(), vec![0, 1, 2]);
+
+        let mut scanned = Vec::new();
+        for (num, mut iterator) in sm.iter_all_vnodes() {
+            for (k, v) in iterator.iter() {
+                assert_eq!(v, &[num as u8]);
+                scanned.push((num, k.to_vec()));
+            }
+        }
+        let mut expected = Vec::new();
+        for num in 0..3 {
+            for key in &[b"a", b"b", b"c"] {
+                expected.push((num, key.to_vec()));
+            }
+        }
+        assert_eq!(scanned, expected);
+        sm.check_pending_iters();
+    }
+
+    #[test]
+    fn test_iter_status() {
+        let _ = fs::remove_dir_all("t/test_iter_status");
+        let sm = StorageManager::new("t/test_iter_status").unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"1", b"1").unwrap();
+        let mut b = storage.batch_new(0);
+        b.log_set((1, 1), b"1");
+        storage.batch_write(b).unwrap();
+
+        let mut iterator = storage.iterator();
+        assert_eq!(iterator.iter().count(), 1);
+        assert!(iterator.status().is_ok());
+        let mut iterator = storage.log_iterator(1, 0);
+        assert_eq!(iterator.iter().count(), 1);
+        assert!(iterator.status().is_ok());
+        let mut iterator = storage.log_iterator(1, 0).filter(|_| false);
+        assert_eq!(iterator.iter().count(), 0);
+        assert!(iterator.status().is_ok());
+    }
+
+    #[test]
+    fn test_iter_log() {
+        let _ = fs::remove_dir_all("t/test_iter_log");
+        let sm = StorageManager::new("t/test_iter_log").unwrap();
+        for &i in &[0u64, 1, 2] {
+            let storage = sm.open(i as u16).unwrap();
+            let mut b = storage.batch_new(0);
+            b.log_set((i, i + 0), (i + 0).to_string().as_bytes());
+            b.log_set((i, i + 1), (i + 1).to_string().as_bytes());
+            b.log_set((i, i + 2), (i + 2).to_string().as_bytes());
+            b.log_set((i + 1, i), b"");
+            storage.batch_write(b).unwrap();
+        }
+        for &i in &[0u64, 1, 2] {
+            let storage = sm.open(i as u16).unwrap();
+            let results: Vec<(_, Vec<u8>)> = storage
+                .log_iterator(i, i + 1)
+                .iter()
+                .map(|(k, v)| (k, v.into()))
+                .collect();
+            assert_eq!(
+                results,
+                vec![
+                    ((i, i + 1), (i + 1).to_string().into_bytes()),
+                    ((i, i + 2), (i + 2).to_string().into_bytes()),
+                ]
+            );
+            assert_eq!(storage.log_iterator(i, i).iter().count(), 3);
+            assert_eq!(storage.log_iterator_all().iter().count(), 4);
+        }
+    }
+
+    #[test]
+    fn test_iter_log_filter() {
+        let _ = fs::remove_dir_all("t/test_iter_log_filter");
+        let sm = StorageManager::new("t/test_iter_log_filter").unwrap();
+        let storage = sm.open(1).unwrap();
+        let mut b = storage.batch_new(0);
+        for i in 1..101u64 {
+            b.log_set((1, i), i.to_string().as_bytes());
+        }
+        storage.batch_write(b).unwrap();
+        let results: Vec<_> = storage
+            .log_iterator(1, 0)
+            .filter(|seq| seq % 2 == 0)
+            .iter()
+            .map(|(k, v)| {
+                assert_eq!(k.1.to_string().as_bytes(), v);
+                k.1
+            }).collect();
+        assert_eq!(results.len(), 50);
+        assert!(results.iter().all(|seq| seq % 2 == 0));
+        assert_eq!(
+            storage.log_iterator(1, 0).filter(|_| false).iter().count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_pending_iters_concurrent() {
+        use std::thread;
+        let _ = fs::remove_dir_all("t/test_pending_iters_concurrent");
+        let sm = StorageManager::new("t/test_pending_iters_concurrent").unwrap();
+        let threads: Vec<_> = (0..8u16)
+            .map(|i| {
+                let storage = sm.open(i).unwrap();
+                storage.set(b"1", b"1").unwrap();
+                thread::spawn(move || {
+                    for _ in 0..200 {
+                        let mut a = storage.iterator();
+                        let b = storage.log_iterator_all();
+                        assert_eq!(a.iter().count(), 1);
+                        drop(b);
+                        let mut c = storage.log_iterator(0, 0);
+                        assert_eq!(c.iter().count(), 0);
+                    }
+                })
+            }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        sm.check_pending_iters();
+
+        let storage = sm.open(0).unwrap();
+        let it = storage.iterator();
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| sm.check_pending_iters())).is_err());
+        drop(it);
+        sm.check_pending_iters();
+    }
+
+    #[test]
+    fn test_atomic_increment() {
+        use std::thread;
+        let _ = fs::remove_dir_all("t/test_atomic_increment");
+        let sm = StorageManager::new("t/test_atomic_increment").unwrap();
+        let storage = Arc::new(sm.open(1).unwrap());
+        assert_eq!(storage.atomic_increment(b"c", 5).unwrap(), 5);
+        assert_eq!(storage.atomic_increment(b"c", -7).unwrap(), -2);
+        assert_eq!(storage.atomic_increment(b"c", 2).unwrap(), 0);
+
+        let threads: Vec<_> = (0..100)
+            .map(|_| {
+                let storage = storage.clone();
+                thread::spawn(move || storage.atomic_increment(b"counter", 1).unwrap())
+            }).collect();
+        // every caller sees its own increment
+        let mut returned: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        returned.sort();
+        assert_eq!(returned, (1..101).collect::<Vec<_>>());
+        assert_eq!(storage.atomic_increment(b"counter", 0).unwrap(), 100);
+        assert_eq!(
+            storage.get_vec(b"counter").unwrap(),
+            Some(vec![0, 0, 0, 0, 0, 0, 0, 100])
+        );
+        // counters aren't visible to other vnodes
+        assert_eq!(sm.open(2).unwrap().get_vec(b"counter").unwrap(), None);
+    }
+
+    #[test]
+    fn test_set_many() {
+        let _ = fs::remove_dir_all("t/test_set_many");
+        let sm = StorageManager::new("t/test_set_many").unwrap();
+        let storage = sm.open(1).unwrap();
+        let mut b = storage.batch_new(0);
+        b.set_many((0..1000).map(|i| (i.to_string(), format!("v{}", i))));
+        b.set_many(vec![(&b"a"[..], &b"b"[..])]);
+        storage.batch_write(b).unwrap();
+        for i in 0..1000 {
+            assert_eq!(
+                storage.get_vec(i.to_string().as_bytes()).unwrap(),
+                Some(format!("v{}", i).into_bytes())
+            );
+        }
+        assert_eq!(storage.get_vec(b"a").unwrap(), Some(b"b".to_vec()));
+        assert_eq!(storage.iterator().iter().count(), 1001);
+    }
+
+    #[test]
+    fn test_log_set_many() {
+        let _ = fs::remove_dir_all("t/test_log_set_many");
+        let sm = StorageManager::new("t/test_log_set_many").unwrap();
+        let (looped, many) = (sm.open(1).unwrap(), sm.open(2).unwrap());
+        let entries: Vec<_> = (0..10_000u64)
+            .map(|i| ((i % 7, i), i.to_string().into_bytes()))
+            .collect();
+
+        let mut b = looped.batch_new(0);
+        for &(key, ref value) in &entries {
+            b.log_set(key, value);
+        }
+        looped.batch_write(b).unwrap();
+
+        let mut b = many.batch_new(0);
+        b.log_set_many(entries.iter().map(|&(key, ref value)| (key, value)));
+        many.batch_write(b).unwrap();
+
+        let dump = |s: &Storage| {
+            s.log_iterator_all()
+                .iter()
+                .map(|(k, v)| (k, v.to_vec()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(dump(&many).len(), 10_000);
+        assert_eq!(dump(&many), dump(&looped));
+    }
+
+    #[test]
+    fn test_log_typed_keys() {
+        let _ = fs::remove_dir_all("t/test_log_typed_keys");
+        let sm = StorageManager::new("t/test_log_typed_keys").unwrap();
+        let storage = sm.open(1).unwrap();
+        let mut b = storage.batch_new(0);
+        b.log_set((1, 1), b"old1");
+        b.log_set_typed((1, 1), 7, b"new1");
+        b.log_set_typed((1, 2), 0, b"new2");
+        b.log_set((1, 3), b"old3");
+        b.log_set((2, 1), b"old4");
+        storage.batch_write(b).unwrap();
+
+        let typed: Vec<_> = storage
+            .log_iterator_all()
+            .iter_typed()
+            .map(|(k, t, v)| (k, t, v.to_vec()))
+            .collect();
+        assert_eq!(
+            typed,
+            vec![
+                ((1, 1), None, b"old1".to_vec()),
+                ((1, 1), Some(7), b"new1".to_vec()),
+                ((1, 2), Some(0), b"new2".to_vec()),
+                ((1, 3), None, b"old3".to_vec()),
+                ((2, 1), None, b"old4".to_vec()),
+            ]
+        );
+        // untyped readers only see untyped entries, so keys are unique
+        let entries: Vec<_> = storage
+            .log_iterator(1, 0)
+            .iter()
+            .map(|(k, v)| (k, v.to_vec()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![((1, 1), b"old1".to_vec()), ((1, 3), b"old3".to_vec())]
+        );
+        let keys: Vec<_> = storage
+            .log_iterator(1, 0)
+            .filter(|_| true)
+            .iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, vec![(1, 1), (1, 3)]);
+        let keys: Vec<_> = storage.log_iterator(1, 0).with_stride(2).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![(1, 1)]);
+        assert_eq!(storage.log_get_vec((1, 1)).unwrap(), Some(b"old1".to_vec()));
+    }
+
+    #[test]
+    fn test_open_with_retry() {
+        let _ = fs::remove_dir_all("t/test_open_with_retry");
+        let sm = StorageManager::new("t/test_open_with_retry").unwrap();
+        let err = StorageManager::open_with_retry(
+            "t/test_open_with_retry",
+            0,
+            Duration::from_millis(1),
+        ).err()
+        .unwrap();
+        assert!(is_lock_error(&*err));
+
+        // racing open succeeds once the first manager goes away
+        let racing = thread::spawn(|| {
+            StorageManager::open_with_retry(
+                "t/test_open_with_retry",
+                10,
+                Duration::from_millis(10),
+            )
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(sm);
+        let sm = racing.join().unwrap().unwrap();
+        sm.open(1).unwrap().set(b"k", b"v").unwrap();
+    }
+
+    #[test]
+    fn test_open_locked() {
+        let _ = fs::remove_dir_all("t/test_open_locked");
+        let _sm = StorageManager::new("t/test_open_locked").unwrap();
+        let err = StorageManager::new("t/test_open_locked").err().unwrap();
+        match err.downcast_ref::<StorageError>() {
+            Some(&StorageError::DatabaseLocked(ref m)) => {
+                assert!(m.starts_with("Can't open storage with"));
+                assert!(m.contains("LOCK"));
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_storage_error_from_rocksdb() {
+        let cases = [
+            (
+                "IO error: While lock file: t/db/LOCK: Resource temporarily unavailable",
+                StorageError::DatabaseLocked as fn(String) -> StorageError,
+            ),
+            ("IO error: No space left on device", StorageError::Io),
+            ("Corruption: block checksum mismatch", StorageError::Corruption),
+            ("Invalid argument: Column family not found: log_1", StorageError::InvalidArgument),
+            ("Resource busy: ", StorageError::Busy),
+            ("Operation timed out: ", StorageError::Busy),
+            ("Operation failed. Try again.: ", StorageError::Busy),
+            ("Not implemented: ", StorageError::Other),
+        ];
+        for &(message, variant) in &cases {
+            assert_eq!(
+                StorageError::from(message.to_string()),
+                variant(message.to_string())
+            );
+        }
+        assert!(StorageError::Busy("".into()).is_retryable());
+        assert!(!StorageError::Corruption("".into()).is_retryable());
+        assert_eq!(
+            StorageError::Busy("busy".into()).context("Can't write"),
+            StorageError::Busy("Can't write: busy".into())
+        );
+    }
+
+    #[test]
+    fn test_export_vnode() {
+        let _ = fs::remove_dir_all("t/test_export_vnode");
+        let sm = StorageManager::new("t/test_export_vnode").unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"1", b"1").unwrap();
+        sm.open(2).unwrap().set(b"2", b"2").unwrap();
+        let export = sm.export_vnode(1).unwrap();
+        storage.set(b"3", b"3").unwrap();
+        storage.del(b"1").unwrap();
+        assert_eq!(export.vnode(), 1);
+        let mut iter = export.iterator();
+        let exported: Vec<_> = iter.iter().map(|(k, v)| (k.to_vec(), v.to_vec())).collect();
+        assert_eq!(exported, vec![(b"1".to_vec(), b"1".to_vec())]);
+    }
+
+    #[test]
+    fn test_sample_keys() {
+        let _ = fs::remove_dir_all("t/test_sample_keys");
+        let sm = StorageManager::new("t/test_sample_keys").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert!(storage.sample_keys(10, 0).is_empty());
+        let mut b = storage.batch_new(0);
+        b.set_many((0..1000).map(|i| (i.to_string(), i.to_string())));
+        storage.batch_write(b).unwrap();
+        sm.open(2).unwrap().set(b"other", b"other").unwrap();
+
+        let sample = storage.sample_keys(100, 1);
+        assert_eq!(sample.len(), 100);
+        let mut unique = sample.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 100);
+        for k in &sample {
+            assert!(storage.get_vec(k).unwrap().is_some());
+        }
+        assert_eq!(storage.sample_keys(100, 1), sample);
+        assert_ne!(storage.sample_keys(100, 2), sample);
+
+        assert_eq!(storage.sample_keys(0, 1).len(), 0);
+        assert_eq!(storage.sample_keys(2000, 1).len(), 1000);
+    }
+
+    #[test]
+    fn test_group_committer() {
+        let _ = fs::remove_dir_all("t/test_group_committer");
+        let sm = StorageManager::new("t/test_group_committer").unwrap();
+        let storage = sm.open(1).unwrap();
+        {
+            let committer = Arc::new(storage.group_committer(Duration::from_millis(5)));
+            let threads: Vec<_> = (0..8u64)
+                .map(|t| {
+                    let committer = committer.clone();
+                    thread::spawn(move || {
+                        for i in 0..50u64 {
+                            let k = format!("{}_{}", t, i).into_bytes();
+                            committer
+                                .commit(vec![
+                                    GroupWrite::Set(k.clone(), k.clone()),
+                                    GroupWrite::LogSet((t, i), k),
+                                ]).unwrap();
+                        }
+                    })
+                }).collect();
+            for t in threads {
+                t.join().unwrap();
+            }
+            assert!(committer.syncs() > 0);
+            assert!(committer.syncs() < 400, "{}", committer.syncs());
+
+            committer
+                .commit(vec![GroupWrite::Del(b"0_0".to_vec())])
+                .unwrap();
+        }
+        assert_eq!(storage.get_vec(b"0_0").unwrap(), None);
+        for t in 0..8u64 {
+            for i in 0..50u64 {
+                let k = format!("{}_{}", t, i).into_bytes();
+                if (t, i) != (0, 0) {
+                    assert_eq!(storage.get_vec(&k).unwrap(), Some(k.clone()));
+                }
+                assert_eq!(storage.log_get_vec((t, i)).unwrap(), Some(k));
+            }
+        }
+    }
+
+    #[test]
+    fn test_watch_prefix() {
+        use futures::Future;
+        let _ = fs::remove_dir_all("t/test_watch_prefix");
+        let sm = StorageManager::new("t/test_watch_prefix").unwrap();
+        let storage = sm.open(1).unwrap();
+        let watcher = storage.watch_prefix(b"user/");
+        // writes from other handles of the same vnode are observed too
+        let other_handle = sm.open(1).unwrap();
+        for i in 0..5 {
+            storage
+                .set(format!("user/{}", i).as_bytes(), b"v")
+                .unwrap();
+            other_handle
+                .set(format!("other/{}", i).as_bytes(), b"v")
+                .unwrap();
+        }
+        sm.open(2).unwrap().set(b"user/x", b"v").unwrap();
+        other_handle.del(b"user/0").unwrap();
+
+        let events = watcher.take(6).collect().wait().unwrap();
+        let mut expected: Vec<_> = (0..5)
+            .map(|i| WatchEvent {
+                key: format!("user/{}", i).into_bytes(),
+                value: Some(b"v".to_vec()),
+            }).collect();
+        expected.push(WatchEvent {
+            key: b"user/0".to_vec(),
+            value: None,
+        });
+        assert_eq!(events, expected);
+
+        // dropped watchers are removed on the next write
+        storage.set(b"user/5", b"v").unwrap();
+        assert!(sm.watchers.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_clear() {
+        let _ = fs::remove_dir_all("t/test_clear");
+        let sm = StorageManager::new("t/test_clear").unwrap();
+        for &i in &[0u64, 1, 2] {
+            let storage = sm.open(i as u16).unwrap();
+            let mut b = storage.batch_new(0);
+            b.set(i.to_string().as_bytes(), i.to_string().as_bytes());
+            b.log_set((i, i), i.to_string().as_bytes());
+            storage.batch_write(b).unwrap();
+        }
+        for &i in &[0u64, 1, 2] {
             let storage = sm.open(i as u16).unwrap();
             assert_eq!(storage.iterator().iter().count(), 1);
             assert_eq!(storage.log_iterator(i, i).iter().count(), 1);
@@ -524,6 +3905,251 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_last_vnode() {
+        let _ = fs::remove_dir_all("t/test_last_vnode");
+        let sm = StorageManager::new("t/test_last_vnode").unwrap();
+        for &num in &[u16::max_value() - 1, u16::max_value()] {
+            let storage = sm.open(num).unwrap();
+            let mut b = storage.batch_new(0);
+            b.set(b"\xFF\xFF", b"1");
+            b.log_set((1, 1), b"1");
+            storage.batch_write(b).unwrap();
+        }
+        // the range of the last vnode has no upper bound
+        let storage = sm.open(u16::max_value()).unwrap();
+        assert_eq!(storage.scan(None, None).iter().count(), 1);
+        assert_eq!(storage.iterator_prefix(b"").iter().count(), 1);
+        assert_eq!(storage.iterator_prefix(b"\xFF").iter().count(), 1);
+        assert_eq!(storage.log_iterator_all().iter().count(), 1);
+        storage.size_of_key_range(b"", b"");
+        storage.size_bytes();
+        storage.disk_usage();
+        storage.compact_range(b"", b"");
+        assert_eq!(storage.clear_opt(true), 2);
+        storage.clear();
+        assert_eq!(storage.scan(None, None).iter().count(), 0);
+        assert_eq!(storage.log_iterator_all().iter().count(), 0);
+
+        let previous = sm.open(u16::max_value() - 1).unwrap();
+        assert_eq!(previous.scan(None, None).iter().count(), 1);
+        assert_eq!(previous.log_iterator_all().iter().count(), 1);
+    }
+
+    #[test]
+    fn test_migrate_values() {
+        let _ = fs::remove_dir_all("t/test_migrate_values");
+        let sm = StorageManager::new("t/test_migrate_values").unwrap();
+        let s1 = sm.open(1).unwrap();
+        let s2 = sm.open(2).unwrap();
+        for i in 0..1_500u32 {
+            s1.set(format!("k{}", i).as_bytes(), b"v1:a").unwrap();
+        }
+        s2.set(b"k", b"v1:b").unwrap();
+        s2.set(b"other", b"v2:c").unwrap();
+        // v1 values get upgraded to v2, anything else is left alone
+        let upgrade = |v: &[u8]| if v.starts_with(b"v1:") {
+            let mut new = b"v2:".to_vec();
+            new.extend_from_slice(&v[3..]);
+            Some(new)
+        } else {
+            None
+        };
+        assert_eq!(sm.migrate_values(&upgrade).unwrap(), 1_501);
+        assert_eq!(s1.get_vec(b"k0").unwrap(), Some(b"v2:a".to_vec()));
+        assert_eq!(s1.get_vec(b"k1499").unwrap(), Some(b"v2:a".to_vec()));
+        assert_eq!(s2.get_vec(b"k").unwrap(), Some(b"v2:b".to_vec()));
+        assert_eq!(s2.get_vec(b"other").unwrap(), Some(b"v2:c".to_vec()));
+        // resuming is a no-op
+        assert_eq!(sm.migrate_values(&upgrade).unwrap(), 0);
+        assert_eq!(s1.iterator().iter().count(), 1_500);
+    }
+
+    #[test]
+    fn test_partition_keys() {
+        let _ = fs::remove_dir_all("t/test_partition_keys");
+        let sm = StorageManager::new("t/test_partition_keys").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert_eq!(storage.partition_keys(10), vec![(None, None)]);
+        let mut b = storage.batch_new(0);
+        for i in 0..10_000 {
+            b.set(format!("k{}", i).as_bytes(), b"v");
+        }
+        storage.batch_write(b).unwrap();
+        // other vnodes don't leak into the scans
+        sm.open(2).unwrap().set(b"k1", b"v").unwrap();
+
+        let full: Vec<Vec<u8>> = storage.iterator().iter().map(|(k, _)| k.to_vec()).collect();
+        let ranges = storage.partition_keys(10);
+        assert_eq!(ranges.len(), 10);
+        let mut union = Vec::new();
+        for (start, end) in ranges {
+            let mut iterator = storage.scan(
+                start.as_ref().map(|s| &s[..]),
+                end.as_ref().map(|e| &e[..]),
+            );
+            let keys: Vec<Vec<u8>> = iterator.iter().map(|(k, _)| k.to_vec()).collect();
+            // roughly balanced
+            assert!(keys.len() > 500 && keys.len() < 2_000, "{}", keys.len());
+            union.extend(keys);
+        }
+        assert_eq!(union, full);
+    }
+
+    #[test]
+    fn test_value_len() {
+        let _ = fs::remove_dir_all("t/test_value_len");
+        let sm = StorageManager::new("t/test_value_len").unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"empty", b"").unwrap();
+        storage.set(b"value", b"12345").unwrap();
+        assert_eq!(storage.value_len(b"missing").unwrap(), None);
+        assert_eq!(storage.value_len(b"empty").unwrap(), Some(0));
+        assert_eq!(storage.value_len(b"value").unwrap(), Some(5));
+
+        // the checksum header isn't part of the length
+        let _ = fs::remove_dir_all("t/test_value_len_checksum");
+        let config = StorageConfig {
+            value_checksums: true,
+            ..Default::default()
+        };
+        let sm = StorageManager::with_config("t/test_value_len_checksum", &config).unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"checksummed", b"12345").unwrap();
+        assert_eq!(storage.value_len(b"checksummed").unwrap(), Some(5));
+    }
+
+    #[test]
+    fn test_cursor_table_reap() {
+        let _ = fs::remove_dir_all("t/test_cursor_table_reap");
+        let sm = StorageManager::new("t/test_cursor_table_reap").unwrap();
+        let storage = sm.open(1).unwrap();
+        for i in 0..10 {
+            storage.set(&[i], b"value").unwrap();
+        }
+        let mut cursors = CursorTable::new(Duration::from_millis(100));
+        let active = cursors.open(storage.iterator());
+        let idle = cursors.open(storage.iterator());
+        assert_eq!(cursors.next(idle).unwrap().unwrap().0, [0]);
+        // advancing the cursor keeps it alive past the timeout
+        for i in 0..5 {
+            thread::sleep(Duration::from_millis(40));
+            assert_eq!(cursors.next(active).unwrap().unwrap().0, [i]);
+            cursors.reap();
+        }
+        assert_eq!(cursors.len(), 1);
+        assert!(cursors.next(idle).is_err());
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| sm.check_pending_iters())).is_err());
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(cursors.reap(), 1);
+        assert!(cursors.next(active).is_err());
+        sm.check_pending_iters();
+        storage.clear();
+    }
+
+    #[test]
+    fn test_log_set_cas() {
+        let _ = fs::remove_dir_all("t/test_log_set_cas");
+        let sm = StorageManager::new("t/test_log_set_cas").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert!(storage.log_set_cas((1, 1), b"first").unwrap());
+        assert!(!storage.log_set_cas((1, 1), b"second").unwrap());
+        assert_eq!(storage.log_get_vec((1, 1)).unwrap().unwrap(), b"first");
+        // other slots and vnodes are independent
+        assert!(storage.log_set_cas((1, 2), b"second").unwrap());
+        assert!(sm.open(2).unwrap().log_set_cas((1, 1), b"other").unwrap());
+        assert_eq!(storage.log_get_vec((1, 1)).unwrap().unwrap(), b"first");
+        // slots written with log_set also count as taken
+        let mut b = storage.batch_new(0);
+        b.log_set((1, 3), b"plain");
+        storage.batch_write(b).unwrap();
+        assert!(!storage.log_set_cas((1, 3), b"cas").unwrap());
+        assert_eq!(storage.log_get_vec((1, 3)).unwrap().unwrap(), b"plain");
+
+        // a single winner among racing callers, even if they write the same value
+        let storage = Arc::new(storage);
+        let threads: Vec<_> = (0..10)
+            .map(|_| {
+                let storage = storage.clone();
+                thread::spawn(move || storage.log_set_cas((1, 4), b"same").unwrap())
+            }).collect();
+        let winners = threads.into_iter().map(|t| t.join().unwrap());
+        assert_eq!(winners.filter(|&w| w).count(), 1);
+    }
+
+    #[test]
+    fn test_properties_summary() {
+        let _ = fs::remove_dir_all("t/test_properties_summary");
+        let sm = StorageManager::new("t/test_properties_summary").unwrap();
+        let storage = sm.open(1).unwrap();
+        let mut b = storage.batch_new(0);
+        for i in 0..1_000u64 {
+            b.set(i.to_string().as_bytes(), b"value");
+            b.log_set((1, i), b"value");
+        }
+        storage.batch_write(b).unwrap();
+        let summary = sm.properties_summary();
+        assert!(!summary.is_empty());
+        assert!(summary.contains("[default]"), "{}", summary);
+        assert!(summary.contains("[log]"), "{}", summary);
+        assert!(summary.contains("rocksdb.num-entries-active-mem-table"), "{}", summary);
+        assert!(summary.contains("1,000"), "{}", summary);
+
+        assert_eq!(format_thousands(0), "0");
+        assert_eq!(format_thousands(999), "999");
+        assert_eq!(format_thousands(1_000), "1,000");
+        assert_eq!(format_thousands(123_456_789), "123,456,789");
+    }
+
+    #[test]
+    fn test_clear_dry_run() {
+        let _ = fs::remove_dir_all("t/test_clear_dry_run");
+        let sm = StorageManager::new("t/test_clear_dry_run").unwrap();
+        for &i in &[0u64, 1, 2] {
+            let storage = sm.open(i as u16).unwrap();
+            let mut b = storage.batch_new(0);
+            for j in 0..(i + 1) * 10 {
+                b.set(j.to_string().as_bytes(), b"v");
+                b.log_set((i, j), b"v");
+            }
+            storage.batch_write(b).unwrap();
+        }
+        let storage = sm.open(1).unwrap();
+        let dry = storage.clear_opt(true);
+        assert_eq!(dry, 40);
+        assert_eq!(storage.clear_opt(true), dry);
+        assert_eq!(storage.iterator().iter().count(), 20);
+        sm.check_pending_iters();
+        assert_eq!(storage.clear_opt(false), dry);
+        assert_eq!(storage.iterator().iter().count(), 0);
+        assert_eq!(storage.clear_opt(true), 0);
+        // other vnodes are untouched
+        assert_eq!(sm.open(0).unwrap().clear_opt(true), 20);
+        assert_eq!(sm.open(2).unwrap().clear_opt(true), 60);
+        sm.check_pending_iters();
+    }
+
+    #[test]
+    fn test_pin_prefix() {
+        let _ = fs::remove_dir_all("t/test_pin_prefix");
+        let sm = StorageManager::new("t/test_pin_prefix").unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.pin_prefix().unwrap();
+        for i in 0..100 {
+            storage
+                .set(i.to_string().as_bytes(), i.to_string().as_bytes())
+                .unwrap();
+        }
+        storage.pin_prefix().unwrap();
+        for i in 0..100 {
+            assert_eq!(
+                storage.get_vec(i.to_string().as_bytes()).unwrap().unwrap(),
+                i.to_string().as_bytes()
+            );
+        }
+    }
+
     #[test]
     fn test_open_all() {
         let _ = fs::remove_dir_all("t/test_open_all");
@@ -536,4 +4162,509 @@ mod tests {
         sm.open(3).unwrap();
     }
 
+    #[test]
+    fn test_index_key() {
+        let _ = fs::remove_dir_all("t/test_index_key");
+        let sm = StorageManager::new("t/test_index_key").unwrap();
+        let storage = sm.open(1).unwrap();
+        let other = sm.open(2).unwrap();
+        // a+bc and ab+c must not collide
+        assert_ne!(
+            IndexKey::new().field(b"a").primary_key(b"bc"),
+            IndexKey::new().field(b"ab").primary_key(b"c")
+        );
+        let entries = vec![
+            (&b"bob"[..], &b"3"[..]),
+            (&b"alice"[..], &b"2"[..]),
+            (&b"al\x00ice"[..], &b"5"[..]),
+            (&b"al"[..], &b"4"[..]),
+            (&b"alice"[..], &b"1"[..]),
+            (&b"\xff"[..], &b"6"[..]),
+        ];
+        for &(field, pk) in &entries {
+            let key = IndexKey::new().field(field).primary_key(pk);
+            storage.set(&key, b"").unwrap();
+            let (fields, decoded_pk) = IndexKey::decode(&key, 1).unwrap();
+            assert_eq!(fields, vec![field.to_vec()]);
+            assert_eq!(decoded_pk, pk);
+        }
+        other.set(&IndexKey::new().field(b"alice").primary_key(b"9"), b"").unwrap();
+
+        let scan = |prefix: &[u8]| -> Vec<Vec<u8>> {
+            let mut iter = storage.iterator_prefix(prefix);
+            let result = iter
+                .iter()
+                .map(|(k, _)| IndexKey::decode(k, 1).unwrap().1.to_vec())
+                .collect();
+            iter.status().unwrap();
+            result
+        };
+        assert_eq!(
+            scan(&IndexKey::new().field(b"alice").prefix()),
+            vec![b"1".to_vec(), b"2".to_vec()]
+        );
+        assert_eq!(scan(&IndexKey::new().field(b"al").prefix()), vec![b"4".to_vec()]);
+        assert_eq!(scan(&IndexKey::new().field(b"\xff").prefix()), vec![b"6".to_vec()]);
+        // all entries come back in field order
+        assert_eq!(
+            scan(b""),
+            vec![b"4", b"5", b"1", b"2", b"3", b"6"]
+                .into_iter()
+                .map(|pk| pk.to_vec())
+                .collect::<Vec<_>>()
+        );
+        assert!(scan(&IndexKey::new().field(b"carol").prefix()).is_empty());
+        assert!(IndexKey::decode(b"abc", 1).is_err());
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let _ = fs::remove_dir_all("t/test_copy_to_source");
+        let _ = fs::remove_dir_all("t/test_copy_to_target");
+        let source_sm = StorageManager::new("t/test_copy_to_source").unwrap();
+        let target_sm = StorageManager::new("t/test_copy_to_target").unwrap();
+        let source = source_sm.open(1).unwrap();
+        let mut b = source.batch_new(0);
+        b.set_many((0..50_000).map(|i| (format!("k{}", i), format!("v{}", i))));
+        for i in 0..2_500u64 {
+            b.log_set((1, i), i.to_string().as_bytes());
+        }
+        source.batch_write(b).unwrap();
+        source_sm.open(2).unwrap().set(b"other", b"other").unwrap();
+        let log_entries = |storage: &Storage| -> Vec<((u64, u64), Vec<u8>)> {
+            let mut iter = storage.log_iterator_all();
+            let result = iter.iter().map(|(k, v)| (k, v.to_vec())).collect();
+            iter.status().unwrap();
+            result
+        };
+
+        let target = target_sm.open(1).unwrap();
+        target.set(b"stale", b"stale").unwrap();
+        assert_eq!(source.diff_with(&target).unwrap().len(), 50_001);
+        target.del(b"stale").unwrap();
+        assert_eq!(source.copy_to(&target).unwrap(), 52_500);
+        assert!(source.diff_with(&target).unwrap().is_empty());
+        assert_eq!(log_entries(&source), log_entries(&target));
+        assert!(target_sm.open(2).unwrap().get_vec(b"other").unwrap().is_none());
+
+        // interrupted right after the first batch and then resumed
+        let target = target_sm.open(3).unwrap();
+        let mut checkpoint = CopyCheckpoint::new();
+        let interrupt = AtomicBool::new(true);
+        source
+            .copy_to_checkpoint(&target, &mut checkpoint, &interrupt)
+            .unwrap();
+        assert!(!checkpoint.is_done());
+        assert_eq!(checkpoint.copied(), COPY_BATCH_SIZE);
+        assert!(!source.diff_with(&target).unwrap().is_empty());
+        interrupt.store(false, Ordering::Relaxed);
+        source
+            .copy_to_checkpoint(&target, &mut checkpoint, &interrupt)
+            .unwrap();
+        assert!(checkpoint.is_done());
+        assert_eq!(checkpoint.copied(), 52_500);
+        assert!(source.diff_with(&target).unwrap().is_empty());
+        assert_eq!(log_entries(&source), log_entries(&target));
+    }
+
+    #[test]
+    fn test_size_bytes() {
+        let _ = fs::remove_dir_all("t/test_size_bytes");
+        let sm = StorageManager::new("t/test_size_bytes").unwrap();
+        let storage = sm.open(1).unwrap();
+        let other = sm.open(2).unwrap();
+        // random values so compression doesn't shrink them
+        let mut rng = XorShiftRng::from_seed([1; 16]);
+        let mut b = storage.batch_new(0);
+        for i in 0..1024 {
+            let mut value = [0u8; 1024];
+            rng.fill(&mut value[..]);
+            b.set(format!("k{:04}", i).as_bytes(), &value);
+        }
+        storage.batch_write(b).unwrap();
+        other.set(b"other", b"other").unwrap();
+        sm.barrier().unwrap();
+
+        let total = storage.size_bytes();
+        assert!(total > 500 * 1024, "{}", total);
+        assert!(other.size_bytes() < 100 * 1024);
+        let half = storage.size_of_key_range(b"k0000", b"k0512");
+        assert!(half > 0 && half < total, "{} {}", half, total);
+        assert!(storage.size_of_key_range(b"", b"") >= half);
+    }
+
+    #[test]
+    fn test_compaction_estimate() {
+        let _ = fs::remove_dir_all("t/test_compaction_estimate");
+        let sm = StorageManager::new("t/test_compaction_estimate").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert_eq!(storage.compaction_estimate(), CompactionEstimate::default());
+        let mut b = storage.batch_new(0);
+        for i in 0..10_000 {
+            b.set(format!("k{:05}", i).as_bytes(), b"value");
+        }
+        storage.batch_write(b).unwrap();
+        let estimate = storage.compaction_estimate();
+        assert!(estimate.num_live_keys > 0, "{:?}", estimate);
+        assert_eq!(estimate.num_deletions, 0);
+
+        let mut b = storage.batch_new(0);
+        for i in 0..10_000 {
+            b.del(format!("k{:05}", i).as_bytes());
+        }
+        storage.batch_write(b).unwrap();
+        let estimate = storage.compaction_estimate();
+        assert!(estimate.num_deletions > 0, "{:?}", estimate);
+    }
+
+    #[test]
+    fn test_load_factor() {
+        let _ = fs::remove_dir_all("t/test_load_factor");
+        let sm = StorageManager::new("t/test_load_factor").unwrap();
+        let storage = sm.open(1).unwrap();
+        let empty = storage.load_factor();
+        assert!(empty >= 0.0 && empty < 0.01, "{}", empty);
+
+        let value = vec![0u8; 1024];
+        let mut b = storage.batch_new(0);
+        for i in 0..4_000 {
+            b.set(format!("k{:05}", i).as_bytes(), &value);
+        }
+        storage.batch_write(b).unwrap();
+        let loaded = storage.load_factor();
+        assert!(loaded > empty && loaded <= 1.0, "{} {}", empty, loaded);
+
+        sm.barrier().unwrap();
+        let flushed = storage.load_factor();
+        assert!(flushed < loaded, "{} {}", loaded, flushed);
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        const EXPIRED: u8 = 0xAA;
+        let _ = fs::remove_dir_all("t/test_compaction_filter");
+        let sm = StorageManager::with_compaction_filter(
+            "t/test_compaction_filter",
+            &Default::default(),
+            Some(Box::new(|vnode: u16, _: &[u8], value: &[u8]| {
+                vnode != 1 || value.first() != Some(&EXPIRED)
+            })),
+        ).unwrap();
+        let storage = sm.open(1).unwrap();
+        let other = sm.open(2).unwrap();
+        for i in 0..100 {
+            let marker = if i % 2 == 0 { EXPIRED } else { 0 };
+            storage.set(i.to_string().as_bytes(), &[marker, 1, 2, 3]).unwrap();
+        }
+        other.set(b"0", &[EXPIRED]).unwrap();
+        storage.compact_range(b"", b"");
+        other.compact_range(b"", b"");
+
+        for i in 0..100 {
+            let value = storage.get_vec(i.to_string().as_bytes()).unwrap();
+            assert_eq!(value.is_none(), i % 2 == 0, "{}", i);
+        }
+        assert_eq!(other.get_vec(b"0").unwrap(), Some(vec![EXPIRED]));
+    }
+
+    #[test]
+    fn test_iterator_with_readahead() {
+        let _ = fs::remove_dir_all("t/test_iterator_with_readahead");
+        let sm = StorageManager::new("t/test_iterator_with_readahead").unwrap();
+        let storage = sm.open(1).unwrap();
+        let mut b = storage.batch_new(0);
+        b.set_many((0..1000).map(|i| (format!("{:04}", i), vec![i as u8; 1024])));
+        storage.batch_write(b).unwrap();
+        sm.open(2).unwrap().set(b"other", b"other").unwrap();
+        sm.barrier().unwrap();
+
+        let collect = |mut iterator: StorageIterator| -> Vec<(Vec<u8>, Vec<u8>)> {
+            let result = iterator
+                .iter()
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect();
+            iterator.status().unwrap();
+            result
+        };
+        let expected = collect(storage.iterator());
+        assert_eq!(expected.len(), 1000);
+        // way more than the data available
+        assert_eq!(collect(storage.iterator_with_readahead(256 * 1024 * 1024)), expected);
+        assert_eq!(collect(storage.iterator_with_readahead(4096)), expected);
+    }
+
+    #[test]
+    fn test_empty_key() {
+        let _ = fs::remove_dir_all("t/test_empty_key");
+        let sm = StorageManager::new("t/test_empty_key").unwrap();
+        let storage = sm.open(1).unwrap();
+        // neighbors in the previous and next vnodes
+        sm.open(0).unwrap().set(b"\xff\xff", b"prev").unwrap();
+        sm.open(2).unwrap().set(b"", b"next").unwrap();
+        assert_eq!(storage.get_vec(b"").unwrap(), None);
+
+        storage.set(b"", b"empty").unwrap();
+        storage.set(b"\x00", b"zero").unwrap();
+        assert_eq!(storage.get_vec(b"").unwrap(), Some(b"empty".to_vec()));
+        assert_eq!(storage.get_vec(b"\x00").unwrap(), Some(b"zero".to_vec()));
+        {
+            let mut iterator = storage.iterator();
+            let results: Vec<_> = iterator
+                .iter()
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect();
+            assert_eq!(
+                results,
+                vec![
+                    (b"".to_vec(), b"empty".to_vec()),
+                    (b"\x00".to_vec(), b"zero".to_vec())
+                ]
+            );
+        }
+
+        storage.del(b"").unwrap();
+        assert_eq!(storage.get_vec(b"").unwrap(), None);
+        assert_eq!(storage.get_vec(b"\x00").unwrap(), Some(b"zero".to_vec()));
+        assert_eq!(sm.open(2).unwrap().get_vec(b"").unwrap(), Some(b"next".to_vec()));
+    }
+
+    #[test]
+    fn test_iter_log_prefixes() {
+        let _ = fs::remove_dir_all("t/test_iter_log_prefixes");
+        let sm = StorageManager::new("t/test_iter_log_prefixes").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert_eq!(storage.iter_log_prefixes().count(), 0);
+        let mut b = storage.batch_new(0);
+        for &prefix in &[100u64, 0, 5] {
+            for i in 0..10 {
+                b.log_set((prefix, i), b"v");
+            }
+        }
+        storage.batch_write(b).unwrap();
+        // other vnodes aren't included
+        let other = sm.open(2).unwrap();
+        let mut b = other.batch_new(0);
+        b.log_set((7, 1), b"v");
+        other.batch_write(b).unwrap();
+
+        assert_eq!(
+            storage.iter_log_prefixes().collect::<Vec<_>>(),
+            vec![0, 5, 100]
+        );
+    }
+    #[test]
+    fn test_transaction_conflict() {
+        let _ = fs::remove_dir_all("t/test_transaction_conflict");
+        let sm = StorageManager::new("t/test_transaction_conflict").unwrap();
+        assert!(sm.open(1).unwrap().begin_transaction().is_err());
+        drop(sm);
+
+        let config = StorageConfig {
+            transactions: true,
+            ..Default::default()
+        };
+        let sm = StorageManager::with_config("t/test_transaction_conflict", &config).unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"counter", b"0").unwrap();
+
+        let mut t1 = storage.begin_transaction().unwrap();
+        let mut t2 = storage.begin_transaction().unwrap();
+        assert_eq!(t1.get_for_update(b"counter").unwrap(), Some(b"0".to_vec()));
+        assert_eq!(t2.get_for_update(b"counter").unwrap(), Some(b"0".to_vec()));
+        t1.put(b"counter", b"1");
+        t2.put(b"counter", b"1");
+        // reads see the transaction's own writes
+        assert_eq!(t1.get_for_update(b"counter").unwrap(), Some(b"1".to_vec()));
+        t1.commit().unwrap();
+        let err = t2.commit().unwrap_err();
+        assert!(err.downcast_ref::<TransactionConflict>().is_some());
+        assert_eq!(storage.get_vec(b"counter").unwrap(), Some(b"1".to_vec()));
+
+        // the retry sees the committed value
+        let mut t2 = storage.begin_transaction().unwrap();
+        assert_eq!(t2.get_for_update(b"counter").unwrap(), Some(b"1".to_vec()));
+        t2.put(b"counter", b"2");
+        t2.commit().unwrap();
+        assert_eq!(storage.get_vec(b"counter").unwrap(), Some(b"2".to_vec()));
+
+        // rolled back writes are discarded
+        let mut t3 = storage.begin_transaction().unwrap();
+        t3.del(b"counter");
+        t3.rollback();
+        assert_eq!(storage.get_vec(b"counter").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn test_wal_limits() {
+        let _ = fs::remove_dir_all("t/test_wal_limits");
+        let config = StorageConfig {
+            max_total_wal_size: Some(64 * 1024),
+            recycle_log_file_num: Some(2),
+            ..Default::default()
+        };
+        {
+            let sm = StorageManager::with_config("t/test_wal_limits", &config).unwrap();
+            let storage = sm.open(1).unwrap();
+            for i in 0..1_000u32 {
+                storage.set(i.to_string().as_bytes(), &[1u8; 1024]).unwrap();
+            }
+            assert_eq!(storage.iterator().iter().count(), 1_000);
+        }
+        // reopen replaying whatever is left in the wal
+        let sm = StorageManager::with_config("t/test_wal_limits", &config).unwrap();
+        let storage = sm.open(1).unwrap();
+        assert_eq!(storage.iterator().iter().count(), 1_000);
+
+        let config = StorageConfig {
+            max_total_wal_size: Some(0),
+            ..Default::default()
+        };
+        assert!(StorageManager::with_config("t/test_wal_limits_invalid", &config).is_err());
+    }
+
+    #[test]
+    fn test_pin_key() {
+        let _ = fs::remove_dir_all("t/test_pin_key");
+        let sm = StorageManager::new("t/test_pin_key").unwrap();
+        let storage = sm.open(1).unwrap();
+        assert!(storage.pin_key(b"hot").unwrap().is_none());
+        storage.set(b"hot", b"original").unwrap();
+        let pinned = storage.pin_key(b"hot").unwrap().unwrap();
+        storage.set(b"hot", b"overwritten").unwrap();
+        assert_eq!(&*pinned, b"original");
+        assert_eq!(storage.get_vec(b"hot").unwrap(), Some(b"overwritten".to_vec()));
+
+        // checksum headers aren't part of the value
+        let _ = fs::remove_dir_all("t/test_pin_key_checksum");
+        let config = StorageConfig {
+            value_checksums: true,
+            ..Default::default()
+        };
+        let sm = StorageManager::with_config("t/test_pin_key_checksum", &config).unwrap();
+        let storage = sm.open(1).unwrap();
+        storage.set(b"hot", b"checksummed").unwrap();
+        assert_eq!(&*storage.pin_key(b"hot").unwrap().unwrap(), b"checksummed");
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let _ = fs::remove_dir_all("t/test_disk_usage");
+        let sm = StorageManager::new("t/test_disk_usage").unwrap();
+        let big = sm.open(1).unwrap();
+        let small = sm.open(2).unwrap();
+        // random values so compression doesn't shrink them
+        let mut rng = XorShiftRng::from_seed([2; 16]);
+        for &(storage, count) in &[(&big, 1024), (&small, 256)] {
+            let mut b = storage.batch_new(0);
+            for i in 0..count {
+                let mut value = [0u8; 1024];
+                rng.fill(&mut value[..]);
+                b.set(format!("k{:04}", i).as_bytes(), &value);
+            }
+            storage.batch_write(b).unwrap();
+        }
+        assert_eq!(sm.open(3).unwrap().disk_usage(), 0);
+        sm.barrier().unwrap();
+
+        let big_usage = big.disk_usage();
+        let small_usage = small.disk_usage();
+        assert!(small_usage > 0);
+        // 4x the data, allowing for the estimate imprecision
+        assert!(
+            big_usage > 2 * small_usage && big_usage < 8 * small_usage,
+            "{} {}",
+            big_usage,
+            small_usage
+        );
+        assert_eq!(sm.open(3).unwrap().disk_usage(), 0);
+    }
+
+    #[test]
+    fn test_log_stride() {
+        let _ = fs::remove_dir_all("t/test_log_stride");
+        let sm = StorageManager::new("t/test_log_stride").unwrap();
+        let storage = sm.open(1).unwrap();
+        let mut b = storage.batch_new(0);
+        for i in 0..100u64 {
+            b.log_set((1, i), i.to_string().as_bytes());
+        }
+        storage.batch_write(b).unwrap();
+
+        let sampled: Vec<_> = storage.log_iterator(1, 0).with_stride(10).collect();
+        assert_eq!(
+            sampled,
+            (0..10u64)
+                .map(|i| ((1, i * 10), (i * 10).to_string().into_bytes()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(storage.log_iterator(1, 0).with_stride(1).count(), 100);
+        assert_eq!(storage.log_iterator(1, 0).with_stride(1000).count(), 1);
+    }
+
+    #[test]
+    fn test_block_cache_shard_bits() {
+        for &bits in &[0, 4, 7] {
+            let path = format!("t/test_block_cache_shard_bits_{}", bits);
+            let _ = fs::remove_dir_all(&path);
+            let config = StorageConfig {
+                block_cache_shard_bits: Some(bits),
+                ..Default::default()
+            };
+            let sm = StorageManager::with_config(&path, &config).unwrap();
+            let storage = sm.open(1).unwrap();
+            for i in 0..100u32 {
+                storage.set(i.to_string().as_bytes(), b"value").unwrap();
+            }
+            sm.barrier().unwrap();
+            for i in 0..100u32 {
+                assert_eq!(
+                    storage.get_vec(i.to_string().as_bytes()).unwrap(),
+                    Some(b"value".to_vec())
+                );
+            }
+        }
+
+        // 2^8 shards of a 64mb cache are too small
+        let config = StorageConfig {
+            block_cache_shard_bits: Some(8),
+            ..Default::default()
+        };
+        assert!(
+            StorageManager::with_config("t/test_block_cache_shard_bits_invalid", &config).is_err()
+        );
+    }
+
+    #[test]
+    fn test_level0_triggers() {
+        let _ = fs::remove_dir_all("t/test_level0_triggers");
+        let config = StorageConfig {
+            level0_file_num_compaction_trigger: Some(2),
+            level0_slowdown_writes_trigger: Some(40),
+            level0_stop_writes_trigger: Some(80),
+            ..Default::default()
+        };
+        let sm = StorageManager::with_config("t/test_level0_triggers", &config).unwrap();
+        let storage = sm.open(1).unwrap();
+        // each flush leaves a level 0 file
+        for round in 0..10 {
+            let mut b = storage.batch_new(0);
+            for i in 0..1_000 {
+                b.set(format!("k{:05}", round * 1_000 + i).as_bytes(), b"value");
+            }
+            storage.batch_write(b).unwrap();
+            sm.barrier().unwrap();
+        }
+        assert!(!storage.is_write_stalled());
+        assert_eq!(storage.iterator().iter().count(), 10_000);
+
+        // stop below slowdown, including the default one
+        for &(slowdown, stop) in &[(Some(10), Some(5)), (None, Some(10))] {
+            let config = StorageConfig {
+                level0_slowdown_writes_trigger: slowdown,
+                level0_stop_writes_trigger: stop,
+                ..Default::default()
+            };
+            assert!(StorageManager::with_config("t/test_level0_triggers", &config).is_err());
+        }
+    }
 }
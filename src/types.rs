@@ -29,7 +29,7 @@ impl fmt::Debug for Cookie {
 }
 
 /// Consistency Level as in Dynamo/Riak/Cassandra style
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ConsistencyLevel {
     One,
     Quorum,
@@ -70,3 +70,11 @@ impl ConsistencyLevel {
         }
     }
 }
+
+/// Cluster wide settings distributed with ConfigSync,
+/// unset fields fall back to the local config
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    pub consistency_read: Option<ConsistencyLevel>,
+    pub consistency_write: Option<ConsistencyLevel>,
+}
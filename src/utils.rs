@@ -1,6 +1,7 @@
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::hash::{BuildHasherDefault, Hasher};
+use std::time::{Duration, Instant};
 use std::{fmt, fs, io, path};
 
 pub type GenericError = Box<Error + Send + Sync + 'static>;
@@ -64,6 +65,22 @@ pub fn join_u64(hi: u32, lo: u32) -> u64 {
     ((hi as u64) << 32) | (lo as u64)
 }
 
+/// Millis left until `deadline`, 0 if it already passed.
+pub fn remaining_ms(deadline: Instant) -> u64 {
+    let now = Instant::now();
+    if deadline <= now {
+        return 0;
+    }
+    let left = deadline - now;
+    left.as_secs() * 1_000 + left.subsec_millis() as u64
+}
+
+/// Whether a request that `arrived` with `deadline` millis left (see remaining_ms)
+/// ran out of time, counting the time it waited since.
+pub fn deadline_passed(arrived: Instant, deadline: Option<u64>) -> bool {
+    deadline.map_or(false, |d| arrived + Duration::from_millis(d) <= Instant::now())
+}
+
 pub fn assume_str(bytes: &[u8]) -> &str {
     unsafe { ::std::str::from_utf8_unchecked(bytes) }
 }
@@ -1,3 +1,4 @@
+use bincode;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use linear_map::set::LinearSet;
 use linear_map::{self, Entry as LMEntry, LinearMap};
@@ -103,10 +104,15 @@ impl BitmappedVersion {
         }
     }
 
+    // folds the contiguous versions after base into it
     fn norm(&mut self) {
-        // TODO: amortize the cost of this
-        while self.bitmap.contains(self.base + 1) {
-            self.base += 1;
+        // the bitmap iterates in order, so stop at the first gap
+        for version in self.bitmap.iter() {
+            if version > self.base + 1 {
+                break;
+            } else if version == self.base + 1 {
+                self.base = version;
+            }
         }
         self.bitmap.remove_range(0..self.base + 1);
     }
@@ -323,6 +329,56 @@ impl BitmappedVersionVector {
         }
     }
 
+    /// Merges all bvvs in a single pass, each entry is only normalized once at the end.
+    /// Equivalent to folding with merge.
+    pub fn merge_all<'a, I: IntoIterator<Item = &'a Self>>(iter: I) -> Self {
+        let mut result = Self::new();
+        for other in iter {
+            for (&id, other_bv) in &other.0 {
+                let bv = result.0.entry(id).or_insert_with(Default::default);
+                bv.bitmap |= &other_bv.bitmap;
+                bv.base = cmp::max(bv.base, other_bv.base);
+            }
+        }
+        for (_, bv) in result.0.iter_mut() {
+            bv.norm();
+        }
+        result
+    }
+
+    /// Folds the contiguous versions in each bitmap into its base, shrinking the bitmaps.
+    /// Mutations already keep entries normalized, but ones built with
+    /// BitmappedVersion::new or deserialized from elsewhere may not be.
+    /// The set of versions contained is unchanged.
+    pub fn compact_contiguous(&mut self) {
+        for (_, bv) in self.0.iter_mut() {
+            bv.norm();
+        }
+    }
+
+    /// Like compact_contiguous but also drops the entries left empty, so a fully
+    /// contiguous bitmap ends up as a single counter. Contained versions are unchanged.
+    pub fn compact(&mut self) {
+        self.compact_contiguous();
+        let empty: Vec<Id> = self.0
+            .iter()
+            .filter(|&(_, bv)| bv.base == 0 && bv.bitmap.is_empty())
+            .map(|(&id, _)| id)
+            .collect();
+        for id in empty {
+            self.0.remove(&id);
+        }
+    }
+
+    /// Serialized bytes that compact would save.
+    pub fn compaction_savings(&self) -> usize {
+        let mut compacted = self.clone();
+        compacted.compact();
+        let before = bincode::serialized_size(self).unwrap();
+        let after = bincode::serialized_size(&compacted).unwrap();
+        before.saturating_sub(after) as usize
+    }
+
     pub fn event(&mut self, id: Id) -> Version {
         match self.0.entry(id) {
             LMEntry::Vacant(vac) => {
@@ -346,6 +402,38 @@ impl BitmappedVersionVector {
         self.0.get(&id).map_or(false, |bv| bv.contains_all(v))
     }
 
+    /// All dots from `node` in ascending order
+    pub fn dots_for<'a>(&'a self, node: Id) -> impl Iterator<Item = Version> + 'a {
+        let bv = self.0.get(&node);
+        let base = bv.map_or(0, |bv| bv.base);
+        (1..base + 1).chain(bv.into_iter().flat_map(|bv| bv.bitmap.iter()))
+    }
+
+    /// Highest version seen from `node`, including non-contiguous ones
+    pub fn max_counter(&self, node: Id) -> Option<Version> {
+        self.0
+            .get(&node)
+            .map(|bv| cmp::max(bv.base, bv.bitmap.max().unwrap_or(0)))
+            .filter(|&v| v != 0)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Number of non-contiguous versions (set bits in the bitmaps) across all nodes
+    pub fn total_entries(&self) -> u64 {
+        self.0.values().map(|bv| bv.bitmap.len()).sum()
+    }
+
+    /// Highest version seen across all nodes
+    pub fn max_version(&self) -> Option<Version> {
+        self.0
+            .keys()
+            .filter_map(|&node| self.max_counter(node))
+            .max()
+    }
+
     pub fn iter_mut(&mut self) -> linear_map::IterMut<Id, BitmappedVersion> {
         self.0.iter_mut()
     }
@@ -619,9 +707,22 @@ impl VersionVector {
         self.0.iter().map(|(&i, &v)| (i, v))
     }
 
+    /// All dots from `node` in ascending order
+    pub fn dots_for(&self, node: Id) -> impl Iterator<Item = Version> {
+        1..self.max_counter(node).unwrap_or(0) + 1
+    }
+
+    pub fn max_counter(&self, node: Id) -> Option<Version> {
+        self.0.get(&node).cloned().filter(|&v| v != 0)
+    }
+
     pub fn contained(&self, bvv: &BitmappedVersionVector) -> bool {
         self.0.iter().all(|(&i, &v)| bvv.contains_all(i, v))
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 }
 
 impl AbsVersionVector for VersionVector {
@@ -640,6 +741,10 @@ impl DotSet {
         result.0.insert(dot);
         result
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 }
 
 impl CausalValue for DotSet {
@@ -830,6 +935,24 @@ mod test_bv {
 mod test_bvv {
     use super::*;
 
+    #[test]
+    fn cardinality() {
+        let mut bvv = BitmappedVersionVector::new();
+        assert_eq!(bvv.node_count(), 0);
+        assert_eq!(bvv.total_entries(), 0);
+        assert_eq!(bvv.max_version(), None);
+
+        bvv.0.insert(1, BitmappedVersion::new(5, 0b1010));
+        bvv.0.insert(2, BitmappedVersion::new(7, 0));
+        bvv.0.insert(3, BitmappedVersion::new(0, 0b100000));
+        bvv.0.insert(4, BitmappedVersion::new(0, 0));
+        assert_eq!(bvv.node_count(), 4);
+        assert_eq!(bvv.total_entries(), 3);
+        // 3's bitmap has version 6, 1's bitmap has 7 and 9
+        assert_eq!(bvv.max_version(), Some(9));
+        assert_eq!(bvv.max_counter(3), Some(6));
+    }
+
     #[test]
     fn add_get() {
         let mut a = BitmappedVersionVector::new();
@@ -899,6 +1022,106 @@ mod test_bvv {
         assert_eq!(a.get(2).unwrap(), &BitmappedVersion::new(2, 4));
     }
 
+    #[test]
+    fn compact_contiguous() {
+        use bincode;
+        let mut bvv = BitmappedVersionVector::new();
+        bvv.0.insert(
+            1,
+            BitmappedVersion {
+                base: 0,
+                bitmap: (1..1001).collect(),
+            },
+        );
+        // a gap at 3, only 1..2 can be folded
+        bvv.0.insert(
+            2,
+            BitmappedVersion {
+                base: 0,
+                bitmap: [1, 2, 4, 5].iter().cloned().collect(),
+            },
+        );
+        let original = bvv.clone();
+        let original_size = bincode::serialize(&bvv).unwrap().len();
+        bvv.compact_contiguous();
+        let compacted_size = bincode::serialize(&bvv).unwrap().len();
+        assert!(compacted_size < 100, "{}", compacted_size);
+        assert!(compacted_size * 10 < original_size, "{} {}", compacted_size, original_size);
+        assert_eq!(bvv.get(1).unwrap(), &BitmappedVersion::new(1000, 0));
+        assert_eq!(bvv.get(2).unwrap(), &BitmappedVersion::new(2, 0b110));
+        for id in 1..3 {
+            for v in 0..1100 {
+                assert_eq!(bvv.contains(id, v), original.contains(id, v), "{} {}", id, v);
+            }
+        }
+    }
+
+    #[test]
+    fn compact() {
+        let mut bvv = BitmappedVersionVector::new();
+        bvv.0.insert(
+            1,
+            BitmappedVersion {
+                base: 0,
+                bitmap: (0..1000).collect(),
+            },
+        );
+        bvv.0.insert(2, BitmappedVersion::default());
+        let original = bvv.clone();
+        let original_size = bincode::serialize(&bvv).unwrap().len();
+        let savings = bvv.compaction_savings();
+        bvv.compact();
+        let compacted_size = bincode::serialize(&bvv).unwrap().len();
+        assert_eq!(original_size - compacted_size, savings);
+        assert!(compacted_size * 10 <= original_size, "{} {}", compacted_size, original_size);
+        assert_eq!(bvv.get(1).unwrap(), &BitmappedVersion::new(999, 0));
+        assert!(bvv.get(2).is_none());
+        assert_eq!(bvv.compaction_savings(), 0);
+        // versions start at 1
+        for id in 1..3 {
+            for v in 1..1100 {
+                assert_eq!(bvv.contains(id, v), original.contains(id, v), "{} {}", id, v);
+            }
+        }
+    }
+
+    #[test]
+    fn merge_all() {
+        use rand::{thread_rng, Rng};
+        let mut rng = thread_rng();
+        let bvvs: Vec<_> = (0..5)
+            .map(|_| {
+                let mut bvv = BitmappedVersionVector::new();
+                for id in 1..4 {
+                    bvv.0
+                        .insert(id, BitmappedVersion::new(rng.gen_range(0, 10), rng.gen()));
+                }
+                bvv
+            }).collect();
+        let folded = bvvs.iter().fold(BitmappedVersionVector::new(), |mut acc, b| {
+            acc.merge(b);
+            acc
+        });
+        assert_eq!(BitmappedVersionVector::merge_all(&bvvs), folded);
+        assert_eq!(
+            BitmappedVersionVector::merge_all(None),
+            BitmappedVersionVector::new()
+        );
+    }
+
+    #[test]
+    fn dots_for() {
+        let mut a = BitmappedVersionVector::new();
+        a.0.insert(1, BitmappedVersion::new(3, 0b101));
+        a.0.insert(2, BitmappedVersion::new(0, 0));
+        assert_eq!(a.dots_for(1).collect::<Vec<_>>(), vec![1, 2, 3, 4, 6]);
+        assert_eq!(a.max_counter(1), Some(6));
+        assert!(a.dots_for(2).next().is_none());
+        assert_eq!(a.max_counter(2), None);
+        assert!(a.dots_for(3).next().is_none());
+        assert_eq!(a.max_counter(3), None);
+    }
+
     #[test]
     fn event() {
         let mut a = BitmappedVersionVector::new();
@@ -1005,6 +1228,16 @@ mod test_vv {
         assert!(!a1.contains(2, 5));
         assert!(!a1.contains(3, 1));
     }
+
+    #[test]
+    fn dots_for() {
+        let mut a1 = VersionVector::new();
+        a1.add(1, 3);
+        assert_eq!(a1.dots_for(1).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(a1.max_counter(1), Some(3));
+        assert!(a1.dots_for(2).next().is_none());
+        assert_eq!(a1.max_counter(2), None);
+    }
 }
 //
 // #[cfg(test)]
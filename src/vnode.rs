@@ -1,4 +1,5 @@
 use bincode;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use bytes::Bytes;
 use command::CommandError;
 use cubes::*;
@@ -8,14 +9,40 @@ use hash::hash_slot;
 use inflightmap::InFlightMap;
 use rand::{thread_rng, Rng};
 use std::collections::hash_map::Entry as HMEntry;
-use std::time::{Duration, Instant};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::mem;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use storage::*;
-use utils::{join_u64, split_u64};
+use utils::{deadline_passed, join_u64, remaining_ms, split_u64};
 use utils::{replace_default, IdHashMap, IdHashSet, IdHasherBuilder};
 use version_vector::*;
 use vnode_sync::*;
 
 const ZOMBIE_TIMEOUT_MS: u64 = 60 * 1_000;
+// max number of remote set request ids remembered for deduplication
+const REMOTE_SET_DEDUP_MAX: usize = 10_000;
+// reserved key prefix of the locks in the vnode data
+const LOCK_KEY_PREFIX: &[u8] = b"\x00sucredb_lock\x00";
+// reserved key prefix of the append logs in the vnode data
+const APPEND_KEY_PREFIX: &[u8] = b"\x00sucredb_append\x00";
+
+/// Keys used by the database itself (locks, append logs), clients can't access them
+pub fn is_reserved_key(key: &[u8]) -> bool {
+    key.starts_with(LOCK_KEY_PREFIX) || key.starts_with(APPEND_KEY_PREFIX)
+}
+
+// delay suggested to coordinators when the storage is stalling writes
+pub const WRITE_STALL_RETRY_MS: u32 = 100;
+// max number of writes waiting for replica acks, the oldest are forgotten and never become stable
+const STABILITY_PENDING_MAX: usize = 100_000;
+
+// Milliseconds since the epoch, for expirations replicated to other nodes.
+// A clock set before the epoch counts as 0 instead of panicking.
+fn wall_clock_ms() -> u64 {
+    let now = UNIX_EPOCH.elapsed().unwrap_or_default();
+    now.as_secs() * 1_000 + (now.subsec_nanos() / 1_000_000) as u64
+}
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum VNodeStatus {
@@ -32,10 +59,37 @@ pub enum VNodeStatus {
     Absent,
 }
 
+/// Estimated replication lag of a vnode, based on the clocks of the last sync with each replica
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LagInfo {
+    /// local dots the most lagging replica isn't known to have
+    pub missing_dots: u64,
+    /// oldest of those dots, None if there's no lag
+    pub oldest_unsynced: Option<(NodeId, Version)>,
+    /// replicas without a finished sync to compare against
+    pub unknown_peers: Vec<NodeId>,
+}
+
 pub struct VNode {
     state: VNodeState,
     syncs: IdHashMap<Cookie, Synchronization>,
     requests: InFlightMap<Cookie, ReqState, Instant, IdHasherBuilder>,
+    // results of recent remote sets with request ids, kept for request_timeout
+    remote_sets: InFlightMap<(NodeId, u64), Result<Vec<Option<Cube>>, FabricError>, Instant>,
+    // results of recently applied remote sets with idempotency keys, kept for idempotency_ttl
+    idempotent_sets: InFlightMap<IdempotencyKey, Vec<Option<Cube>>, Instant>,
+    stability: Stability,
+    // remote sets rate limited or failed with retryable errors, resent once due (due, cookie, node)
+    set_retries: Vec<(Instant, Cookie, NodeId)>,
+    // number of upcoming remote sets to reject as if the storage was stalled
+    #[cfg(test)]
+    forced_write_stalls: u32,
+    // number of upcoming remote sets to reject as if the vnode was bootstrapping
+    #[cfg(test)]
+    forced_not_ready: u32,
+    // backoff delays of the retried remote sets
+    #[cfg(test)]
+    retry_delays: Vec<Duration>,
 }
 
 // This VNodeState id is based on the upper half of the actual node id
@@ -61,6 +115,8 @@ pub struct VNodeState {
     // state for syncs
     pub pending_bootstrap: bool,
     pub sync_nodes: IdHashSet<NodeId>,
+    // clocks each peer was known to have when the last sync with it finished
+    pub peer_clocks: IdHashMap<NodeId, BitmappedVersionVector>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -77,6 +133,13 @@ struct ReqState {
     required: u8,
     total: u8,
     context: Context,
+    // writes as sent to the replicas, resent by retries
+    sent: Vec<(Bytes, Cube, bool)>,
+    // nodes that already replied, later replies from them are ignored
+    replied: IdHashSet<NodeId>,
+    // attempt number of the last remote set sent to each node, if retried
+    attempts: IdHashMap<NodeId, u32>,
+    expire: Instant,
 }
 
 #[cfg(test)]
@@ -121,14 +184,114 @@ macro_rules! forward {
     };
 }
 
+// Tracks which of the writes coordinated by the vnode were acked by all its replicas.
+// Writes from before the current vnode id was first seen are taken as stable.
+#[derive(Default)]
+struct Stability {
+    id: NodeId,
+    // versions of `id` acked by all replicas
+    stable: BitmappedVersion,
+    // writes waiting for replica acks by their first version: (cookie, versions, replicas)
+    pending: BTreeMap<Version, (Cookie, Vec<Version>, Vec<NodeId>)>,
+    cookies: IdHashMap<Cookie, Version>,
+}
+
+impl Stability {
+    fn frontier(&self) -> VersionVector {
+        let mut frontier = VersionVector::new();
+        if self.id != 0 {
+            frontier.add(self.id, self.stable.base());
+        }
+        frontier
+    }
+
+    // returns whether the frontier advanced
+    fn track(
+        &mut self,
+        id: NodeId,
+        cookie: Cookie,
+        versions: Vec<Version>,
+        replicas: Vec<NodeId>,
+    ) -> bool {
+        let first = match versions.iter().min() {
+            Some(&first) => first,
+            None => return false,
+        };
+        if id != self.id {
+            self.id = id;
+            self.stable = BitmappedVersion::new(first - 1, 0);
+            self.pending.clear();
+            self.cookies.clear();
+        }
+        if replicas.is_empty() {
+            return self.stabilize(&versions);
+        }
+        while self.pending.len() >= STABILITY_PENDING_MAX {
+            let oldest = *self.pending.keys().next().unwrap();
+            let (cookie, _, _) = self.pending.remove(&oldest).unwrap();
+            self.cookies.remove(&cookie);
+        }
+        self.pending.insert(first, (cookie, versions, replicas));
+        self.cookies.insert(cookie, first);
+        false
+    }
+
+    // returns whether the frontier advanced
+    fn ack(&mut self, cookie: Cookie, from: NodeId) -> bool {
+        let first = match self.cookies.get(&cookie) {
+            Some(&first) => first,
+            None => return false,
+        };
+        let done = {
+            let replicas = &mut self.pending.get_mut(&first).unwrap().2;
+            replicas.retain(|&n| n != from);
+            replicas.is_empty()
+        };
+        if !done {
+            return false;
+        }
+        self.cookies.remove(&cookie);
+        let (_, versions, _) = self.pending.remove(&first).unwrap();
+        self.stabilize(&versions)
+    }
+
+    fn stabilize(&mut self, versions: &[Version]) -> bool {
+        let base = self.stable.base();
+        for &version in versions {
+            self.stable.add(version);
+        }
+        self.stable.base() != base
+    }
+
+    // replicas holding the frontier back and their number of unacked writes
+    fn blockers(&self) -> Vec<(NodeId, usize)> {
+        let mut blockers = IdHashMap::default();
+        for &(_, _, ref replicas) in self.pending.values() {
+            for &node in replicas {
+                *blockers.entry(node).or_insert(0) += 1;
+            }
+        }
+        blockers.into_iter().collect()
+    }
+}
+
 impl ReqState {
-    fn new(context: Context, nodes: usize, consistency: ConsistencyLevel) -> Self {
+    fn new(
+        context: Context,
+        nodes: usize,
+        consistency: ConsistencyLevel,
+        expire: Instant,
+    ) -> Self {
         ReqState {
             required: consistency.required(nodes as u8),
             total: nodes as u8,
             replies: 0,
             succesfull: 0,
             context,
+            sent: Vec::new(),
+            replied: Default::default(),
+            attempts: Default::default(),
+            expire: expire,
         }
     }
 
@@ -149,7 +312,17 @@ impl VNode {
         let mut vnode = VNode {
             state: state,
             requests: InFlightMap::new(),
+            remote_sets: InFlightMap::new(),
+            idempotent_sets: InFlightMap::new(),
+            set_retries: Vec::new(),
+            stability: Default::default(),
             syncs: Default::default(),
+            #[cfg(test)]
+            forced_write_stalls: 0,
+            #[cfg(test)]
+            forced_not_ready: 0,
+            #[cfg(test)]
+            retry_delays: Vec::new(),
         };
 
         match vnode.status() {
@@ -291,6 +464,17 @@ impl VNode {
             req.context.clear();
             db.respond_error(&mut req.context, CommandError::Timeout);
         }
+        while self.remote_sets.pop_expired(now).is_some() {}
+        while self.idempotent_sets.pop_expired(now).is_some() {}
+        if !self.set_retries.is_empty() {
+            let (due, pending): (Vec<_>, Vec<_>) = mem::replace(&mut self.set_retries, Vec::new())
+                .into_iter()
+                .partition(|&(at, _, _)| at <= now);
+            self.set_retries = pending;
+            for (_, cookie, node) in due {
+                self.retry_set(db, cookie, node);
+            }
+        }
 
         if self.state.pending_bootstrap {
             // check if there's a pending bootstrap we need to start
@@ -313,12 +497,38 @@ impl VNode {
         keys: &[&Bytes],
         consistency: ConsistencyLevel,
         response_fn: ResponseFn,
+    ) -> Result<(), CommandError> {
+        self.do_get_opt(db, context, keys, None, consistency, response_fn)
+    }
+
+    /// Like do_get but replicas only send back `field` of the map stored in `key`
+    pub fn do_get_field(
+        &mut self,
+        db: &Database,
+        context: &mut Context,
+        key: &Bytes,
+        field: &Bytes,
+        consistency: ConsistencyLevel,
+        response_fn: ResponseFn,
+    ) -> Result<(), CommandError> {
+        self.do_get_opt(db, context, &[key], Some(field), consistency, response_fn)
+    }
+
+    fn do_get_opt(
+        &mut self,
+        db: &Database,
+        context: &mut Context,
+        keys: &[&Bytes],
+        field: Option<&Bytes>,
+        consistency: ConsistencyLevel,
+        response_fn: ResponseFn,
     ) -> Result<(), CommandError> {
         debug!(
-            "vnode:{:?} do_get ({:?}) {:?}",
+            "vnode:{:?} do_get ({:?}) {:?} field {:?}",
             self.state.num(),
             context.token,
-            consistency
+            consistency,
+            field
         );
         let nodes = db.dht.nodes_for_vnode(self.state.num, false, true);
         if nodes.is_empty() {
@@ -332,7 +542,11 @@ impl VNode {
         let mut response_fn = Some(response_fn);
         for key in keys {
             let value = if participate {
-                self.state.storage_get(key).unwrap()
+                let value = self.state.storage_get(key).unwrap();
+                match field {
+                    Some(field) => value.project_field(field),
+                    None => value,
+                }
             } else {
                 Default::default()
             };
@@ -342,7 +556,7 @@ impl VNode {
             });
         }
 
-        let req = ReqState::new(replace_default(context), nodes.len(), consistency);
+        let req = ReqState::new(replace_default(context), nodes.len(), consistency, expire);
         self.requests.insert(cookie, req, expire);
 
         if participate {
@@ -352,10 +566,22 @@ impl VNode {
             }
         }
 
-        let msg = MsgRemoteGet {
-            cookie: cookie,
-            vnode: self.state.num,
-            keys: keys.iter().map(|&x| x.clone()).collect(),
+        let msg: FabricMsg = if let Some(field) = field {
+            MsgRemoteGetField {
+                cookie: cookie,
+                vnode: self.state.num,
+                key: keys[0].clone(),
+                field: field.clone(),
+                deadline: Some(remaining_ms(expire)),
+            }.into()
+        } else {
+            MsgRemoteGet {
+                cookie: cookie,
+                vnode: self.state.num,
+                keys: keys.iter().map(|&x| x.clone()).collect(),
+                trace_id: None,
+                deadline: Some(remaining_ms(expire)),
+            }.into()
         };
         for node in nodes {
             if node != db.dht.node() {
@@ -424,7 +650,15 @@ impl VNode {
             self.state.log_clocks.add(self.state.id, write.version);
             let mutator = write.mutator_fn.take().expect("No MutatorFn");
             match mutator(self.state.id, write.version, old_cube) {
-                Ok((cube, opt_resp)) => {
+                Ok((mut cube, opt_resp)) => {
+                    if let Some(max_len) = self.state.storage.cube_history() {
+                        cube.record_history(self.state.id, max_len);
+                    }
+                    // reject before it's stored or sent over the fabric
+                    if db.config.fabric_limits.check_value(&cube).is_err() {
+                        error = Some(CommandError::ValueTooLarge);
+                        break;
+                    }
                     write.cube = cube;
                     write.response = opt_resp;
                 }
@@ -436,6 +670,7 @@ impl VNode {
         }
 
         if let Some(e) = error {
+            self.stabilize_failed(db, context);
             return Err(e);
         }
 
@@ -451,10 +686,26 @@ impl VNode {
                 .map(|w| (w.version, &w.key[..], &w.cube)),
         ) {
             Ok(()) => (),
-            Err(e) => return Err(e),
+            Err(e) => {
+                self.stabilize_failed(db, context);
+                return Err(e);
+            }
         };
 
-        // The code bellow is carefully ordered to move Cubes around without cloning
+        // the writes become stable once all other replicas ack them,
+        // replicas don't ack writes at One so those hold the frontier back
+        let versions = context.writes.iter().map(|w| w.version).collect();
+        let replicas = nodes
+            .iter()
+            .cloned()
+            .filter(|&n| n != db.dht.node())
+            .collect();
+        if self.stability.track(self.state.id, cookie, versions, replicas) {
+            db.notify_stable_frontier(self.state.num, self.stability.frontier());
+        }
+
+        // The code bellow is carefully ordered to move Cubes around,
+        // they're only cloned once to be kept for retries
 
         // 1. move the cubes to the msg
         let msg = MsgRemoteSet {
@@ -466,17 +717,23 @@ impl VNode {
                 .map(|w| (w.key.clone(), replace_default(&mut w.cube), w.reply_result))
                 .collect(),
             reply: consistency != ConsistencyLevel::One,
+            request_id: None,
+            idempotency_key: None,
+            expected_version: None,
+            trace_id: None,
+            deadline: Some(remaining_ms(expire)),
         };
 
         // 2. create reqstate, note that writes have have nil cubes at this point
-        let req = ReqState::new(replace_default(context), nodes.len(), consistency);
+        let mut req = ReqState::new(replace_default(context), nodes.len(), consistency, expire);
+        req.sent = msg.writes.clone();
         self.requests.insert(cookie, req, expire);
 
         // 3. send the msgs
         for &node in &nodes {
             if node != db.dht.node() {
                 if let Err(err) = db.fabric.send_msg(node, &msg) {
-                    if self.process_set::<Option<_>>(db, cookie, Err(err)) {
+                    if self.process_set::<Option<_>>(db, cookie, node, Err(err)) {
                         return Ok(());
                     }
                 }
@@ -484,11 +741,65 @@ impl VNode {
         }
 
         // 4. get back the cubes from msg and process_set
-        self.process_set(db, cookie, Ok(msg.writes.into_iter().map(|w| Some(w.1))));
+        let node = db.dht.node();
+        self.process_set(db, cookie, node, Ok(msg.writes.into_iter().map(|w| Some(w.1))));
 
         Ok(())
     }
 
+    // versions consumed by writes that failed before being replicated
+    fn stabilize_failed(&mut self, db: &Database, context: &Context) {
+        let versions = context
+            .writes
+            .iter()
+            .map(|w| w.version)
+            .filter(|&v| v != 0)
+            .collect();
+        if self
+            .stability
+            .track(self.state.id, Default::default(), versions, Vec::new())
+        {
+            db.notify_stable_frontier(self.state.num, self.stability.frontier());
+        }
+    }
+
+    /// Writes coordinated by this vnode up to the frontier were acked by all replicas
+    pub fn stable_frontier(&self) -> VersionVector {
+        self.stability.frontier()
+    }
+
+    /// Replicas holding the stable frontier back, with their number of unacked writes
+    pub fn stable_frontier_blockers(&self) -> Vec<(NodeId, usize)> {
+        self.stability.blockers()
+    }
+
+    /// Lag of the replicas compared to the local clocks, as of their last finished sync.
+    /// Writes replicated since then count as lag until the next sync.
+    pub fn replication_lag(&self, db: &Database) -> LagInfo {
+        let mut lag = LagInfo::default();
+        let node = db.dht.node();
+        for peer in db.dht.nodes_for_vnode(self.state.num, true, false) {
+            if peer == node {
+                continue;
+            }
+            let peer_clocks = match self.state.peer_clocks.get(&peer) {
+                Some(peer_clocks) => peer_clocks,
+                None => {
+                    lag.unknown_peers.push(peer);
+                    continue;
+                }
+            };
+            let delta = self.state.clocks.delta(peer_clocks);
+            let oldest_unsynced = delta.min_versions().iter().min_by_key(|&&(_, v)| v).cloned();
+            let missing_dots = delta.count() as u64;
+            if missing_dots > lag.missing_dots {
+                lag.missing_dots = missing_dots;
+                lag.oldest_unsynced = oldest_unsynced;
+            }
+        }
+        lag
+    }
+
     // OTHER
     fn process_get<I: IntoIterator<Item = Cube>>(
         &mut self,
@@ -539,12 +850,18 @@ impl VNode {
         &mut self,
         db: &Database,
         cookie: Cookie,
+        from: NodeId,
         response: Result<I, FabricError>,
     ) -> bool {
         if let HMEntry::Occupied(mut o) = self.requests.entry(cookie) {
             debug!("process_set {:?}", cookie);
             let done = {
                 let state = o.get_mut();
+                // retries may reach a node twice, each replica counts once towards the quorum
+                if !state.replied.insert(from) {
+                    debug!("process_set {:?} ignoring duplicated reply from {}", cookie, from);
+                    return false;
+                }
                 state.replies += 1;
                 if let Ok(response) = response {
                     state.succesfull += 1;
@@ -585,89 +902,726 @@ impl VNode {
     }
 
     // CRUD HANDLERS
-    pub fn handler_get_remote_ack(&mut self, db: &Database, _from: NodeId, msg: MsgRemoteGetAck) {
+    pub fn handler_get_remote_ack(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGetAck) {
+        if let Some(trace_id) = msg.trace_id {
+            debug!("Remote get ack {:?} from {} trace_id {:?}", msg.cookie, from, trace_id);
+        }
         self.process_get(db, msg.cookie, msg.result);
     }
 
-    pub fn handler_get_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGet) {
-        // accept zombie to reduce chance of timeouts due to races on cluster change
-        check_status!(
-            self,
-            VNodeStatus::Ready | VNodeStatus::Zombie,
-            db,
-            from,
-            msg,
-            MsgRemoteGetAck,
-            inflight_get
-        );
-        let mut result = Vec::with_capacity(msg.keys.len());
-        for key in &msg.keys {
-            let value = self
-                .state
-                .storage_get(&key)
-                .map_err(|_| FabricError::StorageError)
-                .unwrap();
-            result.push(value);
+    pub fn handler_get_remote(
+        &mut self,
+        db: &Database,
+        from: NodeId,
+        msg: MsgRemoteGet,
+        arrived: Instant,
+    ) {
+        if let Some(trace_id) = msg.trace_id {
+            debug!("Remote get {:?} from {} trace_id {:?}", msg.cookie, from, trace_id);
         }
+        let result = if deadline_passed(arrived, msg.deadline) {
+            debug!("Remote get {:?} from {} expired", msg.cookie, from);
+            Err(FabricError::Expired)
+        } else {
+            self.get_remote(&msg.keys)
+        };
+        let version = result.as_ref().ok().map(|cubes| {
+            let mut version = BitmappedVersionVector::new();
+            for cube in cubes {
+                version.merge(&cube.causal_version());
+            }
+            version
+        });
         let _ = db.fabric.send_msg(
             from,
             &MsgRemoteGetAck {
                 cookie: msg.cookie,
                 vnode: msg.vnode,
-                result: Ok(result),
+                result: result,
+                trace_id: msg.trace_id,
+                version: version,
             },
         );
     }
 
-    pub fn handler_set_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteSet) {
-        check_status!(
-            self,
-            VNodeStatus::Ready | VNodeStatus::Bootstrap,
+    pub fn handler_get_field_remote(
+        &mut self,
+        db: &Database,
+        from: NodeId,
+        msg: MsgRemoteGetField,
+        arrived: Instant,
+    ) {
+        let MsgRemoteGetField {
+            vnode,
+            cookie,
+            key,
+            field,
+            deadline,
+        } = msg;
+        let result = if deadline_passed(arrived, deadline) {
+            debug!("Remote get field {:?} from {} expired", cookie, from);
+            Err(FabricError::Expired)
+        } else {
+            self.get_remote(&[key])
+                .map(|cubes| cubes.into_iter().map(|c| c.project_field(&field)).collect())
+        };
+        let _ = db.fabric.send_msg(
+            from,
+            &MsgRemoteGetAck {
+                cookie: cookie,
+                vnode: vnode,
+                result: result,
+                trace_id: None,
+                version: None,
+            },
+        );
+    }
+
+    pub fn get_remote(&self, keys: &[Bytes]) -> Result<Vec<Cube>, FabricError> {
+        match self.status() {
+            // accept zombie to reduce chance of timeouts due to races on cluster change
+            VNodeStatus::Ready | VNodeStatus::Zombie => (),
+            // still streaming data in, reads could look authoritative but be stale/empty
+            VNodeStatus::Bootstrap => return Err(FabricError::NotReady),
+            status => {
+                debug!(
+                    "Incorrect state for remote get in vnode {}, was {:?}",
+                    self.state.num, status
+                );
+                return Err(FabricError::BadVNodeStatus);
+            }
+        }
+        keys.iter()
+            .map(|key| {
+                self.state
+                    .storage_get(key)
+                    .map_err(|_| FabricError::StorageError)
+            }).collect()
+    }
+
+    /// Reads up to `limit` keys in [start, end) after `cursor`, see MsgRemoteGetRange.
+    /// Reserved keys are skipped.
+    pub fn get_range(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: u32,
+        cursor: Option<&[u8]>,
+    ) -> Result<RangePage, FabricError> {
+        self.read_range(start, end, limit, cursor, false)
+    }
+
+    fn read_range(
+        &self,
+        start: &[u8],
+        end: &[u8],
+        limit: u32,
+        cursor: Option<&[u8]>,
+        reserved: bool,
+    ) -> Result<RangePage, FabricError> {
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Zombie => (),
+            VNodeStatus::Bootstrap => return Err(FabricError::NotReady),
+            status => {
+                debug!("Can't read range in vnode {} with status {:?}", self.state.num, status);
+                return Err(FabricError::BadVNodeStatus);
+            }
+        }
+        if limit == 0 {
+            return Ok((Vec::new(), cursor.map(Bytes::from)));
+        }
+        let mut iterator = self.state.storage.iterator_from(cursor.unwrap_or(start));
+        let mut entries = Vec::new();
+        let mut more = false;
+        for (key, value) in iterator.iter() {
+            if cursor.map_or(false, |c| key <= c) || key < start {
+                continue;
+            }
+            if !reserved && is_reserved_key(key) {
+                continue;
+            }
+            if !end.is_empty() && key >= end {
+                break;
+            }
+            if entries.len() == limit as usize {
+                more = true;
+                break;
+            }
+            let cube = Cube::from_storage(value).map_err(|_| FabricError::StorageError)?;
+            entries.push((Bytes::from(key), cube));
+        }
+        iterator.status().map_err(|_| FabricError::StorageError)?;
+        let next_cursor = if more {
+            entries.last().map(|&(ref k, _)| k.clone())
+        } else {
+            None
+        };
+        Ok((entries, next_cursor))
+    }
+
+    pub fn handler_get_range_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteGetRange) {
+        let cursor = msg.cursor.as_ref().map(|c| &c[..]);
+        let result = self.get_range(&msg.start, &msg.end, msg.limit, cursor);
+        let (result, next_cursor) = match result {
+            Ok((entries, next_cursor)) => (Ok(entries), next_cursor),
+            Err(e) => (Err(e), None),
+        };
+        let _ = db.fabric.send_msg(
+            from,
+            &MsgRemoteGetRangeAck {
+                cookie: msg.cookie,
+                result: result,
+                next_cursor: next_cursor,
+            },
+        );
+    }
+
+    pub fn handler_compare_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteCompare) {
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Zombie => (),
+            status => {
+                debug!("Can't compare in vnode {} with status {:?}", self.state.num, status);
+                return;
+            }
+        }
+        let actual = match self.state.storage_get(&msg.key) {
+            Ok(actual) => actual,
+            Err(_) => {
+                error!("Can't compare {:?}, storage error", msg.key);
+                return;
+            }
+        };
+        let _ = db.fabric.send_msg(
+            from,
+            &MsgRemoteCompareAck::new(msg.vnode, msg.cookie, &msg.expected, actual),
+        );
+    }
+
+    pub fn handler_compare_remote_ack(
+        &mut self,
+        _db: &Database,
+        from: NodeId,
+        msg: MsgRemoteCompareAck,
+    ) {
+        if msg.matches {
+            debug!("Value in vnode {} of node {} matches", msg.vnode, from);
+        } else {
+            warn!(
+                "Value in vnode {} of node {} diverged: {:?}",
+                msg.vnode, from, msg.actual
+            );
+        }
+    }
+
+    // Locks are kept in the vnode data under a reserved key, so they're replicated
+    // (and synced) like any other key and a new lock primary finds them on failover.
+    fn lock_data_key(lock_key: &[u8]) -> Bytes {
+        let mut key = LOCK_KEY_PREFIX.to_vec();
+        key.extend_from_slice(lock_key);
+        Bytes::from(key)
+    }
+
+    // live values of a reserved key, more than one if written concurrently
+    fn read_internal(&self, key: &[u8]) -> Result<Vec<Bytes>, FabricError> {
+        let cube = self
+            .state
+            .storage_get(key)
+            .map_err(|_| FabricError::StorageError)?;
+        let value = cube.into_value().ok_or(FabricError::StorageError)?;
+        let values = value.live_values().cloned().collect();
+        Ok(values)
+    }
+
+    // Overwrites a reserved key as its coordinator, replicating it like do_flush
+    // but without waiting for the other replicas.
+    fn write_internal(
+        &mut self,
+        db: &Database,
+        key: Bytes,
+        value: Option<Bytes>,
+    ) -> Result<(), FabricError> {
+        let cube = self
+            .state
+            .storage_get(&key)
+            .map_err(|_| FabricError::StorageError)?;
+        let mut cube = cube.into_value().ok_or(FabricError::StorageError)?;
+        let version = self.state.clocks.event(self.state.id);
+        self.state.log_clocks.add(self.state.id, version);
+        cube.overwrite(self.state.id, version, value);
+        let cube = Cube::Value(cube);
+        let stored = self
+            .state
+            .storage_set_local(db, Some((version, &key[..], &cube)).into_iter());
+
+        let cookie = self.gen_cookie();
+        let node = db.dht.node();
+        let replicas: Vec<_> = db
+            .dht
+            .nodes_for_vnode(self.state.num, true, true)
+            .into_iter()
+            .filter(|&n| n != node)
+            .collect();
+        if stored.is_err() {
+            // the version is consumed nonetheless
+            if self
+                .stability
+                .track(self.state.id, Default::default(), vec![version], Vec::new())
+            {
+                db.notify_stable_frontier(self.state.num, self.stability.frontier());
+            }
+            return Err(FabricError::StorageError);
+        }
+        if self
+            .stability
+            .track(self.state.id, cookie, vec![version], replicas.clone())
+        {
+            db.notify_stable_frontier(self.state.num, self.stability.frontier());
+        }
+        let msg = MsgRemoteSet {
+            cookie: cookie,
+            vnode: self.state.num,
+            writes: vec![(key, cube, false)],
+            reply: true,
+            request_id: None,
+            idempotency_key: None,
+            expected_version: None,
+            trace_id: None,
+            deadline: None,
+        };
+        for replica in replicas {
+            let _ = db.fabric.send_msg(replica, &msg);
+        }
+        Ok(())
+    }
+
+    /// Grants the lock if it's free or expired, the caller must be the lock primary
+    pub fn acquire_lock(
+        &mut self,
+        db: &Database,
+        lock_key: &[u8],
+        ttl_ms: u32,
+        owner: NodeId,
+    ) -> Result<LockToken, FabricError> {
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Zombie => (),
+            status => {
+                debug!("Can't lock in vnode {} with status {:?}", self.state.num, status);
+                return Err(FabricError::BadVNodeStatus);
+            }
+        }
+        let now_ms = wall_clock_ms();
+        let data_key = Self::lock_data_key(lock_key);
+        for current in self.read_internal(&data_key)? {
+            let (_, expires_at): (LockToken, u64) =
+                bincode::deserialize(&current).map_err(|_| FabricError::StorageError)?;
+            if expires_at > now_ms {
+                return Err(FabricError::LockHeld);
+            }
+        }
+        let token = LockToken {
+            vnode: self.state.num,
+            lock_key: Bytes::from(lock_key),
+            owner: owner,
+            id: thread_rng().gen(),
+        };
+        let value = bincode::serialize(&(&token, now_ms + ttl_ms as u64)).unwrap();
+        self.write_internal(db, data_key, Some(Bytes::from(value)))?;
+        Ok(token)
+    }
+
+    /// Releases the lock if still owned by token
+    pub fn release_lock(&mut self, db: &Database, token: &LockToken) {
+        let data_key = Self::lock_data_key(&token.lock_key);
+        let owned = match self.read_internal(&data_key) {
+            Ok(values) => values.iter().any(|v| {
+                bincode::deserialize::<(LockToken, u64)>(v)
+                    .map(|(ref current, _)| current == token)
+                    .unwrap_or(false)
+            }),
+            Err(e) => return error!("Can't read lock {:?}: {:?}", token.lock_key, e),
+        };
+        if !owned {
+            return debug!("Lock {:?} isn't owned by {:?}", token.lock_key, token);
+        }
+        if let Err(e) = self.write_internal(db, data_key, None) {
+            error!("Can't release lock {:?}: {:?}", token.lock_key, e);
+        }
+    }
+
+    // Like locks the append logs are kept in the vnode data, the counter is at the
+    // bare prefix and each value at the prefix followed by its sequence number.
+    fn append_data_key(prefix: u64, seq: Option<u64>) -> Bytes {
+        let mut key = APPEND_KEY_PREFIX.to_vec();
+        key.write_u64::<BigEndian>(prefix).unwrap();
+        if let Some(seq) = seq {
+            key.write_u64::<BigEndian>(seq).unwrap();
+        }
+        Bytes::from(key)
+    }
+
+    /// Appends value to the log `prefix`, returning its sequence number (starting at 1).
+    /// The caller must be the lock primary, the read-modify-write of the counter
+    /// is serialized by the vnode.
+    pub fn atomic_append(
+        &mut self,
+        db: &Database,
+        prefix: u64,
+        value: &[u8],
+    ) -> Result<u64, FabricError> {
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Zombie => (),
+            status => {
+                debug!("Can't append in vnode {} with status {:?}", self.state.num, status);
+                return Err(FabricError::BadVNodeStatus);
+            }
+        }
+        let counter_key = Self::append_data_key(prefix, None);
+        // concurrent counters may exist after a failover, continue from the highest
+        let mut last = 0u64;
+        for counter in self.read_internal(&counter_key)? {
+            let counter: u64 =
+                bincode::deserialize(&counter).map_err(|_| FabricError::StorageError)?;
+            last = cmp::max(last, counter);
+        }
+        let seq = last + 1;
+        self.write_internal(
             db,
+            Self::append_data_key(prefix, Some(seq)),
+            Some(Bytes::from(value)),
+        )?;
+        let counter = bincode::serialize(&seq).unwrap();
+        self.write_internal(db, counter_key, Some(Bytes::from(counter)))?;
+        Ok(seq)
+    }
+
+    /// Reads up to `limit` values of the log `prefix` starting at sequence number `from_seq`
+    pub fn read_appended(
+        &self,
+        prefix: u64,
+        from_seq: u64,
+        limit: u32,
+    ) -> Result<Vec<(u64, Bytes)>, FabricError> {
+        let start = Self::append_data_key(prefix, Some(from_seq));
+        let end = Self::append_data_key(prefix, Some(u64::max_value()));
+        let (entries, _) = self.read_range(&start, &end, limit, None, true)?;
+        let mut values = Vec::with_capacity(entries.len());
+        for (key, cube) in entries {
+            let seq = BigEndian::read_u64(&key[key.len() - 8..]);
+            let value = cube.into_value().ok_or(FabricError::StorageError)?;
+            if let Some(v) = value.live_values().next() {
+                values.push((seq, v.clone()));
+            }
+        }
+        Ok(values)
+    }
+
+    pub fn handler_atomic_append_remote(
+        &mut self,
+        db: &Database,
+        from: NodeId,
+        msg: MsgRemoteAtomicAppend,
+    ) {
+        let result = self.atomic_append(db, msg.prefix, &msg.value);
+        let _ = db.fabric.send_msg(
             from,
-            msg,
-            MsgRemoteSetAck,
-            inflight_set
+            &MsgRemoteAtomicAppendAck {
+                cookie: msg.cookie,
+                result: result,
+            },
+        );
+    }
+
+    pub fn handler_lock_remote(&mut self, db: &Database, from: NodeId, msg: MsgRemoteLock) {
+        let result = self.acquire_lock(db, &msg.lock_key, msg.ttl_ms, msg.owner);
+        let _ = db.fabric.send_msg(
+            from,
+            &MsgRemoteLockAck {
+                cookie: msg.cookie,
+                result: result,
+            },
         );
+    }
+
+    pub fn handler_unlock_remote(&mut self, db: &Database, _from: NodeId, msg: MsgRemoteUnlock) {
+        self.release_lock(db, &msg.token);
+    }
+
+    pub fn handler_set_remote(
+        &mut self,
+        db: &Database,
+        from: NodeId,
+        msg: MsgRemoteSet,
+        arrived: Instant,
+    ) {
         let MsgRemoteSet {
             writes,
             vnode,
             cookie,
             reply,
+            request_id,
+            idempotency_key,
+            expected_version,
+            trace_id,
+            deadline,
         } = msg;
+        if let Some(trace_id) = trace_id {
+            debug!("Remote set {:?} from {} trace_id {:?}", cookie, from, trace_id);
+        }
+        let send_ack = |result: Result<Vec<Option<Cube>>, FabricError>| {
+            let _ = db.fabric.send_msg(
+                from,
+                &MsgRemoteSetAck {
+                    vnode: vnode,
+                    cookie: cookie,
+                    result: result,
+                    trace_id: trace_id,
+                },
+            );
+        };
+        match self.status() {
+            VNodeStatus::Ready | VNodeStatus::Bootstrap => (),
+            status => {
+                debug!(
+                    "Incorrect state for remote set[{:?}] expected Ready | Bootstrap was {:?}",
+                    cookie, status
+                );
+                return send_ack(Err(FabricError::BadVNodeStatus));
+            }
+        }
+        if deadline_passed(arrived, deadline) {
+            debug!("Remote set {:?} from {} expired", cookie, from);
+            return send_ack(Err(FabricError::Expired));
+        }
+        #[cfg(test)]
+        {
+            if self.forced_not_ready != 0 {
+                self.forced_not_ready -= 1;
+                return send_ack(Err(FabricError::NotReady));
+            }
+        }
+        let dedup_key = request_id.map(|id| (from, id));
+        if let Some(result) = dedup_key.and_then(|k| self.remote_sets.get(&k)) {
+            debug!("Duplicated remote set {:?} from {}", request_id, from);
+            if reply {
+                send_ack(result.clone());
+            }
+            return;
+        }
+        if let Some(result) = idempotency_key.and_then(|k| self.idempotent_sets.get(&k)) {
+            debug!("Remote set {:?} from {} already applied", idempotency_key, from);
+            if reply {
+                send_ack(Ok(result.clone()));
+            }
+            return;
+        }
+        if self.write_stalled() {
+            debug!("Rate limiting remote set {:?} from {}, writes are stalled", cookie, from);
+            if reply {
+                send_ack(Err(FabricError::RateLimited {
+                    retry_after_ms: WRITE_STALL_RETRY_MS,
+                }));
+            }
+            return;
+        }
         // Is this really ok?
         // This optimization prevents a class of errors (storage errrors..)
         // from propagating to the coordinator
         // if reply && !reply_result {
-        //     let _ = db.fabric.send_msg(
-        //         from,
-        //         &MsgRemoteSetAck {
-        //             vnode: vnode,
-        //             cookie: cookie,
-        //             result: Ok(None),
-        //         },
-        //     );
+        //     send_ack(Ok(None));
         // }
-        let result = self
-            .state
-            .storage_set_remote(db, writes)
-            .map_err(|_| FabricError::StorageError);
+        let result = if let Some(e) = writes
+            .iter()
+            .filter_map(|w| db.config.fabric_limits.check_value(&w.1).err())
+            .next()
+        {
+            Err(e)
+        } else if let Err(e) = self.check_expected_version(&writes, expected_version.as_ref()) {
+            Err(e)
+        } else {
+            self.state
+                .storage_set_remote(db, writes)
+                .map_err(|_| FabricError::StorageError)
+        };
+        if let Some(key) = dedup_key {
+            self.remember_remote_set(db, key, result.clone());
+        }
+        if let (Some(key), &Ok(ref applied)) = (idempotency_key, &result) {
+            self.remember_idempotent_set(db, key, applied.clone());
+        }
         if
         /*reply_result && */
         reply {
-            let _ = db.fabric.send_msg(
-                from,
-                &MsgRemoteSetAck {
-                    vnode: vnode,
-                    cookie: cookie,
-                    result: result,
-                },
+            send_ack(result);
+        }
+    }
+
+    // writes conditioned on a version read earlier (see MsgRemoteGetAck::version)
+    // are refused if any of the keys changed in the meantime
+    fn check_expected_version(
+        &self,
+        writes: &[(Bytes, Cube, bool)],
+        expected: Option<&BitmappedVersionVector>,
+    ) -> Result<(), FabricError> {
+        let expected = if let Some(expected) = expected {
+            expected
+        } else {
+            return Ok(());
+        };
+        let mut current = BitmappedVersionVector::new();
+        for &(ref key, _, _) in writes {
+            let cube = self
+                .state
+                .storage_get(key)
+                .map_err(|_| FabricError::StorageError)?;
+            current.merge(&cube.causal_version());
+        }
+        if &current == expected {
+            Ok(())
+        } else {
+            debug!("Version mismatch for remote set, {:?} != {:?}", current, expected);
+            Err(FabricError::VersionMismatch)
+        }
+    }
+
+    fn remember_remote_set(
+        &mut self,
+        db: &Database,
+        key: (NodeId, u64),
+        result: Result<Vec<Option<Cube>>, FabricError>,
+    ) {
+        let expire = Instant::now() + Duration::from_millis(db.config.request_timeout as _);
+        // all entries expire before `expire`, so this evicts the oldest ones
+        while self.remote_sets.len() >= REMOTE_SET_DEDUP_MAX
+            && self.remote_sets.pop_expired(expire).is_some()
+        {}
+        self.remote_sets.insert(key, result, expire);
+    }
+
+    fn remember_idempotent_set(
+        &mut self,
+        db: &Database,
+        key: IdempotencyKey,
+        result: Vec<Option<Cube>>,
+    ) {
+        let expire = Instant::now() + Duration::from_millis(db.config.idempotency_ttl as _);
+        // same as remember_remote_set, the oldest entries are evicted first
+        while self.idempotent_sets.len() >= db.config.idempotency_cache_size as usize
+            && self.idempotent_sets.pop_expired(expire).is_some()
+        {}
+        self.idempotent_sets.insert(key, result, expire);
+    }
+
+    pub fn handler_set_remote_ack(&mut self, db: &Database, from: NodeId, msg: MsgRemoteSetAck) {
+        if let Some(trace_id) = msg.trace_id {
+            debug!("Remote set ack {:?} from {} trace_id {:?}", msg.cookie, from, trace_id);
+        }
+        if msg.result.is_ok() && self.stability.ack(msg.cookie, from) {
+            db.notify_stable_frontier(self.state.num, self.stability.frontier());
+        }
+        if let Err(FabricError::RateLimited { retry_after_ms }) = msg.result {
+            if self.requests.contains_key(&msg.cookie) {
+                debug!(
+                    "Remote set {:?} rate limited by {}, retrying in {}ms",
+                    msg.cookie, from, retry_after_ms
+                );
+                let due = Instant::now() + Duration::from_millis(retry_after_ms as _);
+                self.set_retries.push((due, msg.cookie, from));
+                return;
+            }
+        }
+        if let Err(ref err) = msg.result {
+            if err.is_retryable() && self.schedule_set_retry(db, msg.cookie, from, err) {
+                return;
+            }
+        }
+        self.process_set(db, msg.cookie, from, msg.result);
+    }
+
+    // backs off according to the retry policy, returns false if the attempts are exhausted
+    fn schedule_set_retry(
+        &mut self,
+        db: &Database,
+        cookie: Cookie,
+        from: NodeId,
+        err: &FabricError,
+    ) -> bool {
+        let node = db.dht.node();
+        let target = {
+            let mut entry = match self.requests.entry(cookie) {
+                HMEntry::Occupied(o) => o,
+                HMEntry::Vacant(_) => return false,
+            };
+            let req = entry.get_mut();
+            let attempt = req.attempts.remove(&from).unwrap_or(1) + 1;
+            let delay = match db.config.fabric_retry.delay(attempt) {
+                Some(delay) => delay,
+                None => return false,
+            };
+            // prefer another replica if the error is specific to the one that replied
+            let mut target = from;
+            if err.is_host_specific() {
+                let mut others = db.dht.nodes_for_vnode(self.state.num, true, true);
+                // replicas that already replied have the writes
+                others.retain(|&n| {
+                    n != node
+                        && n != from
+                        && !req.attempts.contains_key(&n)
+                        && !req.replied.contains(&n)
+                });
+                if let Some(&other) = thread_rng().choose(&others) {
+                    target = other;
+                }
+            }
+            debug!(
+                "Remote set {:?} failed in {} with {:?}, retrying in {} in {:?}",
+                cookie, from, err, target, delay
             );
+            req.attempts.insert(target, attempt);
+            self.set_retries
+                .push((Instant::now() + delay, cookie, target));
+            #[cfg(test)]
+            self.retry_delays.push(delay);
+            target
+        };
+        // the other replica was sent the writes as well and only counts once,
+        // so the one that failed must count as failed
+        if target != from {
+            self.process_set::<Option<_>>(db, cookie, from, Err(err.clone()));
         }
+        true
     }
 
-    pub fn handler_set_remote_ack(&mut self, db: &Database, _from: NodeId, msg: MsgRemoteSetAck) {
-        self.process_set(db, msg.cookie, msg.result);
+    // resends the writes of an inflight set to a node that rate limited it
+    fn retry_set(&mut self, db: &Database, cookie: Cookie, node: NodeId) {
+        let msg = match self.requests.get(&cookie) {
+            Some(req) => MsgRemoteSet {
+                cookie: cookie,
+                vnode: self.state.num,
+                writes: req.sent.clone(),
+                reply: true,
+                request_id: None,
+                idempotency_key: None,
+                expected_version: None,
+                trace_id: None,
+                deadline: Some(remaining_ms(req.expire)),
+            },
+            // timed out in the meantime
+            None => return,
+        };
+        if let Err(err) = db.fabric.send_msg(node, &msg) {
+            self.process_set::<Option<_>>(db, cookie, node, Err(err));
+        }
+    }
+
+    fn write_stalled(&mut self) -> bool {
+        #[cfg(test)]
+        {
+            if self.forced_write_stalls != 0 {
+                self.forced_write_stalls -= 1;
+                return true;
+            }
+        }
+        self.state.storage.is_write_stalled()
     }
 
     // SYNC
@@ -708,6 +1662,39 @@ impl VNode {
     }
 
     pub fn handler_sync_send(&mut self, db: &Database, from: NodeId, msg: MsgSyncSend) {
+        check_status!(
+            self,
+            VNodeStatus::Ready | VNodeStatus::Bootstrap,
+            db,
+            from,
+            msg,
+            MsgSyncFin,
+            syncs
+        );
+        let cookie = msg.cookie;
+        let result = if let HMEntry::Occupied(mut o) = self.syncs.entry(cookie) {
+            let result = o.get_mut().on_msg_send(db, &mut self.state, msg);
+            if let SyncResult::Error = result {
+                info!("Removing sync/bootstrap {:?}", cookie);
+                o.remove().on_remove(db, &mut self.state);
+            }
+            result
+        } else {
+            let _ = fabric_send_error!(db, from, msg, MsgSyncFin, FabricError::CookieNotFound);
+            return;
+        };
+
+        if self.status() == VNodeStatus::Bootstrap {
+            self.handle_bootstrap_result(db, result);
+        }
+    }
+
+    pub fn handler_sync_send_batch(
+        &mut self,
+        db: &Database,
+        from: NodeId,
+        msg: MsgSyncSendBatch,
+    ) {
         forward!(
             self,
             VNodeStatus::Ready | VNodeStatus::Bootstrap,
@@ -716,7 +1703,20 @@ impl VNode {
             msg,
             MsgSyncFin,
             syncs,
-            on_msg_send
+            on_msg_send_batch
+        );
+    }
+
+    pub fn handler_sync_checkpoint(&mut self, db: &Database, from: NodeId, msg: MsgSyncCheckpoint) {
+        forward!(
+            self,
+            VNodeStatus::Ready | VNodeStatus::Bootstrap,
+            db,
+            from,
+            msg,
+            MsgSyncFin,
+            syncs,
+            on_msg_checkpoint
         );
     }
 
@@ -733,6 +1733,38 @@ impl VNode {
         );
     }
 
+    pub fn handler_sync_stats(&mut self, db: &Database, from: NodeId, msg: MsgSyncStats) {
+        match self.syncs.get(&msg.cookie).and_then(|s| s.stats(self.state.num)) {
+            Some(ack) => {
+                let _ = db.fabric.send_msg(from, &ack);
+            }
+            None => debug!("Can't find sync sender {:?} for stats", msg.cookie),
+        }
+    }
+
+    pub fn handler_sync_stats_ack(&mut self, _db: &Database, _from: NodeId, msg: MsgSyncStatsAck) {
+        if let Some(sync) = self.syncs.get_mut(&msg.cookie) {
+            sync.on_msg_stats_ack(msg);
+        }
+    }
+
+    /// Asks the senders of the incoming syncs for their progress,
+    /// replies are available through `sync_peer_stats`
+    pub fn request_sync_stats(&self, db: &Database) {
+        for sync in self.syncs.values() {
+            if let SyncDirection::Incomming = sync.direction() {
+                sync.request_stats(db, &self.state);
+            }
+        }
+    }
+
+    pub fn sync_peer_stats(&self) -> Vec<MsgSyncStatsAck> {
+        self.syncs
+            .values()
+            .filter_map(|s| s.peer_stats().cloned())
+            .collect()
+    }
+
     pub fn handler_sync_fin(&mut self, db: &Database, from: NodeId, msg: MsgSyncFin) {
         check_status!(
             self,
@@ -769,6 +1801,23 @@ impl VNode {
         }
     }
 
+    pub fn handler_sync_rollback(&mut self, db: &Database, _from: NodeId, msg: MsgSyncRollback) {
+        let cookie = msg.cookie;
+        let result = if let HMEntry::Occupied(mut o) = self.syncs.entry(cookie) {
+            let result = o.get_mut().on_msg_rollback(db, &mut self.state, msg);
+            info!("Removing sync/bootstrap {:?}", cookie);
+            o.remove().on_remove(db, &mut self.state);
+            result
+        } else {
+            trace!("Can't find cookie {:?} for msg sync rollback", cookie);
+            return;
+        };
+
+        if self.status() == VNodeStatus::Bootstrap {
+            self.handle_bootstrap_result(db, result);
+        }
+    }
+
     fn handle_bootstrap_result(&mut self, db: &Database, result: SyncResult) {
         match result {
             SyncResult::Error => {
@@ -846,6 +1895,26 @@ impl VNode {
         }
     }
 
+    #[cfg(test)]
+    pub fn _set_status(&mut self, db: &Database, status: VNodeStatus) {
+        self.state.set_status(db, status);
+    }
+
+    #[cfg(test)]
+    pub fn _force_write_stalls(&mut self, count: u32) {
+        self.forced_write_stalls = count;
+    }
+
+    #[cfg(test)]
+    pub fn _force_not_ready(&mut self, count: u32) {
+        self.forced_not_ready = count;
+    }
+
+    #[cfg(test)]
+    pub fn _retry_delays(&self) -> &[Duration] {
+        &self.retry_delays
+    }
+
     #[cfg(test)]
     pub fn _start_sync(&mut self, db: &Database) -> bool {
         assert_any!(self.state.status, VNodeStatus::Ready);
@@ -922,18 +1991,21 @@ impl VNodeState {
                 assert!(!self.pending_bootstrap);
                 assert_eq!(self.sync_nodes.len(), 0);
                 self.clear();
+                clear_sync_checkpoints(db, self.num);
                 self.id = Self::generate_id(self.id);
             }
             VNodeStatus::Absent => {
                 assert_eq!(self.sync_nodes.len(), 0);
                 self.clear();
+                clear_sync_checkpoints(db, self.num);
             }
             VNodeStatus::Ready | VNodeStatus::Zombie => {}
         }
 
         self.last_status_change = Instant::now();
         self.pending_bootstrap = false;
-        self.status = new;
+        let old = mem::replace(&mut self.status, new);
+        db.notify_vnode_status(self.num, old, new);
         // not important in all cases but nice to do
         self.save(db, false);
     }
@@ -955,6 +2027,7 @@ impl VNodeState {
             storage: storage,
             pending_bootstrap: false,
             sync_nodes: Default::default(),
+            peer_clocks: Default::default(),
         }
     }
 
@@ -995,6 +2068,7 @@ impl VNodeState {
             storage: storage,
             sync_nodes: Default::default(),
             pending_bootstrap: false,
+            peer_clocks: Default::default(),
         };
 
         if !clean_shutdown {
@@ -1010,6 +2084,7 @@ impl VNodeState {
             for ((_, dot), _) in iterator.iter() {
                 bv.add(dot);
             }
+            iterator.status().expect("Can't recover dots from the log");
         }
     }
 
@@ -1030,7 +2105,7 @@ impl VNodeState {
 
     // STORAGE
     pub fn storage_get(&self, key: &[u8]) -> Result<Cube, ()> {
-        let result = self.storage.get(key, |v| bincode::deserialize::<Cube>(v));
+        let result = self.storage.get(key, Cube::from_storage);
         match result {
             Ok(Some(Ok(cube))) => Ok(cube),
             Ok(Some(Err(_de))) => Err(()),
@@ -1050,8 +2125,7 @@ impl VNodeState {
             if cube.is_subsumed(&self.clocks) {
                 batch.del(key);
             } else {
-                let bytes = bincode::serialize(cube).expect("Can't serialize Cube");
-                batch.set(key, &bytes);
+                batch.set(key, &cube.to_storage());
             }
 
             batch.log_set((self.id, version), key);
@@ -1075,7 +2149,7 @@ impl VNodeState {
             // otherwise the dots might be added to Void cubes
             let old = self.storage_get(&key).map_err(|_| ())?;
 
-            let mut empty = true;
+            let mut new_dots = Vec::new();
             {
                 let clocks = &mut self.clocks;
                 let log_clocks = &mut self.log_clocks;
@@ -1083,11 +2157,12 @@ impl VNodeState {
                     // note that clocks is a superset of log_clocks
                     if log_clocks.add(i, v) {
                         clocks.add(i, v);
-                        empty = false;
-                        batch.log_set((i, v), &key);
+                        new_dots.push((i, v));
                     }
                 });
             }
+            let empty = new_dots.is_empty();
+            batch.log_set_many(new_dots.into_iter().map(|dot| (dot, &key)));
 
             if empty && !reply_result {
                 results.push(None);
@@ -1100,8 +2175,7 @@ impl VNodeState {
                 if new.is_subsumed(&self.clocks) {
                     batch.del(&key);
                 } else {
-                    let serialized = bincode::serialize(&new).expect("Can't serialize Cube");
-                    batch.set(&key, &serialized);
+                    batch.set(&key, &new.to_storage());
                 }
             }
 
@@ -5,9 +5,10 @@ use database::*;
 use fabric::*;
 use inflightmap::InFlightMap;
 use metrics::{self, Meter};
-use std::collections::{hash_set, HashSet};
+use std::collections::btree_map::Entry as BTMEntry;
+use std::collections::{btree_set, BTreeMap, BTreeSet};
 use std::time::{Duration, Instant};
-use utils::IdHasherBuilder;
+use utils::{replace_default, GenericError, IdHasherBuilder};
 use version_vector::*;
 use vnode::VNodeState;
 
@@ -48,11 +49,255 @@ pub enum SyncDirection {
 
 type IteratorFn = Box<FnMut(&VNodeState) -> Result<Option<(Bytes, Cube)>, ()> + Send>;
 
-type InFlightSyncMsgMap = InFlightMap<u64, MsgSyncSend, Instant, IdHasherBuilder>;
+// keyed by the seq of the first entry
+type InFlightSyncMsgMap = InFlightMap<u64, MsgSyncSendBatch, Instant, IdHasherBuilder>;
 
+// how often (in acked msgs) senders checkpoint the sync progress
+const SYNC_CHECKPOINT_INTERVAL: u64 = 100;
+// approximate bytes of staged writes kept in memory, beyond that they're persisted
+const SYNC_STAGING_MEMORY_MAX: usize = 16 * 1024 * 1024;
+
+// keys are fetched (and sorted) in batches
 struct SyncKeysIterator {
     dots_delta: BitmappedVersionVectorDelta,
-    keys: hash_set::IntoIter<Bytes>,
+    keys: btree_set::IntoIter<Bytes>,
+}
+
+fn sync_checkpoint_prefix(vnode: VNodeNo) -> String {
+    format!("{}_sync_checkpoint_", vnode)
+}
+
+fn sync_checkpoint_key(vnode: VNodeNo, peer: NodeId) -> String {
+    format!("{}{}", sync_checkpoint_prefix(vnode), peer)
+}
+
+/// Persists the progress of an incoming sync/bootstrap from `peer`
+pub fn save_sync_checkpoint(
+    db: &Database,
+    vnode: VNodeNo,
+    peer: NodeId,
+    checkpoint: &SyncCheckpoint,
+) {
+    let serialized = bincode::serialize(checkpoint).unwrap();
+    if let Err(e) = db
+        .meta_storage
+        .set(sync_checkpoint_key(vnode, peer).as_bytes(), &serialized)
+    {
+        warn!("Can't save sync checkpoint for vnode {}: {}", vnode, e);
+    }
+}
+
+pub fn load_sync_checkpoint(
+    db: &Database,
+    vnode: VNodeNo,
+    peer: NodeId,
+) -> Option<SyncCheckpoint> {
+    db.meta_storage
+        .get(sync_checkpoint_key(vnode, peer).as_bytes(), |bytes| {
+            bincode::deserialize(bytes).ok()
+        }).unwrap_or_else(|e| {
+            warn!("Can't load sync checkpoint for vnode {}: {}", vnode, e);
+            None
+        }).and_then(|x| x)
+}
+
+fn del_sync_checkpoint(db: &Database, vnode: VNodeNo, peer: NodeId) {
+    let _ = db
+        .meta_storage
+        .del(sync_checkpoint_key(vnode, peer).as_bytes());
+}
+
+/// Removes all checkpoints (and staged writes) of the vnode,
+/// they're useless once data is cleared
+pub fn clear_sync_checkpoints(db: &Database, vnode: VNodeNo) {
+    for prefix in &[sync_checkpoint_prefix(vnode), sync_staging_prefix(vnode)] {
+        let keys: Vec<Vec<u8>> = db
+            .meta_storage
+            .iterator()
+            .iter()
+            .filter(|&(k, _)| k.starts_with(prefix.as_bytes()))
+            .map(|(k, _)| k.into())
+            .collect();
+        for key in keys {
+            let _ = db.meta_storage.del(&key);
+        }
+    }
+}
+
+// Incoming syncs (not bootstraps) stage the received writes and only apply them on a
+// successful SyncFin, so an aborted sync leaves nothing behind. Writes are staged in
+// memory until they grow past SYNC_STAGING_MEMORY_MAX, then they're persisted in the
+// meta storage as they arrive, so large syncs have bounded memory and, along with
+// checkpoints, resuming after a crash doesn't need them to be sent again.
+// Staging is per peer like the checkpoints.
+fn sync_staging_prefix(vnode: VNodeNo) -> String {
+    format!("{}_sync_staged_", vnode)
+}
+
+fn sync_staging_peer_prefix(vnode: VNodeNo, peer: NodeId) -> Vec<u8> {
+    format!("{}{}_", sync_staging_prefix(vnode), peer).into_bytes()
+}
+
+/// Persists writes received by a sync from `peer`, applied by its SyncFin
+pub fn stage_sync_writes<I: IntoIterator<Item = (Bytes, Cube)>>(
+    db: &Database,
+    vnode: VNodeNo,
+    peer: NodeId,
+    writes: I,
+) -> Result<(), GenericError> {
+    let prefix = sync_staging_peer_prefix(vnode, peer);
+    let mut batch = db.meta_storage.batch_new(0);
+    for (key, value) in writes {
+        let mut staged_key = prefix.clone();
+        staged_key.extend_from_slice(&key);
+        // resumed syncs may stage the same key again
+        let value = match db
+            .meta_storage
+            .get(&staged_key, Cube::from_storage)?
+        {
+            Some(staged) => staged?.merge(value),
+            None => value,
+        };
+        batch.set(&staged_key, &value.to_storage());
+    }
+    db.meta_storage.batch_write(batch)
+}
+
+// resends may stage the same key more than once
+fn stage_in_memory(staged: &mut BTreeMap<Bytes, Cube>, key: Bytes, value: Cube) {
+    match staged.entry(key) {
+        BTMEntry::Occupied(mut o) => {
+            let merged = replace_default(o.get_mut()).merge(value);
+            *o.get_mut() = merged;
+        }
+        BTMEntry::Vacant(v) => {
+            v.insert(value);
+        }
+    }
+}
+
+// persists the writes staged in memory and clears them
+fn persist_staged(
+    db: &Database,
+    vnode: VNodeNo,
+    peer: NodeId,
+    staged: &mut BTreeMap<Bytes, Cube>,
+    staged_bytes: &mut usize,
+) -> Result<(), GenericError> {
+    {
+        let writes = staged.iter().map(|(k, v)| (k.clone(), v.clone()));
+        stage_sync_writes(db, vnode, peer, writes)?;
+    }
+    staged.clear();
+    *staged_bytes = 0;
+    Ok(())
+}
+
+// dots of the writes in the cube, Void cubes have none
+fn add_dots(bvv: &mut BitmappedVersionVector, cube: &Cube) {
+    if let Cube::Void(_) = *cube {
+        return;
+    }
+    cube.for_each_dot(|n, v| {
+        bvv.add(n, v);
+    });
+}
+
+// batch_size 1 sends individual MsgSyncSend, which any peer understands
+fn send_entries(
+    db: &Database,
+    peer: NodeId,
+    msg: &MsgSyncSendBatch,
+    batch_size: u32,
+) -> Result<(), FabricError> {
+    if batch_size > 1 {
+        return db.fabric.send_msg(peer, msg);
+    }
+    let (seq, ref key, ref value) = msg.entries[0];
+    db.fabric.send_msg(
+        peer,
+        &MsgSyncSend {
+            vnode: msg.vnode,
+            cookie: msg.cookie,
+            seq: seq,
+            key: key.clone(),
+            value: value.clone(),
+        },
+    )
+}
+
+// applies (or just discards) the persisted staged writes from `peer`, in batches
+fn drain_staged_sync_writes(
+    db: &Database,
+    state: &mut VNodeState,
+    peer: NodeId,
+    apply: bool,
+) -> Result<u64, GenericError> {
+    let prefix = sync_staging_peer_prefix(state.num(), peer);
+    let mut count = 0;
+    loop {
+        let mut staged_keys = Vec::new();
+        let mut writes = Vec::new();
+        for (k, v) in db.meta_storage.iterator_prefix(&prefix).iter().take(1_000) {
+            if apply {
+                let key = Bytes::from(&k[prefix.len()..]);
+                writes.push((key, Cube::from_storage(v)?, false));
+            }
+            staged_keys.push(k.to_vec());
+        }
+        if staged_keys.is_empty() {
+            return Ok(count);
+        }
+        if apply {
+            state
+                .storage_set_remote(db, writes)
+                .map_err(|_| "Can't apply staged sync writes")?;
+        }
+        let mut batch = db.meta_storage.batch_new(staged_keys.len());
+        for key in &staged_keys {
+            batch.del(key);
+        }
+        db.meta_storage.batch_write(batch)?;
+        count += staged_keys.len() as u64;
+    }
+}
+
+// applies the writes staged by the sync from `peer`, persisted or not
+fn apply_staged_sync_writes(
+    db: &Database,
+    state: &mut VNodeState,
+    peer: NodeId,
+    staged: &mut BTreeMap<Bytes, Cube>,
+) -> Result<u64, GenericError> {
+    let mut count = drain_staged_sync_writes(db, state, peer, true)?;
+    let mut writes = Vec::with_capacity(1_000);
+    for (key, value) in replace_default(staged) {
+        writes.push((key, value, false));
+        if writes.len() == 1_000 {
+            count += writes.len() as u64;
+            state
+                .storage_set_remote(db, replace_default(&mut writes))
+                .map_err(|_| "Can't apply staged sync writes")?;
+        }
+    }
+    count += writes.len() as u64;
+    state
+        .storage_set_remote(db, writes)
+        .map_err(|_| "Can't apply staged sync writes")?;
+    Ok(count)
+}
+
+// discards the writes staged by the sync from `peer` and its checkpoint, which claims them
+fn discard_staged_sync_writes(
+    db: &Database,
+    state: &mut VNodeState,
+    peer: NodeId,
+    staged: &mut BTreeMap<Bytes, Cube>,
+) -> Result<u64, GenericError> {
+    let count = staged.len() as u64;
+    staged.clear();
+    del_sync_checkpoint(db, state.num(), peer);
+    Ok(count + drain_staged_sync_writes(db, state, peer, false)?)
 }
 
 // TODO: Refactor into trait objects
@@ -67,10 +312,17 @@ pub enum Synchronization {
         iterator: IteratorFn,
         // TODO: only store keys as resends should be rare
         inflight: InFlightSyncMsgMap,
+        // entries per message, as requested by the receiver
+        batch_size: u32,
         cookie: Cookie,
         peer: NodeId,
         // count of sent keys (includes inflight)
         count: u64,
+        // count of acked keys at the last checkpoint
+        last_checkpoint: u64,
+        // approximate bytes of the sent keys/values (includes resends)
+        bytes_sent: u64,
+        started: Instant,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -79,8 +331,19 @@ pub enum Synchronization {
         clocks_in_peer: BitmappedVersionVector,
         cookie: Cookie,
         peer: NodeId,
+        resume: Option<SyncCheckpoint>,
+        // received writes not persisted yet
+        staged: BTreeMap<Bytes, Cube>,
+        // approximate bytes of staged (includes dups)
+        staged_bytes: usize,
+        // some writes are persisted in the meta storage, by this or a resumed attempt
+        persisted: bool,
+        // dots of the received writes, including the ones of resumed attempts
+        delivered: BitmappedVersionVector,
         // aprox count of received keys (includes dups)
         recv_count: u64,
+        // last progress reported by the sender
+        peer_stats: Option<MsgSyncStatsAck>,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -88,18 +351,27 @@ pub enum Synchronization {
         clocks_snapshot: BitmappedVersionVector,
         iterator: IteratorFn,
         inflight: InFlightSyncMsgMap,
+        batch_size: u32,
         cookie: Cookie,
         peer: NodeId,
         // count of sent keys (includes inflight)
         count: u64,
+        // count of acked keys at the last checkpoint
+        last_checkpoint: u64,
+        // approximate bytes of the sent keys/values (includes resends)
+        bytes_sent: u64,
+        started: Instant,
         last_recv: Instant,
         last_send: Instant,
     },
     BootstrapReceiver {
         cookie: Cookie,
+        resume: Option<SyncCheckpoint>,
         peer: NodeId,
         // aprox count of received keys (includes dups)
         recv_count: u64,
+        // last progress reported by the sender
+        peer_stats: Option<MsgSyncStatsAck>,
         last_recv: Instant,
         last_send: Instant,
     },
@@ -109,7 +381,7 @@ impl SyncKeysIterator {
     fn new(dots_delta: BitmappedVersionVectorDelta) -> Self {
         SyncKeysIterator {
             dots_delta: dots_delta,
-            keys: HashSet::new().into_iter(),
+            keys: BTreeSet::new().into_iter(),
         }
     }
 
@@ -119,10 +391,8 @@ impl SyncKeysIterator {
                 return Ok(Some(key));
             }
             // fetch log in batches of ~1_000 keys
-            let hint_size = self.dots_delta.size_hint().0.min(1_000);
-            let mut keys = HashSet::with_capacity(hint_size);
-            // consider up to 90% of the actual capacity as an alternative limit
-            let limit = (keys.capacity() * 9 / 10).max(1_000);
+            let limit = 1_000;
+            let mut keys = BTreeSet::new();
             for (n, v) in self.dots_delta.by_ref() {
                 let key = state
                     .storage
@@ -150,15 +420,17 @@ use self::Synchronization::*;
 
 impl Synchronization {
     pub fn new_bootstrap_receiver(
-        _db: &Database,
-        _state: &mut VNodeState,
+        db: &Database,
+        state: &mut VNodeState,
         peer: NodeId,
         cookie: Cookie,
     ) -> Self {
         BootstrapReceiver {
             cookie: cookie,
             peer: peer,
+            resume: load_sync_checkpoint(db, state.num(), peer),
             recv_count: 0,
+            peer_stats: None,
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -170,46 +442,79 @@ impl Synchronization {
         peer: NodeId,
         msg: MsgSyncStart,
     ) -> Self {
-        let mut storage_iterator = state.storage.iterator();
+        // a resumed bootstrap continues from the checkpoint key, but only claims
+        // the clocks of the first attempt as newer writes before it aren't sent
+        let (mut storage_iterator, clocks_snapshot, count) = match msg.resume {
+            Some(SyncCheckpoint {
+                last_acked_seq,
+                clocks,
+                next_key: Some(next_key),
+            }) => {
+                info!("Resuming bootstrap {:?} from seq {}", msg.cookie, last_acked_seq + 1);
+                let iterator = state.storage.iterator_from(&next_key);
+                (iterator, clocks, last_acked_seq + 1)
+            }
+            _ => (state.storage.iterator(), state.clocks.clone(), 0),
+        };
         let iterator_fn: IteratorFn = Box::new(move |_| {
             let next = storage_iterator
                 .iter()
                 .map(|(k, v)| {
-                    let cube = bincode::deserialize::<Cube>(v).map_err(|_| ())?;
+                    let cube = Cube::from_storage(v).map_err(|_| ())?;
                     Ok((Bytes::from(k), cube))
                 }).next();
 
             match next {
                 Some(Ok(r)) => Ok(Some(r)),
-                None => Ok(None),
+                None => storage_iterator.status().map(|_| None).map_err(|e| {
+                    error!("Storage iterator error: {}", e);
+                }),
                 Some(Err(e)) => Err(e),
             }
         });
 
         BootstrapSender {
             cookie: msg.cookie,
-            clocks_snapshot: state.clocks.clone(),
+            clocks_snapshot: clocks_snapshot,
             iterator: iterator_fn,
             inflight: InFlightMap::new(),
+            batch_size: msg.batch_size.unwrap_or(1).max(1),
             peer: peer,
-            count: 0,
+            count: count,
+            last_checkpoint: count,
+            bytes_sent: 0,
+            started: Instant::now(),
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
     }
 
     pub fn new_sync_receiver(
-        _db: &Database,
+        db: &Database,
         state: &mut VNodeState,
         peer: NodeId,
         cookie: Cookie,
     ) -> Self {
         assert!(state.sync_nodes.insert(peer));
+        // what was staged by previous attempts doesn't need to be sent again
+        let resume = load_sync_checkpoint(db, state.num(), peer);
+        let delivered = resume
+            .as_ref()
+            .map_or_else(BitmappedVersionVector::new, |c| c.clocks.clone());
+        let persisted = resume.is_some();
+        let mut clocks_in_peer = state.clocks.clone();
+        clocks_in_peer.merge(&delivered);
         SyncReceiver {
-            clocks_in_peer: state.clocks.clone(),
+            clocks_in_peer: clocks_in_peer,
             peer: peer,
             cookie: cookie,
+            resume: resume,
+            staged: BTreeMap::new(),
+            staged_bytes: 0,
+            persisted: persisted,
+            delivered: delivered,
             recv_count: 0,
+            peer_stats: None,
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -225,6 +530,8 @@ impl Synchronization {
             target,
             cookie,
             clocks_in_peer,
+            resume,
+            batch_size,
             ..
         } = msg;
         assert_eq!(target, Some(db.dht.node()));
@@ -247,14 +554,20 @@ impl Synchronization {
             }
         });
 
+        // the resumed delta already excludes the delivered dots, see SyncCheckpoint
+        let count = resume.map_or(0, |c| c.last_acked_seq + 1);
         SyncSender {
             clocks_in_peer: clocks_in_peer,
             clocks_snapshot: clocks_snapshot,
             iterator: iterator_fn,
             inflight: InFlightMap::new(),
+            batch_size: batch_size.unwrap_or(1).max(1),
             cookie: cookie,
             peer: peer,
-            count: 0,
+            count: count,
+            last_checkpoint: count,
+            bytes_sent: 0,
+            started: Instant::now(),
             last_recv: Instant::now(),
             last_send: Instant::now(),
         }
@@ -262,25 +575,39 @@ impl Synchronization {
 
     // send SyncStart message, only valid for Receivers
     fn send_start(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
-        let (peer, cookie, target, clocks_in_peer) = match *self {
+        let (peer, cookie, target, clocks_in_peer, resume) = match *self {
             SyncReceiver {
                 cookie,
                 peer,
+                ref resume,
                 ref mut last_send,
                 ref clocks_in_peer,
                 ..
             } => {
                 *last_send = Instant::now();
-                (peer, cookie, Some(peer), clocks_in_peer.clone())
+                (
+                    peer,
+                    cookie,
+                    Some(peer),
+                    clocks_in_peer.clone(),
+                    resume.clone(),
+                )
             }
             BootstrapReceiver {
                 peer,
                 cookie,
+                ref resume,
                 ref mut last_send,
                 ..
             } => {
                 *last_send = Instant::now();
-                (peer, cookie, None, BitmappedVersionVector::new())
+                (
+                    peer,
+                    cookie,
+                    None,
+                    BitmappedVersionVector::new(),
+                    resume.clone(),
+                )
             }
             _ => unreachable!(),
         };
@@ -294,6 +621,8 @@ impl Synchronization {
                     vnode: state.num(),
                     clocks_in_peer: clocks_in_peer,
                     target: target,
+                    resume: resume,
+                    batch_size: Some(db.config.sync_batch_size),
                 },
             ).into()
     }
@@ -337,6 +666,7 @@ impl Synchronization {
                         cookie: cookie,
                         vnode: state.num(),
                         result: Err(error),
+                        entries_synced: 0,
                     },
                 );
                 SyncResult::Error
@@ -351,6 +681,7 @@ impl Synchronization {
             SyncSender {
                 peer,
                 cookie,
+                count,
                 ref clocks_snapshot,
                 ref mut last_send,
                 ..
@@ -358,6 +689,7 @@ impl Synchronization {
             | BootstrapSender {
                 peer,
                 cookie,
+                count,
                 ref clocks_snapshot,
                 ref mut last_send,
                 ..
@@ -370,6 +702,7 @@ impl Synchronization {
                             cookie: cookie,
                             vnode: state.num(),
                             result: Ok(clocks_snapshot.clone()),
+                            entries_synced: count,
                         },
                     ).into()
             }
@@ -377,8 +710,8 @@ impl Synchronization {
         }
     }
 
-    // send (possibly multiple) SyncSend messages and eventual SyncFin
-    // (also takes care of expired SyncSend)
+    // send (possibly multiple) SyncSend/SyncSendBatch messages and eventual SyncFin
+    // (also takes care of expired ones)
     fn send_next(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
         let now = Instant::now();
         let timeout = now + Duration::from_millis(db.config.sync_msg_timeout as _);
@@ -386,52 +719,66 @@ impl Synchronization {
             SyncSender {
                 peer,
                 cookie,
+                batch_size,
                 ref mut iterator,
                 ref mut count,
                 ref mut inflight,
+                ref mut bytes_sent,
                 ref mut last_send,
                 ..
             }
             | BootstrapSender {
                 peer,
                 cookie,
+                batch_size,
                 ref mut iterator,
                 ref mut count,
                 ref mut inflight,
+                ref mut bytes_sent,
                 ref mut last_send,
                 ..
             } => {
                 while let Some((seq, msg)) = inflight.touch_expired(now, timeout) {
                     debug!("resending seq {} for sync/bootstrap {:?}", seq, cookie);
-                    let _ = stry!(db.fabric.send_msg(peer, msg,));
+                    let _ = stry!(send_entries(db, peer, msg, batch_size));
                     metrics::SYNC_RESEND.mark(1);
                 }
                 let mut error = false;
-                while inflight.len() < db.config.sync_msg_inflight as usize {
-                    match iterator(state) {
-                        Ok(Some((k, v))) => {
-                            let msg = MsgSyncSend {
-                                cookie: cookie,
-                                vnode: state.num(),
-                                seq: *count,
-                                key: k.clone(),
-                                value: v.clone(),
-                            };
-                            let _ = stry!(db.fabric.send_msg(peer, &msg,));
-                            inflight.insert(*count, msg, timeout);
-                            *count += 1;
-                            *last_send = now;
-                            metrics::SYNC_SEND.mark(1);
-                            continue;
-                        }
-                        Ok(None) => {
-                            break;
-                        }
-                        Err(_) => {
-                            error = true;
-                            break;
+                let mut exhausted = false;
+                while !exhausted && inflight.len() < db.config.sync_msg_inflight as usize {
+                    let mut msg = MsgSyncSendBatch {
+                        cookie: cookie,
+                        vnode: state.num(),
+                        entries: Vec::with_capacity(batch_size as usize),
+                    };
+                    let mut msg_bytes = 0;
+                    while msg.entries.len() < batch_size as usize {
+                        match iterator(state) {
+                            Ok(Some((k, v))) => {
+                                msg_bytes += k.len() + v.size_bytes();
+                                let seq = *count + msg.entries.len() as u64;
+                                msg.entries.push((seq, k, v));
+                            }
+                            Ok(None) => {
+                                exhausted = true;
+                                break;
+                            }
+                            Err(_) => {
+                                error = true;
+                                break;
+                            }
                         }
                     }
+                    if error || msg.entries.is_empty() {
+                        break;
+                    }
+                    let _ = stry!(send_entries(db, peer, &msg, batch_size));
+                    let len = msg.entries.len();
+                    *bytes_sent += msg_bytes as u64;
+                    inflight.insert(*count, msg, timeout);
+                    *count += len as u64;
+                    *last_send = now;
+                    metrics::SYNC_SEND.mark(len as _);
                 }
                 (error, inflight.is_empty())
             }
@@ -462,14 +809,26 @@ impl Synchronization {
 
     // called by vnode as soon as the sync is unregistered
     pub fn on_remove(self, db: &Database, state: &mut VNodeState) {
+        let direction = self.direction();
         match self {
-            SyncReceiver { peer, .. } => {
+            SyncReceiver {
+                peer,
+                cookie,
+                mut staged,
+                ..
+            } => {
                 assert!(state.sync_nodes.remove(&peer));
+                // nothing is left after a SyncFin, otherwise the sync timed out or failed
+                match discard_staged_sync_writes(db, state, peer, &mut staged) {
+                    Ok(0) => (),
+                    Ok(count) => info!("Sync {:?} discarded {} staged writes", cookie, count),
+                    Err(e) => warn!("Can't discard staged writes of sync {:?}: {}", cookie, e),
+                }
             }
             _ => (),
         }
 
-        db.signal_sync_end(self.direction());
+        db.signal_sync_end(direction);
     }
 
     pub fn on_tick(&mut self, db: &Database, state: &mut VNodeState) -> SyncResult {
@@ -525,11 +884,33 @@ impl Synchronization {
         state: &mut VNodeState,
         msg: MsgSyncFin,
     ) -> SyncResult {
+        if msg.result.is_ok() {
+            if let SyncReceiver {
+                peer,
+                ref mut staged,
+                ..
+            } = *self
+            {
+                if let Err(e) = apply_staged_sync_writes(db, state, peer, staged) {
+                    warn!("Can't apply staged writes of sync {:?}: {}", msg.cookie, e);
+                    return SyncResult::Error;
+                }
+            }
+        }
         match *self {
             SyncReceiver { peer, .. } | BootstrapReceiver { peer, .. } => {
                 if msg.result.is_ok() {
                     state.clocks.merge(msg.result.as_ref().unwrap());
+                    state
+                        .peer_clocks
+                        .insert(peer, msg.result.as_ref().unwrap().clone());
                     state.save(db, false);
+                    del_sync_checkpoint(db, state.num(), peer);
+                    debug!(
+                        "Sync/bootstrap {:?} finished with {} entries",
+                        msg.cookie, msg.entries_synced
+                    );
+                    db.signal_sync_fin(msg.entries_synced);
                     // send it back as a form of ack-ack
                     let _ = db.fabric.send_msg(peer, &msg);
                     SyncResult::Done
@@ -539,14 +920,88 @@ impl Synchronization {
                     SyncResult::Error
                 }
             }
-            SyncSender { .. } | BootstrapSender { .. } => {
+            SyncSender {
+                peer,
+                ref clocks_in_peer,
+                ref clocks_snapshot,
+                ..
+            } => {
+                if msg.result.is_ok() {
+                    let mut peer_clocks = clocks_in_peer.clone();
+                    peer_clocks.merge(clocks_snapshot);
+                    state.peer_clocks.insert(peer, peer_clocks);
+                }
                 // Senders are always Done on SyncFin messages
                 SyncResult::Done
             }
+            BootstrapSender {
+                peer,
+                ref clocks_snapshot,
+                ..
+            } => {
+                if msg.result.is_ok() {
+                    state.peer_clocks.insert(peer, clocks_snapshot.clone());
+                }
+                SyncResult::Done
+            }
         }
     }
 
-    pub fn on_msg_send(&mut self, db: &Database, state: &mut VNodeState, msg: MsgSyncSend) {
+    // stages a write received by a SyncReceiver, persisting the staged writes if they
+    // grow too large. On errors the write is still staged in memory.
+    fn stage(
+        &mut self,
+        db: &Database,
+        state: &VNodeState,
+        key: Bytes,
+        value: Cube,
+    ) -> Result<(), GenericError> {
+        match *self {
+            SyncReceiver {
+                peer,
+                ref mut staged,
+                ref mut staged_bytes,
+                ref mut persisted,
+                ref mut delivered,
+                ..
+            } => {
+                add_dots(delivered, &value);
+                *staged_bytes += key.len() + value.size_bytes();
+                stage_in_memory(staged, key, value);
+                if *staged_bytes > SYNC_STAGING_MEMORY_MAX {
+                    persist_staged(db, state.num(), peer, staged, staged_bytes)?;
+                    *persisted = true;
+                }
+                Ok(())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn on_msg_send(
+        &mut self,
+        db: &Database,
+        state: &mut VNodeState,
+        msg: MsgSyncSend,
+    ) -> SyncResult {
+        let MsgSyncSend {
+            cookie,
+            seq,
+            key,
+            value,
+            ..
+        } = msg;
+        let applied = match *self {
+            SyncReceiver { .. } => self.stage(db, state, key, value).is_ok(),
+            BootstrapReceiver { .. } => state
+                .storage_set_remote(db, vec![(key, value, false)])
+                .is_ok(),
+            _ => unreachable!(),
+        };
+        if !applied {
+            warn!("Can't apply seq {} of sync/bootstrap {:?}", seq, cookie);
+            return self.send_error_fin(db, state, FabricError::StorageError);
+        }
         match *self {
             SyncReceiver {
                 peer,
@@ -562,17 +1017,12 @@ impl Synchronization {
                 ref mut last_send,
                 ..
             } => {
-                // TODO: what to do with errors here?
-                state
-                    .storage_set_remote(db, vec![(msg.key, msg.value, false)])
-                    .unwrap();
-
                 let _ = db.fabric.send_msg(
                     peer,
                     &MsgSyncAck {
-                        cookie: msg.cookie,
+                        cookie: cookie,
                         vnode: state.num(),
-                        seq: msg.seq,
+                        seq: seq,
                     },
                 );
 
@@ -584,28 +1034,323 @@ impl Synchronization {
             }
             _ => unreachable!(),
         }
+        SyncResult::Continue
+    }
+
+    /// Applies the entries in order and acks the last one applied,
+    /// stopping at the first failure so the sender resends from there.
+    pub fn on_msg_send_batch(
+        &mut self,
+        db: &Database,
+        state: &mut VNodeState,
+        msg: MsgSyncSendBatch,
+    ) {
+        let MsgSyncSendBatch { cookie, entries, .. } = msg;
+        let mut last_applied = None;
+        let mut applied = 0;
+        match *self {
+            SyncReceiver { .. } => for (seq, key, value) in entries {
+                if self.stage(db, state, key, value).is_err() {
+                    warn!("Can't stage seq {} of sync {:?}", seq, cookie);
+                    break;
+                }
+                last_applied = Some(seq);
+                applied += 1;
+            },
+            BootstrapReceiver { .. } => for (seq, key, value) in entries {
+                if state
+                    .storage_set_remote(db, vec![(key, value, false)])
+                    .is_err()
+                {
+                    warn!("Can't apply seq {} of bootstrap {:?}", seq, cookie);
+                    break;
+                }
+                last_applied = Some(seq);
+                applied += 1;
+            },
+            _ => unreachable!(),
+        }
+        match *self {
+            SyncReceiver {
+                peer,
+                ref mut recv_count,
+                ref mut last_recv,
+                ref mut last_send,
+                ..
+            }
+            | BootstrapReceiver {
+                peer,
+                ref mut recv_count,
+                ref mut last_recv,
+                ref mut last_send,
+                ..
+            } => {
+                *recv_count += applied;
+                metrics::SYNC_RECV.mark(applied as _);
+
+                // nothing applied, the whole batch is resent after the timeout
+                if let Some(seq) = last_applied {
+                    let _ = db.fabric.send_msg(
+                        peer,
+                        &MsgSyncAck {
+                            cookie: cookie,
+                            vnode: state.num(),
+                            seq: seq,
+                        },
+                    );
+                }
+
+                let now = Instant::now();
+                *last_recv = now;
+                *last_send = now;
+            }
+            _ => unreachable!(),
+        }
     }
 
     pub fn on_msg_ack(&mut self, db: &Database, state: &mut VNodeState, msg: MsgSyncAck) {
+        let bootstrap = if let BootstrapSender { .. } = *self {
+            true
+        } else {
+            false
+        };
         match *self {
             SyncSender {
+                peer,
+                cookie,
+                count,
+                batch_size,
+                ref clocks_snapshot,
                 ref mut inflight,
+                ref mut last_checkpoint,
                 ref mut last_recv,
                 ..
             }
             | BootstrapSender {
+                peer,
+                cookie,
+                count,
+                batch_size,
+                ref clocks_snapshot,
                 ref mut inflight,
+                ref mut last_checkpoint,
                 ref mut last_recv,
                 ..
             } => {
-                inflight.remove(&msg.seq);
-                *last_recv = Instant::now();
+                let now = Instant::now();
+                *last_recv = now;
+                let first_seq = inflight
+                    .iter()
+                    .find(|&(&first, m)| {
+                        first <= msg.seq && m.entries.last().map_or(false, |e| e.0 >= msg.seq)
+                    }).map(|(&first, _)| first);
+                let mut acked_key = None;
+                if let Some(mut sent) = first_seq.and_then(|first| inflight.remove(&first)) {
+                    acked_key = sent
+                        .entries
+                        .iter()
+                        .take_while(|e| e.0 <= msg.seq)
+                        .last()
+                        .map(|e| e.1.clone());
+                    sent.entries.retain(|e| e.0 > msg.seq);
+                    if !sent.entries.is_empty() {
+                        // acked up to the middle of a batch, the rest failed to apply
+                        debug!("resending seq {}.. for sync/bootstrap {:?}", msg.seq + 1, cookie);
+                        let _ = send_entries(db, peer, &sent, batch_size);
+                        metrics::SYNC_RESEND.mark(1);
+                        let timeout = now + Duration::from_millis(db.config.sync_msg_timeout as _);
+                        inflight.insert(msg.seq + 1, sent, timeout);
+                    }
+                }
+                // everything bellow the smallest inflight seq was acked
+                let acked = inflight.keys().min().cloned().unwrap_or(count);
+                // bootstraps send keys in order and resume from the first unacked one,
+                // or right after the last acked one (resending a few keys is harmless)
+                let next_key = if bootstrap {
+                    inflight.get(&acked).map(|m| m.entries[0].1.clone()).or_else(|| {
+                        acked_key.map(|k| {
+                            let mut next_key = k.to_vec();
+                            next_key.push(0);
+                            Bytes::from(next_key)
+                        })
+                    })
+                } else {
+                    None
+                };
+                if acked >= *last_checkpoint + SYNC_CHECKPOINT_INTERVAL
+                    && (!bootstrap || next_key.is_some())
+                {
+                    *last_checkpoint = acked;
+                    let checkpoint = SyncCheckpoint {
+                        last_acked_seq: acked - 1,
+                        // syncs receivers know the dots they received
+                        clocks: if bootstrap {
+                            clocks_snapshot.clone()
+                        } else {
+                            BitmappedVersionVector::new()
+                        },
+                        next_key: next_key,
+                    };
+                    let _ = db.fabric.send_msg(
+                        peer,
+                        &MsgSyncCheckpoint {
+                            cookie: cookie,
+                            vnode: state.num(),
+                            checkpoint: checkpoint,
+                        },
+                    );
+                }
             }
             _ => unreachable!(),
         }
         let _ = self.send_next(db, state);
     }
 
+    pub fn on_msg_checkpoint(
+        &mut self,
+        db: &Database,
+        state: &mut VNodeState,
+        msg: MsgSyncCheckpoint,
+    ) {
+        match *self {
+            SyncReceiver {
+                peer,
+                cookie,
+                ref mut staged,
+                ref mut staged_bytes,
+                persisted,
+                ref delivered,
+                ref mut last_recv,
+                ..
+            } => {
+                *last_recv = Instant::now();
+                // syncs that fit in memory are cheap to resend and aren't checkpointed
+                if !persisted {
+                    return;
+                }
+                // the checkpoint claims all received writes, so they're persisted first
+                if let Err(e) = persist_staged(db, state.num(), peer, staged, staged_bytes) {
+                    return warn!("Can't persist staged writes of sync {:?}: {}", cookie, e);
+                }
+                let mut checkpoint = msg.checkpoint;
+                checkpoint.clocks = delivered.clone();
+                save_sync_checkpoint(db, state.num(), peer, &checkpoint);
+            }
+            BootstrapReceiver {
+                peer,
+                ref mut last_recv,
+                ..
+            } => {
+                save_sync_checkpoint(db, state.num(), peer, &msg.checkpoint);
+                *last_recv = Instant::now();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Aborts the sync, receivers discard the writes staged for it.
+    /// Bootstraps write directly to storage, so those are retried from scratch.
+    pub fn on_msg_rollback(
+        &mut self,
+        db: &Database,
+        state: &mut VNodeState,
+        msg: MsgSyncRollback,
+    ) -> SyncResult {
+        match *self {
+            SyncReceiver {
+                peer,
+                ref mut staged,
+                ..
+            } => match discard_staged_sync_writes(db, state, peer, staged) {
+                Ok(count) => info!(
+                    "Sync {:?} rolled back, discarded {} staged writes",
+                    msg.cookie, count
+                ),
+                Err(e) => warn!("Can't discard staged writes of sync {:?}: {}", msg.cookie, e),
+            },
+            BootstrapReceiver { peer, .. } => {
+                info!("Bootstrap {:?} rolled back", msg.cookie);
+                del_sync_checkpoint(db, state.num(), peer);
+            }
+            SyncSender { .. } | BootstrapSender { .. } => {
+                info!("Sync/bootstrap {:?} rolled back by peer", msg.cookie);
+            }
+        }
+        SyncResult::Error
+    }
+
+    /// Progress of Senders, None for Receivers
+    pub fn stats(&self, vnode: VNodeNo) -> Option<MsgSyncStatsAck> {
+        match *self {
+            SyncSender {
+                cookie,
+                count,
+                bytes_sent,
+                started,
+                ..
+            }
+            | BootstrapSender {
+                cookie,
+                count,
+                bytes_sent,
+                started,
+                ..
+            } => {
+                let elapsed = started.elapsed();
+                Some(MsgSyncStatsAck {
+                    vnode: vnode,
+                    cookie: cookie,
+                    entries_sent: count,
+                    bytes_sent: bytes_sent,
+                    last_seq: count.saturating_sub(1),
+                    elapsed_ms: elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // asks the sender for its progress, only valid for Receivers
+    pub fn request_stats(&self, db: &Database, state: &VNodeState) {
+        match *self {
+            SyncReceiver { peer, cookie, .. } | BootstrapReceiver { peer, cookie, .. } => {
+                let _ = db.fabric.send_msg(
+                    peer,
+                    &MsgSyncStats {
+                        vnode: state.num(),
+                        cookie: cookie,
+                    },
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn on_msg_stats_ack(&mut self, msg: MsgSyncStatsAck) {
+        match *self {
+            SyncReceiver {
+                ref mut peer_stats,
+                ..
+            }
+            | BootstrapReceiver {
+                ref mut peer_stats,
+                ..
+            } => {
+                *peer_stats = Some(msg);
+            }
+            _ => (),
+        }
+    }
+
+    pub fn peer_stats(&self) -> Option<&MsgSyncStatsAck> {
+        match *self {
+            SyncReceiver { ref peer_stats, .. } | BootstrapReceiver { ref peer_stats, .. } => {
+                peer_stats.as_ref()
+            }
+            _ => None,
+        }
+    }
+
     pub fn direction(&self) -> SyncDirection {
         match *self {
             BootstrapReceiver { .. } | SyncReceiver { .. } => SyncDirection::Incomming,